@@ -0,0 +1,216 @@
+use std::{f64::consts::PI, fmt, str::FromStr};
+
+/// Second order filters of the equalizer, designed after the "Audio EQ
+/// Cookbook" of R. Bristow-Johnson.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FilterKind {
+    Peaking,
+    LowShelf,
+    HighShelf,
+    LowPass,
+    HighPass,
+}
+
+impl FromStr for FilterKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "peak" => Ok(FilterKind::Peaking),
+            "lowshelf" => Ok(FilterKind::LowShelf),
+            "highshelf" => Ok(FilterKind::HighShelf),
+            "lowpass" => Ok(FilterKind::LowPass),
+            "highpass" => Ok(FilterKind::HighPass),
+            _ => Err(format!("unknown filter: {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for FilterKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            FilterKind::Peaking => "peak",
+            FilterKind::LowShelf => "lowshelf",
+            FilterKind::HighShelf => "highshelf",
+            FilterKind::LowPass => "lowpass",
+            FilterKind::HighPass => "highpass",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Biquad in direct form 1, which behaves well when its coefficients change
+/// while it runs.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Biquad {
+    b: [f64; 3],
+    a: [f64; 2],
+    x: [f64; 2],
+    y: [f64; 2],
+}
+
+impl Biquad {
+    /// Coefficients normalized by a0.
+    pub fn new(b: [f64; 3], a: [f64; 2]) -> Self {
+        Biquad {
+            b,
+            a,
+            ..Biquad::default()
+        }
+    }
+
+    /// Designs a filter, `gain` in dB is ignored by the low and high pass.
+    pub fn design(kind: FilterKind, frequency: f64, gain: f64, q: f64, sample_rate: u32) -> Self {
+        let rate = f64::from(sample_rate);
+        // above the Nyquist frequency the design makes no sense
+        let w0 = 2.0 * PI * frequency.max(1.0).min(rate * 0.49) / rate;
+        let (sin, cos) = w0.sin_cos();
+        let alpha = sin / (2.0 * q.max(0.01));
+        let a = 10f64.powf(gain / 40.0);
+        let shelf = 2.0 * a.sqrt() * alpha;
+
+        let (b0, b1, b2, a0, a1, a2) = match kind {
+            FilterKind::Peaking => (
+                1.0 + alpha * a,
+                -2.0 * cos,
+                1.0 - alpha * a,
+                1.0 + alpha / a,
+                -2.0 * cos,
+                1.0 - alpha / a,
+            ),
+            FilterKind::LowShelf => (
+                a * ((a + 1.0) - (a - 1.0) * cos + shelf),
+                2.0 * a * ((a - 1.0) - (a + 1.0) * cos),
+                a * ((a + 1.0) - (a - 1.0) * cos - shelf),
+                (a + 1.0) + (a - 1.0) * cos + shelf,
+                -2.0 * ((a - 1.0) + (a + 1.0) * cos),
+                (a + 1.0) + (a - 1.0) * cos - shelf,
+            ),
+            FilterKind::HighShelf => (
+                a * ((a + 1.0) + (a - 1.0) * cos + shelf),
+                -2.0 * a * ((a - 1.0) + (a + 1.0) * cos),
+                a * ((a + 1.0) + (a - 1.0) * cos - shelf),
+                (a + 1.0) - (a - 1.0) * cos + shelf,
+                2.0 * ((a - 1.0) - (a + 1.0) * cos),
+                (a + 1.0) - (a - 1.0) * cos - shelf,
+            ),
+            FilterKind::LowPass => (
+                (1.0 - cos) / 2.0,
+                1.0 - cos,
+                (1.0 - cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+            FilterKind::HighPass => (
+                (1.0 + cos) / 2.0,
+                -(1.0 + cos),
+                (1.0 + cos) / 2.0,
+                1.0 + alpha,
+                -2.0 * cos,
+                1.0 - alpha,
+            ),
+        };
+        Biquad::new([b0 / a0, b1 / a0, b2 / a0], [a1 / a0, a2 / a0])
+    }
+
+    /// Takes the coefficients of `other` and keeps running from the current
+    /// state.
+    pub fn set_coefficients(&mut self, other: &Biquad) {
+        self.b = other.b;
+        self.a = other.a;
+    }
+
+    pub fn process(&mut self, x: f64) -> f64 {
+        let y = self.b[0] * x + self.b[1] * self.x[0] + self.b[2] * self.x[1]
+            - self.a[0] * self.y[0]
+            - self.a[1] * self.y[1];
+        self.x = [x, self.x[0]];
+        self.y = [y, self.y[0]];
+        y
+    }
+
+    pub fn reset(&mut self) {
+        self.x = [0.0; 2];
+        self.y = [0.0; 2];
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    // gain of the filter for a sine, once the transient has died out
+    fn response(mut filter: Biquad, frequency: f64) -> f64 {
+        let sine = |i: u32| (2.0 * PI * frequency * f64::from(i) / f64::from(RATE)).sin();
+        for i in 0..RATE {
+            filter.process(sine(i));
+        }
+        (RATE..2 * RATE)
+            .map(|i| filter.process(sine(i)).abs())
+            .fold(0.0, f64::max)
+    }
+
+    fn db(gain: f64) -> f64 {
+        20.0 * gain.log10()
+    }
+
+    fn assert_db(actual: f64, expected: f64) {
+        assert!((db(actual) - expected).abs() < 0.1, "{} dB", db(actual));
+    }
+
+    #[test]
+    fn peak_at_its_frequency() {
+        let filter = Biquad::design(FilterKind::Peaking, 1000.0, 6.0, 1.0, RATE);
+        assert_db(response(filter, 1000.0), 6.0);
+        assert_db(response(filter, 20.0), 0.0);
+        assert_db(response(filter, 15000.0), 0.0);
+    }
+
+    #[test]
+    fn shelves() {
+        let low = Biquad::design(FilterKind::LowShelf, 200.0, -9.0, 0.707, RATE);
+        assert_db(response(low, 20.0), -9.0);
+        assert_db(response(low, 10000.0), 0.0);
+        let high = Biquad::design(FilterKind::HighShelf, 5000.0, 4.0, 0.707, RATE);
+        assert_db(response(high, 20.0), 0.0);
+        assert_db(response(high, 20000.0), 4.0);
+    }
+
+    #[test]
+    fn low_and_high_pass() {
+        let low = Biquad::design(FilterKind::LowPass, 1000.0, 0.0, 0.707, RATE);
+        assert_db(response(low, 50.0), 0.0);
+        assert_db(response(low, 1000.0), -3.0);
+        assert!(db(response(low, 10000.0)) < -38.0);
+        let high = Biquad::design(FilterKind::HighPass, 1000.0, 0.0, 0.707, RATE);
+        assert_db(response(high, 15000.0), 0.0);
+        assert!(db(response(high, 100.0)) < -38.0);
+    }
+
+    #[test]
+    fn reset_forgets_the_past_samples() {
+        let mut filter = Biquad::design(FilterKind::LowPass, 100.0, 0.0, 0.707, RATE);
+        let first = filter.process(1.0);
+        filter.process(1.0);
+        filter.reset();
+        assert_eq!(filter.process(1.0), first);
+    }
+
+    #[test]
+    fn names_round_trip() {
+        let kinds = [
+            FilterKind::Peaking,
+            FilterKind::LowShelf,
+            FilterKind::HighShelf,
+            FilterKind::LowPass,
+            FilterKind::HighPass,
+        ];
+        for kind in &kinds {
+            assert_eq!(kind.to_string().parse(), Ok(*kind));
+        }
+        assert!("notch".parse::<FilterKind>().is_err());
+    }
+}
@@ -0,0 +1,176 @@
+use std::{collections::BTreeMap, fs, io, path::PathBuf, time::Duration};
+
+use crate::config::{config_file, write_file};
+
+const BOOKMARKS_FILE: &str = "bookmarks";
+
+/// A named position in a track.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Bookmark {
+    pub name: String,
+    pub position: Duration,
+}
+
+/// Bookmarks of the tracks by path, stored as `millis<TAB>name<TAB>path`
+/// lines in `$XDG_CONFIG_HOME/mmp/bookmarks`.
+pub struct Bookmarks {
+    path: Option<PathBuf>,
+    tracks: BTreeMap<String, Vec<Bookmark>>,
+}
+
+// the fields are separated by tabs, the path comes last since it may contain
+// some
+fn parse_line(line: &str) -> Option<(String, Bookmark)> {
+    let mut fields = line.splitn(3, '\t');
+    let millis = fields.next()?.parse().ok()?;
+    let name = fields.next()?.to_string();
+    let path = fields.next()?.to_string();
+    let position = Duration::from_millis(millis);
+    Some((path, Bookmark { name, position }))
+}
+
+impl Bookmarks {
+    pub fn load() -> Self {
+        Self::load_from(config_file(BOOKMARKS_FILE))
+    }
+
+    pub(crate) fn load_from(path: Option<PathBuf>) -> Self {
+        let mut tracks: BTreeMap<String, Vec<Bookmark>> = BTreeMap::new();
+        let content = path.as_ref().and_then(|path| fs::read_to_string(path).ok());
+        for line in content.as_ref().map_or("", String::as_str).lines() {
+            if let Some((path, bookmark)) = parse_line(line) {
+                tracks.entry(path).or_default().push(bookmark);
+            }
+        }
+        Bookmarks { path, tracks }
+    }
+
+    /// Bookmarks of a track, by position.
+    pub fn get(&self, path: &str) -> &[Bookmark] {
+        self.tracks.get(path).map_or(&[][..], Vec::as_slice)
+    }
+
+    /// Adds a bookmark and writes the file back.
+    pub fn add(&mut self, path: &str, name: &str, position: Duration) {
+        // the name is a field of a line
+        let name = name.replace(|c: char| c == '\t' || c == '\n' || c == '\r', " ");
+        let bookmarks = self.tracks.entry(path.to_string()).or_default();
+        bookmarks.push(Bookmark { name, position });
+        bookmarks.sort_by_key(|bookmark| bookmark.position);
+        self.write();
+    }
+
+    pub fn remove(&mut self, path: &str, index: usize) {
+        let removed = match self.tracks.get_mut(path) {
+            Some(bookmarks) if index < bookmarks.len() => {
+                bookmarks.remove(index);
+                true
+            }
+            _ => false,
+        };
+        if removed {
+            if self.get(path).is_empty() {
+                self.tracks.remove(path);
+            }
+            self.write();
+        }
+    }
+
+    fn write(&self) {
+        if let Err(err) = self.save() {
+            println!("cannot save the bookmarks: {}", err);
+        }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        write_file(path, |file| {
+            for (track, bookmarks) in &self.tracks {
+                for bookmark in bookmarks {
+                    let millis = crate::to_millis(bookmark.position);
+                    writeln!(file, "{}\t{}\t{}", millis, bookmark.name, track)?;
+                }
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mmp-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(BOOKMARKS_FILE)
+    }
+
+    fn bookmark(name: &str, secs: u64) -> Bookmark {
+        Bookmark {
+            name: name.to_string(),
+            position: Duration::from_secs(secs),
+        }
+    }
+
+    #[test]
+    fn bookmarks_round_trip() {
+        let path = temp_path("bookmarks-round-trip");
+        let mut bookmarks = Bookmarks::load_from(Some(path.clone()));
+        bookmarks.add("/music/a b.mp3", "chorus", Duration::from_secs(60));
+        bookmarks.add("/music/a b.mp3", "intro", Duration::from_secs(5));
+        bookmarks.add(
+            "/music/tab\there.flac",
+            "end",
+            Duration::from_millis(90_500),
+        );
+
+        let bookmarks = Bookmarks::load_from(Some(path.clone()));
+        let expected = [bookmark("intro", 5), bookmark("chorus", 60)];
+        assert_eq!(bookmarks.get("/music/a b.mp3"), expected);
+        let end = &bookmarks.get("/music/tab\there.flac")[0];
+        assert_eq!(end.position, Duration::from_millis(90_500));
+        assert!(bookmarks.get("/music/other.ogg").is_empty());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn names_stay_on_their_field() {
+        let path = temp_path("bookmarks-names");
+        let mut bookmarks = Bookmarks::load_from(Some(path.clone()));
+        bookmarks.add(
+            "/music/a.mp3",
+            "two\twords\non two lines",
+            Duration::from_secs(1),
+        );
+
+        let bookmarks = Bookmarks::load_from(Some(path.clone()));
+        let expected = [bookmark("two words on two lines", 1)];
+        assert_eq!(bookmarks.get("/music/a.mp3"), expected);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn removal_is_saved() {
+        let path = temp_path("bookmarks-removal");
+        let mut bookmarks = Bookmarks::load_from(Some(path.clone()));
+        bookmarks.add("/music/a.mp3", "first", Duration::from_secs(1));
+        bookmarks.add("/music/a.mp3", "second", Duration::from_secs(2));
+        bookmarks.remove("/music/a.mp3", 0);
+        bookmarks.remove("/music/a.mp3", 5);
+        assert_eq!(
+            Bookmarks::load_from(Some(path.clone())).get("/music/a.mp3"),
+            [bookmark("second", 2)]
+        );
+
+        bookmarks.remove("/music/a.mp3", 0);
+        assert!(bookmarks.tracks.is_empty());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,169 @@
+// -3 dB, the level of the center and surround channels in a stereo downmix
+const MINUS_3DB: f32 = 0.707_106_77;
+
+/// Highest number of channels the player accepts from a decoder.
+pub const MAX_CHANNELS: usize = 8;
+
+// contribution of each input channel to the left and right outputs, the
+// channels are in the WAV order: FL FR FC LFE BL BR SL SR (the 7.1 layout
+// puts a back center at the place of BL)
+fn stereo_matrix(channels: u16) -> Vec<[f32; 2]> {
+    const LEFT: [f32; 2] = [1.0, 0.0];
+    const RIGHT: [f32; 2] = [0.0, 1.0];
+    const CENTER: [f32; 2] = [MINUS_3DB, MINUS_3DB];
+    const LFE: [f32; 2] = [0.0, 0.0];
+    const SURROUND_LEFT: [f32; 2] = [MINUS_3DB, 0.0];
+    const SURROUND_RIGHT: [f32; 2] = [0.0, MINUS_3DB];
+    const BACK_CENTER: [f32; 2] = [0.5, 0.5];
+
+    let matrix = match channels {
+        1 => vec![[1.0, 1.0]],
+        2 => vec![LEFT, RIGHT],
+        3 => vec![LEFT, RIGHT, CENTER],
+        4 => vec![LEFT, RIGHT, SURROUND_LEFT, SURROUND_RIGHT],
+        5 => vec![LEFT, RIGHT, CENTER, SURROUND_LEFT, SURROUND_RIGHT],
+        6 => vec![LEFT, RIGHT, CENTER, LFE, SURROUND_LEFT, SURROUND_RIGHT],
+        7 => vec![
+            LEFT,
+            RIGHT,
+            CENTER,
+            LFE,
+            BACK_CENTER,
+            SURROUND_LEFT,
+            SURROUND_RIGHT,
+        ],
+        _ => vec![
+            LEFT,
+            RIGHT,
+            CENTER,
+            LFE,
+            SURROUND_LEFT,
+            SURROUND_RIGHT,
+            SURROUND_LEFT,
+            SURROUND_RIGHT,
+        ],
+    };
+
+    // the sum of the contributions must not clip
+    let total = matrix.iter().map(|gains| gains[0]).sum::<f32>().max(1.0);
+    matrix
+        .into_iter()
+        .map(|[left, right]| [left / total, right / total])
+        .collect()
+}
+
+/// Converts the frames of a decoder to the channels the output has been
+/// opened with: the same layout, or stereo by an upmix or a downmix.
+pub struct ChannelMixer {
+    input: u16,
+    output: u16,
+    matrix: Vec<[f32; 2]>,
+}
+
+impl ChannelMixer {
+    pub fn new(input: u16, output: u16) -> Self {
+        ChannelMixer {
+            input,
+            output,
+            matrix: stereo_matrix(input),
+        }
+    }
+
+    pub fn output_channels(&self) -> u16 {
+        self.output
+    }
+
+    /// Replaces `output` by the mixed frames of `input`, which has
+    /// `channels` interleaved channels (it may change within a stream).
+    pub fn mix(&mut self, input: &[f32], channels: u16, output: &mut Vec<f32>) {
+        if channels != self.input {
+            *self = ChannelMixer::new(channels, self.output);
+        }
+
+        output.clear();
+        if self.input == self.output {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        for frame in input.chunks_exact(self.input as usize) {
+            let mut left = 0.0;
+            let mut right = 0.0;
+            for (sample, gains) in frame.iter().zip(&self.matrix) {
+                left += sample * gains[0];
+                right += sample * gains[1];
+            }
+            output.push(left);
+            output.push(right);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mix(input: &[f32], channels: u16, output: u16) -> Vec<f32> {
+        let mut mixed = vec![];
+        ChannelMixer::new(channels, output).mix(input, channels, &mut mixed);
+        mixed
+    }
+
+    fn assert_close(actual: &[f32], expected: &[f32]) {
+        assert_eq!(actual.len(), expected.len());
+        for (actual, expected) in actual.iter().zip(expected) {
+            assert!(
+                (actual - expected).abs() < 1e-6,
+                "{} != {}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn same_layout_is_copied() {
+        let input = [0.1, 0.2, 0.3, 0.4, 0.5, 0.6];
+        assert_eq!(mix(&input, 6, 6), input);
+        assert_eq!(mix(&input, 2, 2), input);
+    }
+
+    #[test]
+    fn mono_goes_to_both_sides() {
+        assert_close(&mix(&[0.5, -0.25], 1, 2), &[0.5, 0.5, -0.25, -0.25]);
+    }
+
+    #[test]
+    fn downmix_5_1() {
+        let total = 1.0 + 2.0 * MINUS_3DB;
+        let left = [1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+        assert_close(&mix(&left, 6, 2), &[1.0 / total, 0.0]);
+        let center = [0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+        assert_close(&mix(&center, 6, 2), &[MINUS_3DB / total, MINUS_3DB / total]);
+        let surround_right = [0.0, 0.0, 0.0, 0.0, 0.0, 1.0];
+        assert_close(&mix(&surround_right, 6, 2), &[0.0, MINUS_3DB / total]);
+        let lfe = [0.0, 0.0, 0.0, 1.0, 0.0, 0.0];
+        assert_close(&mix(&lfe, 6, 2), &[0.0, 0.0]);
+    }
+
+    #[test]
+    fn downmix_does_not_clip() {
+        for channels in 3..=MAX_CHANNELS as u16 {
+            let input = vec![1.0; channels as usize];
+            for sample in mix(&input, channels, 2) {
+                assert!(sample <= 1.0 + 1e-6, "{} channels: {}", channels, sample);
+            }
+        }
+    }
+
+    #[test]
+    fn follows_a_change_of_channels() {
+        let mut mixer = ChannelMixer::new(2, 2);
+        let mut output = vec![];
+        mixer.mix(&[0.5, 0.25], 2, &mut output);
+        assert_eq!(output, [0.5, 0.25]);
+        mixer.mix(&[0.75], 1, &mut output);
+        assert_eq!(output, [0.75, 0.75]);
+        assert_eq!(mixer.output_channels(), 2);
+    }
+}
@@ -0,0 +1,125 @@
+use std::{
+    cell::{Cell, RefCell},
+    rc::Rc,
+};
+
+use gtk::{
+    CellLayoutExt, CellRendererText, ContainerExt, GtkListStoreExt, GtkListStoreExtManual,
+    ListStore, ScrolledWindow, ToValue, TreeModelExt, TreeSelectionExt, TreeView, TreeViewColumn,
+    TreeViewColumnExt, TreeViewExt, Type, WidgetExt,
+};
+
+use mmp::metadata::Chapter;
+
+use crate::{playlist::Playlist, App};
+
+const TITLE_COLUMN: u32 = 0;
+const START_COLUMN: u32 = 1;
+const LENGTH_COLUMN: u32 = 2;
+
+/// Side list of the chapters of the current track, shown when it has some;
+/// activating a chapter plays it.
+pub(crate) struct ChapterList {
+    pub view: ScrolledWindow,
+    treeview: TreeView,
+    model: ListStore,
+    playlist: Rc<Playlist>,
+    // the chapters listed and the one selected
+    shown: RefCell<Option<Rc<Vec<Chapter>>>>,
+    current: Cell<Option<usize>>,
+}
+
+fn add_text_column(treeview: &TreeView, title: &str, column: u32) {
+    let view_column = TreeViewColumn::new();
+    view_column.set_title(title);
+    let renderer = CellRendererText::new();
+    view_column.pack_start(&renderer, true);
+    view_column.add_attribute(&renderer, "text", column as i32);
+    treeview.append_column(&view_column);
+}
+
+impl ChapterList {
+    pub fn new(playlist: Rc<Playlist>) -> Self {
+        let model = ListStore::new(&[Type::String, Type::String, Type::String]);
+        let treeview = TreeView::new_with_model(&model);
+        add_text_column(&treeview, "Chapter", TITLE_COLUMN);
+        add_text_column(&treeview, "Start", START_COLUMN);
+        add_text_column(&treeview, "Length", LENGTH_COLUMN);
+
+        let view = ScrolledWindow::new(None, None);
+        view.set_size_request(250, -1);
+        view.add(&treeview);
+
+        {
+            let playlist = Rc::clone(&playlist);
+            treeview.connect_row_activated(move |_, path, _| {
+                if let Some(index) = path.get_indices().first() {
+                    playlist.seek_chapter(*index as usize);
+                }
+            });
+        }
+
+        ChapterList {
+            view,
+            treeview,
+            model,
+            playlist,
+            shown: RefCell::new(None),
+            current: Cell::new(None),
+        }
+    }
+
+    fn fill(&self, chapters: Option<&Rc<Vec<Chapter>>>) {
+        self.model.clear();
+        for (index, chapter) in chapters
+            .iter()
+            .flat_map(|chapters| chapters.iter())
+            .enumerate()
+        {
+            let title = chapter
+                .title
+                .clone()
+                .unwrap_or_else(|| format!("Chapter {}", index + 1));
+            let start = App::millis_to_minutes(mmp::to_millis(chapter.start));
+            let length = chapter
+                .end
+                .checked_sub(chapter.start)
+                .map(|length| App::millis_to_minutes(mmp::to_millis(length)))
+                .unwrap_or_default();
+
+            let row = self.model.append();
+            self.model.set_value(&row, TITLE_COLUMN, &title.to_value());
+            self.model.set_value(&row, START_COLUMN, &start.to_value());
+            self.model
+                .set_value(&row, LENGTH_COLUMN, &length.to_value());
+        }
+        self.view.set_visible(chapters.is_some());
+    }
+
+    /// Follows the current track and its position, returns true when
+    /// another chapter started.
+    pub fn update(&self) -> bool {
+        let chapters = self.playlist.chapters();
+        let listed = match (&*self.shown.borrow(), &chapters) {
+            (Some(shown), Some(chapters)) => Rc::ptr_eq(shown, chapters),
+            (None, None) => true,
+            _ => false,
+        };
+        if !listed {
+            self.fill(chapters.as_ref());
+            *self.shown.borrow_mut() = chapters;
+            self.current.set(None);
+        }
+
+        let current = self.playlist.current_chapter();
+        if current == self.current.get() {
+            return false;
+        }
+        self.current.set(current);
+        let row = current.and_then(|index| self.model.iter_nth_child(None, index as i32));
+        if let Some(row) = row {
+            self.treeview.get_selection().select_iter(&row);
+        }
+        current.is_some()
+    }
+}
@@ -0,0 +1,224 @@
+use std::{
+    collections::BTreeMap,
+    env,
+    fs::{self, File},
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+const CONFIG_FILE: &str = "config";
+
+/// Settings kept between two runs, stored as `key = value` lines in
+/// `$XDG_CONFIG_HOME/mmp/config`.
+pub struct Config {
+    path: Option<PathBuf>,
+    values: BTreeMap<String, String>,
+    // changed since the file was written
+    dirty: bool,
+}
+
+// directory of the files of mmp, `~/.config/mmp` by default
+fn config_dir() -> Option<PathBuf> {
+    env::var_os("XDG_CONFIG_HOME")
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .map(|dir| dir.join("mmp"))
+}
+
+/// Path of a file of the configuration directory, `None` without a home.
+pub fn config_file(name: &str) -> Option<PathBuf> {
+    config_dir().map(|dir| dir.join(name))
+}
+
+/// Replaces the file at `path` by what `write` outputs. The lines go to a
+/// temporary file first, renamed over the old one once complete, so that a
+/// crash or a full disk never leaves a truncated file.
+pub fn write_file<F>(path: &Path, write: F) -> io::Result<()>
+where
+    F: FnOnce(&mut dyn Write) -> io::Result<()>,
+{
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+
+    let mut temp_name = path.as_os_str().to_owned();
+    temp_name.push(".tmp");
+    let temp = PathBuf::from(temp_name);
+    let written = File::create(&temp).and_then(|file| {
+        let mut writer = BufWriter::new(file);
+        write(&mut writer)?;
+        writer.into_inner()?.sync_all()
+    });
+    match written {
+        Ok(()) => fs::rename(&temp, path),
+        Err(err) => {
+            let _ = fs::remove_file(&temp);
+            Err(err)
+        }
+    }
+}
+
+impl Config {
+    pub fn load() -> Self {
+        Self::load_from(config_file(CONFIG_FILE))
+    }
+
+    pub(crate) fn load_from(path: Option<PathBuf>) -> Self {
+        let values = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| {
+                content
+                    .lines()
+                    .map(str::trim)
+                    .filter(|line| !line.is_empty() && !line.starts_with('#'))
+                    .filter_map(|line| {
+                        let mut parts = line.splitn(2, '=');
+                        let key = parts.next()?.trim();
+                        let value = parts.next()?.trim();
+                        Some((key.to_string(), value.to_string()))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        Config {
+            path,
+            values,
+            dirty: false,
+        }
+    }
+
+    pub fn get<T: FromStr>(&self, key: &str) -> Option<T> {
+        self.values.get(key).and_then(|value| value.parse().ok())
+    }
+
+    /// The keys starting with `prefix`, without it, e.g. the names of a
+    /// family of settings.
+    pub fn keys_with_prefix<'a>(&'a self, prefix: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        self.values
+            .keys()
+            .filter(move |key| key.starts_with(prefix))
+            .map(move |key| &key[prefix.len()..])
+    }
+
+    /// Changes a setting and writes the file back.
+    pub fn set<T: ToString>(&mut self, key: &str, value: T) {
+        self.update(key, value);
+        self.save();
+    }
+
+    /// Changes a setting, written by `save`, for the ones which change
+    /// often, e.g. while a slider moves.
+    pub fn update<T: ToString>(&mut self, key: &str, value: T) {
+        let value = value.to_string();
+        if self.values.get(key) != Some(&value) {
+            self.values.insert(key.to_string(), value);
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the file back if some setting changed.
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        match self.write() {
+            Ok(()) => self.dirty = false,
+            Err(err) => println!("cannot save the configuration: {}", err),
+        }
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        write_file(path, |file| {
+            for (key, value) in &self.values {
+                writeln!(file, "{} = {}", key, value)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::process;
+
+    use super::*;
+
+    // in a directory of its own, which `write_file` creates
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mmp-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(CONFIG_FILE)
+    }
+
+    #[test]
+    fn values_round_trip() {
+        let path = temp_path("config-round-trip");
+        let mut config = Config::load_from(Some(path.clone()));
+        assert_eq!(config.get::<f64>("volume"), None);
+        config.set("volume", 0.5);
+        config.set("sink", "alsa");
+
+        let config = Config::load_from(Some(path.clone()));
+        assert_eq!(config.get("volume"), Some(0.5));
+        assert_eq!(config.get("sink"), Some("alsa".to_string()));
+        assert_eq!(config.get::<u32>("sink"), None);
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        assert!(!PathBuf::from(temp).exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn updates_wait_for_save() {
+        let path = temp_path("config-update");
+        let mut config = Config::load_from(Some(path.clone()));
+        config.update("balance", -0.25);
+        assert!(!path.exists());
+        config.save();
+        assert_eq!(
+            Config::load_from(Some(path.clone())).get("balance"),
+            Some(-0.25)
+        );
+
+        // an unchanged value does not write the file again
+        fs::remove_file(&path).unwrap();
+        config.update("balance", -0.25);
+        config.save();
+        assert!(!path.exists());
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn comments_and_spaces_are_skipped() {
+        let path = temp_path("config-parse");
+        write_file(&path, |file| {
+            writeln!(
+                file,
+                "# comment\n\n  speed =  1.5 \nnot a setting\nurl = a=b"
+            )
+        })
+        .unwrap();
+        let config = Config::load_from(Some(path.clone()));
+        assert_eq!(config.get("speed"), Some(1.5));
+        assert_eq!(config.get("url"), Some("a=b".to_string()));
+        assert_eq!(config.values.len(), 2);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn keys_by_prefix() {
+        let mut config = Config::load_from(None);
+        config.set("eq.preset.rock", "1,2");
+        config.set("eq.preset.jazz", "3,4");
+        config.set("eq.enabled", true);
+        let names: Vec<_> = config.keys_with_prefix("eq.preset.").collect();
+        assert_eq!(names, ["jazz", "rock"]);
+    }
+}
@@ -0,0 +1,172 @@
+use std::{f32::consts::FRAC_PI_2, str::FromStr, time::Duration};
+
+/// Longest crossfade accepted in the configuration.
+pub const MAX_CROSSFADE: Duration = Duration::from_secs(12);
+
+/// How the volumes of the two tracks evolve during a crossfade.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FadeCurve {
+    /// The gains sum to 1, there is a dip in loudness in the middle of the
+    /// fade unless the tracks are alike.
+    Linear,
+    /// The powers sum to 1, the loudness stays even for unrelated tracks.
+    EqualPower,
+}
+
+impl Default for FadeCurve {
+    fn default() -> Self {
+        FadeCurve::EqualPower
+    }
+}
+
+impl FromStr for FadeCurve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "linear" => Ok(FadeCurve::Linear),
+            "equal-power" => Ok(FadeCurve::EqualPower),
+            _ => Err(format!("unknown crossfade curve: {}", s)),
+        }
+    }
+}
+
+impl FadeCurve {
+    // gains of the outgoing and the incoming tracks, `progress` in [0, 1]
+    fn gains(self, progress: f32) -> (f32, f32) {
+        match self {
+            FadeCurve::Linear => (1.0 - progress, progress),
+            FadeCurve::EqualPower => {
+                let angle = progress * FRAC_PI_2;
+                (angle.cos(), angle.sin())
+            }
+        }
+    }
+}
+
+/// How much of the end of a track, `remaining` away from it, overlaps the
+/// next one for a crossfade of `crossfade`: all of it once it is that near,
+/// even when the track is shorter than the crossfade.
+pub fn overlap(remaining: Option<Duration>, crossfade: Duration) -> Option<Duration> {
+    match remaining {
+        Some(remaining) if remaining > Duration::from_secs(0) && remaining <= crossfade => {
+            Some(remaining)
+        }
+        _ => None,
+    }
+}
+
+/// Mixes the end of a track with the beginning of the next one, over a
+/// given number of frames.
+pub struct Crossfade {
+    curve: FadeCurve,
+    length: u64,
+    done: u64,
+}
+
+impl Crossfade {
+    pub fn new(curve: FadeCurve, length: u64) -> Self {
+        Crossfade {
+            curve,
+            length,
+            done: 0,
+        }
+    }
+
+    pub fn is_finished(&self) -> bool {
+        self.done >= self.length
+    }
+
+    /// Mixes `outgoing` into `incoming`, both interleaved, from where the fade
+    /// stopped; `outgoing` may be shorter when its track ended early.
+    pub fn mix(&mut self, outgoing: &[f32], incoming: &mut [f32], channels: u16) {
+        let channels = channels as usize;
+        for (index, frame) in incoming.chunks_mut(channels).enumerate() {
+            let progress = if self.length == 0 {
+                1.0
+            } else {
+                (self.done as f32 / self.length as f32).min(1.0)
+            };
+            let (fade_out, fade_in) = self.curve.gains(progress);
+            let start = index * channels;
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let other = outgoing.get(start + channel).cloned().unwrap_or(0.0);
+                *sample = *sample * fade_in + other * fade_out;
+            }
+            self.done += 1;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn close(a: f32, b: f32) -> bool {
+        (a - b).abs() < 1e-6
+    }
+
+    #[test]
+    fn curves() {
+        for step in 0..=10 {
+            let progress = step as f32 / 10.0;
+            let (fade_out, fade_in) = FadeCurve::Linear.gains(progress);
+            assert!(close(fade_out + fade_in, 1.0));
+            let (fade_out, fade_in) = FadeCurve::EqualPower.gains(progress);
+            assert!(close(fade_out * fade_out + fade_in * fade_in, 1.0));
+        }
+        for &curve in &[FadeCurve::Linear, FadeCurve::EqualPower] {
+            let (fade_out, fade_in) = curve.gains(0.0);
+            assert!(close(fade_out, 1.0) && close(fade_in, 0.0));
+            let (fade_out, fade_in) = curve.gains(1.0);
+            assert!(close(fade_out, 0.0) && close(fade_in, 1.0));
+        }
+        // louder in the middle than a linear fade
+        assert!(FadeCurve::EqualPower.gains(0.5).0 > FadeCurve::Linear.gains(0.5).0);
+
+        assert_eq!("linear".parse(), Ok(FadeCurve::Linear));
+        assert_eq!("equal-power".parse(), Ok(FadeCurve::EqualPower));
+        assert!("cosine".parse::<FadeCurve>().is_err());
+    }
+
+    #[test]
+    fn fade_goes_on_across_buffers() {
+        let mut crossfade = Crossfade::new(FadeCurve::Linear, 4);
+        // stereo, the outgoing track at 1, the incoming one at 0.5
+        let mut incoming = vec![0.5; 4];
+        crossfade.mix(&[1.0; 4], &mut incoming, 2);
+        assert_eq!(incoming, [1.0, 1.0, 0.875, 0.875]);
+        assert!(!crossfade.is_finished());
+
+        // the outgoing track ended early, silence is faded out
+        let mut incoming = vec![0.5; 6];
+        crossfade.mix(&[1.0; 2], &mut incoming, 2);
+        assert_eq!(incoming, [0.75, 0.75, 0.375, 0.375, 0.5, 0.5]);
+        assert!(crossfade.is_finished());
+    }
+
+    #[test]
+    fn track_shorter_than_the_crossfade() {
+        let crossfade = Duration::from_secs(5);
+        let short = Some(Duration::from_secs(3));
+        assert_eq!(overlap(short, crossfade), short);
+        assert_eq!(overlap(Some(crossfade), crossfade), Some(crossfade));
+        assert_eq!(overlap(Some(Duration::from_secs(6)), crossfade), None);
+        assert_eq!(overlap(None, crossfade), None);
+    }
+
+    #[test]
+    fn zero_second_crossfade() {
+        let zero = Duration::from_secs(0);
+        assert_eq!(overlap(Some(zero), zero), None);
+        assert_eq!(overlap(Some(Duration::from_millis(1)), zero), None);
+        assert_eq!(overlap(Some(zero), Duration::from_secs(5)), None);
+
+        // the incoming track plays at once
+        let mut crossfade = Crossfade::new(FadeCurve::Linear, 0);
+        assert!(crossfade.is_finished());
+        let mut incoming = vec![0.5; 4];
+        crossfade.mix(&[1.0; 4], &mut incoming, 2);
+        assert_eq!(incoming, [0.5; 4]);
+    }
+}
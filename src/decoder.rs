@@ -0,0 +1,265 @@
+use std::{
+    fmt,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::Path,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use id3::Tag;
+
+use crate::{
+    flac, id3v2,
+    metadata::{self, Metadata},
+    mp3::{self, Mp3Decoder},
+    mp4, ogg, pcm,
+    replaygain::ReplayGain,
+};
+
+// number of bytes read at the beginning of a file to recognize its format
+const PROBE_SIZE: usize = 4096;
+// errors printed for a stream, a damaged one may fail on every packet
+const MAX_REPORTED_ERRORS: u32 = 5;
+
+/// A source of audio for the player.
+pub trait Decoder {
+    fn sample_rate(&self) -> u32;
+
+    fn channels(&self) -> u16;
+
+    /// Duration of the whole stream when it is known without decoding it.
+    fn duration(&self) -> Option<Duration>;
+
+    fn seek(&mut self, position: Duration) -> io::Result<()>;
+
+    /// Fills `buffer` with interleaved samples in [-1, 1] (a decoder may
+    /// exceed it slightly), returns how many have been written, 0 once the
+    /// stream is over. The channels are in the WAV order:
+    /// front left, front right, center, LFE, back left, back right...
+    fn read(&mut self, buffer: &mut [f32]) -> usize;
+}
+
+/// Reader shared between a decoding library and the seek logic: libraries
+/// like libmad take ownership of their reader, we keep a second handle to
+/// move it.
+pub struct SharedReader<R>(Arc<Mutex<R>>);
+
+impl<R> SharedReader<R> {
+    pub fn new(reader: R) -> Self {
+        SharedReader(Arc::new(Mutex::new(reader)))
+    }
+}
+
+impl<R> Clone for SharedReader<R> {
+    fn clone(&self) -> Self {
+        SharedReader(Arc::clone(&self.0))
+    }
+}
+
+impl<R: Read> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().read(buf)
+    }
+}
+
+impl<R: Seek> Seek for SharedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.0.lock().unwrap().seek(pos)
+    }
+}
+
+/// Reads until `buffer` is full or the end of the stream, returns the number
+/// of bytes read.
+pub fn read_full<R: Read>(data: &mut R, buffer: &mut [u8]) -> usize {
+    let mut length = 0;
+    while length < buffer.len() {
+        match data.read(&mut buffer[length..]) {
+            Ok(0) | Err(_) => break,
+            Ok(size) => length += size,
+        }
+    }
+    length
+}
+
+/// Prints the errors of a stream on stderr, the first ones only.
+#[derive(Default)]
+pub struct ErrorLog {
+    count: u32,
+}
+
+impl ErrorLog {
+    pub fn report(&mut self, error: fmt::Arguments) {
+        self.count = self.count.saturating_add(1);
+        if self.count <= MAX_REPORTED_ERRORS {
+            eprintln!("{}", error);
+        }
+        if self.count == MAX_REPORTED_ERRORS {
+            eprintln!("the next errors of this stream are not reported");
+        }
+    }
+}
+
+// rounded up, so that `duration_to_samples` gives the same sample back
+pub fn samples_to_duration(samples: u64, sample_rate: u32) -> Duration {
+    let rate = u64::from(sample_rate.max(1));
+    Duration::from_secs(samples / rate)
+        + Duration::from_nanos((samples % rate * 1_000_000_000 + rate - 1) / rate)
+}
+
+pub fn duration_to_samples(duration: Duration, sample_rate: u32) -> u64 {
+    let rate = u64::from(sample_rate);
+    duration.as_secs() * rate + u64::from(duration.subsec_nanos()) * rate / 1_000_000_000
+}
+
+struct Format {
+    name: &'static str,
+    /// Tells if the first bytes of a file are of this format.
+    sniff: fn(&[u8]) -> bool,
+    open: fn(File) -> io::Result<Box<dyn Decoder>>,
+    /// Computes the duration from the headers, quickly.
+    duration: fn(File) -> Option<Duration>,
+    /// Computes the duration by scanning the whole file, when the headers
+    /// do not tell it.
+    scan_duration: Option<fn(File) -> Option<Duration>>,
+    metadata: fn(File) -> Option<Metadata>,
+}
+
+fn open_mp3(file: File) -> io::Result<Box<dyn Decoder>> {
+    Mp3Decoder::new(BufReader::new(file))
+        .map(|decoder| Box::new(decoder) as Box<dyn Decoder>)
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid MP3 stream"))
+}
+
+fn mp3_duration(file: File) -> Option<Duration> {
+    mp3::header_duration(&mut BufReader::new(file))
+}
+
+fn mp3_scan_duration(file: File) -> Option<Duration> {
+    Mp3Decoder::compute_duration(BufReader::new(file))
+}
+
+fn mp3_metadata(mut file: File) -> Option<Metadata> {
+    let mut metadata = Tag::read_from(&mut file)
+        .ok()
+        .map(|tag| Metadata::from_id3(&tag))?;
+    // iTunes writes its gapless flag as a comment, other taggers as a user text
+    let frames = id3v2::read_frames(&mut file);
+    metadata.gapless = id3v2::find_text(&frames, "COMM", "iTunPGAP")
+        .or_else(|| id3v2::find_text(&frames, "TXXX", "ITUNESGAPLESS"))
+        .map_or(false, |value| metadata::is_gapless_flag(&value));
+    metadata.replay_gain = ReplayGain::from_id3_frames(&frames);
+    metadata.chapters = id3v2::read_chapters(&frames);
+    Some(metadata)
+}
+
+fn no_metadata(_: File) -> Option<Metadata> {
+    None
+}
+
+fn flac_duration(file: File) -> Option<Duration> {
+    flac::compute_duration(BufReader::new(file))
+}
+
+// the formats with a weak signature come last
+const FORMATS: &[Format] = &[
+    Format {
+        name: "FLAC",
+        sniff: flac::sniff,
+        open: flac::open,
+        duration: flac_duration,
+        scan_duration: None,
+        metadata: flac::read_metadata,
+    },
+    Format {
+        name: "Ogg",
+        sniff: ogg::sniff,
+        open: ogg::open,
+        duration: ogg::compute_duration,
+        scan_duration: None,
+        metadata: ogg::read_metadata,
+    },
+    Format {
+        name: "MP4",
+        sniff: mp4::sniff,
+        open: mp4::open,
+        duration: mp4::compute_duration,
+        scan_duration: None,
+        metadata: mp4::read_metadata,
+    },
+    Format {
+        name: "PCM",
+        sniff: pcm::sniff,
+        open: pcm::open,
+        duration: pcm::compute_duration,
+        scan_duration: None,
+        metadata: no_metadata,
+    },
+    Format {
+        name: "MP3",
+        sniff: mp3::sniff,
+        open: open_mp3,
+        duration: mp3_duration,
+        scan_duration: Some(mp3_scan_duration),
+        metadata: mp3_metadata,
+    },
+];
+
+fn probe(path: &Path) -> io::Result<(&'static Format, File)> {
+    let mut header = Vec::with_capacity(PROBE_SIZE);
+    File::open(path)?
+        .take(PROBE_SIZE as u64)
+        .read_to_end(&mut header)?;
+
+    let format = FORMATS
+        .iter()
+        .find(|format| (format.sniff)(&header))
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "unknown audio format"))?;
+    Ok((format, File::open(path)?))
+}
+
+/// Opens a decoder for the file, its format is recognized from its content
+/// rather than from its extension.
+pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Decoder>> {
+    let (format, file) = probe(path.as_ref())?;
+    (format.open)(file)
+}
+
+/// Duration from the headers of the file, `None` when they do not tell it
+/// (see `scan_duration`).
+pub fn duration<P: AsRef<Path>>(path: P) -> Option<Duration> {
+    let (format, file) = probe(path.as_ref()).ok()?;
+    (format.duration)(file)
+}
+
+/// Duration from a scan of the whole file, slow.
+pub fn scan_duration<P: AsRef<Path>>(path: P) -> Option<Duration> {
+    let (format, file) = probe(path.as_ref()).ok()?;
+    (format.scan_duration?)(file)
+}
+
+/// Name of the format of the file, if it is supported.
+pub fn format_name<P: AsRef<Path>>(path: P) -> Option<&'static str> {
+    probe(path.as_ref()).ok().map(|(format, _)| format.name)
+}
+
+/// Tags of the file, read by the format specific way.
+pub fn metadata<P: AsRef<Path>>(path: P) -> Option<Metadata> {
+    let (format, file) = probe(path.as_ref()).ok()?;
+    (format.metadata)(file)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn samples_and_durations_round_trip() {
+        for rate in &[8000, 11025, 44100, 48000, 96000] {
+            for samples in (0..3 * u64::from(*rate)).step_by(7) {
+                let duration = samples_to_duration(samples, *rate);
+                assert_eq!(duration_to_samples(duration, *rate), samples);
+            }
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::str::FromStr;
+
+use crate::shuffle::XorShift;
+
+/// Noise added before the samples are rounded to the integers of the output,
+/// it turns the quantization error into a constant hiss uncorrelated with
+/// the signal.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum DitherKind {
+    None,
+    /// Triangular noise of 2 LSB peak to peak.
+    Tpdf,
+    /// TPDF whose error is fed back so that the noise moves to the high
+    /// frequencies, where the ear is less sensitive.
+    NoiseShaped,
+}
+
+impl Default for DitherKind {
+    fn default() -> Self {
+        DitherKind::Tpdf
+    }
+}
+
+impl FromStr for DitherKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "none" => Ok(DitherKind::None),
+            "tpdf" => Ok(DitherKind::Tpdf),
+            "shaped" => Ok(DitherKind::NoiseShaped),
+            _ => Err(format!("unknown dither: {}", s)),
+        }
+    }
+}
+
+/// Converts the `f32` samples of the pipeline to integers, the only place
+/// where they lose precision.
+pub struct Dither {
+    kind: DitherKind,
+    rng: XorShift,
+    // the last two errors of each channel, for the noise shaping
+    errors: Vec<[f32; 2]>,
+}
+
+impl Dither {
+    pub fn new(kind: DitherKind) -> Self {
+        Dither {
+            kind,
+            rng: XorShift::new(0),
+            errors: vec![],
+        }
+    }
+
+    // uniform in [-0.5, 0.5)
+    fn uniform(&mut self) -> f32 {
+        (self.rng.next() >> 40) as f32 / (1 << 24) as f32 - 0.5
+    }
+
+    /// Quantizes interleaved samples in [-1, 1] to signed integers of `bits`
+    /// bits, appended to `output`. Out of range samples are clipped.
+    pub fn quantize(&mut self, samples: &[f32], channels: u16, bits: u32, output: &mut Vec<i32>) {
+        let scale = (1i64 << (bits - 1)) as f32;
+        let max = scale - 1.0;
+        // dithering below 24 bits is lost in the noise of any converter
+        let kind = if bits > 24 {
+            DitherKind::None
+        } else {
+            self.kind
+        };
+
+        let channels = channels.max(1) as usize;
+        if self.errors.len() != channels {
+            self.errors = vec![[0.0; 2]; channels];
+        }
+
+        for (i, sample) in samples.iter().enumerate() {
+            let value = sample * scale;
+            let quantized = match kind {
+                DitherKind::None => value.round(),
+                DitherKind::Tpdf => (value + self.uniform() + self.uniform()).round(),
+                DitherKind::NoiseShaped => {
+                    let [previous, before] = self.errors[i % channels];
+                    // the error of the output is (1 - z^-1)^2 times the error
+                    // of the quantizer, a second order high-pass
+                    let shaped = value - (2.0 * previous - before);
+                    let quantized = (shaped + self.uniform() + self.uniform()).round();
+                    // bounded so that a clipped passage cannot make it diverge
+                    let error = (quantized - shaped).max(-2.0).min(2.0);
+                    self.errors[i % channels] = [error, previous];
+                    quantized
+                }
+            };
+            output.push(quantized.max(-scale).min(max) as i32);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LSB: f32 = 1.0 / 32768.0;
+
+    fn quantize(kind: DitherKind, samples: &[f32], bits: u32) -> Vec<i32> {
+        let mut output = vec![];
+        Dither::new(kind).quantize(samples, 1, bits, &mut output);
+        output
+    }
+
+    fn mean(values: &[i32]) -> f64 {
+        values.iter().map(|x| f64::from(*x)).sum::<f64>() / values.len() as f64
+    }
+
+    #[test]
+    fn no_dither_rounds_and_clips() {
+        let samples = [0.0, 0.4 * LSB, 0.6 * LSB, -1.4 * LSB, 1.0, -1.0, 2.0, -2.0];
+        let expected = [0, 0, 1, -1, 32767, -32768, 32767, -32768];
+        assert_eq!(quantize(DitherKind::None, &samples, 16), expected);
+    }
+
+    #[test]
+    fn no_dither_past_24_bits() {
+        let samples = [0.3 * LSB; 100];
+        let output = quantize(DitherKind::Tpdf, &samples, 32);
+        assert!(output.iter().all(|x| *x == output[0]));
+    }
+
+    #[test]
+    fn tpdf_keeps_what_is_below_the_lsb() {
+        // rounding alone would make all of them 0
+        let samples = vec![0.25 * LSB; 100_000];
+        let output = quantize(DitherKind::Tpdf, &samples, 16);
+        assert!(output.iter().all(|x| (-1..=2).contains(x)));
+        let mean = mean(&output);
+        assert!((mean - 0.25).abs() < 0.01, "mean {}", mean);
+    }
+
+    #[test]
+    fn noise_shaping_keeps_the_level() {
+        let samples = vec![-10.25 * LSB; 100_000];
+        let output = quantize(DitherKind::NoiseShaped, &samples, 16);
+        assert!(output.iter().all(|x| (x + 10).abs() <= 6));
+        let mean = mean(&output);
+        assert!((mean + 10.25).abs() < 0.01, "mean {}", mean);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!("shaped".parse(), Ok(DitherKind::NoiseShaped));
+        assert!("triangle".parse::<DitherKind>().is_err());
+    }
+}
@@ -0,0 +1,453 @@
+use std::{fmt, mem, str::FromStr};
+
+use crate::{
+    biquad::{Biquad, FilterKind},
+    filter::AudioFilter,
+    volume::Gain,
+};
+
+/// Center frequencies of the graphic equalizer, an octave apart.
+pub const GRAPHIC_FREQUENCIES: [f32; 10] = [
+    31.0, 62.0, 125.0, 250.0, 500.0, 1000.0, 2000.0, 4000.0, 8000.0, 16000.0,
+];
+/// Range of the gains of the bands and of the preamp, in dB.
+pub const MAX_GAIN: f32 = 12.0;
+// Q of an octave wide peak
+const GRAPHIC_Q: f32 = 1.41;
+// a change of the settings fades between the previous and the new filters
+// over this many milliseconds, longer than the ramp of the preamp
+const TRANSITION_MILLIS: u32 = 50;
+
+/// Presets shipped with the player, gains of the graphic bands.
+pub const BUILTIN_PRESETS: &[(&str, [f32; 10])] = &[
+    ("Flat", [0.0; 10]),
+    ("Rock", [5.0, 4.0, 3.0, 1.0, -1.0, -1.0, 1.0, 3.0, 4.0, 5.0]),
+    ("Pop", [-1.0, 1.0, 3.0, 4.0, 3.0, 0.0, -1.0, -1.0, 0.0, 1.0]),
+    ("Jazz", [3.0, 2.0, 1.0, 2.0, -1.0, -1.0, 0.0, 1.0, 2.0, 3.0]),
+    (
+        "Classical",
+        [4.0, 3.0, 2.0, 1.0, -1.0, -1.0, 0.0, 2.0, 3.0, 4.0],
+    ),
+    (
+        "Bass boost",
+        [6.0, 5.0, 4.0, 2.0, 0.0, 0.0, 0.0, 0.0, 0.0, 0.0],
+    ),
+    (
+        "Treble boost",
+        [0.0, 0.0, 0.0, 0.0, 0.0, 1.0, 2.0, 4.0, 5.0, 6.0],
+    ),
+    (
+        "Vocal",
+        [-2.0, -2.0, -1.0, 1.0, 3.0, 4.0, 3.0, 1.0, 0.0, -1.0],
+    ),
+];
+
+/// A filter of the parametric equalizer.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Band {
+    pub kind: FilterKind,
+    pub frequency: f32,
+    /// In dB, for the peaks and the shelves.
+    pub gain: f32,
+    pub q: f32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum EqualizerMode {
+    Graphic,
+    Parametric,
+}
+
+/// What the equalizer does, saved in the configuration and in the presets
+/// as `mode|preamp|graphic gains|parametric bands`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct EqualizerSettings {
+    pub mode: EqualizerMode,
+    /// Gain in dB before the filters, to make room for the boosts.
+    pub preamp: f32,
+    pub graphic: [f32; 10],
+    pub parametric: Vec<Band>,
+}
+
+impl Default for EqualizerSettings {
+    fn default() -> Self {
+        EqualizerSettings {
+            mode: EqualizerMode::Graphic,
+            preamp: 0.0,
+            graphic: [0.0; 10],
+            parametric: vec![],
+        }
+    }
+}
+
+impl EqualizerSettings {
+    /// Settings of a built-in preset, the preamp compensates the largest
+    /// boost.
+    pub fn from_preset(gains: [f32; 10]) -> Self {
+        let boost = gains.iter().cloned().fold(0.0, f32::max);
+        EqualizerSettings {
+            preamp: -boost,
+            graphic: gains,
+            ..EqualizerSettings::default()
+        }
+    }
+
+    // the filters of the current mode
+    fn bands(&self) -> Vec<Band> {
+        match self.mode {
+            EqualizerMode::Graphic => GRAPHIC_FREQUENCIES
+                .iter()
+                .zip(self.graphic.iter())
+                .map(|(frequency, gain)| Band {
+                    kind: FilterKind::Peaking,
+                    frequency: *frequency,
+                    gain: *gain,
+                    q: GRAPHIC_Q,
+                })
+                .collect(),
+            EqualizerMode::Parametric => self.parametric.clone(),
+        }
+    }
+}
+
+impl fmt::Display for EqualizerSettings {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mode = match self.mode {
+            EqualizerMode::Graphic => "graphic",
+            EqualizerMode::Parametric => "parametric",
+        };
+        let graphic: Vec<_> = self.graphic.iter().map(f32::to_string).collect();
+        let parametric: Vec<_> = self
+            .parametric
+            .iter()
+            .map(|band| format!("{}:{}:{}:{}", band.kind, band.frequency, band.gain, band.q))
+            .collect();
+        write!(
+            f,
+            "{}|{}|{}|{}",
+            mode,
+            self.preamp,
+            graphic.join(","),
+            parametric.join(",")
+        )
+    }
+}
+
+impl FromStr for EqualizerSettings {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        let invalid = || format!("invalid equalizer settings: {}", s);
+        let parts: Vec<_> = s.split('|').map(str::trim).collect();
+        if parts.len() != 4 {
+            return Err(invalid());
+        }
+
+        let mode = match parts[0] {
+            "graphic" => EqualizerMode::Graphic,
+            "parametric" => EqualizerMode::Parametric,
+            _ => return Err(invalid()),
+        };
+        let preamp = parts[1].parse().map_err(|_| invalid())?;
+
+        let mut graphic = [0.0; 10];
+        let gains = parts[2].split(',').filter(|gain| !gain.is_empty());
+        for (index, gain) in gains.enumerate() {
+            *graphic.get_mut(index).ok_or_else(invalid)? = gain.parse().map_err(|_| invalid())?;
+        }
+
+        let mut parametric = vec![];
+        for band in parts[3].split(',').filter(|band| !band.is_empty()) {
+            let fields: Vec<_> = band.split(':').collect();
+            if fields.len() != 4 {
+                return Err(invalid());
+            }
+            parametric.push(Band {
+                kind: fields[0].parse()?,
+                frequency: fields[1].parse().map_err(|_| invalid())?,
+                gain: fields[2].parse().map_err(|_| invalid())?,
+                q: fields[3].parse().map_err(|_| invalid())?,
+            });
+        }
+
+        Ok(EqualizerSettings {
+            mode,
+            preamp,
+            graphic,
+            parametric,
+        })
+    }
+}
+
+// one filter per band and per channel, band after band
+fn design(bands: &[Band], channels: usize, sample_rate: u32) -> Vec<Biquad> {
+    bands
+        .iter()
+        .flat_map(|band| {
+            let filter = Biquad::design(
+                band.kind,
+                f64::from(band.frequency),
+                f64::from(band.gain),
+                f64::from(band.q),
+                sample_rate,
+            );
+            vec![filter; channels]
+        })
+        .collect()
+}
+
+fn filter(filters: &mut [Biquad], channels: usize, channel: usize, sample: f32) -> f32 {
+    filters
+        .iter_mut()
+        .skip(channel)
+        .step_by(channels)
+        .fold(f64::from(sample), |sample, filter| filter.process(sample)) as f32
+}
+
+/// Equalizer running in the player thread, its settings can change while it
+/// plays: the previous filters fade out as the new ones fade in.
+pub struct Equalizer {
+    settings: Option<EqualizerSettings>,
+    channels: usize,
+    sample_rate: u32,
+    filters: Vec<Biquad>,
+    previous: Vec<Biquad>,
+    // frames left in the transition from `previous` to `filters`
+    transition: u32,
+    transition_length: u32,
+    preamp: Gain,
+}
+
+impl Default for Equalizer {
+    fn default() -> Self {
+        Equalizer::new()
+    }
+}
+
+impl Equalizer {
+    pub fn new() -> Self {
+        Equalizer {
+            settings: None,
+            channels: 0,
+            sample_rate: 0,
+            filters: vec![],
+            previous: vec![],
+            transition: 0,
+            transition_length: 1,
+            preamp: Gain::new(1.0),
+        }
+    }
+
+    /// Changes the settings, `None` disables the equalizer.
+    pub fn set(&mut self, settings: Option<EqualizerSettings>) {
+        if settings == self.settings {
+            return;
+        }
+        self.settings = settings;
+        if self.sample_rate == 0 {
+            return;
+        }
+
+        let bands = self.settings.as_ref().map(EqualizerSettings::bands);
+        let designed = design(&bands.unwrap_or_default(), self.channels, self.sample_rate);
+        // the filters which stay keep running, the others start from silence
+        let filters = if designed.len() == self.filters.len() {
+            let mut filters = self.filters.clone();
+            for (filter, new) in filters.iter_mut().zip(designed.iter()) {
+                filter.set_coefficients(new);
+            }
+            filters
+        } else {
+            designed
+        };
+        self.previous = mem::replace(&mut self.filters, filters);
+        self.transition_length = (self.sample_rate * TRANSITION_MILLIS / 1000).max(1);
+        self.transition = self.transition_length;
+        self.preamp.set(self.preamp_gain(), self.sample_rate);
+    }
+
+    fn preamp_gain(&self) -> f32 {
+        self.settings
+            .as_ref()
+            .map_or(1.0, |settings| 10f32.powf(settings.preamp / 20.0))
+    }
+
+    // a change of format restarts the stream, the filters start again
+    fn set_format(&mut self, channels: usize, sample_rate: u32) {
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        let bands = self.settings.as_ref().map(EqualizerSettings::bands);
+        self.filters = design(&bands.unwrap_or_default(), channels, sample_rate);
+        self.previous.clear();
+        self.transition = 0;
+        self.preamp = Gain::new(self.preamp_gain());
+    }
+}
+
+impl AudioFilter for Equalizer {
+    fn process(&mut self, samples: &mut [f32], channels: u16, sample_rate: u32) {
+        let channels = channels as usize;
+        if channels != self.channels || sample_rate != self.sample_rate {
+            self.set_format(channels, sample_rate);
+        }
+        if self.settings.is_none() && self.transition == 0 {
+            return;
+        }
+
+        self.preamp.apply(samples, channels as u16);
+        for frame in samples.chunks_mut(channels) {
+            for (channel, sample) in frame.iter_mut().enumerate() {
+                let filtered = filter(&mut self.filters, channels, channel, *sample);
+                *sample = if self.transition > 0 {
+                    let previous = filter(&mut self.previous, channels, channel, *sample);
+                    let progress = self.transition as f32 / self.transition_length as f32;
+                    previous * progress + filtered * (1.0 - progress)
+                } else {
+                    filtered
+                };
+            }
+            self.transition = self.transition.saturating_sub(1);
+        }
+    }
+
+    fn reset(&mut self) {
+        for filter in self.filters.iter_mut().chain(self.previous.iter_mut()) {
+            filter.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 48000;
+
+    fn sine(frequency: f32, frames: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let phase = 2.0 * std::f32::consts::PI * frequency * i as f32 / RATE as f32;
+                vec![phase.sin(); 2]
+            })
+            .collect()
+    }
+
+    // level in dB of the second half of the samples, after the transients
+    fn level(samples: &[f32]) -> f32 {
+        let peak = samples[samples.len() / 2..]
+            .iter()
+            .fold(0.0, |peak: f32, sample| peak.max(sample.abs()));
+        20.0 * peak.log10()
+    }
+
+    fn equalize(settings: Option<EqualizerSettings>, input: &[f32]) -> Vec<f32> {
+        let mut equalizer = Equalizer::new();
+        equalizer.set(settings);
+        let mut output = input.to_vec();
+        equalizer.process(&mut output, 2, RATE);
+        output
+    }
+
+    fn boost_1000(gain: f32, preamp: f32) -> EqualizerSettings {
+        let mut graphic = [0.0; 10];
+        graphic[5] = gain;
+        EqualizerSettings {
+            preamp,
+            graphic,
+            ..EqualizerSettings::default()
+        }
+    }
+
+    #[test]
+    fn settings_round_trip() {
+        let settings = EqualizerSettings {
+            mode: EqualizerMode::Parametric,
+            preamp: -3.5,
+            graphic: [1.0, -2.0, 0.0, 0.5, 0.0, 0.0, 0.0, 0.0, 12.0, -12.0],
+            parametric: vec![
+                Band {
+                    kind: FilterKind::LowShelf,
+                    frequency: 80.0,
+                    gain: 4.0,
+                    q: 0.7,
+                },
+                Band {
+                    kind: FilterKind::Peaking,
+                    frequency: 3150.5,
+                    gain: -2.5,
+                    q: 2.0,
+                },
+            ],
+        };
+        assert_eq!(settings.to_string().parse(), Ok(settings));
+        let flat = EqualizerSettings::default();
+        assert_eq!(flat.to_string().parse(), Ok(flat));
+    }
+
+    #[test]
+    fn invalid_settings() {
+        for settings in &[
+            "graphic|0|1,2",
+            "loud|0||",
+            "graphic|zero||",
+            "graphic|0|0,0,0,0,0,0,0,0,0,0,0|",
+            "parametric|0||notch:100:1:1",
+            "parametric|0||peak:100:1",
+        ] {
+            assert!(
+                settings.parse::<EqualizerSettings>().is_err(),
+                "{}",
+                settings
+            );
+        }
+    }
+
+    #[test]
+    fn preset_makes_room_for_the_boosts() {
+        let (_, rock) = BUILTIN_PRESETS[1];
+        assert_eq!(EqualizerSettings::from_preset(rock).preamp, -5.0);
+        let (_, flat) = BUILTIN_PRESETS[0];
+        assert_eq!(EqualizerSettings::from_preset(flat).preamp, 0.0);
+    }
+
+    #[test]
+    fn disabled_leaves_the_samples() {
+        let input = sine(1000.0, 4800);
+        assert_eq!(equalize(None, &input), input);
+    }
+
+    #[test]
+    fn flat_is_transparent() {
+        let input = sine(440.0, 4800);
+        let output = equalize(Some(EqualizerSettings::default()), &input);
+        for (output, input) in output.iter().zip(&input) {
+            assert!((output - input).abs() < 1e-5);
+        }
+    }
+
+    #[test]
+    fn graphic_band_and_preamp() {
+        let input = sine(1000.0, RATE as usize);
+        let boosted = level(&equalize(Some(boost_1000(6.0, 0.0)), &input));
+        assert!((boosted - 6.0).abs() < 0.1, "{} dB", boosted);
+        let compensated = level(&equalize(Some(boost_1000(6.0, -6.0)), &input));
+        assert!(compensated.abs() < 0.1, "{} dB", compensated);
+        // an octave away the boost is lower
+        let input = sine(4000.0, RATE as usize);
+        let neighbour = level(&equalize(Some(boost_1000(6.0, 0.0)), &input));
+        assert!(neighbour < 1.0, "{} dB", neighbour);
+    }
+
+    #[test]
+    fn settings_change_while_playing() {
+        let mut equalizer = Equalizer::new();
+        let mut samples = sine(1000.0, RATE as usize);
+        let (before, after) = samples.split_at_mut(RATE as usize);
+        equalizer.process(before, 2, RATE);
+        equalizer.set(Some(boost_1000(-12.0, 0.0)));
+        equalizer.process(after, 2, RATE);
+        assert!(level(before).abs() < 0.01);
+        let cut = level(after);
+        assert!((cut + 12.0).abs() < 0.1, "{} dB", cut);
+    }
+}
@@ -0,0 +1,396 @@
+use std::{
+    cell::Cell,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
+
+use gtk::{
+    prelude::Inhibit,
+    ApplicationWindow, BoxExt, Button, ButtonExt, CheckButton, ComboBoxExt, ComboBoxText,
+    ComboBoxTextExt, ContainerExt, Entry, EntryExt, Grid, GridExt, GtkWindowExt, Label, Notebook,
+    NotebookExt,
+    Orientation::{Horizontal, Vertical},
+    PositionType, RangeExt, Scale, ScaleExt, SpinButton, SpinButtonExt, ToggleButtonExt, WidgetExt,
+    Window, WindowType,
+};
+
+use mmp::{
+    biquad::FilterKind,
+    equalizer::{
+        Band, EqualizerMode, EqualizerSettings, BUILTIN_PRESETS, GRAPHIC_FREQUENCIES, MAX_GAIN,
+    },
+    State,
+};
+
+use crate::playlist::Playlist;
+
+// the presets saved by the user are the settings under these keys
+const PRESET_PREFIX: &str = "equalizer_preset.";
+const PARAMETRIC_BANDS: usize = 6;
+const FILTER_NAMES: [&str; 6] = [
+    "off",
+    "peak",
+    "lowshelf",
+    "highshelf",
+    "lowpass",
+    "highpass",
+];
+
+fn gain_scale(orientation: gtk::Orientation) -> Scale {
+    let range = f64::from(MAX_GAIN);
+    let scale = Scale::new_with_range(orientation, -range, range, 0.5);
+    scale.set_value_pos(PositionType::Bottom);
+    scale.add_mark(0.0, PositionType::Left, None::<&str>);
+    // up is louder
+    scale.set_inverted(orientation == Vertical);
+    scale
+}
+
+fn frequency_label(frequency: f32) -> String {
+    if frequency >= 1000.0 {
+        format!("{}k", frequency / 1000.0)
+    } else {
+        frequency.to_string()
+    }
+}
+
+// the widgets of a band of the parametric equalizer
+struct BandRow {
+    kind: ComboBoxText,
+    frequency: SpinButton,
+    gain: SpinButton,
+    q: SpinButton,
+}
+
+impl BandRow {
+    fn new() -> Self {
+        let kind = ComboBoxText::new();
+        for name in FILTER_NAMES.iter() {
+            kind.append(Some(*name), name);
+        }
+        kind.set_active_id(Some("off"));
+
+        let frequency = SpinButton::new_with_range(20.0, 20000.0, 10.0);
+        frequency.set_value(1000.0);
+        let range = f64::from(MAX_GAIN);
+        let gain = SpinButton::new_with_range(-range, range, 0.5);
+        gain.set_digits(1);
+        let q = SpinButton::new_with_range(0.1, 10.0, 0.1);
+        q.set_digits(2);
+        q.set_value(0.71);
+        BandRow {
+            kind,
+            frequency,
+            gain,
+            q,
+        }
+    }
+
+    fn band(&self) -> Option<Band> {
+        let kind = self.kind.get_active_id()?.parse::<FilterKind>().ok()?;
+        Some(Band {
+            kind,
+            frequency: self.frequency.get_value() as f32,
+            gain: self.gain.get_value() as f32,
+            q: self.q.get_value() as f32,
+        })
+    }
+
+    fn load(&self, band: Option<&Band>) {
+        match band {
+            Some(band) => {
+                self.kind
+                    .set_active_id(Some(band.kind.to_string().as_str()));
+                self.frequency.set_value(f64::from(band.frequency));
+                self.gain.set_value(f64::from(band.gain));
+                self.q.set_value(f64::from(band.q));
+            }
+            None => {
+                self.kind.set_active_id(Some("off"));
+            }
+        }
+    }
+}
+
+/// Window editing the equalizer: the changes are heard at once, and saved
+/// in the configuration when it is closed.
+pub(crate) struct EqualizerWindow {
+    window: Window,
+    enabled: CheckButton,
+    presets: ComboBoxText,
+    preset_name: Entry,
+    notebook: Notebook,
+    mode: Cell<EqualizerMode>,
+    preamp: Scale,
+    graphic: Vec<Scale>,
+    parametric: Vec<BandRow>,
+    playlist: Rc<Playlist>,
+    state: Arc<Mutex<State>>,
+    // set while the widgets are filled, their signals are ignored meanwhile
+    loading: Cell<bool>,
+}
+
+impl EqualizerWindow {
+    /// Builds the window and applies the equalizer saved in the
+    /// configuration.
+    pub fn new(
+        parent: &ApplicationWindow,
+        playlist: Rc<Playlist>,
+        state: Arc<Mutex<State>>,
+    ) -> Rc<Self> {
+        let window = Window::new(WindowType::Toplevel);
+        window.set_title("Equalizer");
+        window.set_transient_for(Some(parent));
+        window.set_border_width(10);
+
+        let vbox = gtk::Box::new(Vertical, 10);
+        window.add(&vbox);
+
+        let hbox = gtk::Box::new(Horizontal, 10);
+        vbox.add(&hbox);
+        let enabled = CheckButton::new_with_label("Enabled");
+        hbox.add(&enabled);
+        let presets = ComboBoxText::new();
+        hbox.add(&presets);
+        let preset_name = Entry::new();
+        preset_name.set_placeholder_text("Preset name");
+        hbox.add(&preset_name);
+        let save_button = Button::new_with_label("Save preset");
+        hbox.add(&save_button);
+
+        let hbox = gtk::Box::new(Horizontal, 10);
+        vbox.add(&hbox);
+        let preamp_box = gtk::Box::new(Vertical, 5);
+        let preamp = gain_scale(Vertical);
+        preamp.set_vexpand(true);
+        preamp_box.pack_start(&preamp, true, true, 0);
+        preamp_box.add(&Label::new("Preamp"));
+        hbox.add(&preamp_box);
+
+        let notebook = Notebook::new();
+        notebook.set_hexpand(true);
+        hbox.add(&notebook);
+
+        let graphic_box = gtk::Box::new(Horizontal, 5);
+        let graphic: Vec<_> = GRAPHIC_FREQUENCIES
+            .iter()
+            .map(|frequency| {
+                let band_box = gtk::Box::new(Vertical, 5);
+                let scale = gain_scale(Vertical);
+                scale.set_size_request(-1, 200);
+                band_box.pack_start(&scale, true, true, 0);
+                band_box.add(&Label::new(frequency_label(*frequency).as_str()));
+                graphic_box.pack_start(&band_box, true, true, 0);
+                scale
+            })
+            .collect();
+        notebook.append_page(&graphic_box, Some(&Label::new("Graphic")));
+
+        let grid = Grid::new();
+        grid.set_column_spacing(10);
+        grid.set_row_spacing(5);
+        for (column, title) in ["Filter", "Frequency (Hz)", "Gain (dB)", "Q"]
+            .iter()
+            .enumerate()
+        {
+            grid.attach(&Label::new(*title), column as i32, 0, 1, 1);
+        }
+        let parametric: Vec<_> = (0..PARAMETRIC_BANDS)
+            .map(|index| {
+                let row = BandRow::new();
+                let top = index as i32 + 1;
+                grid.attach(&row.kind, 0, top, 1, 1);
+                grid.attach(&row.frequency, 1, top, 1, 1);
+                grid.attach(&row.gain, 2, top, 1, 1);
+                grid.attach(&row.q, 3, top, 1, 1);
+                row
+            })
+            .collect();
+        notebook.append_page(&grid, Some(&Label::new("Parametric")));
+
+        let equalizer = Rc::new(EqualizerWindow {
+            window,
+            enabled,
+            presets,
+            preset_name,
+            notebook,
+            mode: Cell::new(EqualizerMode::Graphic),
+            preamp,
+            graphic,
+            parametric,
+            playlist,
+            state,
+            loading: Cell::new(false),
+        });
+        equalizer.fill_presets();
+        equalizer.restore();
+        Self::connect_events(&equalizer, &save_button);
+        equalizer
+    }
+
+    pub fn show(&self) {
+        self.window.show_all();
+        self.window.present();
+    }
+
+    fn settings(&self) -> EqualizerSettings {
+        let mut graphic = [0.0; 10];
+        for (gain, scale) in graphic.iter_mut().zip(self.graphic.iter()) {
+            *gain = scale.get_value() as f32;
+        }
+        EqualizerSettings {
+            mode: self.mode.get(),
+            preamp: self.preamp.get_value() as f32,
+            graphic,
+            parametric: self.parametric.iter().filter_map(BandRow::band).collect(),
+        }
+    }
+
+    fn load(&self, settings: &EqualizerSettings) {
+        self.loading.set(true);
+        self.mode.set(settings.mode);
+        self.notebook.set_current_page(Some(match settings.mode {
+            EqualizerMode::Graphic => 0,
+            EqualizerMode::Parametric => 1,
+        }));
+        self.preamp.set_value(f64::from(settings.preamp));
+        for (scale, gain) in self.graphic.iter().zip(settings.graphic.iter()) {
+            scale.set_value(f64::from(*gain));
+        }
+        for (index, row) in self.parametric.iter().enumerate() {
+            row.load(settings.parametric.get(index));
+        }
+        self.loading.set(false);
+    }
+
+    // sends the settings of the widgets to the player
+    fn apply(&self) {
+        if self.loading.get() {
+            return;
+        }
+        let settings = if self.enabled.get_active() {
+            Some(self.settings())
+        } else {
+            None
+        };
+        self.playlist.set_equalizer(settings);
+    }
+
+    fn restore(&self) {
+        let (settings, enabled) = {
+            let config = &self.state.lock().unwrap().config;
+            (
+                config.get::<EqualizerSettings>("equalizer"),
+                config.get::<bool>("equalizer_enabled"),
+            )
+        };
+        self.loading.set(true);
+        self.enabled.set_active(enabled.unwrap_or(false));
+        self.loading.set(false);
+        self.load(&settings.unwrap_or_default());
+        self.apply();
+    }
+
+    fn save(&self) {
+        let config = &mut self.state.lock().unwrap().config;
+        config.set("equalizer", self.settings());
+        config.set("equalizer_enabled", self.enabled.get_active());
+    }
+
+    // the built-in presets, then those of the user
+    fn fill_presets(&self) {
+        self.loading.set(true);
+        self.presets.remove_all();
+        for (name, _) in BUILTIN_PRESETS.iter() {
+            self.presets.append(Some(*name), name);
+        }
+        let state = self.state.lock().unwrap();
+        for name in state.config.keys_with_prefix(PRESET_PREFIX) {
+            self.presets.append(Some(name), name);
+        }
+        self.loading.set(false);
+    }
+
+    fn load_preset(&self, name: &str) {
+        let saved = {
+            let config = &self.state.lock().unwrap().config;
+            config.get::<EqualizerSettings>(&format!("{}{}", PRESET_PREFIX, name))
+        };
+        let settings = saved.or_else(|| {
+            BUILTIN_PRESETS
+                .iter()
+                .find(|(builtin, _)| *builtin == name)
+                .map(|(_, gains)| EqualizerSettings::from_preset(*gains))
+        });
+        if let Some(settings) = settings {
+            self.load(&settings);
+            self.apply();
+        }
+    }
+
+    fn save_preset(&self) {
+        let name = self.preset_name.get_text().unwrap_or_default();
+        let name = name.trim();
+        // the configuration file is made of `key = value` lines
+        if name.is_empty() || name.contains('=') {
+            return;
+        }
+        {
+            let config = &mut self.state.lock().unwrap().config;
+            config.set(&format!("{}{}", PRESET_PREFIX, name), self.settings());
+        }
+        self.fill_presets();
+        self.loading.set(true);
+        self.presets.set_active_id(Some(name));
+        self.loading.set(false);
+    }
+
+    fn connect_events(this: &Rc<Self>, save_button: &Button) {
+        let equalizer = Rc::clone(this);
+        this.window.connect_delete_event(move |window, _| {
+            equalizer.save();
+            window.hide();
+            Inhibit(true)
+        });
+
+        let equalizer = Rc::clone(this);
+        this.enabled.connect_toggled(move |_| equalizer.apply());
+
+        let equalizer = Rc::clone(this);
+        this.presets.connect_changed(move |presets| {
+            if !equalizer.loading.get() {
+                if let Some(name) = presets.get_active_id() {
+                    equalizer.load_preset(&name);
+                }
+            }
+        });
+
+        let equalizer = Rc::clone(this);
+        save_button.connect_clicked(move |_| equalizer.save_preset());
+
+        let equalizer = Rc::clone(this);
+        this.notebook.connect_switch_page(move |_, _, page| {
+            equalizer.mode.set(if page == 0 {
+                EqualizerMode::Graphic
+            } else {
+                EqualizerMode::Parametric
+            });
+            equalizer.apply();
+        });
+
+        let scales = this.graphic.iter().chain(Some(&this.preamp));
+        for scale in scales {
+            let equalizer = Rc::clone(this);
+            scale.connect_value_changed(move |_| equalizer.apply());
+        }
+
+        for row in &this.parametric {
+            let equalizer = Rc::clone(this);
+            row.kind.connect_changed(move |_| equalizer.apply());
+            for spin_button in &[&row.frequency, &row.gain, &row.q] {
+                let equalizer = Rc::clone(this);
+                spin_button.connect_value_changed(move |_| equalizer.apply());
+            }
+        }
+    }
+}
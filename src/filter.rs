@@ -0,0 +1,177 @@
+use std::time::Duration;
+
+/// An effect of the player thread, run on the samples of the output after
+/// the equalizer and before the volume.
+pub trait AudioFilter: Send {
+    /// Filters interleaved samples in place. The format is the one of the
+    /// output, it changes when a track opens it again.
+    fn process(&mut self, samples: &mut [f32], channels: u16, sample_rate: u32);
+
+    /// Delay added to the sound, subtracted from the position reported.
+    fn latency(&self) -> Duration {
+        Duration::from_secs(0)
+    }
+
+    /// Forgets the past samples, called on seek and when the output is
+    /// opened again.
+    fn reset(&mut self) {}
+}
+
+/// Identifies a filter added to the chain of the player.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct FilterId(pub(crate) u64);
+
+/// Filters run one after the other, in the order of the chain.
+#[derive(Default)]
+pub struct FilterChain {
+    filters: Vec<(FilterId, Box<dyn AudioFilter>)>,
+}
+
+impl FilterChain {
+    pub fn new() -> Self {
+        FilterChain { filters: vec![] }
+    }
+
+    fn index(&self, id: FilterId) -> Option<usize> {
+        self.filters
+            .iter()
+            .position(|(filter_id, _)| *filter_id == id)
+    }
+
+    /// Inserts a filter at `index`, at the end past it.
+    pub fn insert(&mut self, index: usize, id: FilterId, mut filter: Box<dyn AudioFilter>) {
+        filter.reset();
+        let index = index.min(self.filters.len());
+        self.filters.insert(index, (id, filter));
+    }
+
+    pub fn remove(&mut self, id: FilterId) {
+        if let Some(index) = self.index(id) {
+            self.filters.remove(index);
+        }
+    }
+
+    /// Moves a filter to `index`, keeping its state.
+    pub fn move_to(&mut self, id: FilterId, index: usize) {
+        if let Some(current) = self.index(id) {
+            let filter = self.filters.remove(current);
+            let index = index.min(self.filters.len());
+            self.filters.insert(index, filter);
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.filters.clear();
+    }
+}
+
+impl AudioFilter for FilterChain {
+    fn process(&mut self, samples: &mut [f32], channels: u16, sample_rate: u32) {
+        for (_, filter) in &mut self.filters {
+            filter.process(samples, channels, sample_rate);
+        }
+    }
+
+    fn latency(&self) -> Duration {
+        self.filters
+            .iter()
+            .map(|(_, filter)| filter.latency())
+            .sum()
+    }
+
+    fn reset(&mut self) {
+        for (_, filter) in &mut self.filters {
+            filter.reset();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // appends its digit to the samples, which then tell the order the
+    // filters ran in
+    struct Digit {
+        digit: f32,
+        latency: Duration,
+    }
+
+    impl AudioFilter for Digit {
+        fn process(&mut self, samples: &mut [f32], _: u16, _: u32) {
+            for sample in samples {
+                *sample = *sample * 10.0 + self.digit;
+            }
+        }
+
+        fn latency(&self) -> Duration {
+            self.latency
+        }
+    }
+
+    fn digit(digit: u64) -> Box<dyn AudioFilter> {
+        Box::new(Digit {
+            digit: digit as f32,
+            latency: Duration::from_millis(digit),
+        })
+    }
+
+    fn run(chain: &mut FilterChain) -> f32 {
+        let mut samples = [0.0; 4];
+        chain.process(&mut samples, 2, 44100);
+        assert!(samples.iter().all(|sample| *sample == samples[0]));
+        samples[0]
+    }
+
+    fn chain(digits: &[u64]) -> FilterChain {
+        let mut chain = FilterChain::new();
+        for digit in digits {
+            chain.insert(usize::MAX, FilterId(*digit), self::digit(*digit));
+        }
+        chain
+    }
+
+    #[test]
+    fn filters_run_in_order() {
+        let mut chain = chain(&[1, 2, 3]);
+        assert_eq!(run(&mut chain), 123.0);
+        chain.insert(0, FilterId(4), digit(4));
+        chain.insert(2, FilterId(5), digit(5));
+        assert_eq!(run(&mut chain), 41_523.0);
+    }
+
+    #[test]
+    fn move_to() {
+        let mut chain = chain(&[1, 2, 3]);
+        chain.move_to(FilterId(1), 2);
+        assert_eq!(run(&mut chain), 231.0);
+        chain.move_to(FilterId(1), 0);
+        assert_eq!(run(&mut chain), 123.0);
+        // past the end is the end
+        chain.move_to(FilterId(2), 10);
+        assert_eq!(run(&mut chain), 132.0);
+        // unknown ids change nothing
+        chain.move_to(FilterId(9), 0);
+        assert_eq!(run(&mut chain), 132.0);
+    }
+
+    #[test]
+    fn remove_and_clear() {
+        let mut chain = chain(&[1, 2, 3]);
+        chain.remove(FilterId(2));
+        assert_eq!(run(&mut chain), 13.0);
+        chain.remove(FilterId(2));
+        assert_eq!(run(&mut chain), 13.0);
+        chain.clear();
+        assert_eq!(run(&mut chain), 0.0);
+    }
+
+    #[test]
+    fn latency_is_the_sum() {
+        let mut chain = chain(&[1, 2, 3]);
+        assert_eq!(chain.latency(), Duration::from_millis(6));
+        chain.remove(FilterId(3));
+        assert_eq!(chain.latency(), Duration::from_millis(3));
+        assert_eq!(FilterChain::new().latency(), Duration::from_secs(0));
+    }
+}
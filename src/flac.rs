@@ -0,0 +1,486 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use claxon::{
+    frame::{Block, FrameReader},
+    input::BufferedReader,
+};
+
+use crate::{
+    decoder::{duration_to_samples, samples_to_duration, Decoder, ErrorLog, SharedReader},
+    metadata::{self, Metadata, FRONT_COVER},
+};
+
+const STREAMINFO: u8 = 0;
+const SEEKTABLE: u8 = 3;
+const VORBIS_COMMENT: u8 = 4;
+const PICTURE: u8 = 6;
+
+// sample number of the placeholder seek points
+const PLACEHOLDER: u64 = 0xffff_ffff_ffff_ffff;
+
+pub fn sniff(header: &[u8]) -> bool {
+    header.starts_with(b"fLaC")
+}
+
+#[derive(Clone, Copy, Debug)]
+struct StreamInfo {
+    sample_rate: u32,
+    channels: u16,
+    bits_per_sample: u32,
+    // 0 when unknown
+    samples: u64,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct SeekPoint {
+    sample: u64,
+    // relative to the first frame
+    offset: u64,
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn be_u64(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |acc, byte| (acc << 8) | u64::from(*byte))
+}
+
+fn parse_stream_info(block: &[u8]) -> io::Result<StreamInfo> {
+    if block.len() < 34 {
+        return Err(invalid_data("STREAMINFO block too short"));
+    }
+
+    // 20 bits of sample rate, 3 bits of channels - 1, 5 bits of bits per
+    // sample - 1 and 36 bits of total samples
+    let packed = be_u64(&block[10..18]);
+    let info = StreamInfo {
+        sample_rate: (packed >> 44) as u32,
+        channels: ((packed >> 41) & 0x7) as u16 + 1,
+        bits_per_sample: ((packed >> 36) & 0x1f) as u32 + 1,
+        samples: packed & 0xf_ffff_ffff,
+    };
+    if info.sample_rate == 0 {
+        return Err(invalid_data("invalid sample rate"));
+    }
+    Ok(info)
+}
+
+fn parse_seek_table(block: &[u8]) -> Vec<SeekPoint> {
+    block
+        .chunks(18)
+        .filter(|point| point.len() == 18)
+        .map(|point| SeekPoint {
+            sample: be_u64(&point[..8]),
+            offset: be_u64(&point[8..16]),
+        })
+        .filter(|point| point.sample != PLACEHOLDER)
+        .collect()
+}
+
+struct StreamMetadata {
+    info: StreamInfo,
+    seek_table: Vec<SeekPoint>,
+    comments: Vec<(String, String)>,
+    pictures: Vec<(u32, Vec<u8>)>,
+    // offset of the first frame in the stream
+    frames_start: u64,
+}
+
+// reads the metadata blocks, the stream must be at its beginning
+fn read_stream_metadata<R: Read>(data: &mut R) -> io::Result<StreamMetadata> {
+    let mut magic = [0; 4];
+    data.read_exact(&mut magic)?;
+    if !sniff(&magic) {
+        return Err(invalid_data("not a FLAC stream"));
+    }
+
+    let mut info = None;
+    let mut seek_table = vec![];
+    let mut comments = vec![];
+    let mut pictures = vec![];
+    let mut offset = 4;
+    loop {
+        let mut header = [0; 4];
+        data.read_exact(&mut header)?;
+        let last = header[0] & 0x80 != 0;
+        let kind = header[0] & 0x7f;
+        let length = be_u64(&header[1..]) as usize;
+
+        let mut block = vec![0; length];
+        data.read_exact(&mut block)?;
+        match kind {
+            STREAMINFO => info = Some(parse_stream_info(&block)?),
+            SEEKTABLE => seek_table = parse_seek_table(&block),
+            VORBIS_COMMENT => {
+                comments = metadata::parse_vorbis_comments(&block).unwrap_or_default()
+            }
+            PICTURE => pictures.extend(metadata::parse_picture(&block)),
+            _ => {}
+        }
+
+        offset += 4 + length as u64;
+        if last {
+            break;
+        }
+    }
+
+    Ok(StreamMetadata {
+        info: info.ok_or_else(|| invalid_data("no STREAMINFO block"))?,
+        seek_table,
+        comments,
+        pictures,
+        frames_start: offset,
+    })
+}
+
+/// Duration from the STREAMINFO block, no frame is decoded.
+pub fn compute_duration<R: Read>(mut data: R) -> Option<Duration> {
+    let info = read_stream_metadata(&mut data).ok()?.info;
+    if info.samples == 0 {
+        None
+    } else {
+        Some(samples_to_duration(info.samples, info.sample_rate))
+    }
+}
+
+pub struct FlacDecoder<R: Read + Seek> {
+    source: SharedReader<R>,
+    frames: FrameReader<BufferedReader<SharedReader<R>>>,
+    info: StreamInfo,
+    seek_table: Vec<SeekPoint>,
+    frames_start: u64,
+    block: Option<Block>,
+    // next sample (per channel) to output from the current block
+    block_pos: u32,
+    // next channel to output
+    block_channel: u32,
+    errors: ErrorLog,
+}
+
+impl<R: Read + Seek> FlacDecoder<R> {
+    pub fn new(mut data: R) -> io::Result<FlacDecoder<R>> {
+        let metadata = read_stream_metadata(&mut data)?;
+        if metadata.info.channels > 8 {
+            return Err(invalid_data("more than 8 channels"));
+        }
+
+        let source = SharedReader::new(data);
+        Ok(FlacDecoder {
+            frames: FrameReader::new(BufferedReader::new(source.clone())),
+            source,
+            info: metadata.info,
+            seek_table: metadata.seek_table,
+            frames_start: metadata.frames_start,
+            block: None,
+            block_pos: 0,
+            block_channel: 0,
+            errors: ErrorLog::default(),
+        })
+    }
+
+    // decodes the next block, recycling the buffer of the previous one
+    fn next_block(&mut self) -> bool {
+        let buffer = self
+            .block
+            .take()
+            .map(|block| block.into_buffer())
+            .unwrap_or_default();
+        self.block_pos = 0;
+        self.block_channel = 0;
+        match self.frames.read_next_or_eof(buffer) {
+            Ok(block) => {
+                self.block = block;
+                self.block.is_some()
+            }
+            Err(err) => {
+                self.errors
+                    .report(format_args!("FLAC decoding error: {}", err));
+                false
+            }
+        }
+    }
+
+    // restarts the frame reader at `offset`, relative to the first frame
+    fn restart_at(&mut self, offset: u64) -> io::Result<()> {
+        self.source
+            .seek(SeekFrom::Start(self.frames_start + offset))?;
+        self.frames = FrameReader::new(BufferedReader::new(self.source.clone()));
+        self.block = None;
+        self.block_pos = 0;
+        self.block_channel = 0;
+        Ok(())
+    }
+
+    // scales a sample of any depth to [-1, 1)
+    fn to_f32(&self, sample: i32) -> f32 {
+        sample as f32 / (1u32 << (self.info.bits_per_sample - 1)) as f32
+    }
+}
+
+impl<R: Read + Seek> Decoder for FlacDecoder<R> {
+    fn sample_rate(&self) -> u32 {
+        self.info.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.info.channels
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        if self.info.samples == 0 {
+            None
+        } else {
+            Some(samples_to_duration(
+                self.info.samples,
+                self.info.sample_rate,
+            ))
+        }
+    }
+
+    /// Jumps to the last seek point before `position` (or to the first frame
+    /// without SEEKTABLE) then decodes up to the exact sample.
+    fn seek(&mut self, position: Duration) -> io::Result<()> {
+        let target = duration_to_samples(position, self.info.sample_rate);
+        let point = self
+            .seek_table
+            .iter()
+            .take_while(|point| point.sample <= target)
+            .last()
+            .cloned();
+        self.restart_at(point.map_or(0, |point| point.offset))?;
+
+        while self.next_block() {
+            let block = self.block.as_ref().unwrap();
+            let end = block.time() + u64::from(block.duration());
+            if end > target {
+                self.block_pos = target.saturating_sub(block.time()) as u32;
+                return Ok(());
+            }
+        }
+        // beyond the end: the next read returns nothing
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [f32]) -> usize {
+        let mut length = 0;
+        while length < buffer.len() {
+            let exhausted = match self.block {
+                Some(ref block) => self.block_pos >= block.duration(),
+                None => true,
+            };
+            if exhausted && !self.next_block() {
+                break;
+            }
+
+            let sample = {
+                let block = self.block.as_ref().unwrap();
+                block.sample(self.block_channel, self.block_pos)
+            };
+            buffer[length] = self.to_f32(sample);
+            length += 1;
+
+            self.block_channel += 1;
+            if self.block_channel == u32::from(self.info.channels) {
+                self.block_channel = 0;
+                self.block_pos += 1;
+            }
+        }
+        length
+    }
+}
+
+pub fn open(file: File) -> io::Result<Box<dyn Decoder>> {
+    FlacDecoder::new(BufReader::new(file)).map(|decoder| Box::new(decoder) as Box<dyn Decoder>)
+}
+
+/// Tags from the VORBIS_COMMENT block, the cover from the PICTURE blocks.
+pub fn read_metadata(file: File) -> Option<Metadata> {
+    let stream = read_stream_metadata(&mut BufReader::new(file)).ok()?;
+    let mut metadata = Metadata::from_vorbis_comments(&stream.comments);
+    let mut pictures = stream.pictures;
+    let cover = pictures
+        .iter()
+        .position(|(kind, _)| *kind == FRONT_COVER)
+        .unwrap_or(0);
+    if cover < pictures.len() {
+        metadata.picture = Some(pictures.swap_remove(cover).1);
+    }
+    Some(metadata)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const RATE: u32 = 44100;
+    const BLOCK_SIZE: usize = 256;
+
+    fn crc8(data: &[u8]) -> u8 {
+        data.iter().fold(0, |crc, byte| {
+            (0..8).fold(crc ^ byte, |crc, _| {
+                if crc & 0x80 != 0 {
+                    (crc << 1) ^ 0x07
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    fn crc16(data: &[u8]) -> u16 {
+        data.iter().fold(0, |crc, byte| {
+            (0..8).fold(crc ^ (u16::from(*byte) << 8), |crc, _| {
+                if crc & 0x8000 != 0 {
+                    (crc << 1) ^ 0x8005
+                } else {
+                    crc << 1
+                }
+            })
+        })
+    }
+
+    fn stream_info(bits: u32, channels: u32, samples: u64) -> Vec<u8> {
+        let mut block = vec![0, 0, 0, 0];
+        block.extend_from_slice(&[0; 6]);
+        let packed = (u64::from(RATE) << 44)
+            | (u64::from(channels - 1) << 41)
+            | (u64::from(bits - 1) << 36)
+            | samples;
+        block.extend_from_slice(&packed.to_be_bytes());
+        block.extend_from_slice(&[0; 16]);
+        block
+    }
+
+    fn metadata_block(kind: u8, last: bool, data: &[u8]) -> Vec<u8> {
+        let flag = if last { 0x80 } else { 0 };
+        let mut block = vec![kind | flag];
+        block.extend_from_slice(&(data.len() as u32).to_be_bytes()[1..]);
+        block.extend_from_slice(data);
+        block
+    }
+
+    // a frame of 256 samples of one channel, stored verbatim at 16 or 24
+    // bits, at 44.1 kHz
+    fn frame(number: u8, bits: u32, samples: &[i32]) -> Vec<u8> {
+        assert!(number < 128 && samples.len() == BLOCK_SIZE);
+        let size_code = if bits == 16 { 0x08 } else { 0x0c };
+        let mut frame = vec![0xff, 0xf8, 0x89, size_code, number];
+        frame.push(crc8(&frame));
+        frame.push(0x02);
+        for sample in samples {
+            let bytes = sample.to_be_bytes();
+            frame.extend_from_slice(&bytes[4 - bits as usize / 8..]);
+        }
+        let crc = crc16(&frame);
+        frame.extend_from_slice(&crc.to_be_bytes());
+        frame
+    }
+
+    // 4 frames whose samples count from 0, the second one corrupted; a seek
+    // point skips it
+    fn stream_with_seek_table() -> Vec<u8> {
+        let frames: Vec<Vec<u8>> = (0..4)
+            .map(|number| {
+                let start = number as i32 * BLOCK_SIZE as i32;
+                let samples: Vec<i32> = (start..start + BLOCK_SIZE as i32).collect();
+                frame(number, 16, &samples)
+            })
+            .collect();
+        let mut seek_table = vec![];
+        let offset = (frames[0].len() + frames[1].len()) as u64;
+        for &(sample, offset) in &[(0, 0), (2 * BLOCK_SIZE as u64, offset), (PLACEHOLDER, 0)] {
+            seek_table.extend_from_slice(&u64::to_be_bytes(sample));
+            seek_table.extend_from_slice(&u64::to_be_bytes(offset));
+            seek_table.extend_from_slice(&(BLOCK_SIZE as u16).to_be_bytes());
+        }
+
+        let mut stream = b"fLaC".to_vec();
+        stream.extend(metadata_block(STREAMINFO, false, &stream_info(16, 1, 1024)));
+        stream.extend(metadata_block(SEEKTABLE, true, &seek_table));
+        for (number, frame) in frames.iter().enumerate() {
+            let mut frame = frame.clone();
+            if number == 1 {
+                let middle = frame.len() / 2;
+                frame[middle] ^= 0xff;
+            }
+            stream.extend(frame);
+        }
+        stream
+    }
+
+    #[test]
+    fn stream_info_fields() {
+        let info = parse_stream_info(&stream_info(24, 6, 0x1_2345_6789)).unwrap();
+        assert_eq!(info.sample_rate, RATE);
+        assert_eq!(info.channels, 6);
+        assert_eq!(info.bits_per_sample, 24);
+        assert_eq!(info.samples, 0x1_2345_6789);
+        assert!(parse_stream_info(&[0; 20]).is_err());
+        assert!(parse_stream_info(&[0; 34]).is_err());
+    }
+
+    #[test]
+    fn metadata_blocks() {
+        let mut stream = b"fLaC".to_vec();
+        stream.extend(metadata_block(STREAMINFO, false, &stream_info(16, 2, 4410)));
+        stream.extend(metadata_block(1, false, &[0; 10]));
+        stream.extend(metadata_block(SEEKTABLE, true, &[0xff; 18]));
+        let metadata = read_stream_metadata(&mut Cursor::new(&stream)).unwrap();
+        assert_eq!(metadata.frames_start, stream.len() as u64);
+        // a placeholder only
+        assert!(metadata.seek_table.is_empty());
+        let duration = compute_duration(Cursor::new(&stream)).unwrap();
+        assert_eq!(duration, Duration::from_millis(100));
+
+        assert!(read_stream_metadata(&mut Cursor::new(b"fLaC")).is_err());
+        assert!(read_stream_metadata(&mut Cursor::new(b"OggS\0\0\0\0")).is_err());
+    }
+
+    #[test]
+    fn samples_are_scaled_by_their_depth() {
+        for &bits in &[16, 24] {
+            let full = 1 << (bits - 1);
+            let mut samples = vec![0; BLOCK_SIZE];
+            samples[..3].copy_from_slice(&[-full, full / 2, full - 1]);
+            let mut stream = b"fLaC".to_vec();
+            stream.extend(metadata_block(STREAMINFO, true, &stream_info(bits, 1, 256)));
+            stream.extend(frame(0, bits, &samples));
+
+            let mut decoder = FlacDecoder::new(Cursor::new(stream)).unwrap();
+            let mut buffer = [0.0; 3];
+            assert_eq!(decoder.read(&mut buffer), 3);
+            let max = (full - 1) as f32 / full as f32;
+            assert_eq!(buffer, [-1.0, 0.5, max], "{} bits", bits);
+        }
+    }
+
+    #[test]
+    fn seek_table_then_exact_sample() {
+        let mut decoder = FlacDecoder::new(Cursor::new(stream_with_seek_table())).unwrap();
+        assert_eq!(decoder.duration(), Some(samples_to_duration(1024, RATE)));
+
+        let mut buffer = [0.0; 2];
+        for &target in &[600, 512, 1023] {
+            decoder.seek(samples_to_duration(target, RATE)).unwrap();
+            assert_eq!(decoder.read(&mut buffer[..1]), 1);
+            assert_eq!(buffer[0], target as f32 / 32768.0, "sample {}", target);
+        }
+
+        // decoding goes on into the next frame
+        decoder.seek(samples_to_duration(767, RATE)).unwrap();
+        assert_eq!(decoder.read(&mut buffer), 2);
+        assert_eq!(buffer, [767.0 / 32768.0, 768.0 / 32768.0]);
+
+        // past the end
+        decoder.seek(Duration::from_secs(1)).unwrap();
+        assert_eq!(decoder.read(&mut buffer), 0);
+    }
+}
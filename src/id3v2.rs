@@ -0,0 +1,427 @@
+use std::{
+    io::{Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use crate::{decoder::read_full, metadata::Chapter};
+
+// tables of contents nested deeper are ignored
+const MAX_TOC_DEPTH: usize = 4;
+
+/// A frame of an ID3v2 tag, the frames the `id3` crate does not know are
+/// read this way.
+pub struct Frame {
+    pub id: String,
+    /// Major version of the tag, which tells how embedded frames are read.
+    pub version: u8,
+    pub data: Vec<u8>,
+}
+
+// sizes of ID3v2.4 are "synchsafe": 7 bits per byte
+fn synchsafe(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |acc, byte| (acc << 7) | (byte & 0x7f) as usize)
+}
+
+fn be(bytes: &[u8]) -> usize {
+    bytes
+        .iter()
+        .fold(0, |acc, byte| (acc << 8) | *byte as usize)
+}
+
+/// Reads the frames of the tag at the beginning of `data`, none if there is
+/// no tag. The frames of an unsynchronised tag are left as they are.
+pub fn read_frames<R: Read + Seek>(data: &mut R) -> Vec<Frame> {
+    let mut header = [0; 10];
+    if data.seek(SeekFrom::Start(0)).is_err()
+        || read_full(data, &mut header) < 10
+        || &header[..3] != b"ID3"
+    {
+        return vec![];
+    }
+
+    let version = header[3];
+    let mut tag = vec![0; synchsafe(&header[6..10])];
+    let length = read_full(data, &mut tag);
+    tag.truncate(length);
+    parse_frames(&tag, version, header[5])
+}
+
+/// Parses the frames following the header of a tag (or of a CHAP frame,
+/// which embeds frames) of the given major version.
+pub fn parse_frames(tag: &[u8], version: u8, flags: u8) -> Vec<Frame> {
+    let (id_size, header_size) = match version {
+        2 => (3, 6),
+        3 | 4 => (4, 10),
+        _ => return vec![],
+    };
+
+    let mut pos = 0;
+    // skips the extended header
+    if flags & 0x40 != 0 && tag.len() >= 4 {
+        pos = match version {
+            4 => synchsafe(&tag[..4]),
+            _ => be(&tag[..4]) + 4,
+        };
+    }
+
+    let mut frames = vec![];
+    while pos + header_size <= tag.len() {
+        let header = &tag[pos..pos + header_size];
+        // the padding after the last frame
+        if header[0] == 0 {
+            break;
+        }
+        let size = match version {
+            2 => be(&header[3..6]),
+            3 => be(&header[4..8]),
+            _ => synchsafe(&header[4..8]),
+        };
+        let start = pos + header_size;
+        let data = match tag.get(start..start + size) {
+            Some(data) => data,
+            None => break,
+        };
+        frames.push(Frame {
+            id: String::from_utf8_lossy(&header[..id_size]).to_string(),
+            version,
+            data: data.to_vec(),
+        });
+        pos = start + size;
+    }
+    frames
+}
+
+/// Decodes a text of the given encoding byte: ISO-8859-1, UTF-16 with a BOM,
+/// UTF-16BE or UTF-8.
+pub fn decode_text(encoding: u8, bytes: &[u8]) -> String {
+    let utf16 = |bytes: &[u8], big_endian: bool| {
+        let units: Vec<u16> = bytes
+            .chunks_exact(2)
+            .map(|unit| {
+                if big_endian {
+                    u16::from_be_bytes([unit[0], unit[1]])
+                } else {
+                    u16::from_le_bytes([unit[0], unit[1]])
+                }
+            })
+            .collect();
+        String::from_utf16_lossy(&units)
+    };
+
+    let text = match encoding {
+        0 => bytes.iter().map(|byte| *byte as char).collect(),
+        1 if bytes.starts_with(&[0xfe, 0xff]) => utf16(&bytes[2..], true),
+        1 if bytes.starts_with(&[0xff, 0xfe]) => utf16(&bytes[2..], false),
+        1 => utf16(bytes, false),
+        2 => utf16(bytes, true),
+        _ => String::from_utf8_lossy(bytes).to_string(),
+    };
+    text.trim_end_matches('\0').to_string()
+}
+
+/// Splits a text terminated by a null character (two bytes in UTF-16) from
+/// what follows it.
+pub fn split_terminated(encoding: u8, bytes: &[u8]) -> (String, &[u8]) {
+    let end = if encoding == 1 || encoding == 2 {
+        (0..bytes.len() / 2)
+            .map(|i| i * 2)
+            .find(|i| bytes[*i] == 0 && bytes[*i + 1] == 0)
+            .map(|i| (i, i + 2))
+    } else {
+        bytes.iter().position(|byte| *byte == 0).map(|i| (i, i + 1))
+    };
+    match end {
+        Some((end, next)) => (decode_text(encoding, &bytes[..end]), &bytes[next..]),
+        None => (decode_text(encoding, bytes), &[]),
+    }
+}
+
+/// Finds a comment (COMM) or a user text (TXXX) by its description, returns
+/// its text.
+pub fn find_text(frames: &[Frame], id: &str, description: &str) -> Option<String> {
+    frames
+        .iter()
+        .filter(|frame| frame.id == id)
+        .filter_map(|frame| {
+            let encoding = *frame.data.get(0)?;
+            // comments have a language before the description
+            let skip = if id == "COMM" { 4 } else { 1 };
+            let (name, value) = split_terminated(encoding, frame.data.get(skip..)?);
+            if name.eq_ignore_ascii_case(description) {
+                Some(decode_text(encoding, value))
+            } else {
+                None
+            }
+        })
+        .next()
+}
+
+// the image of an APIC frame: encoding, MIME type, picture type,
+// description and data
+fn picture_data(data: &[u8]) -> Option<Vec<u8>> {
+    let encoding = *data.get(0)?;
+    let mime_end = 1 + data.get(1..)?.iter().position(|byte| *byte == 0)?;
+    let (_, image) = split_terminated(encoding, data.get(mime_end + 2..)?);
+    if image.is_empty() {
+        None
+    } else {
+        Some(image.to_vec())
+    }
+}
+
+// a CHAP frame: element id, start and end times in milliseconds, byte
+// offsets, then frames of its own such as its title and its image
+fn parse_chapter(frame: &Frame) -> Option<(String, Chapter)> {
+    let (id, rest) = split_terminated(0, &frame.data);
+    if rest.len() < 16 {
+        return None;
+    }
+    let millis = |bytes: &[u8]| Duration::from_millis(be(bytes) as u64);
+    let frames = parse_frames(&rest[16..], frame.version, 0);
+
+    let title = frames
+        .iter()
+        .find(|frame| frame.id == "TIT2")
+        .and_then(|frame| {
+            let encoding = *frame.data.get(0)?;
+            Some(decode_text(encoding, &frame.data[1..]))
+        })
+        .filter(|title| !title.is_empty());
+    let picture = frames
+        .iter()
+        .find(|frame| frame.id == "APIC")
+        .and_then(|frame| picture_data(&frame.data));
+    let chapter = Chapter {
+        title,
+        start: millis(&rest[0..4]),
+        end: millis(&rest[4..8]),
+        picture,
+    };
+    Some((id, chapter))
+}
+
+// a CTOC frame: element id, flags, the ids of its children
+fn parse_toc(frame: &Frame) -> Option<(String, bool, Vec<String>)> {
+    let (id, rest) = split_terminated(0, &frame.data);
+    let flags = *rest.get(0)?;
+    let count = *rest.get(1)?;
+    let mut rest = rest.get(2..)?;
+    let mut children = vec![];
+    for _ in 0..count {
+        let (child, next) = split_terminated(0, rest);
+        children.push(child);
+        rest = next;
+    }
+    let top_level = flags & 0x02 != 0;
+    Some((id, top_level, children))
+}
+
+// appends the chapters of a table of contents, and of the tables it contains
+fn toc_chapters(
+    children: &[String],
+    tocs: &[(String, bool, Vec<String>)],
+    chapters: &[(String, Chapter)],
+    depth: usize,
+    ordered: &mut Vec<Chapter>,
+) {
+    for child in children {
+        if let Some((_, chapter)) = chapters.iter().find(|(id, _)| id == child) {
+            ordered.push(chapter.clone());
+        } else if let Some((_, _, nested)) = tocs.iter().find(|(id, _, _)| id == child) {
+            if depth < MAX_TOC_DEPTH {
+                toc_chapters(nested, tocs, chapters, depth + 1, ordered);
+            }
+        }
+    }
+}
+
+/// Reads the chapters (CHAP frames), in the order of the top-level table of
+/// contents (CTOC frame) when there is one, by start time otherwise.
+pub fn read_chapters(frames: &[Frame]) -> Vec<Chapter> {
+    let chapters: Vec<_> = frames
+        .iter()
+        .filter(|frame| frame.id == "CHAP")
+        .filter_map(parse_chapter)
+        .collect();
+    let tocs: Vec<_> = frames
+        .iter()
+        .filter(|frame| frame.id == "CTOC")
+        .filter_map(parse_toc)
+        .collect();
+
+    let mut ordered = vec![];
+    if let Some((_, _, children)) = tocs.iter().find(|(_, top_level, _)| *top_level) {
+        toc_chapters(children, &tocs, &chapters, 0, &mut ordered);
+    }
+    if ordered.is_empty() {
+        ordered = chapters.into_iter().map(|(_, chapter)| chapter).collect();
+        ordered.sort_by_key(|chapter| chapter.start);
+    }
+    ordered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // a frame of an ID3v2.3 or ID3v2.4 tag
+    fn frame(version: u8, id: &str, data: &[u8]) -> Vec<u8> {
+        let size = data.len() as u32;
+        let size = if version == 4 {
+            (0..4).fold(0, |acc, i| acc | ((size >> (7 * i)) & 0x7f) << (8 * i))
+        } else {
+            size
+        };
+        let mut frame = id.as_bytes().to_vec();
+        frame.extend_from_slice(&size.to_be_bytes());
+        frame.extend_from_slice(&[0, 0]);
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    fn text_frame(version: u8, id: &str, text: &str) -> Vec<u8> {
+        let mut data = vec![3];
+        data.extend_from_slice(text.as_bytes());
+        frame(version, id, &data)
+    }
+
+    fn chapter(version: u8, id: &str, start: u32, end: u32, title: Option<&str>) -> Vec<u8> {
+        let mut data = id.as_bytes().to_vec();
+        data.push(0);
+        data.extend_from_slice(&start.to_be_bytes());
+        data.extend_from_slice(&end.to_be_bytes());
+        data.extend_from_slice(&[0xff; 8]);
+        if let Some(title) = title {
+            data.extend(text_frame(version, "TIT2", title));
+        }
+        frame(version, "CHAP", &data)
+    }
+
+    fn toc(version: u8, id: &str, top_level: bool, children: &[&str]) -> Vec<u8> {
+        let mut data = id.as_bytes().to_vec();
+        data.push(0);
+        data.push(if top_level { 0x03 } else { 0x01 });
+        data.push(children.len() as u8);
+        for child in children {
+            data.extend_from_slice(child.as_bytes());
+            data.push(0);
+        }
+        frame(version, "CTOC", &data)
+    }
+
+    fn parse(version: u8, frames: &[Vec<u8>]) -> Vec<Frame> {
+        let mut tag = frames.concat();
+        // padding
+        tag.extend_from_slice(&[0; 16]);
+        parse_frames(&tag, version, 0)
+    }
+
+    fn starts(chapters: &[Chapter]) -> Vec<u64> {
+        chapters
+            .iter()
+            .map(|chapter| chapter.start.as_secs())
+            .collect()
+    }
+
+    #[test]
+    fn reads_a_tag_and_its_frames() {
+        let frames = text_frame(4, "TIT2", &"long title ".repeat(20));
+        let size = frames.len() as u32;
+        let mut data = b"ID3\x04\x00\x00".to_vec();
+        data.extend_from_slice(&[0, 0, (size >> 7) as u8 & 0x7f, size as u8 & 0x7f]);
+        data.extend(frames);
+        data.extend_from_slice(b"audio data");
+
+        let frames = read_frames(&mut Cursor::new(data));
+        assert_eq!(frames.len(), 1);
+        assert_eq!(frames[0].id, "TIT2");
+        assert_eq!(frames[0].data.len(), 1 + 220);
+        assert!(read_frames(&mut Cursor::new(b"fLaC".to_vec())).is_empty());
+    }
+
+    #[test]
+    fn text_encodings() {
+        assert_eq!(decode_text(0, b"caf\xe9"), "café");
+        assert_eq!(decode_text(1, &[0xff, 0xfe, b'h', 0, b'i', 0, 0, 0]), "hi");
+        assert_eq!(decode_text(1, &[0xfe, 0xff, 0, b'h', 0, b'i']), "hi");
+        assert_eq!(decode_text(2, &[0, b'o', 0, b'k']), "ok");
+        assert_eq!(decode_text(3, "été\0".as_bytes()), "été");
+    }
+
+    #[test]
+    fn comment_by_description() {
+        let mut data = vec![0];
+        data.extend_from_slice(b"engiTunSMPB\0 00000000 00000210 000003C0");
+        let frames = parse(3, &[frame(3, "COMM", &data)]);
+        let text = find_text(&frames, "COMM", "itunsmpb");
+        let expected = " 00000000 00000210 000003C0";
+        assert_eq!(text.as_deref(), Some(expected));
+        assert_eq!(find_text(&frames, "TXXX", "iTunSMPB"), None);
+    }
+
+    #[test]
+    fn chapters_by_start_without_toc() {
+        for version in &[3, 4] {
+            let frames = parse(
+                *version,
+                &[
+                    chapter(*version, "ch2", 60_000, 120_000, Some("Second")),
+                    chapter(*version, "ch1", 0, 60_000, Some("First")),
+                    chapter(*version, "ch3", 120_000, 150_000, None),
+                ],
+            );
+            let chapters = read_chapters(&frames);
+            assert_eq!(starts(&chapters), [0, 60, 120]);
+            assert_eq!(chapters[0].title.as_ref().unwrap(), "First");
+            assert_eq!(chapters[1].end, Duration::from_secs(120));
+            assert_eq!(chapters[2].title, None);
+        }
+    }
+
+    #[test]
+    fn chapters_in_the_order_of_the_toc() {
+        let frames = parse(
+            4,
+            &[
+                chapter(4, "a", 0, 10_000, None),
+                chapter(4, "b", 10_000, 20_000, None),
+                chapter(4, "c", 20_000, 30_000, None),
+                toc(4, "part", false, &["c", "a"]),
+                toc(4, "root", true, &["b", "part", "missing"]),
+            ],
+        );
+        assert_eq!(starts(&read_chapters(&frames)), [10, 20, 0]);
+    }
+
+    #[test]
+    fn toc_nested_in_itself_ends() {
+        let frames = parse(
+            3,
+            &[
+                chapter(3, "a", 0, 10_000, None),
+                toc(3, "loop", false, &["a", "loop"]),
+                toc(3, "root", true, &["loop"]),
+            ],
+        );
+        assert_eq!(read_chapters(&frames).len(), MAX_TOC_DEPTH);
+    }
+
+    #[test]
+    fn chapter_image() {
+        let mut apic = vec![0];
+        apic.extend_from_slice(b"image/png\0");
+        apic.push(3);
+        apic.extend_from_slice(b"cover\0");
+        apic.extend_from_slice(b"\x89PNG data");
+
+        let mut data = b"ch\0".to_vec();
+        data.extend_from_slice(&[0; 16]);
+        data.extend(frame(4, "APIC", &apic));
+        let frames = parse(4, &[frame(4, "CHAP", &data)]);
+        let chapters = read_chapters(&frames);
+        assert_eq!(chapters[0].picture.as_ref().unwrap(), b"\x89PNG data");
+    }
+}
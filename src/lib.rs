@@ -0,0 +1,49 @@
+//! The audio engine of the player: the decoders, the processing of the
+//! samples and the outputs, and the state shared with the interface.
+
+mod channels;
+mod crossfade;
+mod dither;
+mod flac;
+mod id3v2;
+mod loudness;
+mod mp3;
+mod mp4;
+mod ogg;
+mod pcm;
+mod resampler;
+mod stretch;
+mod volume;
+
+pub mod biquad;
+pub mod bookmarks;
+pub mod config;
+pub mod decoder;
+pub mod equalizer;
+pub mod filter;
+pub mod metadata;
+pub mod player;
+pub mod replaygain;
+pub mod resume;
+pub mod scanner;
+pub mod shuffle;
+pub mod sink;
+
+use std::{collections::HashMap, time::Duration};
+
+use self::{bookmarks::Bookmarks, config::Config, replaygain::ReplayGain, resume::ResumePositions};
+
+/// State shared by the interface and the player thread.
+pub struct State {
+    pub bookmarks: Bookmarks,
+    pub config: Config,
+    pub current_time: u64,
+    pub durations: HashMap<String, u64>,
+    pub gains: HashMap<String, ReplayGain>,
+    pub resume: ResumePositions,
+    pub stopped: bool,
+}
+
+pub fn to_millis(duration: Duration) -> u64 {
+    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+}
@@ -0,0 +1,251 @@
+use std::{collections::VecDeque, f64::consts::PI};
+
+use crate::biquad::Biquad;
+
+// the blocks last 400 ms and overlap by 75%, i.e. a block every 100 ms
+const STEPS_PER_BLOCK: usize = 4;
+const STEPS_PER_SECOND: u32 = 10;
+const ABSOLUTE_GATE: f64 = -70.0;
+// below the loudness of the blocks over the absolute gate, in LU
+const RELATIVE_GATE: f64 = 10.0;
+
+// the high shelf modelling the head and the high pass of BS.1770 in
+// series, whose coefficients are given for 48 kHz, here derived for any rate
+fn k_weighting(sample_rate: u32) -> [Biquad; 2] {
+    let rate = f64::from(sample_rate);
+
+    let k = (PI * 1681.974_450_955_533 / rate).tan();
+    let q = 0.707_175_236_955_419_6;
+    let vh = 10f64.powf(3.999_843_853_973_347 / 20.0);
+    let vb = vh.powf(0.499_666_774_154_541_6);
+    let a0 = 1.0 + k / q + k * k;
+    let shelf = Biquad::new(
+        [
+            (vh + vb * k / q + k * k) / a0,
+            2.0 * (k * k - vh) / a0,
+            (vh - vb * k / q + k * k) / a0,
+        ],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    let k = (PI * 38.135_470_876_024_44 / rate).tan();
+    let q = 0.500_327_037_323_877_3;
+    let a0 = 1.0 + k / q + k * k;
+    let high_pass = Biquad::new(
+        [1.0, -2.0, 1.0],
+        [2.0 * (k * k - 1.0) / a0, (1.0 - k / q + k * k) / a0],
+    );
+
+    [shelf, high_pass]
+}
+
+// weight of a channel in the WAV order: the surround channels count more,
+// the LFE not at all
+fn channel_weight(channels: usize, index: usize) -> f64 {
+    match index {
+        0 | 1 => 1.0,
+        // FL FR BL BR
+        2 if channels == 4 => 1.41,
+        2 => 1.0,
+        3 if channels >= 6 => 0.0,
+        _ => 1.41,
+    }
+}
+
+// loudness of a mean square energy, in LUFS
+fn loudness(energy: f64) -> f64 {
+    -0.691 + 10.0 * energy.log10()
+}
+
+fn mean<I: Iterator<Item = f64>>(blocks: I) -> Option<f64> {
+    let (sum, count) = blocks.fold((0.0, 0u32), |(sum, count), block| (sum + block, count + 1));
+    if count == 0 {
+        None
+    } else {
+        Some(sum / f64::from(count))
+    }
+}
+
+/// Integrated loudness, in LUFS, of the mean square energies of 400 ms
+/// blocks, gated as in EBU R128; `None` for silence. The blocks of several
+/// tracks give the loudness of them played in a row.
+pub fn gated_loudness(blocks: &[f64]) -> Option<f64> {
+    let audible = || {
+        blocks
+            .iter()
+            .cloned()
+            .filter(|block| loudness(*block) > ABSOLUTE_GATE)
+    };
+    let threshold = loudness(mean(audible())?) - RELATIVE_GATE;
+    mean(audible().filter(|block| loudness(*block) > threshold)).map(loudness)
+}
+
+/// Measures the loudness (ITU-R BS.1770) and the sample peak of a stream.
+pub struct LoudnessMeter {
+    channels: usize,
+    sample_rate: u32,
+    filters: Vec<[Biquad; 2]>,
+    weights: Vec<f64>,
+    step_length: usize,
+    // weighted energy of the step in progress and its length in frames
+    step_energy: f64,
+    step_frames: usize,
+    steps: VecDeque<f64>,
+    blocks: Vec<f64>,
+    peak: f32,
+}
+
+impl LoudnessMeter {
+    pub fn new(channels: u16, sample_rate: u32) -> Self {
+        let mut meter = LoudnessMeter {
+            channels: 0,
+            sample_rate: 0,
+            filters: vec![],
+            weights: vec![],
+            step_length: 0,
+            step_energy: 0.0,
+            step_frames: 0,
+            steps: VecDeque::with_capacity(STEPS_PER_BLOCK),
+            blocks: vec![],
+            peak: 0.0,
+        };
+        meter.set_format(channels, sample_rate);
+        meter
+    }
+
+    /// Follows a change of format within the stream, the blocks measured
+    /// so far are kept.
+    pub fn set_format(&mut self, channels: u16, sample_rate: u32) {
+        let channels = channels as usize;
+        if channels == self.channels && sample_rate == self.sample_rate {
+            return;
+        }
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.filters = vec![k_weighting(sample_rate); channels];
+        self.weights = (0..channels)
+            .map(|index| channel_weight(channels, index))
+            .collect();
+        self.step_length = (sample_rate / STEPS_PER_SECOND).max(1) as usize;
+        self.step_energy = 0.0;
+        self.step_frames = 0;
+        self.steps.clear();
+    }
+
+    /// Measures interleaved samples in the current format.
+    pub fn process(&mut self, samples: &[f32]) {
+        for frame in samples.chunks(self.channels) {
+            for (channel, sample) in frame.iter().enumerate() {
+                self.peak = self.peak.max(sample.abs());
+                let [ref mut shelf, ref mut high_pass] = self.filters[channel];
+                let filtered = high_pass.process(shelf.process(f64::from(*sample)));
+                self.step_energy += self.weights[channel] * filtered * filtered;
+            }
+
+            self.step_frames += 1;
+            if self.step_frames == self.step_length {
+                if self.steps.len() == STEPS_PER_BLOCK {
+                    self.steps.pop_front();
+                }
+                self.steps.push_back(self.step_energy);
+                if self.steps.len() == STEPS_PER_BLOCK {
+                    let energy: f64 = self.steps.iter().sum();
+                    let frames = STEPS_PER_BLOCK * self.step_length;
+                    self.blocks.push(energy / frames as f64);
+                }
+                self.step_energy = 0.0;
+                self.step_frames = 0;
+            }
+        }
+    }
+
+    /// Mean square energies of the blocks, for `gated_loudness`.
+    pub fn blocks(&self) -> &[f64] {
+        &self.blocks
+    }
+
+    pub fn peak(&self) -> f32 {
+        self.peak
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a sine of the same `amplitude` in dBFS on each channel
+    fn sine(amplitude: f64, channels: usize, sample_rate: u32, seconds: u32) -> Vec<f32> {
+        let amplitude = 10f64.powf(amplitude / 20.0);
+        (0..sample_rate * seconds)
+            .flat_map(|i| {
+                let phase = 2.0 * PI * 1000.0 * f64::from(i) / f64::from(sample_rate);
+                vec![(amplitude * phase.sin()) as f32; channels]
+            })
+            .collect()
+    }
+
+    fn measure(meter: &LoudnessMeter) -> f64 {
+        gated_loudness(meter.blocks()).unwrap()
+    }
+
+    fn assert_lufs(actual: f64, expected: f64) {
+        assert!((actual - expected).abs() < 0.1, "{} LUFS", actual);
+    }
+
+    #[test]
+    fn sine_at_the_reference_level() {
+        // EBU Tech 3341: a stereo 1 kHz sine at -23 dBFS reads -23 LUFS
+        for rate in &[44100, 48000, 96000] {
+            let mut meter = LoudnessMeter::new(2, *rate);
+            meter.process(&sine(-23.0, 2, *rate, 20));
+            assert_lufs(measure(&meter), -23.0);
+            assert!((meter.peak() - 10f32.powf(-23.0 / 20.0)).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn mono_counts_once() {
+        let mut meter = LoudnessMeter::new(1, 48000);
+        meter.process(&sine(-20.0, 1, 48000, 10));
+        assert_lufs(measure(&meter), -20.0 - 10.0 * 2f64.log10());
+    }
+
+    #[test]
+    fn silence_has_no_loudness() {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        meter.process(&vec![0.0; 48000 * 2 * 5]);
+        assert_eq!(gated_loudness(meter.blocks()), None);
+        assert_eq!(gated_loudness(&[]), None);
+    }
+
+    #[test]
+    fn gates_drop_the_silent_and_quiet_passages() {
+        let mut meter = LoudnessMeter::new(2, 48000);
+        meter.process(&sine(-23.0, 2, 48000, 10));
+        meter.process(&vec![0.0; 48000 * 2 * 10]);
+        // 20 LU below, under the relative gate
+        meter.process(&sine(-43.0, 2, 48000, 10));
+        assert_lufs(measure(&meter), -23.0);
+    }
+
+    #[test]
+    fn format_change_keeps_the_blocks() {
+        let mut meter = LoudnessMeter::new(2, 44100);
+        meter.process(&sine(-23.0, 2, 44100, 10));
+        meter.set_format(2, 48000);
+        meter.process(&sine(-23.0, 2, 48000, 10));
+        assert_lufs(measure(&meter), -23.0);
+    }
+
+    #[test]
+    fn lfe_is_not_measured() {
+        // the same stereo sine in 5.1, with a loud LFE
+        let surround: Vec<f32> = sine(-23.0, 2, 48000, 10)
+            .chunks(2)
+            .flat_map(|frame| vec![frame[0], frame[1], 0.0, 0.5, 0.0, 0.0])
+            .collect();
+        let mut meter = LoudnessMeter::new(6, 48000);
+        meter.process(&surround);
+        assert_lufs(measure(&meter), -23.0);
+    }
+}
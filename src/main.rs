@@ -1,21 +1,32 @@
-mod mp3;
-mod player;
+mod chapter_list;
+mod equalizer_window;
 mod playlist;
+mod position_marks;
 mod toolbar;
 
-use self::{playlist::Playlist, toolbar::MusicToolbar};
+use mmp::{
+    bookmarks::Bookmarks, config::Config, player::PlaybackState, resume::ResumePositions,
+    sink::SinkKind, to_millis, State,
+};
+
+use self::{
+    chapter_list::ChapterList, playlist::Playlist, position_marks::PositionMarks,
+    toolbar::MusicToolbar,
+};
 
 use gio::{ApplicationExt, ApplicationExtManual, ApplicationFlags};
 use gtk::{
     prelude::Inhibit,
-    Adjustment, AdjustmentExt, Application, ApplicationWindow, ApplicationWindowExt, ContainerExt,
-    Continue, GtkWindowExt, Image, ImageExt, Label, LabelExt,
+    Adjustment, AdjustmentExt, Application, ApplicationWindow, ApplicationWindowExt, Button,
+    ButtonExt, ContainerExt, Continue, GtkWindowExt, Image, ImageExt, Label, LabelExt,
     Orientation::{Horizontal, Vertical},
     Scale, ScaleExt, SeparatorToolItem, ToolButton, ToolButtonExt, Toolbar, WidgetExt,
 };
+use gtk_sys::{GTK_STOCK_MEDIA_PAUSE, GTK_STOCK_MEDIA_PLAY};
 use std::{
+    cell::Cell,
     collections::HashMap,
-    env,
+    env, process,
     rc::Rc,
     sync::{Arc, Mutex},
     time::Duration,
@@ -23,25 +34,27 @@ use std::{
 
 // const PLAY_STOCK: &'static str = "gtk-media-play";
 
-struct State {
-    current_time: u64,
-    durations: HashMap<String, u64>,
-    stopped: bool,
-}
-
 struct App {
     adjustment: Adjustment,
+    chapters: Rc<ChapterList>,
     cover: Image,
     current_time_label: Label,
     duration_label: Label,
+    marks: Rc<PositionMarks>,
     playlist: Rc<Playlist>,
+    resume_bar: gtk::Box,
+    resume_button: Button,
+    resume_dismiss_button: Button,
+    resume_label: Label,
+    scale: Scale,
+    seeking: Rc<Cell<bool>>,
     state: Arc<Mutex<State>>,
     toolbar: MusicToolbar,
     window: ApplicationWindow,
 }
 
 impl App {
-    fn new(app: &Application) -> Self {
+    fn new(app: &Application, sink_kind: Option<SinkKind>) -> Self {
         let app_window = ApplicationWindow::new(app);
         app_window.set_title("A minimal music player");
 
@@ -49,20 +62,45 @@ impl App {
         let vbox = gtk::Box::new(Vertical, 0);
         vbox.add(&mt.toolbar);
 
+        let config = Config::load();
+        // the output given on the command line overrides the configuration
+        let sink_kind = sink_kind
+            .or_else(|| config.get::<SinkKind>("sink"))
+            .unwrap_or_default();
+
         let state = Arc::new(Mutex::new(State {
+            bookmarks: Bookmarks::load(),
+            config,
             current_time: 0,
             durations: HashMap::new(),
+            gains: HashMap::new(),
+            resume: ResumePositions::load(),
             stopped: true,
         }));
 
         // add playlist
-        let pl = Rc::new(Playlist::new(state.clone()));
-        vbox.add(&pl.treeview);
+        let pl = Rc::new(Playlist::new(state.clone(), sink_kind));
+        let chapters = Rc::new(ChapterList::new(Rc::clone(&pl)));
+        let playlist_box = gtk::Box::new(Horizontal, 0);
+        playlist_box.add(&pl.treeview);
+        playlist_box.add(&chapters.view);
+        vbox.add(&playlist_box);
 
         // add cover...
         let img = Image::new();
         vbox.add(&img);
 
+        // offer to resume a long track where it was left
+        let resume_bar = gtk::Box::new(Horizontal, 10);
+        let resume_label = Label::new(None);
+        resume_label.set_margin_left(10);
+        resume_bar.add(&resume_label);
+        let resume_button = Button::new_with_label("Resume");
+        resume_bar.add(&resume_button);
+        let resume_dismiss_button = Button::new_with_label("Start over");
+        resume_bar.add(&resume_dismiss_button);
+        vbox.add(&resume_bar);
+
         let hbox = gtk::Box::new(Horizontal, 10);
         vbox.add(&hbox);
 
@@ -86,13 +124,24 @@ impl App {
 
         app_window.add(&vbox);
         app_window.show_all();
+        resume_bar.hide();
+        chapters.view.hide();
 
+        let marks = PositionMarks::new(&app_window, &scale, Rc::clone(&pl), Arc::clone(&state));
         let app = App {
             adjustment,
+            chapters,
             cover: img,
             current_time_label,
             duration_label,
+            marks,
             playlist: pl,
+            resume_bar,
+            resume_button,
+            resume_dismiss_button,
+            resume_label,
+            scale,
+            seeking: Rc::new(Cell::new(false)),
             state,
             toolbar: mt,
             window: app_window,
@@ -117,10 +166,41 @@ impl App {
         let playlist = Rc::clone(&self.playlist);
         let adjustment = self.adjustment.clone();
         let state = Arc::clone(&self.state);
-        // let play_image = self.toolbar.play_image.clone();
+        let play_button = self.toolbar.play_button.clone();
+        let cover = self.cover.clone();
+        let seeking = Rc::clone(&self.seeking);
+        let marks = Rc::clone(&self.marks);
+        let resume_bar = self.resume_bar.clone();
+        let resume_label = self.resume_label.clone();
+        let chapters = Rc::clone(&self.chapters);
         gtk::timeout_add(100, move || {
+            if playlist.handle_events() {
+                Self::set_cover(&cover, &playlist);
+            }
+            marks.follow_track();
+            // the image of the chapter replaces the cover
+            if chapters.update() {
+                Self::set_cover(&cover, &playlist);
+            }
+
+            match playlist.resume_offer() {
+                Some(position) => {
+                    let millis = to_millis(position);
+                    let text = format!("Resume from {}?", Self::millis_to_minutes(millis));
+                    resume_label.set_text(&text);
+                    resume_bar.show();
+                }
+                None => resume_bar.hide(),
+            }
+
+            if playlist.state() == PlaybackState::Playing {
+                toolbar::set_image_icon(&play_button, GTK_STOCK_MEDIA_PAUSE);
+            } else {
+                toolbar::set_image_icon(&play_button, GTK_STOCK_MEDIA_PLAY);
+            }
+
             let state = state.lock().unwrap();
-            if let Some(path) = playlist.selected_path() {
+            if let Some(path) = playlist.current_path() {
                 if let Some(duration) = state.durations.get(&path) {
                     let duration = *duration;
                     adjustment.set_upper(duration as f64);
@@ -128,11 +208,84 @@ impl App {
                 }
             }
 
-            current_time_label.set_text(&Self::millis_to_minutes(state.current_time));
-
-            adjustment.set_value(state.current_time as f64);
+            // the slider is being dragged, don't move it under the pointer
+            if !seeking.get() {
+                current_time_label.set_text(&Self::millis_to_minutes(state.current_time));
+                adjustment.set_value(state.current_time as f64);
+            }
             Continue(true)
         });
+
+        self.connect_scale_events();
+        self.connect_resume_events();
+    }
+
+    fn connect_resume_events(&self) {
+        let playlist = Rc::clone(&self.playlist);
+        self.resume_button
+            .connect_clicked(move |_| playlist.accept_resume());
+
+        let playlist = Rc::clone(&self.playlist);
+        self.resume_dismiss_button
+            .connect_clicked(move |_| playlist.dismiss_resume());
+
+        // the positions and the last settings are written on the way out
+        let playlist = Rc::clone(&self.playlist);
+        let state = Arc::clone(&self.state);
+        self.window.connect_destroy(move |_| {
+            playlist.save_positions();
+            state.lock().unwrap().config.save();
+        });
+    }
+
+    fn connect_scale_events(&self) {
+        let seeking = Rc::clone(&self.seeking);
+        let marks = Rc::clone(&self.marks);
+        let adjustment = self.adjustment.clone();
+        self.scale.connect_button_press_event(move |_, event| {
+            // the right button opens the menu of the loop and the bookmarks
+            if event.get_button() == 3 {
+                let position = Duration::from_millis(adjustment.get_value() as u64);
+                PositionMarks::popup_menu(&marks, position, event.get_button(), event.get_time());
+                return Inhibit(true);
+            }
+            seeking.set(true);
+            Inhibit(false)
+        });
+
+        // the keys of the loop, unless they are typed in a cell being edited
+        // or in the search of the playlist
+        let marks = Rc::clone(&self.marks);
+        let state = Arc::clone(&self.state);
+        let scale = self.scale.clone();
+        let treeview = self.playlist.treeview.clone();
+        self.window.connect_key_press_event(move |_, event| {
+            if !scale.has_focus() && !treeview.has_focus() {
+                return Inhibit(false);
+            }
+            let position = || Duration::from_millis(state.lock().unwrap().current_time);
+            Inhibit(marks.handle_key(event.get_keyval(), position))
+        });
+
+        let current_time_label = self.current_time_label.clone();
+        let seeking = Rc::clone(&self.seeking);
+        self.adjustment.connect_value_changed(move |adjustment| {
+            if seeking.get() {
+                let millis = adjustment.get_value() as u64;
+                current_time_label.set_text(&Self::millis_to_minutes(millis));
+            }
+        });
+
+        let adjustment = self.adjustment.clone();
+        let playlist = Rc::clone(&self.playlist);
+        let seeking = Rc::clone(&self.seeking);
+        self.scale.connect_button_release_event(move |_, _| {
+            if seeking.replace(false) {
+                let millis = adjustment.get_value() as u64;
+                playlist.seek(Duration::from_millis(millis));
+            }
+            Inhibit(false)
+        });
     }
 }
 
@@ -146,13 +299,23 @@ fn main() {
 
     gstreamer::init().expect("Gstreamer fails to initialize");
 
-    app.connect_startup(|a| {
-        let _ = App::new(a);
+    // --sink=<output> is handled here, GTK rejects the options it doesn't know
+    const SINK_OPTION: &str = "--sink=";
+    let (sink_args, args): (Vec<_>, Vec<_>) =
+        env::args().partition(|arg| arg.starts_with(SINK_OPTION));
+    let sink_kind = sink_args.last().map(|arg| {
+        arg[SINK_OPTION.len()..]
+            .parse::<SinkKind>()
+            .unwrap_or_else(|err| {
+                eprintln!("{}", err);
+                eprintln!("accepted outputs: pulse, alsa, null, null-fast, wav:<path>");
+                process::exit(2);
+            })
     });
-    app.connect_activate(|_| {});
-    app.run(&env::args().collect::<Vec<_>>());
-}
 
-fn to_millis(duration: Duration) -> u64 {
-    duration.as_secs() * 1000 + duration.subsec_nanos() as u64 / 1_000_000
+    app.connect_startup(move |a| {
+        let _ = App::new(a, sink_kind.clone());
+    });
+    app.connect_activate(|_| {});
+    app.run(&args);
 }
@@ -0,0 +1,322 @@
+use std::time::Duration;
+
+use id3::Tag;
+
+use crate::replaygain::ReplayGain;
+
+/// Picture type of the front cover in FLAC/Vorbis pictures and in ID3.
+pub const FRONT_COVER: u32 = 3;
+
+/// Tags shown in the playlist, whatever the format they come from.
+#[derive(Default)]
+pub struct Metadata {
+    pub title: Option<String>,
+    pub artist: Option<String>,
+    pub album: Option<String>,
+    pub genre: Option<String>,
+    pub year: Option<String>,
+    pub track: Option<String>,
+    pub total_tracks: Option<String>,
+    /// Encoded image (JPEG, PNG...) of the cover.
+    pub picture: Option<Vec<u8>>,
+    /// Part of an album meant to be played without gaps (the iTunes flag).
+    pub gapless: bool,
+    pub replay_gain: ReplayGain,
+    pub chapters: Vec<Chapter>,
+}
+
+/// A chapter of a podcast or an audiobook.
+#[derive(Clone, Debug, Default)]
+pub struct Chapter {
+    pub title: Option<String>,
+    pub start: Duration,
+    pub end: Duration,
+    /// Encoded image shown instead of the cover during the chapter.
+    pub picture: Option<Vec<u8>>,
+}
+
+/// Value of the iTunes gapless album flag, as stored in text tags.
+pub fn is_gapless_flag(value: &str) -> bool {
+    value.trim() == "1"
+}
+
+impl Metadata {
+    pub fn from_id3(tag: &Tag) -> Self {
+        Metadata {
+            title: tag.title().map(str::to_string),
+            artist: tag.artist().map(str::to_string),
+            album: tag.album().map(str::to_string),
+            genre: tag.genre().map(str::to_string),
+            year: tag.year().map(|year| year.to_string()),
+            track: tag.track().map(|track| track.to_string()),
+            total_tracks: tag.total_tracks().map(|total| total.to_string()),
+            picture: tag.pictures().next().map(|picture| picture.data.clone()),
+            // not known to the id3 crate, see `decoder::mp3_metadata`
+            gapless: false,
+            replay_gain: ReplayGain::default(),
+            chapters: vec![],
+        }
+    }
+
+    /// Reads the usual fields of Vorbis comments, as found in Ogg and FLAC.
+    pub fn from_vorbis_comments(comments: &[(String, String)]) -> Self {
+        let field = |key: &str| {
+            comments
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value.clone())
+        };
+
+        // TRACKNUMBER may be "3/12"
+        let mut track = field("TRACKNUMBER");
+        let mut total_tracks = field("TRACKTOTAL").or_else(|| field("TOTALTRACKS"));
+        if let Some((number, total)) = track
+            .as_ref()
+            .and_then(|track| track.find('/').map(|slash| track.split_at(slash)))
+            .map(|(number, total)| (number.to_string(), total[1..].to_string()))
+        {
+            track = Some(number);
+            total_tracks = total_tracks.or(Some(total));
+        }
+
+        let mut metadata = Metadata {
+            title: field("TITLE"),
+            artist: field("ARTIST"),
+            album: field("ALBUM"),
+            genre: field("GENRE"),
+            // DATE is usually a full date, keep the year
+            year: field("DATE").map(|date| date.chars().take(4).collect()),
+            track,
+            total_tracks,
+            picture: None,
+            gapless: field("ITUNPGAP")
+                .or_else(|| field("ITUNESGAPLESS"))
+                .map_or(false, |value| is_gapless_flag(&value)),
+            replay_gain: ReplayGain::from_vorbis_comments(comments),
+            chapters: vec![],
+        };
+
+        let pictures = comments
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("METADATA_BLOCK_PICTURE"))
+            .filter_map(|(_, value)| decode_base64(value))
+            .filter_map(|block| parse_picture(&block));
+        for (kind, data) in pictures {
+            let front = kind == FRONT_COVER;
+            if front || metadata.picture.is_none() {
+                metadata.picture = Some(data);
+            }
+            if front {
+                break;
+            }
+        }
+        metadata
+    }
+}
+
+fn le_u32(bytes: &[u8]) -> Option<u32> {
+    let bytes = bytes.get(..4)?;
+    Some(
+        u32::from(bytes[0])
+            | (u32::from(bytes[1]) << 8)
+            | (u32::from(bytes[2]) << 16)
+            | (u32::from(bytes[3]) << 24),
+    )
+}
+
+fn be_u32(bytes: &[u8]) -> Option<u32> {
+    let bytes = bytes.get(..4)?;
+    Some(
+        (u32::from(bytes[0]) << 24)
+            | (u32::from(bytes[1]) << 16)
+            | (u32::from(bytes[2]) << 8)
+            | u32::from(bytes[3]),
+    )
+}
+
+/// Parses a list of Vorbis comments (vendor string then `NAME=value`
+/// entries, little endian lengths) into (name, value) pairs.
+pub fn parse_vorbis_comments(data: &[u8]) -> Option<Vec<(String, String)>> {
+    let vendor_length = le_u32(data)? as usize;
+    let mut pos = 4 + vendor_length;
+    let count = le_u32(data.get(pos..)?)?;
+    pos += 4;
+
+    let mut comments = vec![];
+    for _ in 0..count {
+        let length = le_u32(data.get(pos..)?)? as usize;
+        pos += 4;
+        let comment = String::from_utf8_lossy(data.get(pos..pos + length)?);
+        pos += length;
+
+        let mut parts = comment.splitn(2, '=');
+        if let (Some(name), Some(value)) = (parts.next(), parts.next()) {
+            comments.push((name.to_string(), value.to_string()));
+        }
+    }
+    Some(comments)
+}
+
+/// Parses a FLAC PICTURE block (also used, base64 encoded, in Vorbis
+/// comments), returns the picture type and the image data.
+pub fn parse_picture(block: &[u8]) -> Option<(u32, Vec<u8>)> {
+    let kind = be_u32(block)?;
+    let mime_length = be_u32(block.get(4..)?)? as usize;
+    let mut pos = 8 + mime_length;
+    let description_length = be_u32(block.get(pos..)?)? as usize;
+    // width, height, depth and number of colors
+    pos += 4 + description_length + 16;
+    let data_length = be_u32(block.get(pos..)?)? as usize;
+    pos += 4;
+    block
+        .get(pos..pos + data_length)
+        .map(|data| (kind, data.to_vec()))
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some(u32::from(c - b'A')),
+            b'a'..=b'z' => Some(u32::from(c - b'a') + 26),
+            b'0'..=b'9' => Some(u32::from(c - b'0') + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let mut data = Vec::with_capacity(text.len() * 3 / 4);
+    let mut accumulator = 0;
+    let mut bits = 0;
+    for c in text
+        .bytes()
+        .filter(|c| !c.is_ascii_whitespace() && *c != b'=')
+    {
+        accumulator = (accumulator << 6) | value(c)?;
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            data.push((accumulator >> bits) as u8);
+            accumulator &= (1 << bits) - 1;
+        }
+    }
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn vorbis_comments(comments: &[&str]) -> Vec<u8> {
+        let mut data = 6u32.to_le_bytes().to_vec();
+        data.extend_from_slice(b"vendor");
+        data.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            data.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            data.extend_from_slice(comment.as_bytes());
+        }
+        data
+    }
+
+    fn picture(kind: u32, image: &[u8]) -> Vec<u8> {
+        let mut block = kind.to_be_bytes().to_vec();
+        for text in &["image/png", "cover"] {
+            block.extend_from_slice(&(text.len() as u32).to_be_bytes());
+            block.extend_from_slice(text.as_bytes());
+        }
+        block.extend_from_slice(&[0; 16]);
+        block.extend_from_slice(&(image.len() as u32).to_be_bytes());
+        block.extend_from_slice(image);
+        block
+    }
+
+    fn encode_base64(data: &[u8]) -> String {
+        const ALPHABET: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+        let mut text = String::new();
+        for chunk in data.chunks(3) {
+            let mut group = [0; 3];
+            group[..chunk.len()].copy_from_slice(chunk);
+            let bits =
+                (u32::from(group[0]) << 16) | (u32::from(group[1]) << 8) | u32::from(group[2]);
+            for i in 0..4 {
+                if i <= chunk.len() {
+                    text.push(ALPHABET[(bits >> (18 - 6 * i) & 0x3f) as usize] as char);
+                } else {
+                    text.push('=');
+                }
+            }
+        }
+        text
+    }
+
+    fn pair(name: &str, value: &str) -> (String, String) {
+        (name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn vorbis_comments_are_split_at_the_first_equal_sign() {
+        let data = vorbis_comments(&["TITLE=a=b", "no separator", "artist=Someone"]);
+        let comments = parse_vorbis_comments(&data).unwrap();
+        assert_eq!(comments, [pair("TITLE", "a=b"), pair("artist", "Someone")]);
+        assert_eq!(parse_vorbis_comments(&data[..data.len() - 1]), None);
+        assert_eq!(parse_vorbis_comments(&[]), None);
+    }
+
+    #[test]
+    fn usual_fields() {
+        let comments = [
+            pair("title", "Song"),
+            pair("TRACKNUMBER", "3/12"),
+            pair("DATE", "1999-05-01"),
+            pair("ITUNPGAP", "1"),
+        ];
+        let metadata = Metadata::from_vorbis_comments(&comments);
+        let text = |field: &Option<String>| field.clone().unwrap_or_default();
+        assert_eq!(text(&metadata.title), "Song");
+        assert_eq!(text(&metadata.track), "3");
+        assert_eq!(text(&metadata.total_tracks), "12");
+        assert_eq!(text(&metadata.year), "1999");
+        assert!(metadata.gapless);
+        assert!(metadata.picture.is_none());
+    }
+
+    #[test]
+    fn base64() {
+        assert_eq!(decode_base64("TWFu").unwrap(), b"Man");
+        assert_eq!(decode_base64("TWE=").unwrap(), b"Ma");
+        assert_eq!(decode_base64("TQ==\n").unwrap(), b"M");
+        assert_eq!(decode_base64("").unwrap(), b"");
+        assert_eq!(decode_base64("TW*u"), None);
+
+        let data: Vec<u8> = (0..=255).collect();
+        assert_eq!(decode_base64(&encode_base64(&data)).unwrap(), data);
+    }
+
+    #[test]
+    fn picture_blocks() {
+        let block = picture(FRONT_COVER, b"\x89PNG");
+        let expected = (FRONT_COVER, b"\x89PNG".to_vec());
+        assert_eq!(parse_picture(&block), Some(expected));
+        assert_eq!(parse_picture(&block[..block.len() - 1]), None);
+        assert_eq!(parse_picture(&block[..10]), None);
+    }
+
+    #[test]
+    fn front_cover_is_preferred() {
+        let comment = |kind, image| {
+            let block = encode_base64(&picture(kind, image));
+            pair("METADATA_BLOCK_PICTURE", &block)
+        };
+        let comments = [
+            comment(0, b"other"),
+            pair("METADATA_BLOCK_PICTURE", "not base64!"),
+            comment(FRONT_COVER, b"front"),
+            comment(4, b"back"),
+        ];
+        let metadata = Metadata::from_vorbis_comments(&comments);
+        assert_eq!(metadata.picture.unwrap(), b"front");
+
+        let metadata = Metadata::from_vorbis_comments(&[comment(4, b"back")]);
+        assert_eq!(metadata.picture.unwrap(), b"back");
+    }
+}
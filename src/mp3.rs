@@ -1,11 +1,571 @@
-use std::io::{Read, Seek, SeekFrom};
-use std::time::Duration;
+use std::{
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    time::Duration,
+};
 
-pub(crate) struct Mp3Decoder<R: Read> {
-    reader: simplemad::Decoder<R>,
+use crate::{
+    decoder::{duration_to_samples, read_full, samples_to_duration, Decoder, SharedReader},
+    id3v2,
+};
+
+// bit rates in kbps, indexed by the 4 bits of the header (0 is "free format")
+const BIT_RATES: [[u32; 15]; 5] = [
+    // MPEG 1 layer I
+    [
+        0, 32, 64, 96, 128, 160, 192, 224, 256, 288, 320, 352, 384, 416, 448,
+    ],
+    // MPEG 1 layer II
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320, 384,
+    ],
+    // MPEG 1 layer III
+    [
+        0, 32, 40, 48, 56, 64, 80, 96, 112, 128, 160, 192, 224, 256, 320,
+    ],
+    // MPEG 2/2.5 layer I
+    [
+        0, 32, 48, 56, 64, 80, 96, 112, 128, 144, 160, 176, 192, 224, 256,
+    ],
+    // MPEG 2/2.5 layer II and III
+    [0, 8, 16, 24, 32, 40, 48, 56, 64, 80, 96, 112, 128, 144, 160],
+];
+
+const SAMPLE_RATES: [[u32; 3]; 3] = [
+    [44100, 48000, 32000],
+    [22050, 24000, 16000],
+    [11025, 12000, 8000],
+];
+
+// size of the window used to look for a frame sync after a seek
+const SYNC_WINDOW: usize = 64 * 1024;
+
+// frames which must have the bit rate of the first one for a stream without
+// VBR header to be considered as constant bit rate
+const CBR_FRAMES: usize = 8;
+
+// delay of the synthesis filters of the decoders, which the delay of the
+// LAME tag does not count
+const DECODER_DELAY: u64 = 529;
+
+// offset of the LAME tag from the start of the Xing header, whose fields are
+// always all written by LAME
+const LAME_TAG_OFFSET: usize = 120;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Version {
+    Mpeg1,
+    Mpeg2,
+    Mpeg25,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FrameHeader {
+    version: Version,
+    layer: u8,
+    sample_rate: u32,
+    channels: u8,
+    // bits per second
+    bit_rate: u32,
+    // length of the whole frame in bytes, header included
+    length: usize,
+    // samples per channel
+    samples: u32,
+}
+
+impl FrameHeader {
+    fn parse(bytes: &[u8]) -> Option<FrameHeader> {
+        use self::Version::*;
+
+        if bytes.len() < 4 || bytes[0] != 0xff || bytes[1] & 0xe0 != 0xe0 {
+            return None;
+        }
+
+        let version = match (bytes[1] >> 3) & 0b11 {
+            0 => Mpeg25,
+            2 => Mpeg2,
+            3 => Mpeg1,
+            _ => return None,
+        };
+        let layer = match (bytes[1] >> 1) & 0b11 {
+            1 => 3,
+            2 => 2,
+            3 => 1,
+            _ => return None,
+        };
+
+        let bit_rate_index = (bytes[2] >> 4) as usize;
+        let sample_rate_index = ((bytes[2] >> 2) & 0b11) as usize;
+        if bit_rate_index == 0 || bit_rate_index == 15 || sample_rate_index == 3 {
+            return None;
+        }
+        let padding = ((bytes[2] >> 1) & 1) as u32;
+        let channels = if bytes[3] >> 6 == 0b11 { 1 } else { 2 };
+
+        let bit_rate = BIT_RATES[match (version, layer) {
+            (Mpeg1, 1) => 0,
+            (Mpeg1, 2) => 1,
+            (Mpeg1, _) => 2,
+            (_, 1) => 3,
+            _ => 4,
+        }][bit_rate_index]
+            * 1000;
+        let sample_rate = SAMPLE_RATES[match version {
+            Mpeg1 => 0,
+            Mpeg2 => 1,
+            Mpeg25 => 2,
+        }][sample_rate_index];
+        let samples = match (version, layer) {
+            (_, 1) => 384,
+            (Mpeg1, _) | (_, 2) => 1152,
+            _ => 576,
+        };
+        let length = if layer == 1 {
+            (12 * bit_rate / sample_rate + padding) * 4
+        } else {
+            samples / 8 * bit_rate / sample_rate + padding
+        };
+
+        Some(FrameHeader {
+            version,
+            layer,
+            sample_rate,
+            channels,
+            bit_rate,
+            length: length as usize,
+            samples,
+        })
+    }
+
+    // size of the layer III side information following the header
+    fn side_info_size(&self) -> usize {
+        match (self.version == Version::Mpeg1, self.channels) {
+            (true, 1) => 17,
+            (true, _) => 32,
+            (false, 1) => 9,
+            (false, _) => 17,
+        }
+    }
+
+    fn is_same_stream(&self, other: &FrameHeader) -> bool {
+        self.version == other.version
+            && self.layer == other.layer
+            && self.sample_rate == other.sample_rate
+    }
+}
+
+// table of contents of a VBR header, used to map a time to a byte offset
+enum Toc {
+    Xing([u8; 100]),
+    Vbri {
+        scale: u64,
+        entries: Vec<u32>,
+        frames_per_entry: u64,
+    },
+}
+
+/// Samples added by the encoder at the start and at the end of the stream,
+/// from the LAME tag.
+#[derive(Clone, Copy, Debug)]
+pub struct EncoderPadding {
+    pub delay: u32,
+    pub padding: u32,
+}
+
+struct VbrInfo {
+    frames: Option<u32>,
+    bytes: Option<u32>,
+    toc: Option<Toc>,
+    padding: Option<EncoderPadding>,
+}
+
+fn be_u16(bytes: &[u8]) -> u32 {
+    (u32::from(bytes[0]) << 8) | u32::from(bytes[1])
+}
+
+fn be_u32(bytes: &[u8]) -> u32 {
+    (be_u16(bytes) << 16) | be_u16(&bytes[2..])
+}
+
+impl VbrInfo {
+    fn parse(frame: &[u8], header: &FrameHeader) -> Option<VbrInfo> {
+        let xing = 4 + header.side_info_size();
+        match frame.get(xing..xing + 8) {
+            Some(tag) if &tag[..4] == b"Xing" || &tag[..4] == b"Info" => {
+                let flags = be_u32(&tag[4..]);
+                let mut pos = xing + 8;
+                let mut field = |size: usize, present: bool| {
+                    if !present {
+                        return None;
+                    }
+                    let bytes = frame.get(pos..pos + size);
+                    pos += size;
+                    bytes
+                };
+
+                let frames = field(4, flags & 0x1 != 0).map(be_u32);
+                let bytes = field(4, flags & 0x2 != 0).map(be_u32);
+                let toc = field(100, flags & 0x4 != 0).map(|bytes| {
+                    let mut toc = [0; 100];
+                    toc.copy_from_slice(bytes);
+                    Toc::Xing(toc)
+                });
+
+                // the tag starts with the name of the encoder ("LAME3.100",
+                // "Lavc58..."), then 12 bits of delay and 12 bits of padding
+                // at the offset 21
+                let lame = xing + LAME_TAG_OFFSET;
+                let padding = frame.get(lame..lame + 24).and_then(|tag| {
+                    if !tag[..4].iter().all(u8::is_ascii_alphanumeric) {
+                        return None;
+                    }
+                    let packed = (be_u16(&tag[21..]) << 8) | u32::from(tag[23]);
+                    Some(EncoderPadding {
+                        delay: packed >> 12,
+                        padding: packed & 0xfff,
+                    })
+                });
+                return Some(VbrInfo {
+                    frames,
+                    bytes,
+                    toc,
+                    padding,
+                });
+            }
+            _ => {}
+        }
+
+        // VBRI header (Fraunhofer encoder) is always 32 bytes after the header
+        let vbri = frame.get(36..62)?;
+        if &vbri[..4] != b"VBRI" {
+            return None;
+        }
+        let bytes = be_u32(&vbri[10..]);
+        let frames = be_u32(&vbri[14..]);
+        let entry_count = be_u16(&vbri[18..]) as usize;
+        let scale = u64::from(be_u16(&vbri[20..]));
+        let entry_size = be_u16(&vbri[22..]) as usize;
+        let frames_per_entry = u64::from(be_u16(&vbri[24..]));
+
+        let table = frame.get(62..62 + entry_count * entry_size)?;
+        let entries = table
+            .chunks(entry_size)
+            .map(|entry| {
+                entry
+                    .iter()
+                    .fold(0, |acc, byte| (acc << 8) | u32::from(*byte))
+            })
+            .collect();
+
+        Some(VbrInfo {
+            frames: Some(frames),
+            bytes: Some(bytes),
+            toc: Some(Toc::Vbri {
+                scale,
+                entries,
+                frames_per_entry,
+            }),
+            padding: None,
+        })
+    }
+
+    // the number of frames, without what the encoder added
+    fn duration(&self, header: &FrameHeader) -> Option<Duration> {
+        let samples = u64::from(self.frames?) * u64::from(header.samples);
+        let samples = self.padding.map_or(samples, |padding| {
+            samples.saturating_sub(u64::from(padding.delay + padding.padding))
+        });
+        Some(samples_to_duration(samples, header.sample_rate))
+    }
+
+    // byte offset relative to the first frame of a frame before the sample
+    // `target`, and the number of the frame at that offset counted from the
+    // first one (the frame of the header)
+    fn toc_position(&self, target: u64, header: &FrameHeader) -> Option<(u64, u64)> {
+        let samples_per_frame = u64::from(header.samples);
+        match self.toc.as_ref()? {
+            Toc::Xing(toc) => {
+                let frames = u64::from(self.frames?);
+                let bytes = f64::from(self.bytes?);
+                if frames == 0 {
+                    return None;
+                }
+
+                // the table gives, for each percent of the duration, the
+                // position in the stream scaled to 256
+                let audio = target.saturating_sub(samples_per_frame);
+                let percent =
+                    (audio as f64 * 100.0 / (frames * samples_per_frame) as f64).min(99.999);
+                let index = percent as usize;
+                let lower = f64::from(toc[index]);
+                let upper = toc.get(index + 1).map_or(256.0, |x| f64::from(*x));
+                let scaled = lower + (upper - lower) * (percent - index as f64);
+                // the offsets count from the Info frame, offset 0 is frame 0
+                let frame = (percent * frames as f64 / 100.0) as u64;
+                Some(((scaled * bytes / 256.0) as u64, frame))
+            }
+
+            Toc::Vbri {
+                scale,
+                entries,
+                frames_per_entry,
+            } => {
+                if *frames_per_entry == 0 {
+                    return None;
+                }
+                let audio = target.saturating_sub(samples_per_frame);
+                let entry = (audio / samples_per_frame / frames_per_entry) as usize;
+                let entry = entry.min(entries.len());
+                let table_bytes: u64 = entries
+                    .iter()
+                    .take(entry)
+                    .map(|size| u64::from(*size) * scale)
+                    .sum();
+                let frame = 1 + entry as u64 * frames_per_entry;
+                Some((header.length as u64 + table_bytes, frame))
+            }
+        }
+    }
+}
+
+// offset of the audio data, i.e. after an eventual ID3v2 tag
+fn skip_id3v2<R: Read + Seek>(data: &mut R) -> u64 {
+    let mut header = [0; 10];
+    if data.seek(SeekFrom::Start(0)).is_err() || read_full(data, &mut header) < 10 {
+        return 0;
+    }
+    if &header[..3] != b"ID3" {
+        return 0;
+    }
+
+    // the size is a "synchsafe" integer: 7 bits per byte
+    let size = header[6..]
+        .iter()
+        .fold(0u64, |acc, byte| (acc << 7) | u64::from(byte & 0x7f));
+    let footer = if header[5] & 0x10 != 0 { 10 } else { 0 };
+    10 + size + footer
+}
+
+// looks for the first frame at or after `from`, a candidate is accepted
+// only if it is followed by another frame of the same stream
+fn find_frame<R: Read + Seek>(data: &mut R, from: u64) -> Option<(u64, FrameHeader)> {
+    data.seek(SeekFrom::Start(from)).ok()?;
+    let mut window = vec![0; SYNC_WINDOW];
+    let length = read_full(data, &mut window);
+    window.truncate(length);
+
+    (0..length.saturating_sub(4)).find_map(|pos| {
+        let header = FrameHeader::parse(&window[pos..])?;
+        let next = pos + header.length;
+        let confirmed = if next + 4 <= length {
+            FrameHeader::parse(&window[next..]).map_or(false, |next| next.is_same_stream(&header))
+        } else {
+            true
+        };
+        if confirmed {
+            Some((from + pos as u64, header))
+        } else {
+            None
+        }
+    })
+}
+
+// offsets of all the frames starting from the one at `from`
+fn scan_frames<R: Read + Seek>(data: &mut R, from: u64) -> Vec<u64> {
+    let mut offsets = vec![];
+    if data.seek(SeekFrom::Start(from)).is_err() {
+        return offsets;
+    }
+
+    let mut reader = BufReader::new(data);
+    let mut offset = from;
+    let mut window = [0; 4];
+    if reader.read_exact(&mut window).is_err() {
+        return offsets;
+    }
+    loop {
+        if let Some(header) = FrameHeader::parse(&window) {
+            offsets.push(offset);
+            offset += header.length as u64;
+            if reader.seek_relative(header.length as i64 - 4).is_err()
+                || reader.read_exact(&mut window).is_err()
+            {
+                break;
+            }
+        } else {
+            // lost the sync, slide by one byte
+            let mut byte = [0];
+            if reader.read_exact(&mut byte).is_err() {
+                break;
+            }
+            window.rotate_left(1);
+            window[3] = byte[0];
+            offset += 1;
+        }
+    }
+    offsets
+}
+
+// reads the VBR header of the first frame, if any
+fn read_vbr_info<R: Read + Seek>(
+    data: &mut R,
+    offset: u64,
+    header: &FrameHeader,
+) -> Option<VbrInfo> {
+    let mut frame = vec![0; header.length];
+    data.seek(SeekFrom::Start(offset)).ok()?;
+    let length = read_full(data, &mut frame);
+    VbrInfo::parse(&frame[..length], header)
+}
+
+// the duration of a constant bit rate stream follows from its size, the
+// first frames tell whether the bit rate is constant
+fn cbr_duration<R: Read + Seek>(
+    data: &mut R,
+    offset: u64,
+    first: &FrameHeader,
+) -> Option<Duration> {
+    let mut position = offset;
+    for _ in 0..CBR_FRAMES {
+        let mut bytes = [0; 4];
+        data.seek(SeekFrom::Start(position)).ok()?;
+        if read_full(data, &mut bytes) < 4 {
+            break;
+        }
+        let header = FrameHeader::parse(&bytes)?;
+        if header.bit_rate != first.bit_rate || !header.is_same_stream(first) {
+            return None;
+        }
+        position += header.length as u64;
+    }
+
+    // without the ID3v1 tag at the end
+    let mut end = data.seek(SeekFrom::End(0)).ok()?;
+    let mut tag = [0; 3];
+    if end >= 128
+        && data.seek(SeekFrom::End(-128)).is_ok()
+        && read_full(data, &mut tag) == 3
+        && &tag == b"TAG"
+    {
+        end -= 128;
+    }
+    let bits = end.saturating_sub(offset) * 8;
+    Some(Duration::from_nanos(
+        bits * 1_000_000_000 / u64::from(first.bit_rate),
+    ))
+}
+
+/// Duration known from the headers only: the number of frames of a Xing,
+/// Info or VBRI header (without the encoder delay and padding of a LAME tag),
+/// or the size of a constant bit rate stream. `None` when the frames have to
+/// be scanned.
+pub fn header_duration<R: Read + Seek>(data: &mut R) -> Option<Duration> {
+    let audio_start = skip_id3v2(data);
+    let (offset, header) = find_frame(data, audio_start)?;
+    match read_vbr_info(data, offset, &header).and_then(|info| info.duration(&header)) {
+        Some(duration) => Some(duration),
+        None => cbr_duration(data, offset, &header),
+    }
+}
+
+/// Recognizes an MP3 stream from its first bytes: an ID3v2 tag or two
+/// consecutive frames.
+pub fn sniff(header: &[u8]) -> bool {
+    if header.starts_with(b"ID3") {
+        return true;
+    }
+
+    match FrameHeader::parse(header) {
+        Some(first) => match header.get(first.length..) {
+            Some(next) if next.len() >= 4 => {
+                FrameHeader::parse(next).map_or(false, |next| next.is_same_stream(&first))
+            }
+            _ => true,
+        },
+        None => false,
+    }
+}
+
+/// Frames of the decoded stream (samples per channel, counted from the first
+/// frame decoded) which are actual audio: the encoder adds some silence at
+/// both ends.
+#[derive(Clone, Copy, Debug)]
+struct Gapless {
+    start: u64,
+    end: u64,
+}
+
+impl Gapless {
+    // from the LAME tag, or else from the iTunSMPB comment of iTunes:
+    // " 00000000 <delay> <padding> <total samples> ..." in hexadecimal
+    fn new(header: &FrameHeader, vbr_info: Option<&VbrInfo>, tag: &[id3v2::Frame]) -> Option<Self> {
+        let samples_per_frame = u64::from(header.samples);
+        // the frame of the VBR header is decoded as silence
+        let header_frame = vbr_info.map_or(0, |_| samples_per_frame);
+
+        let lame = vbr_info.and_then(|info| Some((info.frames?, info.padding?)));
+        if let Some((frames, padding)) = lame {
+            let start = header_frame + u64::from(padding.delay) + DECODER_DELAY;
+            let length = (u64::from(frames) * samples_per_frame)
+                .saturating_sub(u64::from(padding.delay + padding.padding));
+            return Some(Gapless {
+                start,
+                end: start + length,
+            });
+        }
+
+        match id3v2::find_text(tag, "COMM", "iTunSMPB").and_then(|smpb| parse_smpb(&smpb)) {
+            Some((delay, total)) => {
+                let start = header_frame + delay;
+                Some(Gapless {
+                    start,
+                    end: start + total,
+                })
+            }
+            // the padding is unknown, only the frame of the header is dropped
+            None if header_frame > 0 => Some(Gapless {
+                start: header_frame,
+                end: u64::max_value(),
+            }),
+            None => None,
+        }
+    }
+
+    // the frames to keep among the frames `start..end` of the stream, as
+    // offsets from `start`
+    fn keep(&self, start: u64, end: u64) -> (usize, usize) {
+        let keep_start = (self.start.max(start) - start) as usize;
+        let keep_end = (self.end.min(end).saturating_sub(start)) as usize;
+        (keep_start, keep_end)
+    }
+}
+
+// the delay and the number of samples of an iTunSMPB comment
+fn parse_smpb(smpb: &str) -> Option<(u64, u64)> {
+    let fields: Vec<u64> = smpb
+        .split_whitespace()
+        .map(|field| u64::from_str_radix(field, 16))
+        .collect::<Result<_, _>>()
+        .ok()?;
+    let (delay, total) = (*fields.get(1)?, *fields.get(3)?);
+    if total == 0 {
+        return None;
+    }
+    Some((delay, total))
+}
+
+pub struct Mp3Decoder<R: Read> {
+    source: SharedReader<R>,
+    reader: simplemad::Decoder<SharedReader<R>>,
     current_frame: simplemad::Frame,
     current_frame_channel: usize,
     current_frame_sample_pos: usize,
+    first_frame: Option<(u64, FrameHeader)>,
+    vbr_info: Option<VbrInfo>,
+    frame_index: Option<Vec<u64>>,
+    duration: Option<Duration>,
+    gapless: Option<Gapless>,
+    // frames decoded since the first one, where the next read starts
+    stream_position: u64,
     pub current_time: u64,
 }
 
@@ -31,26 +591,54 @@ fn next_frame<R: Read>(decoder: &mut simplemad::Decoder<R>) -> simplemad::Frame
         })
 }
 
+// samples per channel of a frame, the empty frame marking the end of the
+// stream has no channel at all
+fn frame_len(frame: &simplemad::Frame) -> usize {
+    frame.samples.get(0).map_or(0, |samples| samples.len())
+}
+
 impl<R: Read + Seek> Mp3Decoder<R> {
     pub fn new(mut data: R) -> Result<Mp3Decoder<R>, R> {
         if !is_mp3(data.by_ref()) {
             return Err(data);
         }
 
-        let mut reader = simplemad::Decoder::decode(data).unwrap();
+        let audio_start = skip_id3v2(&mut data);
+        let first_frame = find_frame(&mut data, audio_start);
+        let vbr_info =
+            first_frame.and_then(|(offset, header)| read_vbr_info(&mut data, offset, &header));
+        let duration = header_duration(&mut data);
+        let tag = id3v2::read_frames(&mut data);
+        let gapless =
+            first_frame.and_then(|(_, header)| Gapless::new(&header, vbr_info.as_ref(), &tag));
+        if data.seek(SeekFrom::Start(0)).is_err() {
+            return Err(data);
+        }
+
+        let source = SharedReader::new(data);
+        let mut reader = simplemad::Decoder::decode(source.clone()).unwrap();
 
         let current_frame = next_frame(&mut reader);
         let current_time = crate::to_millis(current_frame.duration);
 
         Ok(Mp3Decoder {
+            source,
             reader,
             current_frame,
             current_frame_channel: 0,
             current_frame_sample_pos: 0,
+            first_frame,
+            vbr_info,
+            frame_index: None,
+            duration,
+            gapless,
+            stream_position: 0,
             current_time,
         })
     }
 
+    /// Duration from the headers of all the frames, for the streams whose
+    /// `header_duration` is unknown.
     pub fn compute_duration(mut data: R) -> Option<Duration> {
         if !is_mp3(data.by_ref()) {
             return None;
@@ -68,40 +656,116 @@ impl<R: Read + Seek> Mp3Decoder<R> {
         )
     }
 
-    pub fn sample_rate(&self) -> u32 {
-        self.current_frame.sample_rate
+    /// Moves the decoder to `position`. The table of contents of a Xing or
+    /// VBRI header is used when there is one, the position is then the start
+    /// of a frame near `position`. Otherwise the frames are indexed (once)
+    /// and the position is exact to the sample.
+    pub fn seek(&mut self, position: Duration) -> io::Result<()> {
+        let (first_offset, header) = self
+            .first_frame
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no MPEG frame found"))?;
+        let samples_per_frame = u64::from(header.samples);
+        let target = duration_to_samples(position, header.sample_rate)
+            + self.gapless.map_or(0, |gapless| gapless.start);
+
+        // the main data of a layer III frame may begin in the previous frames
+        // (bit reservoir), one frame is decoded ahead and dropped
+        if let Some((offset, frame)) = self
+            .vbr_info
+            .as_ref()
+            .and_then(|info| info.toc_position(target, &header))
+        {
+            // the offset of the table is approximate, it may fall inside a
+            // frame: the decoding starts at the next header
+            let (offset, _) =
+                find_frame(&mut self.source, first_offset + offset).ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::UnexpectedEof, "no MPEG frame found")
+                })?;
+            // `frame` is the warm-up, the one after it is played first
+            self.restart_at(offset, 1, 0)?;
+            self.stream_position = (frame + 1) * samples_per_frame;
+        } else {
+            let source = &mut self.source;
+            let index = self
+                .frame_index
+                .get_or_insert_with(|| scan_frames(source, first_offset));
+            if index.is_empty() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "no MPEG frame found",
+                ));
+            }
+
+            let frame = ((target / samples_per_frame) as usize).min(index.len() - 1);
+            let skip = (target - frame as u64 * samples_per_frame).min(samples_per_frame - 1);
+
+            let warm_up = frame.min(1);
+            let offset = index[frame - warm_up];
+            self.restart_at(offset, warm_up, skip as usize)?;
+            self.stream_position = frame as u64 * samples_per_frame + skip;
+        }
+
+        let start = self.gapless.map_or(0, |gapless| gapless.start);
+        let played = self.stream_position.saturating_sub(start);
+        self.current_time = crate::to_millis(samples_to_duration(played, header.sample_rate));
+        Ok(())
+    }
+
+    // stops where the sample rate or the mode changes, so that all the
+    // samples returned have the format reported before the call
+    fn read_untrimmed(&mut self, buffer: &mut [f32]) -> usize {
+        let format = (self.sample_rate(), self.channels());
+        let mut length = 0;
+        for slot in buffer.iter_mut() {
+            if length % format.1 as usize == 0 && (self.sample_rate(), self.channels()) != format {
+                break;
+            }
+            match self.next() {
+                Some(sample) => *slot = sample,
+                None => break,
+            }
+            length += 1;
+        }
+        length
+    }
+
+    fn restart_at(&mut self, offset: u64, warm_up: usize, skip: usize) -> io::Result<()> {
+        self.source.seek(SeekFrom::Start(offset))?;
+        self.reader = simplemad::Decoder::decode(self.source.clone())
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "cannot restart decoder"))?;
+
+        for _ in 0..warm_up {
+            self.reader.next();
+        }
+
+        self.current_frame = next_frame(&mut self.reader);
+        self.current_frame_channel = 0;
+        self.current_frame_sample_pos = skip.min(frame_len(&self.current_frame).saturating_sub(1));
+        Ok(())
     }
 }
 
-fn next_sample<R: Read>(decoder: &mut Mp3Decoder<R>) -> Option<i16> {
-    if decoder.current_frame.samples[0].len() == 0 {
+fn next_sample<R: Read>(decoder: &mut Mp3Decoder<R>) -> Option<f32> {
+    if frame_len(&decoder.current_frame) == 0 {
         return None;
     }
 
-    // getting the sample and converting it from fixed step to i16
+    // libmad's fixed point has 28 fractional bits, the headroom above 1.0
+    // is kept and the output clips only once, after the gain
     let sample = decoder.current_frame.samples[decoder.current_frame_channel]
         [decoder.current_frame_sample_pos];
-    let sample = sample.to_i32() + (1 << (28 - 16));
-    let sample = if sample >= 0x10000000 {
-        0x10000000
-    } else if sample <= -0x10000000 {
-        -0x10000000
-    } else {
-        sample
-    };
-    let sample = sample >> (28 + 1 - 16);
-    let sample = sample as i16;
+    let sample = sample.to_i32() as f32 / (1 << 28) as f32;
 
     decoder.current_frame_channel += 1;
 
-    if decoder.current_frame_channel < decoder.current_frame.samples[0].len() {
+    if decoder.current_frame_channel < decoder.current_frame.samples.len() {
         return Some(sample);
     }
 
     decoder.current_frame_channel = 0;
     decoder.current_frame_sample_pos += 1;
 
-    if decoder.current_frame_sample_pos < decoder.current_frame.samples[0].len() {
+    if decoder.current_frame_sample_pos < frame_len(&decoder.current_frame) {
         return Some(sample);
     }
 
@@ -113,14 +777,243 @@ fn next_sample<R: Read>(decoder: &mut Mp3Decoder<R>) -> Option<i16> {
     return Some(sample);
 }
 
+impl<R: Read + Seek> Decoder for Mp3Decoder<R> {
+    fn sample_rate(&self) -> u32 {
+        self.current_frame.sample_rate
+    }
+
+    // the mode may change between two frames
+    fn channels(&self) -> u16 {
+        match self.current_frame.samples.len() {
+            0 => self
+                .first_frame
+                .map(|(_, header)| u16::from(header.channels))
+                .unwrap_or(2),
+            channels => channels as u16,
+        }
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.duration
+    }
+
+    fn seek(&mut self, position: Duration) -> io::Result<()> {
+        Mp3Decoder::seek(self, position)
+    }
+
+    // drops the delay and the padding of the encoder
+    fn read(&mut self, buffer: &mut [f32]) -> usize {
+        loop {
+            let channels = self.channels() as usize;
+            let length = self.read_untrimmed(buffer);
+            let gapless = match self.gapless {
+                Some(gapless) => gapless,
+                None => return length,
+            };
+
+            let start = self.stream_position;
+            let end = start + (length / channels) as u64;
+            self.stream_position = end;
+            if length == 0 || start >= gapless.end {
+                return 0;
+            }
+
+            let (keep_start, keep_end) = gapless.keep(start, end);
+            if keep_start < keep_end {
+                buffer.copy_within(keep_start * channels..keep_end * channels, 0);
+                return (keep_end - keep_start) * channels;
+            }
+        }
+    }
+}
+
 impl<R: Read> Iterator for Mp3Decoder<R> {
-    type Item = i16;
+    type Item = f32;
 
-    fn next(&mut self) -> Option<i16> {
+    fn next(&mut self) -> Option<f32> {
         next_sample(self)
     }
 
     fn size_hint(&self) -> (usize, Option<usize>) {
-        (self.current_frame.samples[0].len(), None)
+        (frame_len(&self.current_frame), None)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // MPEG-1 layer III, 128 kbps, 44.1 kHz, stereo: 417 bytes of 1152 samples
+    const HEADER: [u8; 4] = [0xff, 0xfb, 0x90, 0x00];
+    const FRAME_LENGTH: usize = 417;
+    const SAMPLES: u64 = 1152;
+    // after the header and the side information
+    const XING: usize = 36;
+
+    fn header() -> FrameHeader {
+        FrameHeader::parse(&HEADER).unwrap()
+    }
+
+    fn frame() -> Vec<u8> {
+        let mut frame = vec![0; FRAME_LENGTH];
+        frame[..4].copy_from_slice(&HEADER);
+        frame
+    }
+
+    // an Info frame with the number of frames and bytes, a TOC if `toc`,
+    // and the delay and padding of a LAME tag if any
+    fn info_frame(frames: u32, toc: bool, padding: Option<(u32, u32)>) -> Vec<u8> {
+        let mut frame = frame();
+        let flags: u32 = if toc { 0x7 } else { 0x3 };
+        let bytes = frames * FRAME_LENGTH as u32;
+        let mut fields = b"Info".to_vec();
+        fields.extend_from_slice(&flags.to_be_bytes());
+        fields.extend_from_slice(&frames.to_be_bytes());
+        fields.extend_from_slice(&bytes.to_be_bytes());
+        if toc {
+            fields.extend((0..100).map(|i| (i * 256 / 100) as u8));
+        }
+        frame[XING..XING + fields.len()].copy_from_slice(&fields);
+
+        if let Some((delay, padding)) = padding {
+            let lame = XING + LAME_TAG_OFFSET;
+            frame[lame..lame + 9].copy_from_slice(b"LAME3.100");
+            let packed = (delay << 12) | padding;
+            frame[lame + 21..lame + 24].copy_from_slice(&packed.to_be_bytes()[1..]);
+        }
+        frame
+    }
+
+    fn comment(description: &str, text: &str) -> id3v2::Frame {
+        let mut data = b"\0eng".to_vec();
+        data.extend_from_slice(description.as_bytes());
+        data.push(0);
+        data.extend_from_slice(text.as_bytes());
+        id3v2::Frame {
+            id: "COMM".to_string(),
+            version: 3,
+            data,
+        }
+    }
+
+    // the frames kept when the whole stream is read frame by frame from
+    // `from`
+    fn kept(gapless: &Gapless, from: u64, frames: u64) -> u64 {
+        (from / SAMPLES..frames)
+            .map(|frame| {
+                let start = (frame * SAMPLES).max(from);
+                let (keep_start, keep_end) = gapless.keep(start, (frame + 1) * SAMPLES);
+                keep_end.saturating_sub(keep_start) as u64
+            })
+            .sum()
+    }
+
+    #[test]
+    fn frame_header() {
+        let header = header();
+        assert_eq!(header.sample_rate, 44100);
+        assert_eq!(header.channels, 2);
+        assert_eq!(header.bit_rate, 128_000);
+        assert_eq!(header.length, FRAME_LENGTH);
+        assert_eq!(u64::from(header.samples), SAMPLES);
+
+        // padded, MPEG-2 mono
+        let padded = FrameHeader::parse(&[0xff, 0xfb, 0x92, 0x00]).unwrap();
+        assert_eq!(padded.length, 418);
+        let mpeg2 = FrameHeader::parse(&[0xff, 0xf3, 0x84, 0xc0]).unwrap();
+        assert_eq!(mpeg2.sample_rate, 24000);
+        assert_eq!(mpeg2.channels, 1);
+        assert_eq!(mpeg2.samples, 576);
+        assert!(FrameHeader::parse(&[0xff, 0xfb, 0xf0, 0x00]).is_none());
+        assert!(FrameHeader::parse(b"ID3\x04").is_none());
+    }
+
+    #[test]
+    fn lame_tag_trims_to_the_exact_length() {
+        let frame = info_frame(100, false, Some((576, 1000)));
+        let info = VbrInfo::parse(&frame, &header()).unwrap();
+        assert_eq!(info.frames, Some(100));
+        let padding = info.padding.unwrap();
+        assert_eq!((padding.delay, padding.padding), (576, 1000));
+
+        let gapless = Gapless::new(&header(), Some(&info), &[]).unwrap();
+        // the Info frame, then the delays of the encoder and of the decoder
+        assert_eq!(gapless.start, SAMPLES + 576 + DECODER_DELAY);
+        let length = 100 * SAMPLES - 576 - 1000;
+        assert_eq!(gapless.end - gapless.start, length);
+        assert_eq!(kept(&gapless, 0, 102), length);
+        // after a seek in the middle of a frame
+        assert_eq!(kept(&gapless, 5000, 102), gapless.end - 5000);
+
+        let duration = info.duration(&header()).unwrap();
+        assert_eq!(duration_to_samples(duration, 44100), length);
+    }
+
+    #[test]
+    fn itunes_comment_trims_to_the_exact_length() {
+        let smpb = " 00000000 00000840 000001CA 00000000001CF9F6";
+        let tag = [comment("iTunSMPB", smpb)];
+        let gapless = Gapless::new(&header(), None, &tag).unwrap();
+        assert_eq!(gapless.start, 0x840);
+        assert_eq!(kept(&gapless, 0, 2000), 0x1c_f9f6);
+        assert!(Gapless::new(&header(), None, &[comment("iTunSMPB", "garbage")]).is_none());
+    }
+
+    #[test]
+    fn info_frame_alone_is_dropped() {
+        let frame = info_frame(100, false, None);
+        let info = VbrInfo::parse(&frame, &header()).unwrap();
+        let gapless = Gapless::new(&header(), Some(&info), &[]).unwrap();
+        assert_eq!(gapless.start, SAMPLES);
+        assert_eq!(kept(&gapless, 0, 101), 100 * SAMPLES);
+        assert!(Gapless::new(&header(), None, &[]).is_none());
+    }
+
+    #[test]
+    fn toc_maps_a_time_to_a_frame() {
+        let frame = info_frame(1000, true, None);
+        let info = VbrInfo::parse(&frame, &header()).unwrap();
+        let bytes = 1000 * FRAME_LENGTH as u64;
+        // the start of the audio maps to the Info frame, decoded as warm-up
+        assert_eq!(info.toc_position(SAMPLES, &header()), Some((0, 0)));
+        let middle = SAMPLES + 500 * SAMPLES;
+        let (offset, frame) = info.toc_position(middle, &header()).unwrap();
+        assert_eq!(frame, 500);
+        assert!((offset as i64 - bytes as i64 / 2).abs() < 2 * FRAME_LENGTH as i64);
+    }
+
+    #[test]
+    fn durations_from_the_headers() {
+        let mut stream = info_frame(20, false, Some((576, 1000)));
+        for _ in 0..20 {
+            stream.extend(frame());
+        }
+        let duration = header_duration(&mut Cursor::new(&stream)).unwrap();
+        assert_eq!(duration_to_samples(duration, 44100), 20 * SAMPLES - 1576);
+
+        // constant bit rate, behind an ID3v2 tag
+        let mut stream = b"ID3\x04\x00\x00\x00\x00\x00\x0a".to_vec();
+        stream.extend_from_slice(&[0; 10]);
+        for _ in 0..20 {
+            stream.extend(frame());
+        }
+        let duration = header_duration(&mut Cursor::new(&stream)).unwrap();
+        let nanos = 20 * FRAME_LENGTH as u64 * 8 * 1_000_000_000 / 128_000;
+        assert_eq!(duration, Duration::from_nanos(nanos));
+    }
+
+    #[test]
+    fn frames_are_found_past_garbage() {
+        let mut stream = vec![0xff; 3];
+        stream.extend_from_slice(b"junk");
+        for _ in 0..4 {
+            stream.extend(frame());
+        }
+        let (offset, _) = find_frame(&mut Cursor::new(&stream), 0).unwrap();
+        assert_eq!(offset, 7);
+        let offsets = scan_frames(&mut Cursor::new(&stream), offset);
+        let expected: Vec<u64> = (0..4).map(|i| 7 + i * FRAME_LENGTH as u64).collect();
+        assert_eq!(offsets, expected);
     }
 }
@@ -0,0 +1,786 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use crate::{
+    decoder::{duration_to_samples, samples_to_duration, Decoder, ErrorLog},
+    metadata::Metadata,
+    replaygain::ReplayGain,
+};
+
+// the largest frame of AAC (2048 samples with SBR) times 8 channels
+const AAC_MAX_OUTPUT: usize = 2048 * 8;
+
+// object type indication of MPEG-4 audio in the esds descriptor
+const MPEG4_AUDIO: u8 = 0x40;
+
+// descriptor tags of the esds box
+const ES_DESCRIPTOR: u8 = 0x03;
+const DECODER_CONFIG_DESCRIPTOR: u8 = 0x04;
+const DECODER_SPECIFIC_INFO: u8 = 0x05;
+
+/// Recognizes the ISO base media files (MP4, M4A...).
+pub fn sniff(header: &[u8]) -> bool {
+    header.get(4..8) == Some(&b"ftyp"[..])
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |acc, byte| (acc << 8) | u64::from(*byte))
+}
+
+fn be_at(data: &[u8], pos: usize, size: usize) -> Option<u64> {
+    data.get(pos..pos + size).map(be)
+}
+
+/// A box ("atom") of an ISO base media file.
+struct Atom<'a> {
+    kind: [u8; 4],
+    data: &'a [u8],
+}
+
+// iterates over the boxes contained in `data`
+struct Atoms<'a> {
+    data: &'a [u8],
+}
+
+impl<'a> Iterator for Atoms<'a> {
+    type Item = Atom<'a>;
+
+    fn next(&mut self) -> Option<Atom<'a>> {
+        let size = be_at(self.data, 0, 4)? as usize;
+        let mut kind = [0; 4];
+        kind.copy_from_slice(self.data.get(4..8)?);
+        let (header, size) = match size {
+            0 => (8, self.data.len()),
+            1 => (16, be_at(self.data, 8, 8)? as usize),
+            _ => (8, size),
+        };
+        if size < header || size > self.data.len() {
+            return None;
+        }
+
+        let data = &self.data[header..size];
+        self.data = &self.data[size..];
+        Some(Atom { kind, data })
+    }
+}
+
+fn atoms(data: &[u8]) -> Atoms {
+    Atoms { data }
+}
+
+fn child<'a>(data: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    atoms(data)
+        .find(|atom| &atom.kind == kind)
+        .map(|atom| atom.data)
+}
+
+// follows a path of nested boxes
+fn descend<'a>(data: &'a [u8], path: &[&[u8; 4]]) -> Option<&'a [u8]> {
+    path.iter().try_fold(data, |data, kind| child(data, kind))
+}
+
+// reads the top level "moov" box, the samples are read later from the
+// absolute offsets of its tables
+fn read_moov<R: Read + Seek>(data: &mut R) -> io::Result<Vec<u8>> {
+    let length = data.seek(SeekFrom::End(0))?;
+    let mut offset = 0;
+    while offset + 8 <= length {
+        data.seek(SeekFrom::Start(offset))?;
+        let mut header = [0; 16];
+        data.read_exact(&mut header[..8])?;
+        let (header_size, size) = match be(&header[..4]) {
+            0 => (8, length - offset),
+            1 => {
+                data.read_exact(&mut header[8..])?;
+                (16, be(&header[8..]))
+            }
+            size => (8, size),
+        };
+        if size < header_size {
+            break;
+        }
+
+        if &header[4..8] == b"moov" {
+            // the size comes from the file, it must not allocate more
+            if size > length - offset {
+                return Err(invalid_data("truncated moov box"));
+            }
+            let mut moov = vec![0; (size - header_size) as usize];
+            data.read_exact(&mut moov)?;
+            return Ok(moov);
+        }
+        offset += size;
+    }
+    Err(invalid_data("no moov box"))
+}
+
+// the track whose handler is "soun"
+fn audio_track(moov: &[u8]) -> Option<&[u8]> {
+    atoms(moov)
+        .filter(|atom| &atom.kind == b"trak")
+        .map(|atom| atom.data)
+        .find(|trak| {
+            descend(trak, &[b"mdia", b"hdlr"])
+                .and_then(|hdlr| hdlr.get(8..12))
+                .map_or(false, |handler| handler == b"soun")
+        })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+struct Sample {
+    offset: u64,
+    size: u32,
+    // decoding time, in units of the time scale of the track
+    time: u64,
+}
+
+// the number of entries of a table whose count is at `pos`, followed by
+// entries of `entry_size` bytes, if they all fit in the box
+fn entry_count(data: &[u8], pos: usize, entry_size: usize) -> Option<usize> {
+    let count = be_at(data, pos, 4)? as usize;
+    let size = count.checked_mul(entry_size)?;
+    if size > data.len().saturating_sub(pos + 4) {
+        return None;
+    }
+    Some(count)
+}
+
+// builds the position and time of every sample from the stbl tables, up to
+// the first one past `file_length` (the file is truncated)
+fn read_samples(stbl: &[u8], file_length: u64) -> Option<Vec<Sample>> {
+    // all the tables are full boxes: 4 bytes of version and flags, then
+    // the number of entries
+    let stsz = child(stbl, b"stsz")?;
+    let constant_size = be_at(stsz, 4, 4)? as u32;
+    let sizes: Vec<u32> = if constant_size != 0 {
+        // no table, the samples must fit in the file instead
+        let count = be_at(stsz, 8, 4)?;
+        if count * u64::from(constant_size) > file_length {
+            return None;
+        }
+        vec![constant_size; count as usize]
+    } else {
+        (0..entry_count(stsz, 8, 4)?)
+            .map(|i| be_at(stsz, 12 + i * 4, 4).map(|size| size as u32))
+            .collect::<Option<_>>()?
+    };
+
+    let chunk_offsets: Vec<u64> = if let Some(stco) = child(stbl, b"stco") {
+        (0..entry_count(stco, 4, 4)?)
+            .map(|i| be_at(stco, 8 + i * 4, 4))
+            .collect::<Option<_>>()?
+    } else {
+        let co64 = child(stbl, b"co64")?;
+        (0..entry_count(co64, 4, 8)?)
+            .map(|i| be_at(co64, 8 + i * 8, 8))
+            .collect::<Option<_>>()?
+    };
+
+    // runs of chunks with the same number of samples: (first chunk, samples)
+    let stsc = child(stbl, b"stsc")?;
+    let runs: Vec<(usize, usize)> = (0..entry_count(stsc, 4, 12)?)
+        .map(|i| {
+            let first = be_at(stsc, 8 + i * 12, 4)? as usize;
+            let samples = be_at(stsc, 12 + i * 12, 4)? as usize;
+            Some((first.saturating_sub(1), samples))
+        })
+        .collect::<Option<_>>()?;
+
+    // durations of the samples: (count, delta)
+    let stts = child(stbl, b"stts")?;
+    let deltas = (0..entry_count(stts, 4, 8)?).flat_map(|i| {
+        let count = be_at(stts, 8 + i * 8, 4).unwrap_or(0) as usize;
+        let delta = be_at(stts, 12 + i * 8, 4).unwrap_or(0);
+        (0..count).map(move |_| delta)
+    });
+
+    let mut samples = Vec::with_capacity(sizes.len());
+    let mut index = 0;
+    'chunks: for (chunk, offset) in chunk_offsets.iter().enumerate() {
+        let per_chunk = runs
+            .iter()
+            .take_while(|(first, _)| *first <= chunk)
+            .last()
+            .map_or(0, |(_, samples)| *samples);
+        let mut offset = *offset;
+        for _ in 0..per_chunk {
+            let size = match sizes.get(index) {
+                Some(size) => *size,
+                None => break,
+            };
+            if offset + u64::from(size) > file_length {
+                break 'chunks;
+            }
+            samples.push(Sample {
+                offset,
+                size,
+                time: 0,
+            });
+            offset += u64::from(size);
+            index += 1;
+        }
+    }
+
+    let mut time = 0;
+    for (sample, delta) in samples.iter_mut().zip(deltas) {
+        sample.time = time;
+        time += delta;
+    }
+    Some(samples)
+}
+
+// the sample to decode from to reach the time `target`, and its time: the
+// one before the sample of the target, for the overlap of the transforms
+fn seek_point(samples: &[Sample], target: u64) -> (usize, u64) {
+    let index = samples
+        .iter()
+        .position(|sample| sample.time > target)
+        .unwrap_or(samples.len())
+        .saturating_sub(2);
+    let start = samples.get(index).map_or(target, |sample| sample.time);
+    (index, start)
+}
+
+// the time at the end of the last sample, which lasts as long as the
+// previous one
+fn end_time(samples: &[Sample]) -> Option<u64> {
+    let last = samples.last()?;
+    let delta = samples
+        .len()
+        .checked_sub(2)
+        .map_or(0, |before| last.time - samples[before].time);
+    Some(last.time + delta)
+}
+
+// the audio specific config of AAC, in the esds box of the "mp4a" entry
+fn read_esds(esds: &[u8]) -> Option<Vec<u8>> {
+    // descriptors have a tag and a length of up to 4 bytes of 7 bits
+    fn descriptor(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+        let tag = *data.get(0)?;
+        let mut length = 0;
+        let mut pos = 1;
+        loop {
+            let byte = *data.get(pos)?;
+            length = (length << 7) | (byte & 0x7f) as usize;
+            pos += 1;
+            if byte & 0x80 == 0 || pos == 5 {
+                break;
+            }
+        }
+        let content = data.get(pos..pos + length)?;
+        Some((tag, content, &data[pos + length..]))
+    }
+
+    let (tag, es, _) = descriptor(esds.get(4..)?)?;
+    if tag != ES_DESCRIPTOR {
+        return None;
+    }
+    // ES id then flags telling which optional fields follow
+    let flags = *es.get(2)?;
+    let mut pos = 3;
+    if flags & 0x80 != 0 {
+        pos += 2;
+    }
+    if flags & 0x40 != 0 {
+        pos += 1 + *es.get(pos)? as usize;
+    }
+    if flags & 0x20 != 0 {
+        pos += 2;
+    }
+
+    let (tag, config, _) = descriptor(es.get(pos..)?)?;
+    if tag != DECODER_CONFIG_DESCRIPTOR || *config.get(0)? != MPEG4_AUDIO {
+        return None;
+    }
+    let (tag, specific, _) = descriptor(config.get(13..)?)?;
+    if tag != DECODER_SPECIFIC_INFO {
+        return None;
+    }
+    Some(specific.to_vec())
+}
+
+enum Codec {
+    Aac(fdk_aac::dec::Decoder),
+    Alac(alac::Decoder),
+}
+
+// reads the sample description, returns the codec
+fn read_codec(stbl: &[u8]) -> io::Result<Codec> {
+    let stsd = child(stbl, b"stsd").ok_or_else(|| invalid_data("no stsd box"))?;
+    // version, flags and number of entries
+    let entry = atoms(stsd.get(8..).unwrap_or_default())
+        .next()
+        .ok_or_else(|| invalid_data("no sample description"))?;
+
+    // audio sample entry: 28 bytes, 16 or 36 more for the QuickTime
+    // versions 1 and 2
+    let version = be_at(entry.data, 8, 2).unwrap_or(0);
+    let children_start = match version {
+        1 => 28 + 16,
+        2 => 28 + 36,
+        _ => 28,
+    };
+    let children = entry.data.get(children_start..).unwrap_or_default();
+
+    match &entry.kind {
+        b"mp4a" => {
+            let config = child(children, b"esds")
+                .and_then(read_esds)
+                .ok_or_else(|| invalid_data("no AAC configuration"))?;
+            let mut decoder = fdk_aac::dec::Decoder::new(fdk_aac::dec::Transport::Raw);
+            decoder
+                .config_raw(&config)
+                .map_err(|err| invalid_data(format!("{:?}", err)))?;
+            Ok(Codec::Aac(decoder))
+        }
+        b"alac" => {
+            // the "magic cookie" follows the version and flags of the box
+            let cookie = child(children, b"alac")
+                .and_then(|alac| alac.get(4..))
+                .ok_or_else(|| invalid_data("no ALAC configuration"))?;
+            let info = alac::StreamInfo::from_cookie(cookie)
+                .map_err(|err| invalid_data(format!("{:?}", err)))?;
+            Ok(Codec::Alac(alac::Decoder::new(info)))
+        }
+        _ => Err(invalid_data("unsupported MP4 audio codec")),
+    }
+}
+
+pub struct Mp4Decoder<R: Read + Seek> {
+    reader: R,
+    codec: Codec,
+    samples: Vec<Sample>,
+    time_scale: u32,
+    sample_rate: u32,
+    channels: u16,
+    // next sample (i.e. packet) to decode
+    next: usize,
+    packet: Vec<u8>,
+    aac_output: Vec<i16>,
+    alac_output: Vec<i32>,
+    pending: VecDeque<f32>,
+    // frames to drop after a seek
+    skip: usize,
+    errors: ErrorLog,
+}
+
+impl<R: Read + Seek> Mp4Decoder<R> {
+    pub fn new(mut data: R) -> io::Result<Mp4Decoder<R>> {
+        let moov = read_moov(&mut data)?;
+        let file_length = data.seek(SeekFrom::End(0))?;
+        let trak = audio_track(&moov).ok_or_else(|| invalid_data("no audio track"))?;
+        let mdia = child(trak, b"mdia").unwrap_or_default();
+        // time scale of the track, after version, flags, creation and
+        // modification times (on 64 bits in the version 1)
+        let time_scale = child(mdia, b"mdhd")
+            .and_then(|mdhd| match mdhd.get(0) {
+                Some(1) => be_at(mdhd, 20, 4),
+                _ => be_at(mdhd, 12, 4),
+            })
+            .unwrap_or(0) as u32;
+        let stbl = descend(mdia, &[b"minf", b"stbl"]).ok_or_else(|| invalid_data("no stbl box"))?;
+        let samples =
+            read_samples(stbl, file_length).ok_or_else(|| invalid_data("invalid sample tables"))?;
+        let codec = read_codec(stbl)?;
+        if time_scale == 0 {
+            return Err(invalid_data("invalid time scale"));
+        }
+
+        let mut decoder = Mp4Decoder {
+            reader: data,
+            codec,
+            samples,
+            time_scale,
+            sample_rate: time_scale,
+            channels: 2,
+            next: 0,
+            packet: vec![],
+            aac_output: vec![],
+            alac_output: vec![],
+            pending: VecDeque::new(),
+            skip: 0,
+            errors: ErrorLog::default(),
+        };
+        // the actual format is known once a packet has been decoded
+        decoder.decode_packet()?;
+        Ok(decoder)
+    }
+
+    // the samples decoded are appended to `pending`, which is empty
+    fn decode_packet(&mut self) -> io::Result<bool> {
+        let sample = match self.samples.get(self.next) {
+            Some(sample) => *sample,
+            None => return Ok(false),
+        };
+        self.next += 1;
+
+        self.packet.resize(sample.size as usize, 0);
+        self.reader.seek(SeekFrom::Start(sample.offset))?;
+        self.reader.read_exact(&mut self.packet)?;
+
+        match self.codec {
+            // the decoder of AAC only has a 16 bits output
+            Codec::Aac(ref mut decoder) => {
+                self.aac_output.resize(AAC_MAX_OUTPUT, 0);
+                let output = &mut self.aac_output;
+                decoder
+                    .fill(&self.packet)
+                    .and_then(|_| decoder.decode_frame(output))
+                    .map_err(|err| invalid_data(format!("{:?}", err)))?;
+                let info = decoder.stream_info();
+                self.sample_rate = info.sampleRate as u32;
+                self.channels = info.numChannels as u16;
+                let decoded = &self.aac_output[..decoder.decoded_frame_size()];
+                self.pending
+                    .extend(decoded.iter().map(|sample| f32::from(*sample) / 32768.0));
+            }
+            // samples of any depth are left aligned in 32 bits
+            Codec::Alac(ref mut decoder) => {
+                let info = decoder.stream_info();
+                self.sample_rate = info.sample_rate();
+                self.channels = u16::from(info.channels());
+                self.alac_output.resize(
+                    info.max_frames_per_packet() as usize * info.channels() as usize,
+                    0,
+                );
+                let decoded = decoder
+                    .decode_packet(&self.packet, &mut self.alac_output)
+                    .map_err(|err| invalid_data(format!("{:?}", err)))?;
+                self.pending.extend(
+                    decoded
+                        .iter()
+                        .map(|sample| *sample as f32 / 2_147_483_648.0),
+                );
+            }
+        }
+
+        let drop = (self.skip * self.channels as usize).min(self.pending.len());
+        self.pending.drain(..drop);
+        self.skip -= drop / self.channels.max(1) as usize;
+        Ok(true)
+    }
+}
+
+impl<R: Read + Seek> Decoder for Mp4Decoder<R> {
+    fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        let end = end_time(&self.samples)?;
+        Some(samples_to_duration(end, self.time_scale))
+    }
+
+    /// Decodes from the packet before the target, the overlap of the
+    /// transforms needs it, and drops the samples up to the target.
+    fn seek(&mut self, position: Duration) -> io::Result<()> {
+        let target = duration_to_samples(position, self.time_scale);
+        let (index, start) = seek_point(&self.samples, target);
+
+        self.next = index;
+        self.pending.clear();
+        self.skip = ((target.saturating_sub(start)) * u64::from(self.sample_rate)
+            / u64::from(self.time_scale)) as usize;
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [f32]) -> usize {
+        let mut length = 0;
+        while length < buffer.len() {
+            match self.pending.pop_front() {
+                Some(sample) => {
+                    buffer[length] = sample;
+                    length += 1;
+                }
+                None => match self.decode_packet() {
+                    Ok(true) => {}
+                    Ok(false) => break,
+                    Err(err) => {
+                        self.errors
+                            .report(format_args!("MP4 decoding error: {}", err));
+                        break;
+                    }
+                },
+            }
+        }
+        length
+    }
+}
+
+pub fn open(file: File) -> io::Result<Box<dyn Decoder>> {
+    Mp4Decoder::new(BufReader::new(file)).map(|decoder| Box::new(decoder) as Box<dyn Decoder>)
+}
+
+/// Duration from the sample tables, no packet is decoded.
+pub fn compute_duration(file: File) -> Option<Duration> {
+    let moov = read_moov(&mut BufReader::new(file)).ok()?;
+    let mdhd = descend(audio_track(&moov)?, &[b"mdia", b"mdhd"])?;
+    let (time_scale, duration) = match mdhd.get(0) {
+        Some(1) => (be_at(mdhd, 20, 4)?, be_at(mdhd, 24, 8)?),
+        _ => (be_at(mdhd, 12, 4)?, be_at(mdhd, 16, 4)?),
+    };
+    if time_scale == 0 {
+        return None;
+    }
+    Some(samples_to_duration(duration, time_scale as u32))
+}
+
+// value of an iTunes metadata item: its "data" box holds a type and a
+// locale before the value
+fn item<'a>(ilst: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    child(ilst, kind)
+        .and_then(|item| child(item, b"data"))
+        .and_then(|data| data.get(8..))
+}
+
+fn text_item(ilst: &[u8], kind: &[u8; 4]) -> Option<String> {
+    item(ilst, kind).map(|text| String::from_utf8_lossy(text).to_string())
+}
+
+// value of a freeform ("----") item, identified by the text of its "name"
+// box rather than by its type
+fn freeform_item(ilst: &[u8], name: &str) -> Option<String> {
+    atoms(ilst)
+        .filter(|atom| &atom.kind == b"----")
+        .find(|atom| {
+            // "name" is a full box
+            child(atom.data, b"name")
+                .and_then(|text| text.get(4..))
+                .map_or(false, |text| {
+                    String::from_utf8_lossy(text).eq_ignore_ascii_case(name)
+                })
+        })
+        .and_then(|atom| child(atom.data, b"data"))
+        .and_then(|data| data.get(8..))
+        .map(|text| String::from_utf8_lossy(text).to_string())
+}
+
+/// Tags of the "ilst" box (©nam, ©ART, covr, trkn...).
+pub fn read_metadata(file: File) -> Option<Metadata> {
+    let moov = read_moov(&mut BufReader::new(file)).ok()?;
+    let meta = descend(&moov, &[b"udta", b"meta"])?;
+    // "meta" is a full box
+    let ilst = child(meta.get(4..)?, b"ilst")?;
+
+    // track number and total on 16 bits, after 2 bytes of padding
+    let trkn = item(ilst, b"trkn");
+    let track_field = |pos| {
+        trkn.and_then(|trkn| be_at(trkn, pos, 2))
+            .filter(|value| *value != 0)
+            .map(|value| value.to_string())
+    };
+
+    Some(Metadata {
+        title: text_item(ilst, b"\xa9nam"),
+        artist: text_item(ilst, b"\xa9ART").or_else(|| text_item(ilst, b"aART")),
+        album: text_item(ilst, b"\xa9alb"),
+        genre: text_item(ilst, b"\xa9gen"),
+        // the date is often complete, keep the year
+        year: text_item(ilst, b"\xa9day").map(|date| date.chars().take(4).collect()),
+        track: track_field(2),
+        total_tracks: track_field(4),
+        picture: item(ilst, b"covr").map(|data| data.to_vec()),
+        gapless: item(ilst, b"pgap")
+            .and_then(|flag| flag.first())
+            .map_or(false, |flag| *flag != 0),
+        replay_gain: ReplayGain::from_texts(|name| freeform_item(ilst, name)),
+        chapters: vec![],
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    fn atom(kind: &[u8; 4], data: &[u8]) -> Vec<u8> {
+        let mut atom = (data.len() as u32 + 8).to_be_bytes().to_vec();
+        atom.extend_from_slice(kind);
+        atom.extend_from_slice(data);
+        atom
+    }
+
+    // a full box whose table has `count` entries of 32 bits fields
+    fn table(kind: &[u8; 4], count: u32, fields: &[u32]) -> Vec<u8> {
+        let mut data = vec![0; 4];
+        data.extend_from_slice(&count.to_be_bytes());
+        for field in fields {
+            data.extend_from_slice(&field.to_be_bytes());
+        }
+        atom(kind, &data)
+    }
+
+    // a stsz box of samples of `size` bytes, without table
+    fn constant_sizes(size: u32, count: u32) -> Vec<u8> {
+        let mut data = vec![0; 4];
+        data.extend_from_slice(&size.to_be_bytes());
+        data.extend_from_slice(&count.to_be_bytes());
+        atom(b"stsz", &data)
+    }
+
+    // 5 samples of 100 bytes in 2 chunks of 3 and 2 samples, at 1000 and
+    // 5000, of 1024 units of time each
+    fn sample_tables() -> Vec<u8> {
+        let mut stbl = constant_sizes(100, 5);
+        stbl.extend(table(b"stco", 2, &[1000, 5000]));
+        stbl.extend(table(b"stsc", 2, &[1, 3, 1, 2, 2, 1]));
+        stbl.extend(table(b"stts", 1, &[5, 1024]));
+        stbl
+    }
+
+    #[test]
+    fn boxes_are_walked() {
+        let mut data = atom(b"free", b"abc");
+        // a 64 bits size, then a size of 0 which runs to the end
+        data.extend_from_slice(&[0, 0, 0, 1]);
+        data.extend_from_slice(b"wide");
+        data.extend_from_slice(&20u64.to_be_bytes());
+        data.extend_from_slice(b"1234");
+        data.extend_from_slice(&[0, 0, 0, 0]);
+        data.extend_from_slice(b"last");
+        data.extend_from_slice(b"end");
+
+        let found: Vec<_> = atoms(&data).map(|atom| (atom.kind, atom.data)).collect();
+        let expected: [([u8; 4], &[u8]); 3] =
+            [(*b"free", b"abc"), (*b"wide", b"1234"), (*b"last", b"end")];
+        assert_eq!(found, expected);
+
+        let nested = atom(b"moov", &atom(b"trak", &atom(b"mdia", b"x")));
+        let moov = child(&nested, b"moov").unwrap();
+        assert_eq!(descend(moov, &[b"trak", b"mdia"]), Some(&b"x"[..]));
+        assert_eq!(descend(moov, &[b"trak", b"minf"]), None);
+    }
+
+    #[test]
+    fn boxes_past_their_parent_stop_the_walk() {
+        let mut data = atom(b"free", b"abc");
+        data.extend_from_slice(&[0, 0, 0, 100]);
+        data.extend_from_slice(b"trak");
+        assert_eq!(atoms(&data).count(), 1);
+        // smaller than its header
+        assert_eq!(atoms(&[0, 0, 0, 4, b'f', b'r', b'e', b'e']).count(), 0);
+    }
+
+    #[test]
+    fn moov_is_read_past_the_other_boxes() {
+        let mut file = atom(b"ftyp", b"M4A ");
+        file.extend(atom(b"mdat", &[0; 64]));
+        file.extend(atom(b"moov", b"tables"));
+        assert_eq!(read_moov(&mut Cursor::new(file)).unwrap(), b"tables");
+
+        // a moov of 4 GiB in a file of a few bytes is not allocated
+        let mut file = atom(b"ftyp", b"M4A ");
+        file.extend_from_slice(&[0xff, 0xff, 0xff, 0xff]);
+        file.extend_from_slice(b"moov");
+        let err = read_moov(&mut Cursor::new(file)).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(read_moov(&mut Cursor::new(atom(b"ftyp", b"M4A "))).is_err());
+    }
+
+    #[test]
+    fn samples_from_32_bits_offsets() {
+        let samples = read_samples(&sample_tables(), 10_000).unwrap();
+        let offsets: Vec<u64> = samples.iter().map(|sample| sample.offset).collect();
+        assert_eq!(offsets, [1000, 1100, 1200, 5000, 5100]);
+        assert!(samples.iter().all(|sample| sample.size == 100));
+        let times: Vec<u64> = samples.iter().map(|sample| sample.time).collect();
+        assert_eq!(times, [0, 1024, 2048, 3072, 4096]);
+        assert_eq!(end_time(&samples), Some(5 * 1024));
+    }
+
+    #[test]
+    fn samples_from_64_bits_offsets_and_a_size_table() {
+        let mut co64 = vec![0; 4];
+        co64.extend_from_slice(&2u32.to_be_bytes());
+        co64.extend_from_slice(&0x1_0000_0000u64.to_be_bytes());
+        co64.extend_from_slice(&0x2_0000_0000u64.to_be_bytes());
+        let mut stbl = table(b"stsz", 0, &[5, 10, 20, 30, 40, 50]);
+        stbl.extend(atom(b"co64", &co64));
+        stbl.extend(table(b"stsc", 1, &[1, 3, 1]));
+        stbl.extend(table(b"stts", 2, &[2, 1000, 3, 500]));
+
+        let samples = read_samples(&stbl, 0x3_0000_0000).unwrap();
+        let found: Vec<_> = samples
+            .iter()
+            .map(|sample| (sample.offset, sample.size, sample.time))
+            .collect();
+        let expected = [
+            (0x1_0000_0000, 10, 0),
+            (0x1_0000_000a, 20, 1000),
+            (0x1_0000_001e, 30, 2000),
+            (0x2_0000_0000, 40, 2500),
+            (0x2_0000_0028, 50, 3000),
+        ];
+        assert_eq!(found, expected);
+        assert_eq!(end_time(&samples), Some(3500));
+    }
+
+    #[test]
+    fn counts_past_the_tables_are_rejected() {
+        let huge = |kind| table(kind, 0x4000_0000, &[1, 1, 1]);
+        let tables = |stsz, stco, stsc, stts| [stsz, stco, stsc, stts].concat();
+        let stsz = || constant_sizes(1, 1);
+        let stco = || table(b"stco", 1, &[0]);
+        let stsc = || table(b"stsc", 1, &[1, 1, 1]);
+        let stts = || table(b"stts", 1, &[1, 1]);
+        assert!(read_samples(&tables(stsz(), stco(), stsc(), stts()), 10).is_some());
+
+        let sizes = table(b"stsz", 0, &[0x4000_0000, 1, 1]);
+        assert!(read_samples(&tables(sizes, stco(), stsc(), stts()), 10).is_none());
+        let co64 = huge(b"co64");
+        assert!(read_samples(&tables(stsz(), co64, stsc(), stts()), 10).is_none());
+        assert!(read_samples(&tables(stsz(), huge(b"stco"), stsc(), stts()), 10).is_none());
+        assert!(read_samples(&tables(stsz(), stco(), huge(b"stsc"), stts()), 10).is_none());
+        assert!(read_samples(&tables(stsz(), stco(), stsc(), huge(b"stts")), 10).is_none());
+
+        // without a table, the samples must fit in the file
+        let constant = constant_sizes(256, 0xffff_ffff);
+        assert!(read_samples(&tables(constant, stco(), stsc(), stts()), 10).is_none());
+    }
+
+    #[test]
+    fn samples_past_the_end_of_the_file_are_dropped() {
+        let samples = read_samples(&sample_tables(), 5150).unwrap();
+        assert_eq!(samples.len(), 4);
+    }
+
+    #[test]
+    fn seek_starts_a_sample_before_the_target() {
+        let samples = read_samples(&sample_tables(), 10_000).unwrap();
+        assert_eq!(seek_point(&samples, 0), (0, 0));
+        assert_eq!(seek_point(&samples, 1023), (0, 0));
+        assert_eq!(seek_point(&samples, 2048), (1, 1024));
+        assert_eq!(seek_point(&samples, 2500), (1, 1024));
+        // past the end, the last two samples are decoded
+        assert_eq!(seek_point(&samples, 100_000), (3, 3072));
+        assert_eq!(seek_point(&[], 500), (0, 500));
+        assert_eq!(end_time(&[]), None);
+    }
+
+    #[test]
+    fn audio_track_is_found_by_its_handler() {
+        let handler = |kind: &[u8; 4]| {
+            let mut hdlr = vec![0; 8];
+            hdlr.extend_from_slice(kind);
+            atom(b"mdia", &atom(b"hdlr", &hdlr))
+        };
+        let mut moov = atom(b"trak", &handler(b"vide"));
+        moov.extend(atom(b"trak", &handler(b"soun")));
+        assert_eq!(audio_track(&moov), Some(&handler(b"soun")[..]));
+        assert!(audio_track(&atom(b"trak", &handler(b"text"))).is_none());
+    }
+}
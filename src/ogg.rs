@@ -0,0 +1,794 @@
+use std::{
+    collections::VecDeque,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use lewton::{
+    audio::{read_audio_packet_generic, PreviousWindowRight},
+    header::{read_header_ident, read_header_setup, IdentHeader, SetupHeader},
+};
+
+use crate::{
+    decoder::{duration_to_samples, read_full, samples_to_duration, Decoder, ErrorLog},
+    metadata::{self, Metadata},
+};
+
+const CAPTURE_PATTERN: &[u8] = b"OggS";
+const HEADER_SIZE: usize = 27;
+
+// flags of the page header
+const CONTINUED: u8 = 0x01;
+
+// granule position of the pages where no packet ends
+const NO_GRANULE: u64 = 0xffff_ffff_ffff_ffff;
+
+// below this size the bisection of a seek falls back to a linear scan
+const BISECTION_THRESHOLD: u64 = 64 * 1024;
+// size of the end of the file read to find the last granule position
+const TAIL_SIZE: u64 = 64 * 1024;
+
+// Opus always counts granule positions at 48 kHz
+const OPUS_RATE: u32 = 48_000;
+// 120 ms at 48 kHz, the longest Opus packet
+const OPUS_MAX_FRAME: usize = 5760;
+
+/// Recognizes an Ogg stream whose first packet is a Vorbis or Opus header.
+pub fn sniff(header: &[u8]) -> bool {
+    if !header.starts_with(CAPTURE_PATTERN) || header.len() < HEADER_SIZE {
+        return false;
+    }
+    let data_start = HEADER_SIZE + header[HEADER_SIZE - 1] as usize;
+    match header.get(data_start..) {
+        Some(data) => data.get(1..7) == Some(&b"vorbis"[..]) || data.starts_with(b"OpusHead"),
+        None => false,
+    }
+}
+
+fn invalid_data<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, err.to_string())
+}
+
+fn le_u64(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, byte| (acc << 8) | u64::from(*byte))
+}
+
+struct Page {
+    granule: u64,
+    serial: u32,
+    flags: u8,
+    lacing: Vec<u8>,
+    data: Vec<u8>,
+}
+
+impl Page {
+    fn size(&self) -> u64 {
+        (HEADER_SIZE + self.lacing.len() + self.data.len()) as u64
+    }
+}
+
+// reads the page at the current position, or the first one after it when
+// the stream is not at a page boundary
+fn read_page<R: Read>(data: &mut R) -> io::Result<Option<Page>> {
+    let mut header = [0; HEADER_SIZE];
+    if read_full(data, &mut header[..4]) < 4 {
+        return Ok(None);
+    }
+    // resynchronize on the capture pattern
+    while &header[..4] != CAPTURE_PATTERN {
+        header.copy_within(1..4, 0);
+        if read_full(data, &mut header[3..4]) < 1 {
+            return Ok(None);
+        }
+    }
+    if read_full(data, &mut header[4..]) < HEADER_SIZE - 4 {
+        return Ok(None);
+    }
+
+    let mut lacing = vec![0; header[26] as usize];
+    data.read_exact(&mut lacing)?;
+    let length = lacing.iter().map(|value| *value as usize).sum();
+    let mut body = vec![0; length];
+    data.read_exact(&mut body)?;
+
+    Ok(Some(Page {
+        granule: le_u64(&header[6..14]),
+        serial: le_u64(&header[14..18]) as u32,
+        flags: header[5],
+        lacing,
+        data: body,
+    }))
+}
+
+struct Packet {
+    data: Vec<u8>,
+    // granule position of the page, for the last packet completed in it
+    granule: Option<u64>,
+}
+
+/// Splits the pages of the first logical stream of an Ogg file in packets.
+pub struct OggReader<R: Read + Seek> {
+    reader: R,
+    serial: Option<u32>,
+    packets: VecDeque<Packet>,
+    // packet continued on the next page
+    partial: Vec<u8>,
+    // the first packet of the next page is the end of a packet we don't have
+    skip_continued: bool,
+}
+
+impl<R: Read + Seek> OggReader<R> {
+    fn new(reader: R) -> Self {
+        OggReader {
+            reader,
+            serial: None,
+            packets: VecDeque::new(),
+            partial: vec![],
+            skip_continued: false,
+        }
+    }
+
+    fn next_page(&mut self) -> io::Result<Option<Page>> {
+        while let Some(page) = read_page(&mut self.reader)? {
+            match self.serial {
+                Some(serial) if serial != page.serial => continue,
+                None => self.serial = Some(page.serial),
+                _ => {}
+            }
+            return Ok(Some(page));
+        }
+        Ok(None)
+    }
+
+    fn next_packet(&mut self) -> io::Result<Option<Packet>> {
+        while self.packets.is_empty() {
+            let page = match self.next_page()? {
+                Some(page) => page,
+                None => return Ok(None),
+            };
+
+            let mut skip = page.flags & CONTINUED != 0 && self.skip_continued;
+            if page.flags & CONTINUED == 0 {
+                self.partial.clear();
+            }
+            self.skip_continued = false;
+
+            let mut pos = 0;
+            let mut completed = vec![];
+            for value in &page.lacing {
+                let end = pos + *value as usize;
+                if !skip {
+                    self.partial.extend_from_slice(&page.data[pos..end]);
+                }
+                pos = end;
+                // a lacing value below 255 ends a packet
+                if *value < 255 {
+                    if !skip {
+                        completed.push(std::mem::replace(&mut self.partial, vec![]));
+                    }
+                    skip = false;
+                }
+            }
+
+            let count = completed.len();
+            for (index, data) in completed.into_iter().enumerate() {
+                let granule = if index + 1 == count && page.granule != NO_GRANULE {
+                    Some(page.granule)
+                } else {
+                    None
+                };
+                self.packets.push_back(Packet { data, granule });
+            }
+        }
+        Ok(self.packets.pop_front())
+    }
+
+    // start, granule position and end of the first page at or after
+    // `offset` which ends a packet
+    fn granule_after(&mut self, offset: u64) -> io::Result<Option<(u64, u64, u64)>> {
+        self.reader.seek(SeekFrom::Start(offset))?;
+        while let Some(page) = self.next_page()? {
+            if page.granule != NO_GRANULE {
+                let end = self.reader.seek(SeekFrom::Current(0))?;
+                return Ok(Some((end - page.size(), page.granule, end)));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Moves to the page following the last one whose granule position is
+    /// below `target`, `start` is the offset of the first audio page.
+    fn seek_granule(&mut self, target: u64, start: u64) -> io::Result<()> {
+        // bisection on the byte offsets: the page at `low` ends before
+        // `target`, the one at `high` after it
+        let mut low = start;
+        let mut high = self.reader.seek(SeekFrom::End(0))?;
+        while high - low > BISECTION_THRESHOLD {
+            let middle = low + (high - low) / 2;
+            match self.granule_after(middle)? {
+                Some((page, granule, _)) if page < high => {
+                    if granule < target {
+                        low = page;
+                    } else {
+                        high = page;
+                    }
+                }
+                _ => high = middle,
+            }
+        }
+
+        // then linear scan of the pages
+        let mut resume = low;
+        let mut offset = low;
+        while let Some((_, granule, end)) = self.granule_after(offset)? {
+            if granule >= target {
+                break;
+            }
+            resume = end;
+            offset = end;
+        }
+
+        self.reader.seek(SeekFrom::Start(resume))?;
+        self.packets.clear();
+        self.partial.clear();
+        self.skip_continued = true;
+        Ok(())
+    }
+
+    /// Granule position of the last page of the stream.
+    fn last_granule(&mut self) -> io::Result<Option<u64>> {
+        let length = self.reader.seek(SeekFrom::End(0))?;
+        let mut offset = length.saturating_sub(TAIL_SIZE);
+        let mut last = None;
+        while let Some((_, granule, end)) = self.granule_after(offset)? {
+            last = Some(granule);
+            offset = end;
+        }
+        Ok(last)
+    }
+}
+
+enum Codec {
+    Vorbis {
+        ident: IdentHeader,
+        setup: SetupHeader,
+        previous: PreviousWindowRight,
+    },
+    Opus {
+        decoder: opus::Decoder,
+        channels: u16,
+        // output rate, granule positions are at 48 kHz
+        rate: u32,
+        pre_skip: u64,
+        // linear factor of the output gain of the header
+        gain: f32,
+    },
+}
+
+// Vorbis orders the channels as FL FC FR..., the decoders output the WAV
+// order FL FR FC LFE...: the index in the packet of each output channel
+fn vorbis_channel_order(channels: usize) -> Vec<usize> {
+    match channels {
+        3 => vec![0, 2, 1],
+        5 => vec![0, 2, 1, 3, 4],
+        6 => vec![0, 2, 1, 5, 3, 4],
+        7 => vec![0, 2, 1, 6, 5, 3, 4],
+        8 => vec![0, 2, 1, 7, 5, 6, 3, 4],
+        _ => (0..channels).collect(),
+    }
+}
+
+impl Codec {
+    fn sample_rate(&self) -> u32 {
+        match self {
+            Codec::Vorbis { ident, .. } => ident.audio_sample_rate,
+            Codec::Opus { rate, .. } => *rate,
+        }
+    }
+
+    fn channels(&self) -> u16 {
+        match self {
+            Codec::Vorbis { ident, .. } => u16::from(ident.audio_channels),
+            Codec::Opus { channels, .. } => *channels,
+        }
+    }
+
+    // converts a granule position to a sample number of the output, the
+    // samples of the Opus pre-skip come before 0
+    fn granule_to_samples(&self, granule: u64) -> u64 {
+        match self {
+            Codec::Vorbis { .. } => granule,
+            Codec::Opus { rate, pre_skip, .. } => {
+                granule.saturating_sub(*pre_skip) * u64::from(*rate) / u64::from(OPUS_RATE)
+            }
+        }
+    }
+
+    fn samples_to_granule(&self, samples: u64) -> u64 {
+        match self {
+            Codec::Vorbis { .. } => samples,
+            Codec::Opus { rate, pre_skip, .. } => {
+                samples * u64::from(OPUS_RATE) / u64::from(*rate) + pre_skip
+            }
+        }
+    }
+
+    // forgets the state depending on the previous packets, after a seek
+    fn reset(&mut self) {
+        match self {
+            Codec::Vorbis { previous, .. } => *previous = PreviousWindowRight::new(),
+            Codec::Opus { decoder, .. } => {
+                let _ = decoder.reset_state();
+            }
+        }
+    }
+
+    // appends the interleaved samples of a packet to `output`
+    fn decode(&mut self, packet: &[u8], output: &mut Vec<f32>) -> io::Result<()> {
+        match self {
+            Codec::Vorbis {
+                ident,
+                setup,
+                previous,
+            } => {
+                let channels: Vec<Vec<f32>> =
+                    read_audio_packet_generic(ident, setup, packet, previous)
+                        .map_err(invalid_data)?;
+                let length = channels.get(0).map_or(0, |channel| channel.len());
+                let order = vorbis_channel_order(channels.len());
+                for i in 0..length {
+                    output.extend(order.iter().map(|channel| channels[*channel][i]));
+                }
+            }
+
+            Codec::Opus {
+                decoder,
+                channels,
+                gain,
+                ..
+            } => {
+                let channels = *channels as usize;
+                let start = output.len();
+                output.resize(start + OPUS_MAX_FRAME * channels, 0.0);
+                let frames = decoder
+                    .decode_float(packet, &mut output[start..], false)
+                    .map_err(invalid_data)?;
+                output.truncate(start + frames * channels);
+                if *gain != 1.0 {
+                    for sample in &mut output[start..] {
+                        *sample *= *gain;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// the output rates libopus can decode to, other rates are decoded at 48 kHz
+fn opus_output_rate(input_rate: u32) -> u32 {
+    match input_rate {
+        8000 | 12000 | 16000 | 24000 => input_rate,
+        _ => OPUS_RATE,
+    }
+}
+
+fn read_headers<R: Read + Seek>(
+    reader: &mut OggReader<R>,
+) -> io::Result<(Codec, Vec<(String, String)>)> {
+    let mut next = || -> io::Result<Vec<u8>> {
+        reader
+            .next_packet()?
+            .map(|packet| packet.data)
+            .ok_or_else(|| invalid_data("missing header"))
+    };
+
+    let first = next()?;
+    if first.starts_with(b"OpusHead") {
+        if first.len() < 19 {
+            return Err(invalid_data("OpusHead too short"));
+        }
+        let channels = u16::from(first[9]);
+        let pre_skip = u64::from(first[10]) | (u64::from(first[11]) << 8);
+        let input_rate = le_u64(&first[12..16]) as u32;
+        // in dB, Q7.8 fixed point, to be applied to the decoded samples
+        let output_gain = i16::from_le_bytes([first[16], first[17]]);
+        let gain = 10f32.powf(f32::from(output_gain) / (20.0 * 256.0));
+        // other mapping families need the multistream decoder
+        let mapping_family = first[18];
+        let opus_channels = match (channels, mapping_family) {
+            (1, 0) => opus::Channels::Mono,
+            (2, 0) => opus::Channels::Stereo,
+            (_, 0) => return Err(invalid_data("Opus mapping family 0 has 1 or 2 channels")),
+            (_, family) => {
+                let message = format!("unsupported Opus channel mapping family {}", family);
+                return Err(invalid_data(message));
+            }
+        };
+
+        let tags = next()?;
+        let comments = tags
+            .get(8..)
+            .filter(|_| tags.starts_with(b"OpusTags"))
+            .and_then(metadata::parse_vorbis_comments)
+            .unwrap_or_default();
+
+        let rate = opus_output_rate(input_rate);
+        let decoder = opus::Decoder::new(rate, opus_channels).map_err(invalid_data)?;
+        return Ok((
+            Codec::Opus {
+                decoder,
+                channels,
+                rate,
+                pre_skip,
+                gain,
+            },
+            comments,
+        ));
+    }
+
+    let ident = read_header_ident(&first).map_err(invalid_data)?;
+    // the comment header starts with 0x03 "vorbis"
+    let comment = next()?;
+    let comments = comment
+        .get(7..)
+        .and_then(metadata::parse_vorbis_comments)
+        .unwrap_or_default();
+    let setup = read_header_setup(
+        &next()?,
+        ident.audio_channels,
+        (ident.blocksize_0, ident.blocksize_1),
+    )
+    .map_err(invalid_data)?;
+
+    Ok((
+        Codec::Vorbis {
+            ident,
+            setup,
+            previous: PreviousWindowRight::new(),
+        },
+        comments,
+    ))
+}
+
+// the frames to drop among `frames` frames ending at the sample `end`, to
+// start at `target` (the start is negative with the pre-skip of Opus)
+fn frames_before(target: u64, end: u64, frames: usize) -> usize {
+    let start = end as i64 - frames as i64;
+    (target as i64 - start).max(0).min(frames as i64) as usize
+}
+
+pub struct OggDecoder<R: Read + Seek> {
+    reader: OggReader<R>,
+    codec: Codec,
+    // offset of the first page after the headers
+    audio_start: u64,
+    duration: Option<u64>,
+    pending: VecDeque<f32>,
+    // after a seek, samples are dropped up to this position (per channel),
+    // known once a packet with a granule position is decoded
+    skip_to: Option<u64>,
+    skipped: Vec<f32>,
+    errors: ErrorLog,
+}
+
+impl<R: Read + Seek> OggDecoder<R> {
+    pub fn new(data: R) -> io::Result<OggDecoder<R>> {
+        let mut reader = OggReader::new(data);
+        let (codec, _) = read_headers(&mut reader)?;
+        let audio_start = reader.reader.seek(SeekFrom::Current(0))?;
+        let duration = reader
+            .last_granule()?
+            .map(|granule| codec.granule_to_samples(granule));
+        reader.reader.seek(SeekFrom::Start(audio_start))?;
+
+        Ok(OggDecoder {
+            reader,
+            codec,
+            audio_start,
+            duration,
+            pending: VecDeque::new(),
+            // drops the pre-skip of Opus
+            skip_to: Some(0),
+            skipped: vec![],
+            errors: ErrorLog::default(),
+        })
+    }
+
+    // decodes the next packet into `pending`, false at the end of the stream
+    fn decode_packet(&mut self) -> bool {
+        let packet = match self.reader.next_packet() {
+            Ok(Some(packet)) => packet,
+            _ => return false,
+        };
+
+        let mut output = std::mem::replace(&mut self.skipped, vec![]);
+        if let Err(err) = self.codec.decode(&packet.data, &mut output) {
+            self.errors
+                .report(format_args!("Ogg decoding error: {}", err));
+        }
+
+        match (self.skip_to, packet.granule) {
+            (Some(target), Some(granule)) => {
+                // the samples decoded since the seek end at `granule`
+                let channels = self.codec.channels().max(1) as usize;
+                let end = self.codec.granule_to_samples(granule);
+                let drop = frames_before(target, end, output.len() / channels) * channels;
+                self.pending.extend(&output[drop..]);
+                self.skip_to = None;
+            }
+            (Some(_), None) => self.skipped = output,
+            (None, _) => self.pending.extend(output),
+        }
+        true
+    }
+}
+
+impl<R: Read + Seek> Decoder for OggDecoder<R> {
+    fn sample_rate(&self) -> u32 {
+        self.codec.sample_rate()
+    }
+
+    fn channels(&self) -> u16 {
+        self.codec.channels()
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        self.duration
+            .map(|samples| samples_to_duration(samples, self.codec.sample_rate()))
+    }
+
+    fn seek(&mut self, position: Duration) -> io::Result<()> {
+        let target = duration_to_samples(position, self.codec.sample_rate());
+        let granule = self.codec.samples_to_granule(target);
+        self.reader.seek_granule(granule, self.audio_start)?;
+        self.codec.reset();
+        self.pending.clear();
+        self.skipped.clear();
+        self.skip_to = Some(target);
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [f32]) -> usize {
+        let mut length = 0;
+        while length < buffer.len() {
+            match self.pending.pop_front() {
+                Some(sample) => {
+                    buffer[length] = sample;
+                    length += 1;
+                }
+                None => {
+                    if !self.decode_packet() {
+                        break;
+                    }
+                }
+            }
+        }
+        length
+    }
+}
+
+pub fn open(file: File) -> io::Result<Box<dyn Decoder>> {
+    OggDecoder::new(BufReader::new(file)).map(|decoder| Box::new(decoder) as Box<dyn Decoder>)
+}
+
+pub fn compute_duration(file: File) -> Option<Duration> {
+    OggDecoder::new(BufReader::new(file))
+        .ok()
+        .and_then(|decoder| decoder.duration())
+}
+
+pub fn read_metadata(file: File) -> Option<Metadata> {
+    let mut reader = OggReader::new(BufReader::new(file));
+    let (_, comments) = read_headers(&mut reader).ok()?;
+    Some(Metadata::from_vorbis_comments(&comments))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    const SERIAL: u32 = 0x1234;
+
+    // a page of the segments `lacing`, the data is a count of its bytes
+    fn page(serial: u32, granule: u64, flags: u8, lacing: &[u8]) -> Vec<u8> {
+        let mut page = CAPTURE_PATTERN.to_vec();
+        page.extend_from_slice(&[0, flags]);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&serial.to_le_bytes());
+        // sequence number and checksum, not checked
+        page.extend_from_slice(&[0; 8]);
+        page.push(lacing.len() as u8);
+        page.extend_from_slice(lacing);
+        let length: usize = lacing.iter().map(|value| *value as usize).sum();
+        page.extend((0..length).map(|i| i as u8));
+        page
+    }
+
+    // a page of whole packets, with their data
+    fn packets_page(granule: u64, packets: &[&[u8]]) -> Vec<u8> {
+        let mut page = CAPTURE_PATTERN.to_vec();
+        page.extend_from_slice(&[0, 0]);
+        page.extend_from_slice(&granule.to_le_bytes());
+        page.extend_from_slice(&SERIAL.to_le_bytes());
+        page.extend_from_slice(&[0; 8]);
+        let lacing: Vec<u8> = packets
+            .iter()
+            .flat_map(|packet| {
+                let mut lacing = vec![255; packet.len() / 255];
+                lacing.push((packet.len() % 255) as u8);
+                lacing
+            })
+            .collect();
+        page.push(lacing.len() as u8);
+        page.extend(lacing);
+        for packet in packets {
+            page.extend_from_slice(packet);
+        }
+        page
+    }
+
+    fn opus_head(channels: u8, pre_skip: u16, rate: u32, gain: i16, family: u8) -> Vec<u8> {
+        let mut head = b"OpusHead\x01".to_vec();
+        head.push(channels);
+        head.extend_from_slice(&pre_skip.to_le_bytes());
+        head.extend_from_slice(&rate.to_le_bytes());
+        head.extend_from_slice(&gain.to_le_bytes());
+        head.push(family);
+        head
+    }
+
+    fn opus_tags(comments: &[&str]) -> Vec<u8> {
+        let mut tags = b"OpusTags".to_vec();
+        tags.extend_from_slice(&[0; 4]);
+        tags.extend_from_slice(&(comments.len() as u32).to_le_bytes());
+        for comment in comments {
+            tags.extend_from_slice(&(comment.len() as u32).to_le_bytes());
+            tags.extend_from_slice(comment.as_bytes());
+        }
+        tags
+    }
+
+    fn opus_reader(head: Vec<u8>) -> OggReader<Cursor<Vec<u8>>> {
+        let mut stream = packets_page(0, &[&head]);
+        stream.extend(packets_page(0, &[&opus_tags(&["TITLE=Song"])]));
+        stream.extend(packets_page(960, &[&[0xfc; 3]]));
+        OggReader::new(Cursor::new(stream))
+    }
+
+    fn packets<R: Read + Seek>(reader: &mut OggReader<R>) -> Vec<(usize, Option<u64>)> {
+        let mut packets = vec![];
+        while let Some(packet) = reader.next_packet().unwrap() {
+            packets.push((packet.data.len(), packet.granule));
+        }
+        packets
+    }
+
+    #[test]
+    fn packets_are_reassembled_across_pages() {
+        // garbage, then 300 bytes and the start of 600 bytes
+        let mut stream = b"junkOgg".to_vec();
+        stream.extend(page(SERIAL, 100, 0, &[255, 45, 255, 255]));
+        // a page of another logical stream
+        stream.extend(page(SERIAL + 1, 150, 0, &[10]));
+        // the end of the 600 bytes, then 10 bytes
+        stream.extend(page(SERIAL, 200, CONTINUED, &[90, 10]));
+        // a page where no packet ends
+        stream.extend(page(SERIAL, NO_GRANULE, 0, &[255]));
+        stream.extend(page(SERIAL, 300, CONTINUED, &[5]));
+
+        let mut reader = OggReader::new(Cursor::new(stream));
+        let expected = [
+            (300, Some(100)),
+            (600, None),
+            (10, Some(200)),
+            (260, Some(300)),
+        ];
+        assert_eq!(packets(&mut reader), expected);
+    }
+
+    #[test]
+    fn a_packet_without_its_start_is_dropped_after_a_seek() {
+        let mut stream = page(SERIAL, 100, 0, &[255]);
+        stream.extend(page(SERIAL, 200, CONTINUED, &[255, 1, 20]));
+        let mut reader = OggReader::new(Cursor::new(stream));
+        reader.seek_granule(150, 0).unwrap();
+        assert_eq!(packets(&mut reader), [(20, Some(200))]);
+    }
+
+    #[test]
+    fn seek_bisects_to_the_page_before_the_target() {
+        // 500 pages of 1000 bytes, 1000 samples each
+        let mut stream = vec![];
+        for i in 1..=500 {
+            stream.extend(packets_page(i * 1000, &[&[0; 1000]]));
+        }
+        let mut reader = OggReader::new(Cursor::new(stream));
+        assert_eq!(reader.last_granule().unwrap(), Some(500_000));
+
+        let mut granule_after = |target| {
+            reader.seek_granule(target, 0).unwrap();
+            reader
+                .next_packet()
+                .unwrap()
+                .and_then(|packet| packet.granule)
+        };
+        assert_eq!(granule_after(50_500), Some(51_000));
+        assert_eq!(granule_after(0), Some(1000));
+        assert_eq!(granule_after(1000), Some(1000));
+        assert_eq!(granule_after(1001), Some(2000));
+        // past the end of the stream
+        assert_eq!(granule_after(500_001), None);
+    }
+
+    #[test]
+    fn opus_header() {
+        let head = opus_head(2, 312, 44100, -1536, 0);
+        let (codec, comments) = read_headers(&mut opus_reader(head)).unwrap();
+        assert_eq!(comments, [("TITLE".to_string(), "Song".to_string())]);
+        match codec {
+            Codec::Opus {
+                channels,
+                rate,
+                pre_skip,
+                gain,
+                ..
+            } => {
+                assert_eq!((channels, rate, pre_skip), (2, OPUS_RATE, 312));
+                // -6 dB
+                assert!((gain - 0.501).abs() < 1e-3, "{}", gain);
+            }
+            Codec::Vorbis { .. } => panic!("not Opus"),
+        }
+
+        let err = |head| match read_headers(&mut opus_reader(head)) {
+            Ok(_) => String::new(),
+            Err(err) => err.to_string(),
+        };
+        assert!(err(opus_head(3, 0, 48000, 0, 0)).contains("1 or 2 channels"));
+        assert!(err(opus_head(6, 0, 48000, 0, 1)).contains("family 1"));
+        assert!(err(b"OpusHead\x01\x02".to_vec()).contains("too short"));
+    }
+
+    #[test]
+    fn opus_pre_skip_comes_before_the_first_sample() {
+        let head = opus_head(1, 312, 24000, 0, 0);
+        let (codec, _) = read_headers(&mut opus_reader(head)).unwrap();
+        assert_eq!(codec.sample_rate(), 24000);
+        // granule positions count at 48 kHz, from the start of the pre-skip
+        assert_eq!(codec.granule_to_samples(312 + 960), 480);
+        assert_eq!(codec.granule_to_samples(100), 0);
+        assert_eq!(codec.samples_to_granule(480), 312 + 960);
+
+        // the first packet of 20 ms ends at 960 - 312 at 48 kHz
+        let (codec, _) = read_headers(&mut opus_reader(opus_head(2, 312, 0, 0, 0))).unwrap();
+        let end = codec.granule_to_samples(960);
+        assert_eq!(frames_before(0, end, 960), 312);
+        assert_eq!(frames_before(0, 5000, 960), 0);
+        assert_eq!(frames_before(4500, 5000, 960), 460);
+        assert_eq!(frames_before(6000, 5000, 960), 960);
+    }
+
+    #[test]
+    fn streams_are_recognized() {
+        let opus = packets_page(0, &[&opus_head(2, 0, 48000, 0, 0)]);
+        assert!(sniff(&opus));
+        let vorbis = packets_page(0, &[b"\x01vorbis\0\0\0\0"]);
+        assert!(sniff(&vorbis));
+        let flac = packets_page(0, &[b"\x7fFLAC"]);
+        assert!(!sniff(&flac));
+        assert!(!sniff(b"OggS"));
+    }
+
+    #[test]
+    fn vorbis_channels_in_the_wav_order() {
+        // FL FC FR RL RR LFE to FL FR FC LFE RL RR
+        assert_eq!(vorbis_channel_order(6), [0, 2, 1, 5, 3, 4]);
+        assert_eq!(vorbis_channel_order(2), [0, 1]);
+    }
+}
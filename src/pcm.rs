@@ -0,0 +1,566 @@
+use std::{
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use crate::decoder::{duration_to_samples, read_full, samples_to_duration, Decoder};
+
+// format tags of the WAVE "fmt " chunk
+pub const WAVE_FORMAT_PCM: u16 = 0x0001;
+pub const WAVE_FORMAT_IEEE_FLOAT: u16 = 0x0003;
+pub const WAVE_FORMAT_EXTENSIBLE: u16 = 0xfffe;
+
+// size of the RF64 data chunk when the real one is in the "ds64" chunk
+pub const RF64_PLACEHOLDER: u32 = 0xffff_ffff;
+
+// the format chunks are a few bytes, a larger size is not allocated
+const MAX_FORMAT_CHUNK: u64 = 64 * 1024;
+
+/// Recognizes the RIFF/RF64 WAVE and AIFF/AIFC files.
+pub fn sniff(header: &[u8]) -> bool {
+    match (header.get(..4), header.get(8..12)) {
+        (Some(b"RIFF"), Some(b"WAVE")) | (Some(b"RF64"), Some(b"WAVE")) => true,
+        (Some(b"FORM"), Some(b"AIFF")) | (Some(b"FORM"), Some(b"AIFC")) => true,
+        _ => false,
+    }
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+fn le(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .rev()
+        .fold(0, |acc, byte| (acc << 8) | u64::from(*byte))
+}
+
+fn be(bytes: &[u8]) -> u64 {
+    bytes
+        .iter()
+        .fold(0, |acc, byte| (acc << 8) | u64::from(*byte))
+}
+
+// AIFF stores its sample rate as an 80 bits IEEE 754 extended float
+fn extended_to_f64(bytes: &[u8]) -> f64 {
+    let sign = if bytes[0] & 0x80 != 0 { -1.0 } else { 1.0 };
+    let exponent = ((i32::from(bytes[0] & 0x7f)) << 8) | i32::from(bytes[1]);
+    let mantissa = be(&bytes[2..10]);
+    if exponent == 0 && mantissa == 0 {
+        return 0.0;
+    }
+    sign * mantissa as f64 * 2f64.powi(exponent - 16383 - 63)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Encoding {
+    Integer { signed: bool },
+    Float,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct PcmFormat {
+    sample_rate: u32,
+    channels: u16,
+    // samples narrower than their container are left aligned in it
+    bytes_per_sample: usize,
+    encoding: Encoding,
+    big_endian: bool,
+}
+
+impl PcmFormat {
+    fn block_align(&self) -> usize {
+        self.bytes_per_sample * self.channels as usize
+    }
+
+    fn validate(self) -> io::Result<PcmFormat> {
+        let valid = self.sample_rate > 0
+            && self.channels > 0
+            && match self.encoding {
+                Encoding::Integer { .. } => (1..=4).contains(&self.bytes_per_sample),
+                Encoding::Float => self.bytes_per_sample == 4 || self.bytes_per_sample == 8,
+            };
+        if valid {
+            Ok(self)
+        } else {
+            Err(invalid_data("unsupported PCM format"))
+        }
+    }
+
+    // converts a sample to [-1, 1)
+    fn to_f32(&self, bytes: &[u8]) -> f32 {
+        let raw = if self.big_endian {
+            be(bytes)
+        } else {
+            le(bytes)
+        };
+        match self.encoding {
+            Encoding::Integer { signed } => {
+                let width = self.bytes_per_sample as u32 * 8;
+                // left align in 32 bits, 8 bits samples are unsigned in WAV
+                let value = (raw << (32 - width)) as u32;
+                let value = if signed {
+                    value as i32
+                } else {
+                    (value ^ 0x8000_0000) as i32
+                };
+                value as f32 / 2_147_483_648.0
+            }
+            Encoding::Float => {
+                if self.bytes_per_sample == 4 {
+                    f32::from_bits(raw as u32)
+                } else {
+                    f64::from_bits(raw) as f32
+                }
+            }
+        }
+    }
+}
+
+struct Layout {
+    format: PcmFormat,
+    data_start: u64,
+    // number of frames
+    frames: u64,
+}
+
+fn read_chunk_header<R: Read>(data: &mut R, big_endian: bool) -> Option<([u8; 4], u64)> {
+    let mut header = [0; 8];
+    if read_full(data, &mut header) < 8 {
+        return None;
+    }
+    let mut id = [0; 4];
+    id.copy_from_slice(&header[..4]);
+    let size = if big_endian {
+        be(&header[4..])
+    } else {
+        le(&header[4..])
+    };
+    Some((id, size))
+}
+
+// reads a chunk describing the format, whose size comes from the file
+fn read_format_chunk<R: Read>(data: &mut R, size: u64) -> io::Result<Vec<u8>> {
+    if size > MAX_FORMAT_CHUNK {
+        return Err(invalid_data("format chunk too large"));
+    }
+    let mut chunk = vec![0; size as usize];
+    data.read_exact(&mut chunk)?;
+    Ok(chunk)
+}
+
+fn parse_fmt(chunk: &[u8]) -> io::Result<PcmFormat> {
+    if chunk.len() < 16 {
+        return Err(invalid_data("fmt chunk too short"));
+    }
+
+    let mut tag = le(&chunk[0..2]) as u16;
+    let channels = le(&chunk[2..4]) as u16;
+    let sample_rate = le(&chunk[4..8]) as u32;
+    let block_align = le(&chunk[12..14]) as usize;
+    if tag == WAVE_FORMAT_EXTENSIBLE && chunk.len() >= 40 {
+        // after the valid bits and the channel mask, the sub format GUID
+        // starts with the actual tag
+        tag = le(&chunk[24..26]) as u16;
+    }
+
+    let bytes_per_sample = if channels > 0 {
+        block_align / channels as usize
+    } else {
+        0
+    };
+    let encoding = match tag {
+        // 8 bits samples are unsigned, the others signed
+        WAVE_FORMAT_PCM => Encoding::Integer {
+            signed: bytes_per_sample > 1,
+        },
+        WAVE_FORMAT_IEEE_FLOAT => Encoding::Float,
+        _ => return Err(invalid_data("compressed WAVE files are not supported")),
+    };
+
+    PcmFormat {
+        sample_rate,
+        channels,
+        bytes_per_sample,
+        encoding,
+        big_endian: false,
+    }
+    .validate()
+}
+
+fn read_wave<R: Read + Seek>(data: &mut R, rf64: bool) -> io::Result<Layout> {
+    let mut format = None;
+    let mut rf64_data_size = None;
+    // after the RIFF header
+    let mut offset = 12;
+    data.seek(SeekFrom::Start(offset))?;
+
+    while let Some((id, size)) = read_chunk_header(data, false) {
+        offset += 8;
+        match &id {
+            b"fmt " => {
+                let chunk = read_format_chunk(data, size)?;
+                format = Some(parse_fmt(&chunk)?);
+            }
+            b"ds64" if rf64 => {
+                let chunk = read_format_chunk(data, size)?;
+                // RIFF size then data size, both on 64 bits
+                rf64_data_size = chunk.get(8..16).map(le);
+            }
+            b"data" => {
+                let format = format.ok_or_else(|| invalid_data("data before fmt chunk"))?;
+                let size = match rf64_data_size {
+                    Some(real) if size == u64::from(RF64_PLACEHOLDER) => real,
+                    _ => size,
+                };
+                // streaming writers may leave a zero or oversized length
+                let available = data.seek(SeekFrom::End(0))?.saturating_sub(offset);
+                let size = if size == 0 || size > available {
+                    available
+                } else {
+                    size
+                };
+                return Ok(Layout {
+                    format,
+                    data_start: offset,
+                    frames: size / format.block_align() as u64,
+                });
+            }
+            _ => {}
+        }
+        // chunks are padded to an even size
+        offset += size + (size & 1);
+        data.seek(SeekFrom::Start(offset))?;
+    }
+    Err(invalid_data("no data chunk"))
+}
+
+fn read_aiff<R: Read + Seek>(data: &mut R, aifc: bool) -> io::Result<Layout> {
+    let mut format = None;
+    let mut offset = 12;
+    data.seek(SeekFrom::Start(offset))?;
+
+    while let Some((id, size)) = read_chunk_header(data, true) {
+        offset += 8;
+        match &id {
+            b"COMM" => {
+                let chunk = read_format_chunk(data, size)?;
+                if chunk.len() < 18 {
+                    return Err(invalid_data("COMM chunk too short"));
+                }
+
+                let channels = be(&chunk[0..2]) as u16;
+                let bits = be(&chunk[6..8]) as u16;
+                let sample_rate = extended_to_f64(&chunk[8..18]).round() as u32;
+                let compression = if aifc { chunk.get(18..22) } else { None };
+                let (encoding, big_endian, bytes_per_sample) = match compression {
+                    None | Some(b"NONE") => (
+                        Encoding::Integer { signed: true },
+                        true,
+                        (bits as usize + 7) / 8,
+                    ),
+                    Some(b"sowt") => (
+                        Encoding::Integer { signed: true },
+                        false,
+                        (bits as usize + 7) / 8,
+                    ),
+                    Some(b"fl32") | Some(b"FL32") => (Encoding::Float, true, 4),
+                    Some(b"fl64") | Some(b"FL64") => (Encoding::Float, true, 8),
+                    _ => return Err(invalid_data("compressed AIFC files are not supported")),
+                };
+
+                format = Some(
+                    PcmFormat {
+                        sample_rate,
+                        channels,
+                        bytes_per_sample,
+                        encoding,
+                        big_endian,
+                    }
+                    .validate()?,
+                );
+            }
+            b"SSND" => {
+                let format = format.ok_or_else(|| invalid_data("SSND before COMM chunk"))?;
+                let mut header = [0; 8];
+                data.read_exact(&mut header)?;
+                // the samples start after an offset meant for block alignment
+                let data_offset = be(&header[..4]);
+                let data_start = offset + 8 + data_offset;
+                let size = size.saturating_sub(8 + data_offset);
+                return Ok(Layout {
+                    format,
+                    data_start,
+                    frames: size / format.block_align() as u64,
+                });
+            }
+            _ => {}
+        }
+        offset += size + (size & 1);
+        data.seek(SeekFrom::Start(offset))?;
+    }
+    Err(invalid_data("no SSND chunk"))
+}
+
+fn read_layout<R: Read + Seek>(data: &mut R) -> io::Result<Layout> {
+    let mut header = [0; 12];
+    data.seek(SeekFrom::Start(0))?;
+    data.read_exact(&mut header)?;
+    match (&header[..4], &header[8..]) {
+        (b"RIFF", b"WAVE") => read_wave(data, false),
+        (b"RF64", b"WAVE") => read_wave(data, true),
+        (b"FORM", b"AIFF") => read_aiff(data, false),
+        (b"FORM", b"AIFC") => read_aiff(data, true),
+        _ => Err(invalid_data("not a WAVE or AIFF file")),
+    }
+}
+
+/// Uncompressed WAVE or AIFF stream, its duration comes from the header.
+pub struct PcmDecoder<R: Read + Seek> {
+    reader: R,
+    format: PcmFormat,
+    data_start: u64,
+    frames: u64,
+    // current frame
+    position: u64,
+    bytes: Vec<u8>,
+}
+
+impl<R: Read + Seek> PcmDecoder<R> {
+    pub fn new(mut data: R) -> io::Result<PcmDecoder<R>> {
+        let layout = read_layout(&mut data)?;
+        data.seek(SeekFrom::Start(layout.data_start))?;
+        Ok(PcmDecoder {
+            reader: data,
+            format: layout.format,
+            data_start: layout.data_start,
+            frames: layout.frames,
+            position: 0,
+            bytes: vec![],
+        })
+    }
+}
+
+impl<R: Read + Seek> Decoder for PcmDecoder<R> {
+    fn sample_rate(&self) -> u32 {
+        self.format.sample_rate
+    }
+
+    fn channels(&self) -> u16 {
+        self.format.channels
+    }
+
+    fn duration(&self) -> Option<Duration> {
+        Some(samples_to_duration(self.frames, self.format.sample_rate))
+    }
+
+    fn seek(&mut self, position: Duration) -> io::Result<()> {
+        let frame = duration_to_samples(position, self.format.sample_rate).min(self.frames);
+        let offset = self.data_start + frame * self.format.block_align() as u64;
+        self.reader.seek(SeekFrom::Start(offset))?;
+        self.position = frame;
+        Ok(())
+    }
+
+    fn read(&mut self, buffer: &mut [f32]) -> usize {
+        let channels = self.format.channels as usize;
+        let frames = (buffer.len() / channels).min((self.frames - self.position) as usize);
+        let size = self.format.bytes_per_sample;
+
+        self.bytes.resize(frames * channels * size, 0);
+        let length = read_full(&mut self.reader, &mut self.bytes);
+        let frames = length / self.format.block_align();
+        self.position += frames as u64;
+
+        for (sample, bytes) in buffer
+            .iter_mut()
+            .zip(self.bytes[..frames * channels * size].chunks(size))
+        {
+            *sample = self.format.to_f32(bytes);
+        }
+        frames * channels
+    }
+}
+
+pub fn open(file: File) -> io::Result<Box<dyn Decoder>> {
+    PcmDecoder::new(BufReader::new(file)).map(|decoder| Box::new(decoder) as Box<dyn Decoder>)
+}
+
+/// Exact duration, read from the header only.
+pub fn compute_duration(file: File) -> Option<Duration> {
+    let layout = read_layout(&mut BufReader::new(file)).ok()?;
+    Some(samples_to_duration(
+        layout.frames,
+        layout.format.sample_rate,
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+
+    // 44100 as an 80 bits extended float: 1.3458... * 2^15
+    const RATE_44100: [u8; 10] = [0x40, 0x0e, 0xac, 0x44, 0, 0, 0, 0, 0, 0];
+
+    fn chunk(id: &[u8], data: &[u8], big_endian: bool) -> Vec<u8> {
+        let size = data.len() as u32;
+        let mut chunk = id.to_vec();
+        if big_endian {
+            chunk.extend_from_slice(&size.to_be_bytes());
+        } else {
+            chunk.extend_from_slice(&size.to_le_bytes());
+        }
+        chunk.extend_from_slice(data);
+        if data.len() % 2 == 1 {
+            chunk.push(0);
+        }
+        chunk
+    }
+
+    fn aiff(compression: Option<&[u8]>, channels: u16, bits: u16, samples: &[u8]) -> Vec<u8> {
+        let frames = samples.len() as u32 / u32::from(channels * (bits + 7) / 8);
+        let mut comm = channels.to_be_bytes().to_vec();
+        comm.extend_from_slice(&frames.to_be_bytes());
+        comm.extend_from_slice(&bits.to_be_bytes());
+        comm.extend_from_slice(&RATE_44100);
+        if let Some(compression) = compression {
+            comm.extend_from_slice(compression);
+            comm.extend_from_slice(b"\x00");
+        }
+        // an offset of 4 bytes before the samples
+        let mut ssnd = vec![0, 0, 0, 4, 0, 0, 0, 0, 0xaa, 0xaa, 0xaa, 0xaa];
+        ssnd.extend_from_slice(samples);
+
+        let mut body = if compression.is_some() {
+            b"AIFC".to_vec()
+        } else {
+            b"AIFF".to_vec()
+        };
+        body.extend(chunk(b"COMM", &comm, true));
+        body.extend(chunk(b"NAME", b"odd", true));
+        body.extend(chunk(b"SSND", &ssnd, true));
+        chunk(b"FORM", &body, true)
+    }
+
+    fn wav(format_tag: u16, channels: u16, bits: u16, samples: &[u8]) -> Vec<u8> {
+        let bytes = u32::from(bits / 8);
+        let mut fmt = format_tag.to_le_bytes().to_vec();
+        fmt.extend_from_slice(&channels.to_le_bytes());
+        fmt.extend_from_slice(&44100u32.to_le_bytes());
+        fmt.extend_from_slice(&(44100 * bytes * u32::from(channels)).to_le_bytes());
+        fmt.extend_from_slice(&(bytes as u16 * channels).to_le_bytes());
+        fmt.extend_from_slice(&bits.to_le_bytes());
+
+        let mut body = b"WAVE".to_vec();
+        body.extend(chunk(b"fmt ", &fmt, false));
+        body.extend(chunk(b"data", samples, false));
+        chunk(b"RIFF", &body, false)
+    }
+
+    fn decode(file: Vec<u8>) -> (PcmDecoder<Cursor<Vec<u8>>>, Vec<f32>) {
+        let mut decoder = PcmDecoder::new(Cursor::new(file)).unwrap();
+        let mut samples = vec![0.0; 64];
+        let length = decoder.read(&mut samples);
+        samples.truncate(length);
+        (decoder, samples)
+    }
+
+    #[test]
+    fn extended_floats() {
+        assert_eq!(extended_to_f64(&RATE_44100), 44100.0);
+        let rate_48000 = [0x40, 0x0e, 0xbb, 0x80, 0, 0, 0, 0, 0, 0];
+        assert_eq!(extended_to_f64(&rate_48000), 48000.0);
+        let half = [0x3f, 0xfe, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(extended_to_f64(&half), 0.5);
+        let negative = [0xc0, 0x00, 0x80, 0, 0, 0, 0, 0, 0, 0];
+        assert_eq!(extended_to_f64(&negative), -2.0);
+        assert_eq!(extended_to_f64(&[0; 10]), 0.0);
+    }
+
+    #[test]
+    fn aiff_16_bits() {
+        let file = aiff(
+            None,
+            2,
+            16,
+            &[0x40, 0x00, 0x80, 0x00, 0xff, 0xff, 0x7f, 0xff],
+        );
+        assert!(sniff(&file));
+        let (decoder, samples) = decode(file);
+        assert_eq!(decoder.sample_rate(), 44100);
+        assert_eq!(decoder.channels(), 2);
+        assert_eq!(decoder.frames, 2);
+        assert_eq!(samples, [0.5, -1.0, -1.0 / 32768.0, 32767.0 / 32768.0]);
+    }
+
+    #[test]
+    fn aiff_24_bits_in_odd_chunks() {
+        let (decoder, samples) = decode(aiff(None, 1, 24, &[0xc0, 0x00, 0x00]));
+        assert_eq!(decoder.frames, 1);
+        assert_eq!(samples, [-0.5]);
+    }
+
+    #[test]
+    fn aifc_compressions() {
+        let (_, samples) = decode(aiff(Some(b"sowt"), 1, 16, &[0x00, 0x40]));
+        assert_eq!(samples, [0.5]);
+        let (_, samples) = decode(aiff(Some(b"fl32"), 1, 32, &0.25f32.to_be_bytes()));
+        assert_eq!(samples, [0.25]);
+        let (_, samples) = decode(aiff(Some(b"FL64"), 1, 64, &(-0.75f64).to_be_bytes()));
+        assert_eq!(samples, [-0.75]);
+
+        let file = aiff(Some(b"ulaw"), 1, 8, &[0]);
+        let err = PcmDecoder::new(Cursor::new(file)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn wav_8_bits_are_unsigned() {
+        let (_, samples) = decode(wav(WAVE_FORMAT_PCM, 1, 8, &[0x80, 0x00, 0xc0]));
+        assert_eq!(samples, [0.0, -1.0, 0.5]);
+    }
+
+    #[test]
+    fn wav_seek_and_duration() {
+        let samples: Vec<u8> = (0..44100 * 2)
+            .flat_map(|i: u32| (i as i16).to_le_bytes().to_vec())
+            .collect();
+        let mut decoder =
+            PcmDecoder::new(Cursor::new(wav(WAVE_FORMAT_PCM, 2, 16, &samples))).unwrap();
+        assert_eq!(decoder.duration(), Some(Duration::from_secs(1)));
+
+        decoder.seek(Duration::from_millis(500)).unwrap();
+        let mut buffer = [0.0; 2];
+        assert_eq!(decoder.read(&mut buffer), 2);
+        assert_eq!(buffer[0], 44100.0 / 32768.0 - 2.0);
+
+        decoder.seek(Duration::from_secs(2)).unwrap();
+        assert_eq!(decoder.read(&mut buffer), 0);
+    }
+
+    #[test]
+    fn oversized_format_chunks_are_not_allocated() {
+        let mut file = b"WAVE".to_vec();
+        file.extend_from_slice(b"fmt \xff\xff\xff\xff");
+        let file = chunk(b"RIFF", &file, false);
+        let err = PcmDecoder::new(Cursor::new(file)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+
+        let mut file = b"AIFF".to_vec();
+        file.extend_from_slice(b"COMM\x7f\xff\xff\xff");
+        let file = chunk(b"FORM", &file, true);
+        let err = PcmDecoder::new(Cursor::new(file)).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_files() {
+        assert!(!sniff(b"RIFF\0\0\0\0AVI "));
+        assert!(PcmDecoder::new(Cursor::new(b"FORM\0\0\0\x04AIFF".to_vec())).is_err());
+        let float_24 = wav(WAVE_FORMAT_IEEE_FLOAT, 1, 24, &[0; 3]);
+        assert!(PcmDecoder::new(Cursor::new(float_24)).is_err());
+    }
+}
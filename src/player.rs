@@ -1,152 +1,1103 @@
 use std::{
-    fs::File,
-    io::BufReader,
+    cell::Cell,
+    collections::VecDeque,
+    io, mem,
     path::{Path, PathBuf},
     sync::{Arc, Condvar, Mutex},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use crossbeam::channel::{self, Receiver, Sender};
 // use crossbeam::sync::SegQueue;
 use crossbeam::queue::SegQueue;
 
-use pulse_simple::Playback;
-
-use crate::mp3::Mp3Decoder;
+use crate::{
+    channels::{ChannelMixer, MAX_CHANNELS},
+    crossfade::{self, Crossfade, FadeCurve, MAX_CROSSFADE},
+    decoder::{self, Decoder, ErrorLog},
+    dither::DitherKind,
+    equalizer::{Equalizer, EqualizerSettings},
+    filter::{AudioFilter, FilterChain, FilterId},
+    replaygain::GainMode,
+    resampler::{Resampler, ResamplerQuality},
+    sink::{self, AudioSink, OutputFormat, SampleFormat, SinkKind},
+    stretch::TimeStretch,
+    volume::{self, Gain},
+};
 
+// frames decoded at once
 const BUFFER_SIZE: usize = 1000;
 const DEFAULT_RATE: u32 = 44100;
+// minimal interval between two position events sent to the subscribers
+const POSITION_INTERVAL: Duration = Duration::from_millis(50);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PlaybackState {
+    Stopped,
+    Playing,
+    Paused,
+}
+
+/// Notifications sent by the player thread to the subscribers.
+#[derive(Clone, Copy, Debug)]
+pub enum Event {
+    EndOfTrack,
+    /// The queued track started, right after the previous one or fading in
+    /// over its end.
+    NextTrack,
+    Position(Duration),
+    State(PlaybackState),
+}
 
 enum Action {
+    ClearFilters,
+    Equalizer(Option<EqualizerSettings>),
+    InsertFilter(usize, FilterId, Box<dyn AudioFilter>),
     Load(PathBuf),
+    Loop(Option<(Duration, Duration)>),
+    MoveFilter(FilterId, usize),
+    Mute(bool),
+    Pause,
+    Queue(Option<PathBuf>, Duration),
+    RemoveFilter(FilterId),
+    Resume,
+    Seek(Duration),
+    Speed(f64),
     Stop,
+    Volume(f32),
+}
+
+// a point of the output where the position jumps, to the beginning of the
+// next track or back to the start of a loop
+struct Jump {
+    // frames written before it
+    at: u64,
+    to: Duration,
+    next_track: bool,
+}
+
+// The sink accepts samples ahead of what is heard; since writing blocks once
+// its buffer is full, the wall clock elapsed since the first write tells how
+// much of what has been written is actually played. The position is in the
+// time of the track, which goes by `speed` times faster than the output.
+struct PlaybackClock {
+    origin: Duration,
+    written: u64,
+    rate: u32,
+    speed: f64,
+    started: Option<Instant>,
+    // jumps written but not heard yet
+    jumps: VecDeque<Jump>,
+}
+
+impl PlaybackClock {
+    fn new() -> Self {
+        PlaybackClock {
+            origin: Duration::from_secs(0),
+            written: 0,
+            rate: DEFAULT_RATE,
+            speed: 1.0,
+            started: None,
+            jumps: VecDeque::new(),
+        }
+    }
+
+    // time of the track played in a duration of output
+    fn media_time(&self, duration: Duration) -> Duration {
+        let frames = decoder::duration_to_samples(duration, self.rate) as f64 * self.speed;
+        decoder::samples_to_duration(frames as u64, self.rate)
+    }
+
+    // forgets the first `frames` written, which have been played
+    fn consume(&mut self, frames: u64) {
+        self.written -= frames;
+        for jump in &mut self.jumps {
+            jump.at = jump.at.saturating_sub(frames);
+        }
+    }
+
+    // what has been played so far counts at the previous speed, what is still
+    // buffered by the sink at the new one
+    fn set_speed(&mut self, speed: f64) {
+        if let Some(started) = self.started {
+            let played = self.played();
+            let frames = decoder::duration_to_samples(played, self.rate).min(self.written);
+            self.origin = self.position();
+            self.consume(frames);
+            self.started = Some(started + played);
+        }
+        self.speed = speed;
+    }
+
+    fn reset(&mut self, origin: Duration, rate: u32) {
+        self.origin = origin;
+        self.written = 0;
+        self.rate = rate;
+        self.started = None;
+        self.jumps.clear();
+    }
+
+    fn next_track_pending(&self) -> bool {
+        self.jumps.iter().any(|jump| jump.next_track)
+    }
+
+    // restarts from the end of what has been written, used on resume since
+    // the sink has drained its buffer meanwhile; returns true if this crossed
+    // a track boundary
+    fn rebase(&mut self) -> bool {
+        let rate = self.rate;
+        let next_track = self.next_track_pending();
+        let origin = match self.jumps.back() {
+            Some(jump) => {
+                let written = decoder::samples_to_duration(self.written - jump.at, rate);
+                jump.to + self.media_time(written)
+            }
+            None => self.origin + self.media_time(self.written_duration()),
+        };
+        self.reset(origin, rate);
+        next_track
+    }
+
+    fn advance(&mut self, frames: usize) {
+        if self.started.is_none() {
+            self.started = Some(Instant::now());
+        }
+        self.written += frames as u64;
+    }
+
+    fn written_duration(&self) -> Duration {
+        decoder::samples_to_duration(self.written, self.rate)
+    }
+
+    fn played(&self) -> Duration {
+        self.started
+            .map(|started| started.elapsed().min(self.written_duration()))
+            .unwrap_or_default()
+    }
+
+    fn position(&self) -> Duration {
+        self.origin + self.media_time(self.played())
+    }
+
+    // the next track starts after what has been written so far, returns true
+    // if the previous boundary was not heard yet
+    fn mark_track_boundary(&mut self) -> bool {
+        let pending = self.next_track_pending();
+        self.jumps.push_back(Jump {
+            at: self.written,
+            to: Duration::from_secs(0),
+            next_track: true,
+        });
+        pending
+    }
+
+    // the track goes on from `position` after what has been written so far
+    fn mark_loop(&mut self, position: Duration) {
+        self.jumps.push_back(Jump {
+            at: self.written,
+            to: position,
+            next_track: false,
+        });
+    }
+
+    // follows the jumps which are heard, returns true if one of them is the
+    // beginning of the next track
+    fn cross_jumps(&mut self) -> bool {
+        let mut next_track = false;
+        while let Some(at) = self.jumps.front().map(|jump| jump.at) {
+            let offset = decoder::samples_to_duration(at, self.rate);
+            if self.played() < offset {
+                break;
+            }
+
+            let jump = self.jumps.pop_front().unwrap();
+            self.origin = jump.to;
+            self.consume(at);
+            self.started = self.started.map(|started| started + offset);
+            next_track |= jump.next_track;
+        }
+        next_track
+    }
 }
 
 #[derive(Clone)]
 struct EventLoop {
     // condition_variable: Arc::new((Mutex::new(false), Condvar::new())),
     condition_variable: Arc<(Mutex<bool>, Condvar)>,
+    position: Arc<Mutex<Duration>>,
     queue: Arc<SegQueue<Action>>,
-    playing: Arc<Mutex<bool>>,
+    state: Arc<Mutex<PlaybackState>>,
+    subscribers: Arc<Mutex<Vec<Sender<Event>>>>,
 }
 
 impl EventLoop {
     fn new() -> Self {
         EventLoop {
             condition_variable: Arc::new((Mutex::new(false), Condvar::new())),
+            position: Arc::new(Mutex::new(Duration::from_secs(0))),
             queue: Arc::new(SegQueue::new()),
-            playing: Arc::new(Mutex::new(false)),
+            state: Arc::new(Mutex::new(PlaybackState::Stopped)),
+            subscribers: Arc::new(Mutex::new(vec![])),
         }
     }
+
+    fn state(&self) -> PlaybackState {
+        *self.state.lock().unwrap()
+    }
+
+    fn wake_up(&self) {
+        let (ref lock, ref condition_variable) = *self.condition_variable;
+        *lock.lock().unwrap() = true;
+        condition_variable.notify_one();
+    }
+
+    // waits until some action is emitted, a wake up which happened before we
+    // get here is not lost since the flag stays set
+    fn block(&self) {
+        let (ref lock, ref condition_variable) = *self.condition_variable;
+        let mut started = lock.lock().unwrap();
+        while !*started {
+            started = condition_variable.wait(started).unwrap();
+        }
+        *started = false;
+    }
+
+    // subscribers whose receiver has been dropped are forgotten
+    fn publish(&self, event: Event) {
+        self.subscribers
+            .lock()
+            .unwrap()
+            .retain(|subscriber| subscriber.send(event).is_ok());
+    }
 }
 
-pub struct Player {
-    app_state: Arc<Mutex<super::State>>,
+fn open_decoder(path: &Path) -> Option<Box<dyn Decoder>> {
+    match decoder::open(path) {
+        Ok(ref decoder) if decoder.channels() as usize > MAX_CHANNELS => {
+            eprintln!(
+                "cannot play {}: {} channels",
+                path.display(),
+                decoder.channels()
+            );
+            None
+        }
+        Ok(decoder) => Some(decoder),
+        Err(err) => {
+            eprintln!("cannot play {}: {}", path.display(), err);
+            None
+        }
+    }
+}
+
+// a decoder with the conversion of its samples to the output format
+struct Track {
+    decoder: Box<dyn Decoder>,
+    mixer: ChannelMixer,
+    resampler: Resampler,
+    // time read from the decoder, to know when its end is near
+    position: Duration,
+    // ReplayGain of the track
+    gain: f32,
+}
+
+impl Track {
+    fn new(
+        decoder: Box<dyn Decoder>,
+        gain: f32,
+        format: OutputFormat,
+        quality: ResamplerQuality,
+    ) -> Self {
+        Track {
+            mixer: ChannelMixer::new(decoder.channels(), format.channels),
+            resampler: Resampler::new(
+                format.channels,
+                decoder.sample_rate(),
+                format.sample_rate,
+                quality,
+            ),
+            decoder,
+            position: Duration::from_secs(0),
+            gain,
+        }
+    }
+
+    // left to decode, when the decoder knows its duration
+    fn remaining(&self) -> Option<Duration> {
+        let duration = self.decoder.duration()?;
+        Some(duration.checked_sub(self.position).unwrap_or_default())
+    }
+
+    fn seek(&mut self, position: Duration) -> io::Result<()> {
+        self.decoder.seek(position)?;
+        self.resampler.reset();
+        self.position = position;
+        Ok(())
+    }
+
+    // appends samples in the format of the output to `output`, returns the
+    // number of frames, 0 once the decoder and the resampler are both
+    // exhausted
+    fn decode(
+        &mut self,
+        samples: &mut [f32],
+        mixed: &mut Vec<f32>,
+        output: &mut Vec<f32>,
+    ) -> usize {
+        let start = output.len();
+        // the decoders stop reading where the format changes, these are the
+        // rate and the channels of the samples read below
+        let channels = self.decoder.channels();
+        let rate = self.decoder.sample_rate();
+        if rate != self.resampler.input_rate() {
+            self.resampler.set_input_rate(rate, output);
+        }
+
+        let length = self
+            .decoder
+            .read(&mut samples[..BUFFER_SIZE * channels as usize]);
+        // an incomplete frame can only be the truncated end of a stream
+        let length = length - length % channels as usize;
+        if length == 0 {
+            self.resampler.drain(output);
+        } else {
+            let frames = length as u64 / u64::from(channels);
+            self.position += decoder::samples_to_duration(frames, rate);
+            self.mixer.mix(&samples[..length], channels, mixed);
+            self.resampler.process(mixed, output);
+        }
+        if self.gain != 1.0 {
+            for sample in &mut output[start..] {
+                *sample *= self.gain;
+            }
+        }
+        (output.len() - start) / self.mixer.output_channels() as usize
+    }
+}
+
+// state of the player thread
+struct Worker {
+    app_state: Arc<Mutex<crate::State>>,
     event_loop: EventLoop,
+    sink: Box<dyn AudioSink>,
+    // channels asked to the sink for the current stream, and the format it
+    // has been opened with
+    requested_channels: u16,
+    format: OutputFormat,
+    quality: ResamplerQuality,
+    equalizer: Equalizer,
+    filters: FilterChain,
+    stretch: TimeStretch,
+    gain: Gain,
+    volume: f32,
+    muted: bool,
+    clock: PlaybackClock,
+    last_published: Instant,
+    samples: Vec<f32>,
+    mixed: Vec<f32>,
+    buffer: Vec<f32>,
+    stretched: Vec<f32>,
+    // errors of the output since it has been opened
+    write_errors: ErrorLog,
+    // samples flushed by a speed change while paused, played when it resumes
+    held: Vec<f32>,
+    source: Option<Track>,
+    // the track queued to follow the current one, opened in advance, and how
+    // long they overlap
+    next: Option<(Box<dyn Decoder>, f32)>,
+    crossfade: Duration,
+    // the end of the previous track while the current one fades in, with its
+    // samples not mixed yet
+    fading: Option<(Track, Crossfade)>,
+    fade_buffer: Vec<f32>,
+    // the section of the current track played over and over
+    loop_range: Option<(Duration, Duration)>,
 }
 
-impl Player {
-    pub(crate) fn new(app_state: Arc<Mutex<super::State>>) -> Self {
-        fn iter_to_buffer<I: Iterator<Item = i16>>(
-            iter: &mut I,
-            buffer: &mut [[i16; 2]; BUFFER_SIZE],
-        ) -> usize {
-            let mut iter = iter.take(BUFFER_SIZE);
-            let mut index = 0;
-            while let Some(sample1) = iter.next() {
-                if let Some(sample2) = iter.next() {
-                    buffer[index][0] = sample1;
-                    buffer[index][1] = sample2;
-                }
-                index += 1;
+impl Worker {
+    fn new(
+        app_state: Arc<Mutex<crate::State>>,
+        event_loop: EventLoop,
+        sink_kind: &SinkKind,
+    ) -> Self {
+        let dither = app_state.lock().unwrap().config.get::<DitherKind>("dither");
+        Worker {
+            sink: sink::new_sink(sink_kind, dither.unwrap_or_default()),
+            app_state,
+            event_loop,
+            requested_channels: 2,
+            format: OutputFormat {
+                sample_rate: DEFAULT_RATE,
+                channels: 2,
+                sample_format: SampleFormat::default(),
+            },
+            quality: ResamplerQuality::default(),
+            equalizer: Equalizer::new(),
+            filters: FilterChain::new(),
+            stretch: TimeStretch::new(),
+            gain: Gain::new(1.0),
+            volume: 1.0,
+            muted: false,
+            clock: PlaybackClock::new(),
+            last_published: Instant::now(),
+            samples: vec![0.0; BUFFER_SIZE * MAX_CHANNELS],
+            mixed: Vec::with_capacity(BUFFER_SIZE * MAX_CHANNELS),
+            buffer: Vec::with_capacity(BUFFER_SIZE * MAX_CHANNELS),
+            stretched: Vec::with_capacity(BUFFER_SIZE * MAX_CHANNELS * 2),
+            write_errors: ErrorLog::default(),
+            held: vec![],
+            source: None,
+            next: None,
+            crossfade: Duration::from_secs(0),
+            fading: None,
+            fade_buffer: Vec::with_capacity(BUFFER_SIZE * MAX_CHANNELS),
+            loop_range: None,
+        }
+    }
+
+    fn run(mut self) {
+        loop {
+            if let Some(action) = self.event_loop.queue.try_pop() {
+                self.handle(action);
+            } else if self.event_loop.state() == PlaybackState::Playing {
+                self.play();
+            } else {
+                self.event_loop.block();
             }
-            index
         }
+    }
 
-        let event_loop = EventLoop::new();
-        {
-            let app_state = Arc::clone(&app_state);
-            let event_loop = event_loop.clone();
-            let condition_variable = Arc::clone(&event_loop.condition_variable);
-
-            thread::spawn(move || {
-                let block = || {
-                    let (ref lock, ref condition_variable) = *condition_variable;
-                    let mut started = lock.lock().unwrap();
-                    *started = false;
-                    while !*started {
-                        started = condition_variable.wait(started).unwrap();
+    fn set_state(&self, state: PlaybackState) {
+        *self.event_loop.state.lock().unwrap() = state;
+        self.app_state.lock().unwrap().stopped = state == PlaybackState::Stopped;
+        self.event_loop.publish(Event::State(state));
+    }
+
+    fn set_position(&self, position: Duration) {
+        *self.event_loop.position.lock().unwrap() = position;
+        self.app_state.lock().unwrap().current_time = crate::to_millis(position);
+        self.event_loop.publish(Event::Position(position));
+    }
+
+    // position of what is heard, the filters delay the sound
+    fn position(&self) -> Duration {
+        let latency = self.equalizer.latency() + self.filters.latency();
+        self.clock
+            .position()
+            .checked_sub(latency)
+            .unwrap_or_default()
+    }
+
+    // the factor applied to a file for its ReplayGain
+    fn replay_gain(&self, path: &Path) -> f32 {
+        let state = self.app_state.lock().unwrap();
+        let mode = state.config.get::<GainMode>("replay_gain");
+        let preamp = state.config.get::<f32>("replay_gain_preamp");
+        state
+            .gains
+            .get(&*path.to_string_lossy())
+            .map_or(1.0, |gain| {
+                gain.factor(mode.unwrap_or_default(), preamp.unwrap_or(0.0))
+            })
+    }
+
+    // opens the sink for the format of `source` and plays it from the
+    // beginning; the output runs at the rate of the track unless a fixed one
+    // is configured
+    fn start(&mut self, source: Box<dyn Decoder>, gain: f32) -> bool {
+        let (quality, output_rate, sample_format) = {
+            let config = &self.app_state.lock().unwrap().config;
+            (
+                config.get::<ResamplerQuality>("resampler"),
+                config.get::<u32>("output_rate"),
+                config.get::<SampleFormat>("sample_format"),
+            )
+        };
+
+        self.fading = None;
+        // mono is played on both speakers
+        self.requested_channels = source.channels().max(2);
+        let opened = self.sink.open(OutputFormat {
+            sample_rate: output_rate.unwrap_or_else(|| source.sample_rate()),
+            channels: self.requested_channels,
+            sample_format: sample_format.unwrap_or_default(),
+        });
+        match opened {
+            Ok(format) => {
+                self.format = format;
+                self.write_errors = ErrorLog::default();
+                self.quality = quality.unwrap_or_default();
+                self.clock.reset(Duration::from_secs(0), format.sample_rate);
+                self.stretch.reset();
+                self.held.clear();
+                self.equalizer.reset();
+                self.filters.reset();
+                self.source = Some(Track::new(source, gain, format, self.quality));
+                true
+            }
+            Err(err) => {
+                eprintln!("cannot open the audio output: {}", err);
+                self.source = None;
+                false
+            }
+        }
+    }
+
+    fn handle(&mut self, action: Action) {
+        match action {
+            Action::ClearFilters => self.filters.clear(),
+
+            Action::Equalizer(settings) => self.equalizer.set(settings),
+
+            Action::InsertFilter(index, id, filter) => self.filters.insert(index, id, filter),
+
+            Action::Load(path) => {
+                self.loop_range = None;
+                self.set_position(Duration::from_secs(0));
+                let started = match open_decoder(&path) {
+                    Some(source) => {
+                        let gain = self.replay_gain(&path);
+                        self.start(source, gain)
+                    }
+                    None => {
+                        self.source = None;
+                        false
                     }
                 };
+                self.set_state(if started {
+                    PlaybackState::Playing
+                } else {
+                    PlaybackState::Stopped
+                });
+            }
 
-                let mut buffer = [[0; 2]; BUFFER_SIZE];
-                let mut playback = Playback::new("MP3", "MP3 Playback", None, DEFAULT_RATE);
-                let mut source = None;
-                loop {
-                    if let Some(action) = event_loop.queue.try_pop() {
-                        match action {
-                            self::Action::Load(path) => {
-                                let file = File::open(path).unwrap();
-                                source = Some(Mp3Decoder::new(BufReader::new(file)).unwrap());
-                                let rate = source
-                                    .as_ref()
-                                    .map(|src| src.sample_rate())
-                                    .unwrap_or(DEFAULT_RATE);
-                                playback = Playback::new("MP3", "MP3 Playback", None, rate);
-
-                                app_state.lock().unwrap().stopped = false;
-                                // let mut guard = app_state.lock().unwrap();
-                                // guard.stopped = false;
-                            }
-
-                            self::Action::Stop => {}
-                        }
-                    } else if *event_loop.playing.lock().unwrap() {
-                        let mut written = false;
-                        if let Some(ref mut source) = source {
-                            let size = iter_to_buffer(source, &mut buffer);
-                            println!("buffer size: {}", size);
-                            if size > 0 {
-                                playback.write(&buffer[..size]);
-                                written = true;
-                            }
-                        }
+            Action::Loop(range) => self.loop_range = range,
 
-                        if !written {
-                            app_state.lock().unwrap().stopped = true;
-                            *event_loop.playing.lock().unwrap() = false;
-                            source = None;
+            Action::MoveFilter(id, index) => self.filters.move_to(id, index),
 
-                            block()
+            Action::Mute(mute) => {
+                self.muted = mute;
+                self.update_gain();
+            }
+
+            Action::Volume(value) => {
+                self.volume = value;
+                self.update_gain();
+            }
+
+            Action::Pause => {
+                if self.event_loop.state() == PlaybackState::Playing {
+                    self.set_position(self.position());
+                    self.set_state(PlaybackState::Paused);
+                }
+            }
+
+            Action::Queue(path, crossfade) => {
+                self.next = path.and_then(|path| {
+                    open_decoder(&path).map(|next| (next, self.replay_gain(&path)))
+                });
+                self.crossfade = crossfade.min(MAX_CROSSFADE);
+            }
+
+            Action::RemoveFilter(id) => self.filters.remove(id),
+
+            Action::Resume => {
+                if self.event_loop.state() == PlaybackState::Paused {
+                    if self.clock.rebase() {
+                        self.event_loop.publish(Event::NextTrack);
+                    }
+                    self.set_state(PlaybackState::Playing);
+                    mem::swap(&mut self.buffer, &mut self.held);
+                    self.write();
+                    self.held.clear();
+                }
+            }
+
+            Action::Seek(position) => {
+                if let Some(ref mut source) = self.source {
+                    if source.seek(position).is_ok() {
+                        // the seek is in the track which is decoded
+                        if self.clock.next_track_pending() {
+                            self.event_loop.publish(Event::NextTrack);
                         }
-                    } else {
-                        block()
+                        self.fading = None;
+                        self.stretch.reset();
+                        self.held.clear();
+                        self.equalizer.reset();
+                        self.filters.reset();
+                        let _ = self.sink.discard();
+                        self.clock.reset(position, self.format.sample_rate);
+                        self.set_position(position);
                     }
                 }
-            });
+            }
+
+            // the samples kept by the time stretch are played at the previous
+            // speed, at once while it plays
+            Action::Speed(speed) => {
+                if self.event_loop.state() == PlaybackState::Playing {
+                    self.buffer.clear();
+                    self.stretch.set_speed(speed, &mut self.buffer);
+                    self.write();
+                } else {
+                    self.stretch.set_speed(speed, &mut self.held);
+                }
+                self.clock.set_speed(self.stretch.speed());
+            }
+
+            Action::Stop => {
+                self.source = None;
+                self.fading = None;
+                self.stretch.reset();
+                self.held.clear();
+                let _ = self.sink.discard();
+                self.set_position(Duration::from_secs(0));
+                self.set_state(PlaybackState::Stopped);
+            }
+        }
+    }
+
+    fn update_gain(&mut self) {
+        let gain = if self.muted { 0.0 } else { self.volume };
+        self.gain.set(gain, self.clock.rate);
+    }
+
+    // the queued track can share the stream of the sink when it needs the
+    // same channels, a different rate is resampled
+    fn take_compatible_next(&mut self) -> Option<(Box<dyn Decoder>, f32)> {
+        match self.next {
+            Some((ref next, _)) if next.channels().max(2) == self.requested_channels => {
+                self.next.take()
+            }
+            _ => None,
+        }
+    }
+
+    // when the end of the current track is near, the queued one starts and
+    // the current one fades out over what is left of it
+    fn begin_crossfade(&mut self) {
+        if self.loop_range.is_some() || self.fading.is_some() {
+            return;
+        }
+        let remaining = self.source.as_ref().and_then(Track::remaining);
+        let remaining = match crossfade::overlap(remaining, self.crossfade) {
+            Some(remaining) => remaining,
+            None => return,
+        };
+        let (next, gain) = match self.take_compatible_next() {
+            Some(next) => next,
+            None => return,
+        };
+
+        let curve = self
+            .app_state
+            .lock()
+            .unwrap()
+            .config
+            .get::<FadeCurve>("crossfade_curve");
+        let length = decoder::duration_to_samples(remaining, self.format.sample_rate);
+        let incoming = Track::new(next, gain, self.format, self.quality);
+        self.fading = self
+            .source
+            .replace(incoming)
+            .map(|outgoing| (outgoing, Crossfade::new(curve.unwrap_or_default(), length)));
+        self.fade_buffer.clear();
+        if self.clock.mark_track_boundary() {
+            self.event_loop.publish(Event::NextTrack);
+        }
+    }
+
+    // mixes the end of the previous track into `buffer`
+    fn mix_fading(&mut self) {
+        let channels = self.format.channels;
+        let finished = match self.fading {
+            Some((ref mut outgoing, ref mut crossfade)) => {
+                while self.fade_buffer.len() < self.buffer.len() {
+                    let buffer = &mut self.fade_buffer;
+                    if outgoing.decode(&mut self.samples, &mut self.mixed, buffer) == 0 {
+                        break;
+                    }
+                }
+                let length = self.fade_buffer.len().min(self.buffer.len());
+                crossfade.mix(&self.fade_buffer[..length], &mut self.buffer, channels);
+                self.fade_buffer.drain(..length);
+                crossfade.is_finished()
+            }
+            None => return,
+        };
+        if finished {
+            self.fading = None;
+        }
+    }
+
+    // returns the number of frames put in `buffer`
+    fn decode(&mut self) -> usize {
+        self.buffer.clear();
+        match self.source {
+            Some(ref mut source) => {
+                source.decode(&mut self.samples, &mut self.mixed, &mut self.buffer)
+            }
+            None => 0,
+        }
+    }
+
+    // goes on with the queued track once the current one is over, without a
+    // gap when it shares the stream of the sink
+    fn continue_with_next(&mut self) -> bool {
+        if let Some((next, gain)) = self.take_compatible_next() {
+            self.source = Some(Track::new(next, gain, self.format, self.quality));
+            if self.clock.mark_track_boundary() {
+                self.event_loop.publish(Event::NextTrack);
+            }
+            return true;
+        }
+
+        let (next, gain) = match self.next.take() {
+            Some(next) => next,
+            None => return false,
+        };
+        let _ = self.sink.drain();
+        if self.start(next, gain) {
+            self.set_position(Duration::from_secs(0));
+            self.event_loop.publish(Event::NextTrack);
+            true
+        } else {
+            false
+        }
+    }
+
+    // goes back to the start of the loop, the position follows once what has
+    // been written before is heard
+    fn loop_back(&mut self) -> bool {
+        let start = match self.loop_range {
+            Some((start, _)) => start,
+            None => return false,
+        };
+        let sought = match self.source {
+            Some(ref mut source) => source.seek(start).is_ok(),
+            None => false,
+        };
+        if sought {
+            self.clock.mark_loop(start);
+        }
+        sought
+    }
+
+    // drops the frames of `buffer` decoded past the end of the loop, returns
+    // true if it has been reached
+    fn trim_to_loop(&mut self) -> bool {
+        let end = match self.loop_range {
+            Some((_, end)) => end,
+            None => return false,
+        };
+        let position = match self.source {
+            Some(ref source) if source.position >= end => source.position,
+            _ => return false,
+        };
+        let channels = self.format.channels as usize;
+        let beyond = decoder::duration_to_samples(position - end, self.format.sample_rate);
+        let frames = (self.buffer.len() / channels).saturating_sub(beyond as usize);
+        self.buffer.truncate(frames * channels);
+        true
+    }
+
+    fn play(&mut self) {
+        self.begin_crossfade();
+        let mut size = self.decode();
+        // a loop ending past the end of the track loops from there
+        if size == 0 && self.loop_back() {
+            size = self.decode();
+        }
+        while size == 0 && self.continue_with_next() {
+            size = self.decode();
+        }
+
+        if size == 0 {
+            self.stretch.flush(&mut self.buffer);
+            self.write();
+            self.source = None;
+            self.fading = None;
+            let _ = self.sink.drain();
+            self.set_state(PlaybackState::Stopped);
+            self.event_loop.publish(Event::EndOfTrack);
+            return;
+        }
+
+        self.mix_fading();
+        let looped = self.trim_to_loop();
+        let format = self.format;
+        self.stretched.clear();
+        self.stretch.process(
+            &self.buffer,
+            format.channels,
+            format.sample_rate,
+            &mut self.stretched,
+        );
+        mem::swap(&mut self.buffer, &mut self.stretched);
+        self.write();
+        if looped {
+            self.loop_back();
+        }
+
+        if self.clock.cross_jumps() {
+            self.event_loop.publish(Event::NextTrack);
+            self.set_position(self.position());
+            self.last_published = Instant::now();
+        } else if self.last_published.elapsed() >= POSITION_INTERVAL {
+            self.set_position(self.position());
+            self.last_published = Instant::now();
+        }
+    }
+
+    // sends `buffer` through the effects to the sink
+    fn write(&mut self) {
+        let format = self.format;
+        let frames = self.buffer.len() / format.channels as usize;
+        if frames == 0 {
+            return;
+        }
+        // the equalizer, then the filters added through the player
+        self.equalizer
+            .process(&mut self.buffer, format.channels, format.sample_rate);
+        self.filters
+            .process(&mut self.buffer, format.channels, format.sample_rate);
+        self.gain.apply(&mut self.buffer, format.channels);
+        if let Err(err) = self.sink.write(&self.buffer) {
+            self.write_errors
+                .report(format_args!("cannot write to the audio output: {}", err));
+        }
+        self.clock.advance(frames);
+    }
+}
+
+pub struct Player {
+    event_loop: EventLoop,
+    last_filter_id: Cell<u64>,
+}
+
+impl Player {
+    pub fn new(app_state: Arc<Mutex<crate::State>>, sink_kind: SinkKind) -> Self {
+        let event_loop = EventLoop::new();
+        {
+            let event_loop = event_loop.clone();
+            thread::spawn(move || Worker::new(app_state, event_loop, &sink_kind).run());
         }
         Player {
-            app_state,
             event_loop,
+            last_filter_id: Cell::new(0),
         }
     }
 
     pub fn load<P: AsRef<Path>>(&self, path: P) {
         let path_buf = path.as_ref().to_path_buf();
         self.emit(Action::Load(path_buf));
-        self.set_playing(true);
+    }
+
+    /// Opens the track to play when the current one ends, so that it follows
+    /// without a gap, or overlaps the last `crossfade` of it (at most 12 s);
+    /// `NextTrack` is sent once it is heard.
+    pub fn queue<P: AsRef<Path>>(&self, path: Option<P>, crossfade: Duration) {
+        let path_buf = path.map(|path| path.as_ref().to_path_buf());
+        self.emit(Action::Queue(path_buf, crossfade));
+    }
+
+    /// Pauses the playback, the decoder keeps its position so that `resume`
+    /// continues from where it stopped.
+    pub fn pause(&self) {
+        self.emit(Action::Pause);
+    }
+
+    pub fn resume(&self) {
+        self.emit(Action::Resume);
+    }
+
+    pub fn stop(&self) {
+        self.emit(Action::Stop);
+    }
+
+    pub fn seek(&self, position: Duration) {
+        self.emit(Action::Seek(position));
+    }
+
+    /// Sets the volume from the position of a slider in [0, 1].
+    pub fn set_volume(&self, volume: f64) {
+        self.emit(Action::Volume(volume::volume_to_gain(volume)));
+    }
+
+    pub fn mute(&self, mute: bool) {
+        self.emit(Action::Mute(mute));
+    }
+
+    /// Plays faster or slower without changing the pitch, from 0.5 to 3
+    /// times the normal speed. The position stays in the time of the track.
+    pub fn set_speed(&self, speed: f64) {
+        self.emit(Action::Speed(speed));
+    }
+
+    /// Changes the equalizer while it plays, `None` disables it.
+    pub fn set_equalizer(&self, settings: Option<EqualizerSettings>) {
+        self.emit(Action::Equalizer(settings));
+    }
+
+    /// Adds a filter at the end of the chain, returns the id which moves or
+    /// removes it.
+    pub fn add_filter(&self, filter: Box<dyn AudioFilter>) -> FilterId {
+        self.insert_filter(usize::MAX, filter)
+    }
+
+    /// Inserts a filter at `index` of the chain, at the end past it. The
+    /// filters run in the player thread and must not block it.
+    pub fn insert_filter(&self, index: usize, filter: Box<dyn AudioFilter>) -> FilterId {
+        let id = FilterId(self.last_filter_id.get() + 1);
+        self.last_filter_id.set(id.0);
+        self.emit(Action::InsertFilter(index, id, filter));
+        id
+    }
+
+    pub fn move_filter(&self, id: FilterId, index: usize) {
+        self.emit(Action::MoveFilter(id, index));
+    }
+
+    pub fn remove_filter(&self, id: FilterId) {
+        self.emit(Action::RemoveFilter(id));
+    }
+
+    pub fn clear_filters(&self) {
+        self.emit(Action::ClearFilters);
+    }
+
+    /// Plays the section between two positions of the current track over and
+    /// over, until `None` or another track is loaded.
+    pub fn set_loop(&self, range: Option<(Duration, Duration)>) {
+        let range = range.filter(|(start, end)| start < end);
+        self.emit(Action::Loop(range));
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.event_loop.state()
+    }
+
+    /// Position of what is currently heard, i.e. the position of the decoder
+    /// minus what is still buffered by the audio output.
+    pub fn position(&self) -> Duration {
+        *self.event_loop.position.lock().unwrap()
+    }
+
+    /// Returns a channel receiving the position and state changes and the ends
+    /// of track, it stays registered until the receiver is dropped.
+    pub fn subscribe(&self) -> Receiver<Event> {
+        let (sender, receiver) = channel::unbounded();
+        self.event_loop.subscribers.lock().unwrap().push(sender);
+        receiver
     }
 
     fn emit(&self, action: Action) {
         self.event_loop.queue.push(action);
+        self.event_loop.wake_up();
     }
 
-    fn set_playing(&self, playing: bool) {
-        *self.event_loop.playing.lock().unwrap() = playing;
+    pub fn compute_duration<P: AsRef<Path>>(path: P) -> Option<Duration> {
+        decoder::duration(path)
     }
+}
 
-    pub fn compute_duration<P: AsRef<Path>>(path: P) -> Option<Duration> {
-        let file = File::open(path).unwrap();
-        Mp3Decoder::compute_duration(BufReader::new(file))
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const RATE: u32 = 1000;
+
+    // the clock runs meanwhile, a position read after the setup is a bit
+    // later than the exact one
+    fn assert_near(position: Duration, expected: Duration) {
+        let slack = Duration::from_millis(200);
+        assert!(
+            position >= expected && position < expected + slack,
+            "{:?} is not {:?}",
+            position,
+            expected
+        );
+    }
+
+    fn seconds(seconds: f64) -> Duration {
+        Duration::from_millis((seconds * 1000.0) as u64)
+    }
+
+    // a clock which started to play `played` ago with `written` buffered
+    fn clock(origin: Duration, written: f64, played: f64) -> PlaybackClock {
+        let mut clock = PlaybackClock::new();
+        clock.reset(origin, RATE);
+        clock.advance((written * f64::from(RATE)) as usize);
+        clock.started = Some(Instant::now() - seconds(played));
+        clock
+    }
+
+    #[test]
+    fn position_follows_the_output() {
+        let clock = clock(seconds(10.0), 2.0, 1.0);
+        assert_near(clock.position(), seconds(11.0));
+    }
+
+    #[test]
+    fn position_stops_at_what_was_written() {
+        let clock = clock(seconds(10.0), 2.0, 5.0);
+        assert_eq!(clock.position(), seconds(12.0));
+    }
+
+    #[test]
+    fn speed_scales_the_track_time() {
+        let mut clock = clock(seconds(0.0), 4.0, 1.0);
+        clock.speed = 2.0;
+        assert_near(clock.position(), seconds(2.0));
+    }
+
+    #[test]
+    fn set_speed_keeps_the_time_played() {
+        let mut clock = clock(seconds(0.0), 4.0, 1.0);
+        clock.set_speed(2.0);
+        assert_near(clock.origin, seconds(1.0));
+        assert_near(clock.position(), clock.origin);
+        assert!(clock.written <= 3 * u64::from(RATE));
+    }
+
+    #[test]
+    fn next_track_is_heard_after_the_buffer() {
+        let mut clock = clock(seconds(30.0), 1.0, 0.0);
+        assert!(!clock.mark_track_boundary());
+        clock.advance(RATE as usize);
+        assert!(clock.next_track_pending());
+        assert!(!clock.cross_jumps());
+
+        clock.started = Some(Instant::now() - seconds(1.5));
+        assert!(clock.cross_jumps());
+        assert!(!clock.next_track_pending());
+        assert_near(clock.position(), seconds(0.5));
+    }
+
+    #[test]
+    fn loop_jumps_back_when_heard() {
+        let mut clock = clock(seconds(8.0), 2.0, 2.5);
+        clock.mark_loop(seconds(5.0));
+        clock.advance(RATE as usize);
+        assert!(!clock.cross_jumps());
+        assert_near(clock.position(), seconds(5.5));
+    }
+
+    #[test]
+    fn rebase_starts_after_what_was_written() {
+        let mut clock = clock(seconds(1.0), 3.0, 0.5);
+        assert!(!clock.rebase());
+        assert_eq!(clock.position(), seconds(4.0));
+        assert_eq!(clock.written, 0);
+        assert!(clock.started.is_none());
+    }
+
+    #[test]
+    fn rebase_crosses_a_pending_track_boundary() {
+        let mut clock = clock(seconds(60.0), 1.0, 0.0);
+        clock.mark_track_boundary();
+        clock.advance(2 * RATE as usize);
+        assert!(clock.rebase());
+        assert_eq!(clock.position(), seconds(2.0));
     }
 }
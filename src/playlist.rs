@@ -1,19 +1,30 @@
 use std::{
-    cell::RefCell,
+    cell::{Cell, RefCell},
+    collections::HashMap,
     path::Path,
+    rc::Rc,
     sync::{Arc, Mutex},
-    thread,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
+use crossbeam::channel::Receiver;
 use gdk_pixbuf::{InterpType, Pixbuf, PixbufExt, PixbufLoader, PixbufLoaderExt};
 use gtk::{
     CellLayoutExt, CellRendererPixbuf, CellRendererText, GtkListStoreExt, GtkListStoreExtManual,
     ListStore, StaticType, ToValue, TreeIter, TreeModelExt, TreeSelectionExt, TreeView,
     TreeViewColumn, TreeViewColumnExt, TreeViewExt, Type, WidgetExt,
 };
-use id3::Tag;
-
-use crate::{player::Player, State};
+use mmp::{
+    decoder,
+    equalizer::EqualizerSettings,
+    metadata::{Chapter, Metadata},
+    player::{Event, PlaybackState, Player},
+    replaygain::GainMode,
+    scanner::{DurationScanner, GainScanner},
+    shuffle::Shuffle,
+    sink::SinkKind,
+    State,
+};
 
 const THUMBNAIL_COLUMN: u32 = 0;
 const TITLE_COLUMN: u32 = 1;
@@ -24,15 +35,57 @@ const YEAR_COLUMN: u32 = 5;
 const TRACK_COLUMN: u32 = 6;
 const PATH_COLUMN: u32 = 7;
 const PIXBUF_COLUMN: u32 = 8;
+const ID_COLUMN: u32 = 9;
+const GAPLESS_COLUMN: u32 = 10;
 
 const IMAGE_SIZE: i32 = 256;
 const THUMBNAIL_SIZE: i32 = 64;
 
+// "previous" restarts the current track when it has played for longer
+const RESTART_THRESHOLD: Duration = Duration::from_secs(3);
+
+// the position is kept for the tracks this long, or of these genres, unless
+// configured otherwise
+const DEFAULT_RESUME_MINUTES: f64 = 30.0;
+const DEFAULT_RESUME_GENRES: &str = "Audiobook,Podcast";
+// resuming closer to the beginning is not worth asking
+const MIN_RESUME: Duration = Duration::from_secs(10);
+// the positions are written at this interval while playing
+const RESUME_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RepeatMode {
+    Off,
+    All,
+    One,
+}
+
 pub(crate) struct Playlist {
-    current_song: RefCell<Option<String>>,
+    // the A and B points of the loop in the current track
+    ab_loop: Cell<(Option<Duration>, Option<Duration>)>,
+    // next and previous move between the chapters of the current track
+    chapter_steps: Cell<bool>,
+    chapters: RefCell<HashMap<String, Rc<Vec<Chapter>>>>,
+    // the images of the chapters, decoded once with the chapters
+    chapter_pixbufs: RefCell<HashMap<String, Vec<Option<Pixbuf>>>>,
+    current_song: RefCell<Option<TreeIter>>,
+    events: Receiver<Event>,
+    gain_scanner: GainScanner,
     pub model: ListStore,
+    next_id: Cell<u64>,
     player: Player,
+    // the row and path given to the player to follow the current track, with
+    // the crossfade between them
+    queued: RefCell<Option<(u64, String, Duration)>>,
+    repeat: Cell<RepeatMode>,
+    // where the current track was left, until the user chooses to resume
+    resume_offer: Cell<Option<Duration>>,
+    resume_saved: Cell<Instant>,
+    scanner: DurationScanner,
+    shuffle: RefCell<Shuffle>,
+    shuffled: Cell<bool>,
     state: Arc<Mutex<State>>,
+    stop_after_current: Cell<bool>,
     pub treeview: TreeView,
 }
 
@@ -82,23 +135,20 @@ impl Playlist {
 
     const INTERP_HYPER: InterpType = InterpType::Bilinear;
 
-    fn set_pixbuf(&self, row: &TreeIter, tag: &Tag) {
-        if let Some(pict) = tag.pictures().next() {
-            let pbl = PixbufLoader::new();
-            pbl.set_size(IMAGE_SIZE, IMAGE_SIZE);
-            pbl.write(&pict.data).unwrap();
-
-            if let Some(pb) = pbl.get_pixbuf() {
-                let tbn = pb.scale_simple(THUMBNAIL_SIZE, THUMBNAIL_SIZE, Self::INTERP_HYPER);
-                self.model.set_value(row, THUMBNAIL_COLUMN, &tbn.to_value());
-                self.model.set_value(row, PIXBUF_COLUMN, &pb.to_value());
-            }
-
-            pbl.close().unwrap();
+    // a truncated or unsupported picture leaves the row without a cover
+    fn set_pixbuf(&self, row: &TreeIter, metadata: &Metadata) {
+        let picture = metadata
+            .picture
+            .as_ref()
+            .and_then(|data| decode_image(data));
+        if let Some(pb) = picture {
+            let tbn = pb.scale_simple(THUMBNAIL_SIZE, THUMBNAIL_SIZE, Self::INTERP_HYPER);
+            self.model.set_value(row, THUMBNAIL_COLUMN, &tbn.to_value());
+            self.model.set_value(row, PIXBUF_COLUMN, &pb.to_value());
         }
     }
 
-    pub(crate) fn new(state: Arc<Mutex<State>>) -> Self {
+    pub(crate) fn new(state: Arc<Mutex<State>>, sink_kind: SinkKind) -> Self {
         let model = ListStore::new(&[
             Pixbuf::static_type(),
             Type::String,
@@ -109,6 +159,8 @@ impl Playlist {
             Type::String,
             Type::String,
             Pixbuf::static_type(),
+            Type::U64,
+            Type::Bool,
         ]);
         let tw = TreeView::new_with_model(&model);
         tw.set_hexpand(true);
@@ -116,24 +168,73 @@ impl Playlist {
 
         Self::create_columns(&tw);
 
+        let player = Player::new(state.clone(), sink_kind);
+        let seed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|time| time.as_secs() ^ u64::from(time.subsec_nanos()))
+            .unwrap_or_default();
+
         Playlist {
+            ab_loop: Cell::new((None, None)),
+            chapter_steps: Cell::new(false),
+            chapters: RefCell::new(HashMap::new()),
+            chapter_pixbufs: RefCell::new(HashMap::new()),
             current_song: RefCell::new(None),
+            events: player.subscribe(),
+            gain_scanner: GainScanner::new(Arc::clone(&state)),
             model,
-            player: Player::new(state.clone()),
+            next_id: Cell::new(0),
+            player,
+            queued: RefCell::new(None),
+            repeat: Cell::new(RepeatMode::Off),
+            resume_offer: Cell::new(None),
+            resume_saved: Cell::new(Instant::now()),
+            scanner: DurationScanner::new(Arc::clone(&state)),
+            shuffle: RefCell::new(Shuffle::new(seed)),
+            shuffled: Cell::new(false),
             state,
+            stop_after_current: Cell::new(false),
             treeview: tw,
         }
     }
 
+    // most files tell their duration in their headers, the others are
+    // scanned in the background
     fn compute_duration(&self, path: &Path) {
-        let state = Arc::clone(&self.state);
-        let path = path.to_string_lossy().to_string();
-        thread::spawn(move || {
-            if let Some(duration) = Player::compute_duration(&path) {
-                let mut state = state.lock().unwrap();
-                state.durations.insert(path, crate::to_millis(duration));
+        let key = path.to_string_lossy().to_string();
+        match Player::compute_duration(path) {
+            Some(duration) => {
+                let mut state = self.state.lock().unwrap();
+                state.durations.insert(key, mmp::to_millis(duration));
             }
-        });
+            None => self.scanner.scan(key),
+        }
+    }
+
+    // the gains found in the tags are used as they are, the files lacking
+    // some are measured in the background
+    fn compute_gain(&self, path: &Path, metadata: &Metadata) {
+        let key = path.to_string_lossy().to_string();
+        let gain = metadata.replay_gain;
+        let mode = {
+            let mut state = self.state.lock().unwrap();
+            if gain.track_gain.is_some() || gain.album_gain.is_some() {
+                state.gains.insert(key.clone(), gain);
+            }
+            state
+                .config
+                .get::<GainMode>("replay_gain")
+                .unwrap_or_default()
+        };
+
+        if mode != GainMode::Off && !gain.is_complete() {
+            // the tracks of an album are in the same directory
+            let album = metadata.album.as_ref().map(|album| {
+                let directory = path.parent().unwrap_or_else(|| Path::new(""));
+                format!("{}/{}", directory.display(), album)
+            });
+            self.gain_scanner.scan(key, album);
+        }
     }
 
     pub(crate) fn add(&self, path: &Path) {
@@ -147,26 +248,27 @@ impl Playlist {
 
         let row = self.model.append();
 
-        if let Ok(tag) = Tag::read_from_path(path) {
-            let title = tag.title().unwrap_or(filename);
-            let artist = tag.artist().unwrap_or("unknown");
-            let album = tag.album().unwrap_or("unknown");
-            let genre = tag.genre().unwrap_or("unknown");
-            let year = tag
-                .year()
-                .map(|y| y.to_string())
-                .unwrap_or("unknown".to_string());
-            let track = tag
-                .track()
-                .map(|tr| tr.to_string())
-                .unwrap_or("unknown".to_string());
-            let total_tracks = tag
-                .total_tracks()
-                .map(|ttr| ttr.to_string())
-                .unwrap_or("unknown".to_string());
+        let id = self.next_id.get();
+        self.next_id.set(id + 1);
+        self.model.set_value(&row, ID_COLUMN, &id.to_value());
+        self.shuffle.borrow_mut().insert(id);
+
+        if let Some(metadata) = decoder::metadata(path) {
+            let unknown = || "unknown".to_string();
+            let title = metadata
+                .title
+                .clone()
+                .unwrap_or_else(|| filename.to_string());
+            let artist = metadata.artist.clone().unwrap_or_else(unknown);
+            let album = metadata.album.clone().unwrap_or_else(unknown);
+            let genre = metadata.genre.clone().unwrap_or_else(unknown);
+            let year = metadata.year.clone().unwrap_or_else(unknown);
+            let track = metadata.track.clone().unwrap_or_else(unknown);
+            let total_tracks = metadata.total_tracks.clone().unwrap_or_else(unknown);
             let tr_val = format!("{} / {}", track, total_tracks);
 
-            self.set_pixbuf(&row, &tag);
+            self.set_pixbuf(&row, &metadata);
+            self.compute_gain(path, &metadata);
 
             self.model.set_value(&row, TITLE_COLUMN, &title.to_value());
             self.model
@@ -175,9 +277,28 @@ impl Playlist {
             self.model.set_value(&row, GENRE_COLUMN, &genre.to_value());
             self.model.set_value(&row, YEAR_COLUMN, &year.to_value());
             self.model.set_value(&row, TRACK_COLUMN, &tr_val.to_value());
+            self.model
+                .set_value(&row, GAPLESS_COLUMN, &metadata.gapless.to_value());
+            if !metadata.chapters.is_empty() {
+                let key = path.to_string_lossy().to_string();
+                let pixbufs: Vec<_> = metadata
+                    .chapters
+                    .iter()
+                    .map(|chapter| chapter.picture.as_ref().and_then(|data| decode_image(data)))
+                    .collect();
+                if pixbufs.iter().any(Option::is_some) {
+                    self.chapter_pixbufs
+                        .borrow_mut()
+                        .insert(key.clone(), pixbufs);
+                }
+                self.chapters
+                    .borrow_mut()
+                    .insert(key, Rc::new(metadata.chapters));
+            }
         } else {
             self.model
                 .set_value(&row, TITLE_COLUMN, &filename.to_value());
+            self.compute_gain(path, &Metadata::default());
         }
 
         let path = path.to_str().unwrap_or_default();
@@ -187,18 +308,72 @@ impl Playlist {
     pub(crate) fn remove_selection(&self) {
         let selection = self.treeview.get_selection();
         if let Some((_, iter)) = selection.get_selected() {
+            if self.is_current(&iter) {
+                *self.current_song.borrow_mut() = None;
+            }
+            let id = self.row_id(&iter);
+            if let Some(id) = id {
+                self.shuffle.borrow_mut().remove(id);
+            }
             self.model.remove(&iter);
+
+            // the player may have opened the removed track ahead, it gets the
+            // new upcoming one instead
+            let queued = self.queued.borrow().as_ref().map(|queued| queued.0);
+            if id.is_some() && queued == id {
+                *self.queued.borrow_mut() = None;
+                self.player.queue(None::<&str>, Duration::from_secs(0));
+            }
+            self.queue_upcoming();
+        }
+    }
+
+    fn row_id(&self, row: &TreeIter) -> Option<u64> {
+        self.model.get_value(row, ID_COLUMN as i32).get::<u64>()
+    }
+
+    fn find_row(&self, id: u64) -> Option<TreeIter> {
+        let row = self.model.get_iter_first()?;
+        loop {
+            if self.row_id(&row) == Some(id) {
+                return Some(row);
+            }
+            if !self.model.iter_next(&row) {
+                return None;
+            }
         }
     }
 
+    fn is_current(&self, row: &TreeIter) -> bool {
+        let indices = |row: &TreeIter| self.model.get_path(row).map(|path| path.get_indices());
+        match *self.current_song.borrow() {
+            Some(ref current) => indices(current) == indices(row),
+            None => false,
+        }
+    }
+
+    // the row being played, or the selected one when nothing has been played
+    fn current_row(&self) -> Option<TreeIter> {
+        self.current_song.borrow().clone().or_else(|| {
+            let selection = self.treeview.get_selection();
+            selection.get_selected().map(|(_, iter)| iter)
+        })
+    }
+
+    /// The cover of the current track, or the image of its current chapter.
     pub(crate) fn pixbuf(&self) -> Option<Pixbuf> {
-        let selection = self.treeview.get_selection();
-        if let Some((_, iter)) = selection.get_selected() {
-            let value = self.model.get_value(&iter, PIXBUF_COLUMN as i32);
-            value.get()
-        } else {
-            None
+        if let Some(pixbuf) = self.chapter_pixbuf() {
+            return Some(pixbuf);
         }
+        let row = self.current_row()?;
+        let value = self.model.get_value(&row, PIXBUF_COLUMN as i32);
+        value.get()
+    }
+
+    pub fn current_path(&self) -> Option<String> {
+        let row = self.current_row()?;
+        let value = self.model.get_value(&row, PATH_COLUMN as i32);
+        value.get::<String>()
     }
 
     pub fn selected_path(&self) -> Option<String> {
@@ -210,13 +385,501 @@ impl Playlist {
         None
     }
 
+    fn play_row(&self, row: &TreeIter) -> bool {
+        let path = match self
+            .model
+            .get_value(row, PATH_COLUMN as i32)
+            .get::<String>()
+        {
+            Some(path) => path,
+            None => return false,
+        };
+
+        self.save_positions();
+        self.treeview.get_selection().select_iter(row);
+        *self.current_song.borrow_mut() = Some(row.clone());
+        // the player forgets the loop of the previous track
+        self.ab_loop.set((None, None));
+        self.player.load(&path);
+        self.offer_resume(row);
+        true
+    }
+
+    // the tracks whose position is kept: long ones, audiobooks, podcasts...
+    fn is_long_form(&self, row: &TreeIter) -> bool {
+        let path = match self
+            .model
+            .get_value(row, PATH_COLUMN as i32)
+            .get::<String>()
+        {
+            Some(path) => path,
+            None => return false,
+        };
+        let genre = self
+            .model
+            .get_value(row, GENRE_COLUMN as i32)
+            .get::<String>();
+
+        let state = self.state.lock().unwrap();
+        let minutes = state.config.get::<f64>("resume_min_minutes");
+        let min_length = (minutes.unwrap_or(DEFAULT_RESUME_MINUTES) * 60_000.0) as u64;
+        if state
+            .durations
+            .get(&path)
+            .map_or(false, |duration| *duration >= min_length)
+        {
+            return true;
+        }
+
+        let genres = state.config.get::<String>("resume_genres");
+        let genres = genres
+            .as_ref()
+            .map_or(DEFAULT_RESUME_GENRES, String::as_str);
+        genre.map_or(false, |genre| {
+            genres
+                .split(',')
+                .any(|configured| configured.trim().eq_ignore_ascii_case(genre.trim()))
+        })
+    }
+
+    // offers to go on from where the track was left
+    fn offer_resume(&self, row: &TreeIter) {
+        let path = self
+            .model
+            .get_value(row, PATH_COLUMN as i32)
+            .get::<String>();
+        let position = match path {
+            Some(ref path) if self.is_long_form(row) => self.state.lock().unwrap().resume.get(path),
+            _ => None,
+        };
+        self.resume_offer
+            .set(position.filter(|position| *position >= MIN_RESUME));
+    }
+
+    /// The position where the current track was left the last time, while
+    /// it has neither been accepted nor dismissed.
+    pub fn resume_offer(&self) -> Option<Duration> {
+        self.resume_offer.get()
+    }
+
+    pub fn accept_resume(&self) {
+        if let Some(position) = self.resume_offer.take() {
+            self.player.seek(position);
+        }
+    }
+
+    pub fn dismiss_resume(&self) {
+        self.resume_offer.set(None);
+    }
+
+    // keeps the position of the current track, unless the user has not said
+    // yet whether to resume it
+    fn remember_position(&self, position: Duration) {
+        if self.resume_offer.get().is_some() || self.player.state() == PlaybackState::Stopped {
+            return;
+        }
+        let row = match self.current_song.borrow().clone() {
+            Some(ref row) if self.is_long_form(row) => row.clone(),
+            _ => return,
+        };
+        if let Some(path) = self
+            .model
+            .get_value(&row, PATH_COLUMN as i32)
+            .get::<String>()
+        {
+            let mut state = self.state.lock().unwrap();
+            if position >= MIN_RESUME {
+                state.resume.set(&path, position);
+            } else {
+                state.resume.remove(&path);
+            }
+        }
+        if self.resume_saved.get().elapsed() >= RESUME_SAVE_INTERVAL {
+            self.save_positions();
+        }
+    }
+
+    // the current track ended, it starts from the beginning next time
+    fn forget_position(&self) {
+        if let Some(path) = self.current_path() {
+            self.state.lock().unwrap().resume.remove(&path);
+        }
+    }
+
+    /// Writes the positions of the long tracks, e.g. before quitting.
+    pub fn save_positions(&self) {
+        self.state.lock().unwrap().resume.save();
+        self.resume_saved.set(Instant::now());
+    }
+
     pub fn play(&self) -> bool {
-        if let Some(path) = self.selected_path() {
-            self.player.load(&path);
-            println!("start playing {}", path);
-            true
+        let selection = self.treeview.get_selection();
+        if let Some((_, iter)) = selection.get_selected() {
+            if let Some(id) = self.row_id(&iter) {
+                self.shuffle.borrow_mut().set_current(id);
+            }
+            self.play_row(&iter)
+        } else {
+            false
+        }
+    }
+
+    fn stop_at_end(&self) -> bool {
+        *self.current_song.borrow_mut() = None;
+        self.player.stop();
+        false
+    }
+
+    // moves to the following track in the playing order, `wrap` restarts
+    // from the beginning of the list (or a new random cycle) at the end
+    fn play_next(&self, wrap: bool) -> bool {
+        if self.shuffled.get() {
+            let next = self.shuffle.borrow_mut().next(wrap);
+            return match next.and_then(|id| self.find_row(id)) {
+                Some(row) => self.play_row(&row),
+                None => self.stop_at_end(),
+            };
+        }
+
+        let current = self.current_song.borrow().clone();
+        let next = match current {
+            Some(row) => {
+                if self.model.iter_next(&row) {
+                    Some(row)
+                } else if wrap {
+                    self.model.get_iter_first()
+                } else {
+                    None
+                }
+            }
+            None => return self.play(),
+        };
+        match next {
+            Some(row) => self.play_row(&row),
+            None => self.stop_at_end(),
+        }
+    }
+
+    /// Plays the track following the current one, stops at the end of the list
+    /// unless some repeat mode is set.
+    pub fn next(&self) -> bool {
+        if self.chapter_steps.get() {
+            let next = self.current_chapter().map(|current| current + 1);
+            if let Some(next) = next.filter(|next| self.chapter_start(*next).is_some()) {
+                return self.seek_chapter(next);
+            }
+        }
+        self.play_next(self.repeat.get() != RepeatMode::Off)
+    }
+
+    /// Restarts the current track once it played for a few seconds, plays the
+    /// previous one otherwise.
+    pub fn previous(&self) -> bool {
+        let current = self.current_song.borrow().clone();
+        let row = match current {
+            Some(row) => row,
+            None => return self.play(),
+        };
+
+        // like tracks, a chapter which played for a few seconds restarts
+        if let Some(current) = self.current_chapter().filter(|_| self.chapter_steps.get()) {
+            let start = self.chapter_start(current).unwrap_or_default();
+            if self.player.position() > start + RESTART_THRESHOLD {
+                return self.seek_chapter(current);
+            } else if current > 0 {
+                return self.seek_chapter(current - 1);
+            }
+        }
+
+        if self.player.position() > RESTART_THRESHOLD {
+            return self.play_row(&row);
+        }
+
+        if self.shuffled.get() {
+            let previous = self.shuffle.borrow_mut().previous();
+            match previous.and_then(|id| self.find_row(id)) {
+                Some(row) => self.play_row(&row),
+                None => false,
+            }
+        } else if self.model.iter_previous(&row) {
+            self.play_row(&row)
         } else {
             false
         }
     }
+
+    // called when a track ended by itself
+    fn advance(&self) -> bool {
+        if self.stop_after_current.get() {
+            return self.stop_at_end();
+        }
+
+        match self.repeat.get() {
+            RepeatMode::One => match self.current_song.borrow().clone() {
+                Some(row) => self.play_row(&row),
+                None => false,
+            },
+            RepeatMode::All => self.play_next(true),
+            RepeatMode::Off => self.play_next(false),
+        }
+    }
+
+    // the row `advance` would play, when it is known in advance; at the end
+    // of a random cycle the next one is only drawn by `advance`
+    fn upcoming_row(&self) -> Option<TreeIter> {
+        if self.stop_after_current.get() {
+            return None;
+        }
+
+        let current = self.current_song.borrow().clone()?;
+        match self.repeat.get() {
+            RepeatMode::One => Some(current),
+            _ if self.shuffled.get() => {
+                let next = self.shuffle.borrow().peek_next();
+                next.and_then(|id| self.find_row(id))
+            }
+            repeat => {
+                if self.model.iter_next(&current) {
+                    Some(current)
+                } else if repeat == RepeatMode::All {
+                    self.model.get_iter_first()
+                } else {
+                    None
+                }
+            }
+        }
+    }
+
+    // tracks of an album flagged as gapless follow each other as they are
+    fn is_gapless_transition(&self, current: &TreeIter, next: &TreeIter) -> bool {
+        let album = |row: &TreeIter| {
+            self.model
+                .get_value(row, ALBUM_COLUMN as i32)
+                .get::<String>()
+        };
+        let gapless = |row: &TreeIter| {
+            self.model
+                .get_value(row, GAPLESS_COLUMN as i32)
+                .get::<bool>()
+                .unwrap_or(false)
+        };
+        let same_album = album(current).is_some() && album(current) == album(next);
+        same_album && (gapless(current) || gapless(next))
+    }
+
+    // the configured crossfade from the current track to `next`, the player
+    // limits it
+    fn crossfade(&self, next: &TreeIter) -> Duration {
+        let seconds = self.state.lock().unwrap().config.get::<f64>("crossfade");
+        let current = self.current_song.borrow().clone();
+        match (seconds, current) {
+            (Some(seconds), Some(ref current))
+                if seconds > 0.0 && !self.is_gapless_transition(current, next) =>
+            {
+                Duration::from_millis((seconds * 1000.0) as u64)
+            }
+            _ => Duration::from_secs(0),
+        }
+    }
+
+    // lets the player open the upcoming track ahead, so that it follows the
+    // current one without a gap or fades in over its end
+    fn queue_upcoming(&self) {
+        let upcoming = self.upcoming_row().and_then(|row| {
+            let id = self.row_id(&row)?;
+            let path = self
+                .model
+                .get_value(&row, PATH_COLUMN as i32)
+                .get::<String>()?;
+            Some((id, path, self.crossfade(&row)))
+        });
+        if *self.queued.borrow() != upcoming {
+            match upcoming {
+                Some((_, ref path, crossfade)) => self.player.queue(Some(path), crossfade),
+                None => self.player.queue(None::<&str>, Duration::from_secs(0)),
+            }
+            *self.queued.borrow_mut() = upcoming;
+        }
+    }
+
+    // the player went on with the queued track by itself
+    fn follow_queued(&self) -> bool {
+        let queued = self.queued.borrow_mut().take();
+        let (id, _, _) = match queued {
+            Some(queued) => queued,
+            None => return false,
+        };
+        let row = match self.find_row(id) {
+            Some(row) => row,
+            None => return false,
+        };
+
+        if self.shuffled.get() {
+            self.shuffle.borrow_mut().set_current(id);
+        }
+        self.treeview.get_selection().select_iter(&row);
+        *self.current_song.borrow_mut() = Some(row.clone());
+        self.offer_resume(&row);
+        true
+    }
+
+    /// Processes the events sent by the player, goes to the next track when the
+    /// current one ended. Returns true if another track started.
+    pub fn handle_events(&self) -> bool {
+        let mut started = false;
+        while let Ok(event) = self.events.try_recv() {
+            match event {
+                Event::EndOfTrack => {
+                    self.forget_position();
+                    started = self.advance();
+                }
+                Event::NextTrack => {
+                    self.forget_position();
+                    started = self.follow_queued();
+                }
+                Event::Position(position) => self.remember_position(position),
+                _ => (),
+            }
+        }
+        self.queue_upcoming();
+        started
+    }
+
+    pub fn set_shuffle(&self, shuffled: bool) {
+        if shuffled && !self.shuffled.get() {
+            // a new random order starting from the track being played
+            let current = self.current_song.borrow().clone();
+            let mut shuffle = self.shuffle.borrow_mut();
+            shuffle.reshuffle();
+            if let Some(id) = current.and_then(|row| self.row_id(&row)) {
+                shuffle.set_current(id);
+            }
+        }
+        self.shuffled.set(shuffled);
+    }
+
+    pub fn set_repeat(&self, repeat: RepeatMode) {
+        self.repeat.set(repeat);
+    }
+
+    pub fn set_stop_after_current(&self, stop: bool) {
+        self.stop_after_current.set(stop);
+    }
+
+    pub fn pause(&self) {
+        self.player.pause();
+        self.save_positions();
+    }
+
+    pub fn resume(&self) {
+        self.player.resume();
+    }
+
+    pub fn stop(&self) {
+        self.player.stop();
+        self.save_positions();
+    }
+
+    pub fn seek(&self, position: Duration) {
+        self.player.seek(position);
+    }
+
+    pub fn set_volume(&self, volume: f64) {
+        self.player.set_volume(volume);
+    }
+
+    pub fn mute(&self, mute: bool) {
+        self.player.mute(mute);
+    }
+
+    pub fn set_speed(&self, speed: f64) {
+        self.player.set_speed(speed);
+    }
+
+    /// Makes next and previous step by chapter in the tracks which have
+    /// some.
+    pub fn set_chapter_steps(&self, steps: bool) {
+        self.chapter_steps.set(steps);
+    }
+
+    /// Chapters of the current track, none if it has no chapters.
+    pub(crate) fn chapters(&self) -> Option<Rc<Vec<Chapter>>> {
+        let path = self.current_path()?;
+        self.chapters.borrow().get(&path).cloned()
+    }
+
+    /// Index of the chapter being played, the last one started.
+    pub fn current_chapter(&self) -> Option<usize> {
+        let position = self.player.position();
+        let chapters = self.chapters()?;
+        let started = chapters.iter().filter(|chapter| chapter.start <= position);
+        Some(started.count().max(1) - 1)
+    }
+
+    fn chapter_start(&self, index: usize) -> Option<Duration> {
+        self.chapters()?.get(index).map(|chapter| chapter.start)
+    }
+
+    /// Goes to the beginning of a chapter of the current track.
+    pub fn seek_chapter(&self, index: usize) -> bool {
+        match self.chapter_start(index) {
+            Some(start) => {
+                self.player.seek(start);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn chapter_pixbuf(&self) -> Option<Pixbuf> {
+        let path = self.current_path()?;
+        let pixbufs = self.chapter_pixbufs.borrow();
+        pixbufs.get(&path)?.get(self.current_chapter()?)?.clone()
+    }
+
+    pub fn loop_points(&self) -> (Option<Duration>, Option<Duration>) {
+        self.ab_loop.get()
+    }
+
+    // the loop plays once its end is set, from the beginning of the track
+    // when it has no start
+    fn set_loop_points(&self, start: Option<Duration>, end: Option<Duration>) {
+        self.ab_loop.set((start, end));
+        let range = end.map(|end| (start.unwrap_or_default(), end));
+        self.player.set_loop(range);
+    }
+
+    /// Sets the A point of the loop, forgets the B point before it.
+    pub fn set_loop_start(&self, position: Duration) {
+        let (_, end) = self.ab_loop.get();
+        self.set_loop_points(Some(position), end.filter(|end| *end > position));
+    }
+
+    /// Sets the B point of the loop, forgets the A point after it.
+    pub fn set_loop_end(&self, position: Duration) {
+        let (start, _) = self.ab_loop.get();
+        self.set_loop_points(start.filter(|start| *start < position), Some(position));
+    }
+
+    pub fn clear_loop(&self) {
+        self.set_loop_points(None, None);
+    }
+
+    pub(crate) fn set_equalizer(&self, settings: Option<EqualizerSettings>) {
+        self.player.set_equalizer(settings);
+    }
+
+    pub fn state(&self) -> PlaybackState {
+        self.player.state()
+    }
+}
+
+// an image of the size of the covers
+fn decode_image(data: &[u8]) -> Option<Pixbuf> {
+    let loader = PixbufLoader::new();
+    loader.set_size(IMAGE_SIZE, IMAGE_SIZE);
+    let written = loader.write(data);
+    // closed after an error too, an open loader warns when dropped
+    written.and(loader.close()).ok()?;
+    loader.get_pixbuf()
 }
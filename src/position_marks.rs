@@ -0,0 +1,204 @@
+use std::{
+    cell::RefCell,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use gdk::enums::key;
+use gtk::{
+    ApplicationWindow, ContainerExt, Dialog, DialogExt, Entry, EntryExt, GtkMenuExtManual,
+    GtkWindowExt, Menu, MenuExt, MenuItem, MenuItemExt, MenuShellExt, PositionType, Scale,
+    ScaleExt, SeparatorMenuItem, WidgetExt,
+};
+use gtk_sys::{GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL};
+
+use mmp::State;
+
+use crate::{playlist::Playlist, App};
+
+const RESPONSE_ACCEPT: i32 = GTK_RESPONSE_ACCEPT as i32;
+const RESPONSE_CANCEL: i32 = GTK_RESPONSE_CANCEL as i32;
+
+fn escape_markup(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+fn minutes(position: Duration) -> String {
+    App::millis_to_minutes(mmp::to_millis(position))
+}
+
+/// The A-B loop and the bookmarks of the current track: marks on the
+/// position slider, its context menu and the keys `[`, `]` and `\` which set
+/// A, B and clear the loop.
+pub(crate) struct PositionMarks {
+    window: ApplicationWindow,
+    scale: Scale,
+    menu: Menu,
+    playlist: Rc<Playlist>,
+    state: Arc<Mutex<State>>,
+    // the track whose marks are shown
+    path: RefCell<Option<String>>,
+}
+
+impl PositionMarks {
+    pub fn new(
+        window: &ApplicationWindow,
+        scale: &Scale,
+        playlist: Rc<Playlist>,
+        state: Arc<Mutex<State>>,
+    ) -> Rc<Self> {
+        let menu = Menu::new();
+        menu.set_attach_widget(Some(scale));
+        Rc::new(PositionMarks {
+            window: window.clone(),
+            scale: scale.clone(),
+            menu,
+            playlist,
+            state,
+            path: RefCell::new(None),
+        })
+    }
+
+    /// Shows the marks of the current track.
+    pub fn update(&self) {
+        let path = self.playlist.current_path();
+        self.scale.clear_marks();
+        if let Some(ref path) = path {
+            let state = self.state.lock().unwrap();
+            for bookmark in state.bookmarks.get(path) {
+                let millis = mmp::to_millis(bookmark.position) as f64;
+                let name = escape_markup(&bookmark.name);
+                self.scale
+                    .add_mark(millis, PositionType::Bottom, Some(name.as_str()));
+            }
+        }
+
+        let (start, end) = self.playlist.loop_points();
+        for (point, label) in &[(start, "A"), (end, "B")] {
+            if let Some(point) = point {
+                let millis = mmp::to_millis(*point) as f64;
+                self.scale.add_mark(millis, PositionType::Top, Some(*label));
+            }
+        }
+        *self.path.borrow_mut() = path;
+    }
+
+    /// Updates the marks when another track started.
+    pub fn follow_track(&self) {
+        if *self.path.borrow() != self.playlist.current_path() {
+            self.update();
+        }
+    }
+
+    /// Handles the keys of the loop, returns false for the others. The
+    /// position is asked only for the keys setting a loop point.
+    pub fn handle_key<F: FnOnce() -> Duration>(&self, keyval: u32, position: F) -> bool {
+        match keyval {
+            key::bracketleft => self.playlist.set_loop_start(position()),
+            key::bracketright => self.playlist.set_loop_end(position()),
+            key::backslash => self.playlist.clear_loop(),
+            _ => return false,
+        }
+        self.update();
+        true
+    }
+
+    fn add_item<F: Fn() + 'static>(menu: &Menu, label: &str, activate: F) -> MenuItem {
+        let item = MenuItem::new_with_label(label);
+        item.connect_activate(move |_| activate());
+        menu.append(&item);
+        item
+    }
+
+    /// Opens the context menu of the slider, the loop points and the
+    /// bookmarks added are at `position`.
+    pub fn popup_menu(this: &Rc<Self>, position: Duration, button: u32, time: u32) {
+        let menu = &this.menu;
+        for child in menu.get_children() {
+            menu.remove(&child);
+        }
+
+        let marks = Rc::clone(this);
+        Self::add_item(menu, "Set loop start (A)", move || {
+            marks.playlist.set_loop_start(position);
+            marks.update();
+        });
+        let marks = Rc::clone(this);
+        Self::add_item(menu, "Set loop end (B)", move || {
+            marks.playlist.set_loop_end(position);
+            marks.update();
+        });
+        let marks = Rc::clone(this);
+        let clear_item = Self::add_item(menu, "Clear loop", move || {
+            marks.playlist.clear_loop();
+            marks.update();
+        });
+        clear_item.set_sensitive(this.playlist.loop_points() != (None, None));
+
+        let path = match this.playlist.current_path() {
+            Some(path) => path,
+            None => return Self::show_menu(menu, button, time),
+        };
+        menu.append(&SeparatorMenuItem::new());
+        let marks = Rc::clone(this);
+        let bookmark_path = path.clone();
+        Self::add_item(menu, "Add bookmark…", move || {
+            marks.add_bookmark(&bookmark_path, position);
+        });
+
+        let bookmarks = this.state.lock().unwrap().bookmarks.get(&path).to_vec();
+        if !bookmarks.is_empty() {
+            let remove_menu = Menu::new();
+            for (index, bookmark) in bookmarks.into_iter().enumerate() {
+                let label = format!("{} ({})", bookmark.name, minutes(bookmark.position));
+                let marks = Rc::clone(this);
+                Self::add_item(menu, &label, move || marks.playlist.seek(bookmark.position));
+
+                let marks = Rc::clone(this);
+                let path = path.clone();
+                Self::add_item(&remove_menu, &label, move || {
+                    marks.state.lock().unwrap().bookmarks.remove(&path, index);
+                    marks.update();
+                });
+            }
+            let remove_item = MenuItem::new_with_label("Remove bookmark");
+            remove_item.set_submenu(Some(&remove_menu));
+            menu.append(&remove_item);
+        }
+        Self::show_menu(menu, button, time);
+    }
+
+    fn show_menu(menu: &Menu, button: u32, time: u32) {
+        menu.show_all();
+        menu.popup_easy(button, time);
+    }
+
+    // asks the name of the bookmark, the position by default
+    fn add_bookmark(&self, path: &str, position: Duration) {
+        let dialog = Dialog::new();
+        dialog.set_title("Add a bookmark");
+        dialog.set_transient_for(Some(&self.window));
+        dialog.set_modal(true);
+        let entry = Entry::new();
+        entry.set_text(&minutes(position));
+        entry.set_activates_default(true);
+        dialog.get_content_area().add(&entry);
+        dialog.add_button("Cancel", RESPONSE_CANCEL);
+        dialog.add_button("Add", RESPONSE_ACCEPT);
+        dialog.set_default_response(RESPONSE_ACCEPT);
+        dialog.show_all();
+
+        let accepted = dialog.run() == RESPONSE_ACCEPT;
+        let name = entry.get_text().unwrap_or_default();
+        dialog.destroy();
+        let name = name.trim();
+        if accepted && !name.is_empty() {
+            let mut state = self.state.lock().unwrap();
+            state.bookmarks.add(path, name, position);
+        }
+        self.update();
+    }
+}
@@ -0,0 +1,350 @@
+use std::{path::Path, str::FromStr};
+
+use id3::{Tag, Version};
+
+use crate::id3v2::{self, Frame};
+
+/// Loudness the gains bring the tracks to, in LUFS (ReplayGain 2.0).
+pub const REFERENCE_LOUDNESS: f64 = -18.0;
+// the R128 gains of Opus are relative to -23 LUFS, 5 dB below ReplayGain
+const R128_OFFSET: f32 = 5.0;
+// RVA2 channel type of the master volume
+const RVA2_MASTER: u8 = 1;
+
+const TRACK_GAIN: &str = "REPLAYGAIN_TRACK_GAIN";
+const TRACK_PEAK: &str = "REPLAYGAIN_TRACK_PEAK";
+const ALBUM_GAIN: &str = "REPLAYGAIN_ALBUM_GAIN";
+const ALBUM_PEAK: &str = "REPLAYGAIN_ALBUM_PEAK";
+
+/// Which of the gains of a track is applied.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GainMode {
+    Off,
+    /// Every track at the same loudness.
+    Track,
+    /// The tracks of an album keep their relative loudness.
+    Album,
+}
+
+impl Default for GainMode {
+    fn default() -> Self {
+        GainMode::Track
+    }
+}
+
+impl FromStr for GainMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "off" => Ok(GainMode::Off),
+            "track" => Ok(GainMode::Track),
+            "album" => Ok(GainMode::Album),
+            _ => Err(format!("unknown ReplayGain mode: {}", s)),
+        }
+    }
+}
+
+/// Gains in dB and sample peaks (1 is full scale) of a track and of its
+/// album, as tagged or scanned.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct ReplayGain {
+    pub track_gain: Option<f32>,
+    pub track_peak: Option<f32>,
+    pub album_gain: Option<f32>,
+    pub album_peak: Option<f32>,
+}
+
+// "-6.48 dB", the unit is optional
+fn parse_gain(text: &str) -> Option<f32> {
+    let text = text.trim();
+    let text = if text.to_ascii_lowercase().ends_with("db") {
+        &text[..text.len() - 2]
+    } else {
+        text
+    };
+    text.trim().parse().ok()
+}
+
+// RVA2: identification, then for each channel its type, the adjustment in
+// 1/512 dB and the peak on a given number of bits
+fn parse_rva2(data: &[u8]) -> Option<(String, f32, Option<f32>)> {
+    let (identification, mut channels) = id3v2::split_terminated(0, data);
+    while channels.len() >= 4 {
+        let kind = channels[0];
+        let adjustment = (u16::from(channels[1]) << 8 | u16::from(channels[2])) as i16;
+        let adjustment = f32::from(adjustment) / 512.0;
+        let bits = usize::from(channels[3]);
+        let size = (bits + 7) / 8;
+        let peak = channels.get(4..4 + size)?;
+        channels = &channels[4 + size..];
+        if kind == RVA2_MASTER {
+            let peak = if bits == 0 {
+                None
+            } else {
+                let value = peak
+                    .iter()
+                    .fold(0.0, |acc, byte| acc * 256.0 + f64::from(*byte));
+                Some((value / 2f64.powi(bits as i32 - 1)) as f32)
+            };
+            return Some((identification, adjustment, peak));
+        }
+    }
+    None
+}
+
+impl ReplayGain {
+    pub fn is_complete(&self) -> bool {
+        self.track_gain.is_some() && self.album_gain.is_some()
+    }
+
+    /// Reads the TXXX frames written by most taggers, or else the RVA2 ones.
+    pub fn from_id3_frames(frames: &[Frame]) -> Self {
+        let value = |name| id3v2::find_text(frames, "TXXX", name);
+        let mut gain = ReplayGain {
+            track_gain: value(TRACK_GAIN).and_then(|text| parse_gain(&text)),
+            track_peak: value(TRACK_PEAK).and_then(|text| text.trim().parse().ok()),
+            album_gain: value(ALBUM_GAIN).and_then(|text| parse_gain(&text)),
+            album_peak: value(ALBUM_PEAK).and_then(|text| text.trim().parse().ok()),
+        };
+
+        let rva2 = frames
+            .iter()
+            .filter(|frame| frame.id == "RVA2")
+            .filter_map(|frame| parse_rva2(&frame.data));
+        for (identification, adjustment, peak) in rva2 {
+            if identification.eq_ignore_ascii_case("album") {
+                gain.album_gain = gain.album_gain.or(Some(adjustment));
+                gain.album_peak = gain.album_peak.or(peak);
+            } else {
+                gain.track_gain = gain.track_gain.or(Some(adjustment));
+                gain.track_peak = gain.track_peak.or(peak);
+            }
+        }
+        gain
+    }
+
+    /// Reads the REPLAYGAIN_* comments, or the R128_* ones of Opus.
+    pub fn from_vorbis_comments(comments: &[(String, String)]) -> Self {
+        let field = |key: &str| {
+            comments
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(key))
+                .map(|(_, value)| value.as_str())
+        };
+        // Q7.8 fixed point
+        let r128 = |key| {
+            field(key)
+                .and_then(|value| value.trim().parse::<i16>().ok())
+                .map(|value| f32::from(value) / 256.0 + R128_OFFSET)
+        };
+
+        ReplayGain {
+            track_gain: field(TRACK_GAIN)
+                .and_then(parse_gain)
+                .or_else(|| r128("R128_TRACK_GAIN")),
+            track_peak: field(TRACK_PEAK).and_then(|text| text.trim().parse().ok()),
+            album_gain: field(ALBUM_GAIN)
+                .and_then(parse_gain)
+                .or_else(|| r128("R128_ALBUM_GAIN")),
+            album_peak: field(ALBUM_PEAK).and_then(|text| text.trim().parse().ok()),
+        }
+    }
+
+    /// Reads the values given by name, as in the freeform items of MP4.
+    pub fn from_texts<F: Fn(&str) -> Option<String>>(value: F) -> Self {
+        let text = |name: &str| value(&name.to_ascii_lowercase());
+        let gain = |name| text(name).and_then(|text| parse_gain(&text));
+        let peak = |name| text(name).and_then(|text| text.trim().parse().ok());
+        ReplayGain {
+            track_gain: gain(TRACK_GAIN),
+            track_peak: peak(TRACK_PEAK),
+            album_gain: gain(ALBUM_GAIN),
+            album_peak: peak(ALBUM_PEAK),
+        }
+    }
+
+    /// Linear factor to apply to the samples, `preamp` in dB; it is lowered
+    /// when the peak would clip. Each mode falls back to the other gain.
+    pub fn factor(&self, mode: GainMode, preamp: f32) -> f32 {
+        let (gain, peak) = match mode {
+            GainMode::Off => return 1.0,
+            GainMode::Track => (
+                self.track_gain.or(self.album_gain),
+                self.track_peak.or(self.album_peak),
+            ),
+            GainMode::Album => (
+                self.album_gain.or(self.track_gain),
+                self.album_peak.or(self.track_peak),
+            ),
+        };
+        let factor = match gain {
+            Some(gain) => 10f32.powf((gain + preamp) / 20.0),
+            None => return 1.0,
+        };
+        match peak {
+            Some(peak) if peak > 0.0 => factor.min(1.0 / peak),
+            _ => factor,
+        }
+    }
+}
+
+/// Stores the gains in the ID3 tag of a file, as TXXX frames.
+pub fn write_id3<P: AsRef<Path>>(path: P, gain: &ReplayGain) -> Result<(), id3::Error> {
+    let path = path.as_ref();
+    let mut tag = Tag::read_from_path(path).unwrap_or_else(|_| Tag::new());
+    let values = [
+        (
+            TRACK_GAIN,
+            gain.track_gain.map(|gain| format!("{:.2} dB", gain)),
+        ),
+        (
+            TRACK_PEAK,
+            gain.track_peak.map(|peak| format!("{:.6}", peak)),
+        ),
+        (
+            ALBUM_GAIN,
+            gain.album_gain.map(|gain| format!("{:.2} dB", gain)),
+        ),
+        (
+            ALBUM_PEAK,
+            gain.album_peak.map(|peak| format!("{:.6}", peak)),
+        ),
+    ];
+    for (name, value) in values.iter() {
+        if let Some(ref value) = *value {
+            tag.remove_extended_text(Some(*name), None);
+            tag.add_extended_text(*name, value.as_str());
+        }
+    }
+    tag.write_to_path(path, Version::Id3v24)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn txxx(description: &str, value: &str) -> Frame {
+        let mut data = vec![3];
+        data.extend_from_slice(description.as_bytes());
+        data.push(0);
+        data.extend_from_slice(value.as_bytes());
+        Frame {
+            id: "TXXX".to_string(),
+            version: 4,
+            data,
+        }
+    }
+
+    // master volume adjustment in 1/512 dB, peak on 16 bits
+    fn rva2(identification: &str, adjustment: i16, peak: u16) -> Frame {
+        let mut data = identification.as_bytes().to_vec();
+        data.push(0);
+        // a front right channel first, which is skipped
+        data.extend_from_slice(&[3, 0x12, 0x34, 8, 0xff]);
+        data.push(RVA2_MASTER);
+        data.extend_from_slice(&adjustment.to_be_bytes());
+        data.push(16);
+        data.extend_from_slice(&peak.to_be_bytes());
+        Frame {
+            id: "RVA2".to_string(),
+            version: 4,
+            data,
+        }
+    }
+
+    fn comments(fields: &[(&str, &str)]) -> Vec<(String, String)> {
+        fields
+            .iter()
+            .map(|(name, value)| (name.to_string(), value.to_string()))
+            .collect()
+    }
+
+    #[test]
+    fn gain_with_or_without_unit() {
+        assert_eq!(parse_gain("-6.48 dB"), Some(-6.48));
+        assert_eq!(parse_gain(" +2.5dB "), Some(2.5));
+        assert_eq!(parse_gain("1.25"), Some(1.25));
+        assert_eq!(parse_gain("loud"), None);
+    }
+
+    #[test]
+    fn id3_txxx() {
+        let frames = [
+            txxx("replaygain_track_gain", "-7.20 dB"),
+            txxx("REPLAYGAIN_TRACK_PEAK", "0.988"),
+            txxx("REPLAYGAIN_ALBUM_GAIN", "-6.10 dB"),
+        ];
+        let gain = ReplayGain::from_id3_frames(&frames);
+        assert_eq!(gain.track_gain, Some(-7.2));
+        assert_eq!(gain.track_peak, Some(0.988));
+        assert_eq!(gain.album_gain, Some(-6.1));
+        assert_eq!(gain.album_peak, None);
+        assert!(gain.is_complete());
+    }
+
+    #[test]
+    fn id3_rva2_when_there_is_no_txxx() {
+        let frames = [
+            rva2("track", -3 * 512, 0x4000),
+            rva2("album", 512 / 2, 0x8000),
+            txxx("REPLAYGAIN_ALBUM_GAIN", "-1 dB"),
+        ];
+        let gain = ReplayGain::from_id3_frames(&frames);
+        assert_eq!(gain.track_gain, Some(-3.0));
+        assert_eq!(gain.track_peak, Some(0.5));
+        assert_eq!(gain.album_gain, Some(-1.0));
+        assert_eq!(gain.album_peak, Some(1.0));
+    }
+
+    #[test]
+    fn vorbis_comments_and_opus_r128() {
+        let gain = ReplayGain::from_vorbis_comments(&comments(&[
+            ("REPLAYGAIN_TRACK_GAIN", "-8.00 dB"),
+            ("replaygain_track_peak", "1.05"),
+        ]));
+        assert_eq!(gain.track_gain, Some(-8.0));
+        assert_eq!(gain.track_peak, Some(1.05));
+
+        // -23 LUFS plus 5 dB
+        let gain = ReplayGain::from_vorbis_comments(&comments(&[
+            ("R128_TRACK_GAIN", "-512"),
+            ("R128_ALBUM_GAIN", "256"),
+        ]));
+        assert_eq!(gain.track_gain, Some(3.0));
+        assert_eq!(gain.album_gain, Some(6.0));
+    }
+
+    #[test]
+    fn mp4_texts() {
+        let gain = ReplayGain::from_texts(|name| match name {
+            "replaygain_album_gain" => Some("2.5 dB".to_string()),
+            "replaygain_album_peak" => Some("0.5".to_string()),
+            _ => None,
+        });
+        assert_eq!(gain.album_gain, Some(2.5));
+        assert_eq!(gain.album_peak, Some(0.5));
+        assert_eq!(gain.track_gain, None);
+    }
+
+    #[test]
+    fn factor_falls_back_and_does_not_clip() {
+        let gain = ReplayGain {
+            track_gain: Some(-6.0),
+            track_peak: Some(0.5),
+            album_gain: Some(12.0),
+            album_peak: Some(0.5),
+        };
+        assert!((gain.factor(GainMode::Track, 0.0) - 0.501).abs() < 1e-3);
+        // +12 dB would clip the peak of 0.5
+        assert_eq!(gain.factor(GainMode::Album, 0.0), 2.0);
+        assert_eq!(gain.factor(GainMode::Off, 0.0), 1.0);
+
+        let album_only = ReplayGain {
+            album_gain: Some(-6.0),
+            ..ReplayGain::default()
+        };
+        assert_eq!(album_only.factor(GainMode::Track, 6.0), 1.0);
+        assert_eq!(ReplayGain::default().factor(GainMode::Track, 6.0), 1.0);
+    }
+}
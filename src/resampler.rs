@@ -0,0 +1,319 @@
+use std::{f64::consts::PI, str::FromStr};
+
+// the filter is tabulated for this many fractional positions between two
+// input samples, the coefficients in between are interpolated
+const PHASES: usize = 256;
+
+/// Trade-off between the CPU used and the attenuation of the aliases.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ResamplerQuality {
+    Fast,
+    Medium,
+    Best,
+}
+
+impl ResamplerQuality {
+    // zero crossings of the sinc on each side and beta of the Kaiser window
+    fn parameters(self) -> (usize, f64) {
+        match self {
+            ResamplerQuality::Fast => (8, 5.0),
+            ResamplerQuality::Medium => (16, 7.0),
+            ResamplerQuality::Best => (32, 9.0),
+        }
+    }
+}
+
+impl Default for ResamplerQuality {
+    fn default() -> Self {
+        ResamplerQuality::Medium
+    }
+}
+
+impl FromStr for ResamplerQuality {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "fast" => Ok(ResamplerQuality::Fast),
+            "medium" => Ok(ResamplerQuality::Medium),
+            "best" => Ok(ResamplerQuality::Best),
+            _ => Err(format!("unknown resampler quality: {}", s)),
+        }
+    }
+}
+
+// modified Bessel function of the first kind and order 0
+fn bessel_i0(x: f64) -> f64 {
+    let mut sum = 1.0;
+    let mut term = 1.0;
+    let mut k = 1.0;
+    while term > sum * 1e-12 {
+        term *= (x / (2.0 * k)) * (x / (2.0 * k));
+        sum += term;
+        k += 1.0;
+    }
+    sum
+}
+
+fn kaiser(x: f64, beta: f64) -> f64 {
+    if x.abs() >= 1.0 {
+        return 0.0;
+    }
+    bessel_i0(beta * (1.0 - x * x).sqrt()) / bessel_i0(beta)
+}
+
+fn sinc(x: f64) -> f64 {
+    if x == 0.0 {
+        1.0
+    } else {
+        (PI * x).sin() / (PI * x)
+    }
+}
+
+/// Band-limited sample rate converter: a Kaiser windowed sinc evaluated at
+/// the fractional position of every output frame. Works on interleaved
+/// samples and keeps its history between two calls.
+pub struct Resampler {
+    channels: usize,
+    quality: ResamplerQuality,
+    input_rate: u32,
+    output_rate: u32,
+    // input frames per output frame
+    step: f64,
+    // half the number of taps
+    half: usize,
+    // PHASES + 1 rows of 2 * half coefficients
+    table: Vec<f32>,
+    history: Vec<f32>,
+    // position of the next output frame in the history, in input frames
+    position: f64,
+}
+
+impl Resampler {
+    pub fn new(
+        channels: u16,
+        input_rate: u32,
+        output_rate: u32,
+        quality: ResamplerQuality,
+    ) -> Self {
+        let (zero_crossings, beta) = quality.parameters();
+        let step = f64::from(input_rate) / f64::from(output_rate);
+        // when downsampling, the cutoff follows the output Nyquist frequency
+        // and the filter gets longer to keep the same steepness
+        let cutoff = if step > 1.0 { 0.97 / step } else { 1.0 };
+        let half = (zero_crossings as f64 / cutoff).ceil() as usize;
+
+        let taps = 2 * half;
+        let mut table = Vec::with_capacity((PHASES + 1) * taps);
+        for phase in 0..=PHASES {
+            let fraction = phase as f64 / PHASES as f64;
+            for tap in 0..taps {
+                // distance from the output position to the input sample
+                let x = fraction - (tap as f64 - half as f64 + 1.0);
+                let coefficient = cutoff * sinc(cutoff * x) * kaiser(x / half as f64, beta);
+                table.push(coefficient as f32);
+            }
+        }
+
+        let mut resampler = Resampler {
+            channels: channels as usize,
+            quality,
+            input_rate,
+            output_rate,
+            step,
+            half,
+            table,
+            history: vec![],
+            position: 0.0,
+        };
+        resampler.reset();
+        resampler
+    }
+
+    pub fn input_rate(&self) -> u32 {
+        self.input_rate
+    }
+
+    pub fn is_passthrough(&self) -> bool {
+        self.input_rate == self.output_rate
+    }
+
+    /// Follows a change of rate within a stream: the frames of the previous
+    /// rate still held are appended to `output` first, so there is no gap.
+    pub fn set_input_rate(&mut self, input_rate: u32, output: &mut Vec<f32>) {
+        self.drain(output);
+        *self = Resampler::new(
+            self.channels as u16,
+            input_rate,
+            self.output_rate,
+            self.quality,
+        );
+    }
+
+    /// Forgets the history, after a seek.
+    pub fn reset(&mut self) {
+        // silence before the first frame, so that the output is not delayed
+        self.history.clear();
+        self.history.resize(self.half * self.channels, 0.0);
+        self.position = self.half as f64;
+    }
+
+    /// Appends the resampled frames of `input` to `output`.
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        self.history.extend_from_slice(input);
+        self.convert(output);
+    }
+
+    /// Appends the frames still held by the filter, at the end of a stream.
+    pub fn drain(&mut self, output: &mut Vec<f32>) {
+        if self.is_passthrough() {
+            return;
+        }
+
+        let frames = self.history.len() / self.channels;
+        self.history
+            .resize((frames + self.half) * self.channels, 0.0);
+        // only the frames up to the end of the input are produced
+        while self.position < frames as f64 {
+            self.convert_frame(output);
+        }
+        self.reset();
+    }
+
+    fn convert(&mut self, output: &mut Vec<f32>) {
+        let frames = self.history.len() / self.channels;
+        // the last tap of the next output frame must be in the history
+        while (self.position as usize) + self.half < frames {
+            self.convert_frame(output);
+        }
+
+        // drops the frames which no output frame needs anymore
+        let consumed = (self.position as usize).saturating_sub(self.half - 1);
+        self.history.drain(..consumed * self.channels);
+        self.position -= consumed as f64;
+    }
+
+    fn convert_frame(&mut self, output: &mut Vec<f32>) {
+        let index = self.position as usize;
+        let fraction = (self.position - index as f64) * PHASES as f64;
+        let phase = fraction as usize;
+        let weight = (fraction - phase as f64) as f32;
+
+        let taps = 2 * self.half;
+        let row = &self.table[phase * taps..(phase + 1) * taps];
+        let next_row = &self.table[(phase + 1) * taps..(phase + 2) * taps];
+        let first = (index + 1 - self.half) * self.channels;
+        for channel in 0..self.channels {
+            let mut sum = 0.0;
+            for (tap, (low, high)) in row.iter().zip(next_row).enumerate() {
+                let coefficient = low + (high - low) * weight;
+                sum += self.history[first + tap * self.channels + channel] * coefficient;
+            }
+            output.push(sum);
+        }
+        self.position += self.step;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sine(frequency: f64, rate: u32, frames: usize, channels: usize) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|i| {
+                let sample = (2.0 * PI * frequency * i as f64 / f64::from(rate)).sin();
+                std::iter::repeat(sample as f32).take(channels)
+            })
+            .collect()
+    }
+
+    fn resample(resampler: &mut Resampler, input: &[f32], chunk: usize) -> Vec<f32> {
+        let mut output = vec![];
+        for chunk in input.chunks(chunk) {
+            resampler.process(chunk, &mut output);
+        }
+        resampler.drain(&mut output);
+        output
+    }
+
+    fn rms(samples: &[f32]) -> f64 {
+        let sum: f64 = samples.iter().map(|x| f64::from(*x).powi(2)).sum();
+        (sum / samples.len() as f64).sqrt()
+    }
+
+    #[test]
+    fn same_rate_is_passthrough() {
+        let input = sine(1000.0, 48000, 100, 2);
+        let mut resampler = Resampler::new(2, 48000, 48000, ResamplerQuality::Fast);
+        assert!(resampler.is_passthrough());
+        assert_eq!(resample(&mut resampler, &input, 64), input);
+    }
+
+    #[test]
+    fn output_length_follows_the_ratio() {
+        let input = sine(1000.0, 44100, 44100, 2);
+        let mut resampler = Resampler::new(2, 44100, 48000, ResamplerQuality::Medium);
+        let frames = resample(&mut resampler, &input, 1000).len() / 2;
+        assert!((frames as i64 - 48000).abs() <= 1, "{} frames", frames);
+    }
+
+    #[test]
+    fn sine_keeps_its_phase_and_level() {
+        let input = sine(1000.0, 44100, 4410, 1);
+        let mut resampler = Resampler::new(1, 44100, 48000, ResamplerQuality::Best);
+        let output = resample(&mut resampler, &input, 512);
+        let expected = sine(1000.0, 48000, output.len(), 1);
+        // away from the edges, where the filter sees the silence around
+        for (actual, expected) in output.iter().zip(&expected).skip(200).take(4000) {
+            assert!(
+                (actual - expected).abs() < 2e-3,
+                "{} != {}",
+                actual,
+                expected
+            );
+        }
+    }
+
+    #[test]
+    fn downsampling_removes_what_is_above_nyquist() {
+        let input = sine(20000.0, 48000, 48000, 1);
+        let mut resampler = Resampler::new(1, 48000, 22050, ResamplerQuality::Medium);
+        let output = resample(&mut resampler, &input, 4096);
+        let middle = &output[1000..output.len() - 1000];
+        assert!(rms(middle) < 1e-3, "rms {}", rms(middle));
+    }
+
+    #[test]
+    fn chunks_do_not_change_the_output() {
+        let input = sine(440.0, 32000, 3000, 2);
+        let resampler = || Resampler::new(2, 32000, 44100, ResamplerQuality::Fast);
+        let whole = resample(&mut resampler(), &input, 6000);
+        let chunked = resample(&mut resampler(), &input, 34);
+        assert_eq!(whole.len(), chunked.len());
+        // the position is rebased at each call, it may round differently
+        for (whole, chunked) in whole.iter().zip(&chunked) {
+            assert!((whole - chunked).abs() < 1e-4, "{} != {}", whole, chunked);
+        }
+    }
+
+    #[test]
+    fn rate_change_keeps_the_frames_held() {
+        let mut resampler = Resampler::new(1, 44100, 48000, ResamplerQuality::Medium);
+        let mut output = vec![];
+        resampler.process(&sine(1000.0, 44100, 4410, 1), &mut output);
+        resampler.set_input_rate(32000, &mut output);
+        let first = output.len();
+        assert!((first as i64 - 4800).abs() <= 1, "{} frames", first);
+        assert_eq!(resampler.input_rate(), 32000);
+        resampler.process(&sine(1000.0, 32000, 3200, 1), &mut output);
+        resampler.drain(&mut output);
+        let second = output.len() - first;
+        assert!((second as i64 - 4800).abs() <= 1, "{} frames", second);
+    }
+}
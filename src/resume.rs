@@ -0,0 +1,135 @@
+use std::{collections::BTreeMap, fs, io, path::PathBuf, time::Duration};
+
+use crate::config::{config_file, write_file};
+
+const POSITIONS_FILE: &str = "positions";
+
+/// Where the long tracks were left, stored as `millis<TAB>path` lines in
+/// `$XDG_CONFIG_HOME/mmp/positions`.
+pub struct ResumePositions {
+    path: Option<PathBuf>,
+    positions: BTreeMap<String, u64>,
+    // changed since the file was written
+    dirty: bool,
+}
+
+impl ResumePositions {
+    pub fn load() -> Self {
+        Self::load_from(config_file(POSITIONS_FILE))
+    }
+
+    pub(crate) fn load_from(path: Option<PathBuf>) -> Self {
+        let positions = path
+            .as_ref()
+            .and_then(|path| fs::read_to_string(path).ok())
+            .map(|content| {
+                content
+                    .lines()
+                    .filter_map(|line| {
+                        let mut fields = line.splitn(2, '\t');
+                        let millis = fields.next()?.parse().ok()?;
+                        Some((fields.next()?.to_string(), millis))
+                    })
+                    .collect()
+            })
+            .unwrap_or_default();
+
+        ResumePositions {
+            path,
+            positions,
+            dirty: false,
+        }
+    }
+
+    pub fn get(&self, path: &str) -> Option<Duration> {
+        self.positions.get(path).cloned().map(Duration::from_millis)
+    }
+
+    /// Remembers a position, written by `save`.
+    pub fn set(&mut self, path: &str, position: Duration) {
+        let millis = crate::to_millis(position);
+        if self.positions.insert(path.to_string(), millis) != Some(millis) {
+            self.dirty = true;
+        }
+    }
+
+    pub fn remove(&mut self, path: &str) {
+        if self.positions.remove(path).is_some() {
+            self.dirty = true;
+        }
+    }
+
+    /// Writes the file back if some position changed.
+    pub fn save(&mut self) {
+        if !self.dirty {
+            return;
+        }
+        match self.write() {
+            Ok(()) => self.dirty = false,
+            Err(err) => println!("cannot save the playback positions: {}", err),
+        }
+    }
+
+    fn write(&self) -> io::Result<()> {
+        let path = match self.path {
+            Some(ref path) => path,
+            None => return Ok(()),
+        };
+        write_file(path, |file| {
+            for (track, millis) in &self.positions {
+                writeln!(file, "{}\t{}", millis, track)?;
+            }
+            Ok(())
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, process};
+
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("mmp-{}-{}", process::id(), name));
+        let _ = fs::remove_dir_all(&dir);
+        dir.join(POSITIONS_FILE)
+    }
+
+    #[test]
+    fn positions_round_trip() {
+        let path = temp_path("positions-round-trip");
+        let mut positions = ResumePositions::load_from(Some(path.clone()));
+        positions.set("/books/a\tb.m4b", Duration::from_millis(3_600_250));
+        positions.set("/books/c.mp3", Duration::from_secs(90));
+        assert!(!path.exists());
+        positions.save();
+
+        let positions = ResumePositions::load_from(Some(path.clone()));
+        let position = positions.get("/books/a\tb.m4b");
+        assert_eq!(position, Some(Duration::from_millis(3_600_250)));
+        assert_eq!(positions.get("/books/c.mp3"), Some(Duration::from_secs(90)));
+        assert_eq!(positions.get("/books/d.mp3"), None);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn only_changes_are_written() {
+        let path = temp_path("positions-changes");
+        let mut positions = ResumePositions::load_from(Some(path.clone()));
+        positions.set("/books/a.mp3", Duration::from_secs(10));
+        positions.save();
+
+        fs::remove_file(&path).unwrap();
+        positions.set("/books/a.mp3", Duration::from_secs(10));
+        positions.remove("/books/other.mp3");
+        positions.save();
+        assert!(!path.exists());
+
+        positions.remove("/books/a.mp3");
+        positions.save();
+        let positions = ResumePositions::load_from(Some(path.clone()));
+        assert_eq!(positions.get("/books/a.mp3"), None);
+        fs::remove_dir_all(path.parent().unwrap()).unwrap();
+    }
+}
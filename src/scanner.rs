@@ -0,0 +1,326 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crossbeam::channel::{self, Sender};
+
+use crate::{
+    channels::MAX_CHANNELS,
+    decoder,
+    loudness::{self, LoudnessMeter},
+    replaygain::{self, REFERENCE_LOUDNESS},
+    State,
+};
+
+// each scan reads a whole file, more threads would only compete for the disk
+const WORKERS: usize = 2;
+// frames decoded at once by the loudness scan
+const SCAN_BUFFER: usize = 4096;
+
+/// Pool of threads computing the durations the headers of the files do not
+/// tell, the results go to `State::durations`. The threads stop when the
+/// scanner is dropped.
+pub struct DurationScanner {
+    sender: Sender<String>,
+}
+
+impl DurationScanner {
+    pub fn new(state: Arc<Mutex<State>>) -> Self {
+        Self::with_scan(state, |path| decoder::scan_duration(path))
+    }
+
+    // the pool, with the way to compute the duration of a file
+    fn with_scan(state: Arc<Mutex<State>>, scan: fn(&str) -> Option<Duration>) -> Self {
+        let (sender, receiver) = channel::unbounded::<String>();
+        for _ in 0..WORKERS {
+            let receiver = receiver.clone();
+            let state = Arc::clone(&state);
+            thread::spawn(move || {
+                for path in receiver.iter() {
+                    if let Some(duration) = scan(&path) {
+                        let mut state = state.lock().unwrap();
+                        state.durations.insert(path, crate::to_millis(duration));
+                    }
+                }
+            });
+        }
+        DurationScanner { sender }
+    }
+
+    /// Queues a file, the files are scanned in the order they are queued.
+    pub fn scan(&self, path: String) {
+        let _ = self.sender.send(path);
+    }
+}
+
+// what has been measured of the tracks of an album
+#[derive(Default)]
+struct AlbumScan {
+    paths: Vec<String>,
+    blocks: Vec<f64>,
+    peak: f32,
+    // tracks queued and not scanned yet
+    pending: usize,
+}
+
+type Albums = Arc<Mutex<HashMap<String, AlbumScan>>>;
+
+// decodes a whole file through a loudness meter
+fn measure(path: &str) -> Option<LoudnessMeter> {
+    let mut decoder = decoder::open(path).ok()?;
+    let mut meter = LoudnessMeter::new(decoder.channels(), decoder.sample_rate());
+    let mut buffer = vec![0.0; SCAN_BUFFER * MAX_CHANNELS];
+    loop {
+        let channels = decoder.channels() as usize;
+        if channels > MAX_CHANNELS {
+            return None;
+        }
+        meter.set_format(decoder.channels(), decoder.sample_rate());
+        let length = decoder.read(&mut buffer[..SCAN_BUFFER * channels]);
+        if length == 0 {
+            return Some(meter);
+        }
+        meter.process(&buffer[..length - length % channels]);
+    }
+}
+
+fn gain_of(blocks: &[f64]) -> Option<f32> {
+    loudness::gated_loudness(blocks).map(|loudness| (REFERENCE_LOUDNESS - loudness) as f32)
+}
+
+// stores the gains of a scanned track, and those of its album once all the
+// tracks of the album queued so far are scanned; returns the files whose
+// gains changed
+fn record(
+    state: &Mutex<State>,
+    albums: &Mutex<HashMap<String, AlbumScan>>,
+    path: String,
+    album: Option<String>,
+    meter: Option<LoudnessMeter>,
+) -> Vec<String> {
+    if let Some(ref meter) = meter {
+        let mut state = state.lock().unwrap();
+        let gain = state.gains.entry(path.clone()).or_default();
+        gain.track_gain = gain_of(meter.blocks());
+        gain.track_peak = Some(meter.peak());
+    }
+
+    let album = match album {
+        Some(album) => album,
+        None => return meter.map(|_| vec![path]).unwrap_or_default(),
+    };
+    let mut albums = albums.lock().unwrap();
+    let scan = albums.entry(album).or_default();
+    scan.pending -= 1;
+    if let Some(ref meter) = meter {
+        scan.blocks.extend_from_slice(meter.blocks());
+        scan.peak = scan.peak.max(meter.peak());
+        scan.paths.push(path);
+    }
+    if scan.pending > 0 {
+        return vec![];
+    }
+
+    let album_gain = gain_of(&scan.blocks);
+    let mut state = state.lock().unwrap();
+    for path in &scan.paths {
+        let gain = state.gains.entry(path.clone()).or_default();
+        gain.album_gain = album_gain;
+        gain.album_peak = Some(scan.peak);
+    }
+    scan.paths.clone()
+}
+
+/// Pool of threads measuring the loudness of the files (EBU R128) to compute
+/// their ReplayGain, the results go to `State::gains`. The gain of an album
+/// is computed once its tracks queued so far are all measured, it covers
+/// only the scanned ones.
+pub struct GainScanner {
+    sender: Sender<(String, Option<String>)>,
+    albums: Albums,
+}
+
+impl GainScanner {
+    pub fn new(state: Arc<Mutex<State>>) -> Self {
+        let (sender, receiver) = channel::unbounded::<(String, Option<String>)>();
+        let albums = Albums::default();
+        for _ in 0..WORKERS {
+            let receiver = receiver.clone();
+            let state = Arc::clone(&state);
+            let albums = Arc::clone(&albums);
+            thread::spawn(move || {
+                for (path, album) in receiver.iter() {
+                    let meter = measure(&path);
+                    let changed = record(&state, &albums, path, album, meter);
+                    let write = state
+                        .lock()
+                        .unwrap()
+                        .config
+                        .get::<bool>("replay_gain_write");
+                    if write == Some(true) {
+                        write_tags(&state, &changed);
+                    }
+                }
+            });
+        }
+        GainScanner { sender, albums }
+    }
+
+    /// Queues a file, `album` identifies the files sharing an album gain.
+    pub fn scan(&self, path: String, album: Option<String>) {
+        if let Some(ref album) = album {
+            let mut albums = self.albums.lock().unwrap();
+            albums.entry(album.clone()).or_default().pending += 1;
+        }
+        let _ = self.sender.send((path, album));
+    }
+}
+
+// the tags are written with the id3 crate, thus only to the MP3 files
+fn write_tags(state: &Mutex<State>, paths: &[String]) {
+    for path in paths
+        .iter()
+        .filter(|path| decoder::format_name(path) == Some("MP3"))
+    {
+        let gain = state.lock().unwrap().gains.get(path).cloned();
+        if let Some(gain) = gain {
+            if let Err(err) = replaygain::write_id3(path, &gain) {
+                eprintln!("cannot write the ReplayGain of {}: {}", path, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{bookmarks::Bookmarks, config::Config, resume::ResumePositions};
+    use std::{
+        f32::consts::PI,
+        sync::atomic::{AtomicUsize, Ordering},
+        time::Instant,
+    };
+
+    const RATE: u32 = 48000;
+
+    fn empty_state() -> Arc<Mutex<State>> {
+        Arc::new(Mutex::new(State {
+            bookmarks: Bookmarks::load_from(None),
+            config: Config::load_from(None),
+            current_time: 0,
+            durations: HashMap::new(),
+            gains: HashMap::new(),
+            resume: ResumePositions::load_from(None),
+            stopped: true,
+        }))
+    }
+
+    static SCANNED: AtomicUsize = AtomicUsize::new(0);
+
+    // the duration is the length of the name in seconds, the files whose name
+    // starts with `bad` cannot be scanned
+    fn fake_scan(path: &str) -> Option<Duration> {
+        thread::sleep(Duration::from_millis(5));
+        let duration = if path.starts_with("bad") {
+            None
+        } else {
+            Some(Duration::from_secs(path.len() as u64))
+        };
+        SCANNED.fetch_add(1, Ordering::SeqCst);
+        duration
+    }
+
+    // a meter which measured a second of a mono sine
+    fn meter(amplitude: f32) -> Option<LoudnessMeter> {
+        let samples: Vec<f32> = (0..RATE)
+            .map(|i| amplitude * (2.0 * PI * 1000.0 * i as f32 / RATE as f32).sin())
+            .collect();
+        let mut meter = LoudnessMeter::new(1, RATE);
+        meter.process(&samples);
+        Some(meter)
+    }
+
+    fn albums(album: &str, pending: usize) -> Mutex<HashMap<String, AlbumScan>> {
+        let mut albums = HashMap::new();
+        let scan = AlbumScan {
+            pending,
+            ..AlbumScan::default()
+        };
+        albums.insert(album.to_string(), scan);
+        Mutex::new(albums)
+    }
+
+    #[test]
+    fn durations_are_cached() {
+        let state = empty_state();
+        let scanner = DurationScanner::with_scan(Arc::clone(&state), fake_scan);
+        let paths: Vec<String> = (0..20)
+            .map(|i| format!("{}{}.mp3", if i % 5 == 0 { "bad" } else { "" }, i))
+            .collect();
+        for path in &paths {
+            scanner.scan(path.clone());
+        }
+
+        // the scans which failed store nothing, they are counted
+        let start = Instant::now();
+        while SCANNED.load(Ordering::SeqCst) < paths.len()
+            || state.lock().unwrap().durations.len() < 16
+        {
+            let elapsed = start.elapsed();
+            assert!(elapsed < Duration::from_secs(10), "scan not finished");
+            thread::sleep(Duration::from_millis(5));
+        }
+        let state = state.lock().unwrap();
+        assert_eq!(state.durations.len(), 16);
+        for path in paths.iter().filter(|path| !path.starts_with("bad")) {
+            assert_eq!(state.durations.get(path), Some(&(path.len() as u64 * 1000)));
+        }
+    }
+
+    #[test]
+    fn track_gain_without_album() {
+        let state = empty_state();
+        let albums = Mutex::new(HashMap::new());
+        let changed = record(&state, &albums, "a.mp3".to_string(), None, meter(0.5));
+        assert_eq!(changed, ["a.mp3"]);
+        let state = state.lock().unwrap();
+        let gain = &state.gains["a.mp3"];
+        assert!(gain.track_gain.is_some());
+        assert_eq!(gain.track_peak, meter(0.5).map(|meter| meter.peak()));
+        assert_eq!(gain.album_gain, None);
+        drop(state);
+
+        // a file which could not be decoded
+        let changed = record(&empty_state(), &albums, "b.mp3".to_string(), None, None);
+        assert!(changed.is_empty());
+    }
+
+    #[test]
+    fn album_gain_once_its_tracks_are_scanned() {
+        let state = empty_state();
+        let albums = albums("album", 3);
+        let album = || Some("album".to_string());
+        assert!(record(&state, &albums, "1.mp3".to_string(), album(), meter(0.5)).is_empty());
+        assert!(record(&state, &albums, "2.mp3".to_string(), album(), None).is_empty());
+        {
+            let state = state.lock().unwrap();
+            assert!(state.gains["1.mp3"].track_gain.is_some());
+            assert_eq!(state.gains["1.mp3"].album_gain, None);
+        }
+
+        // the album gain covers the tracks measured
+        let changed = record(&state, &albums, "3.mp3".to_string(), album(), meter(0.25));
+        assert_eq!(changed, ["1.mp3", "3.mp3"]);
+        let state = state.lock().unwrap();
+        let (first, third) = (&state.gains["1.mp3"], &state.gains["3.mp3"]);
+        assert_eq!(first.album_gain, third.album_gain);
+        let album_gain = first.album_gain.unwrap();
+        assert!(album_gain > first.track_gain.unwrap() && album_gain < third.track_gain.unwrap());
+        assert_eq!(first.album_peak, first.track_peak);
+        assert_eq!(third.album_peak, first.track_peak);
+        assert!(!state.gains.contains_key("2.mp3"));
+    }
+}
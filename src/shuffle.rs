@@ -0,0 +1,257 @@
+/// xorshift64*, good enough to shuffle a playlist or to dither, and
+/// reproducible from a seed.
+pub struct XorShift(u64);
+
+impl XorShift {
+    pub fn new(seed: u64) -> Self {
+        // the state must never be zero
+        let state = seed ^ 0x9e37_79b9_7f4a_7c15;
+        XorShift(if state == 0 { 1 } else { state })
+    }
+
+    pub fn next(&mut self) -> u64 {
+        let mut x = self.0;
+        x ^= x >> 12;
+        x ^= x << 25;
+        x ^= x >> 27;
+        self.0 = x;
+        x.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    // uniform in [0, bound), bound must be positive
+    fn below(&mut self, bound: usize) -> usize {
+        (self.next() % bound as u64) as usize
+    }
+}
+
+/// Random order of the tracks of a playlist, identified by the ids of their
+/// rows. Each track is played once per cycle, tracks inserted during a cycle
+/// are played later in the same cycle.
+pub struct Shuffle {
+    order: Vec<u64>,
+    // index in `order` of the track being played, `None` before the first one
+    current: Option<usize>,
+    rng: XorShift,
+}
+
+impl Shuffle {
+    pub fn new(seed: u64) -> Self {
+        Shuffle {
+            order: vec![],
+            current: None,
+            rng: XorShift::new(seed),
+        }
+    }
+
+    fn position(&self, id: u64) -> Option<usize> {
+        self.order.iter().position(|x| *x == id)
+    }
+
+    // first index of the part of the order not played yet in this cycle
+    fn unplayed(&self) -> usize {
+        self.current.map_or(0, |current| current + 1)
+    }
+
+    pub fn insert(&mut self, id: u64) {
+        let start = self.unplayed();
+        let index = start + self.rng.below(self.order.len() - start + 1);
+        self.order.insert(index, id);
+    }
+
+    pub fn remove(&mut self, id: u64) {
+        if let Some(index) = self.position(id) {
+            self.order.remove(index);
+            self.current = match self.current {
+                Some(current) if index < current => Some(current - 1),
+                // the next track is the one which followed the removed one
+                Some(current) if index == current => current.checked_sub(1),
+                current => current,
+            };
+        }
+    }
+
+    /// Makes `id` the current track, e.g. when it has been chosen by the user.
+    pub fn set_current(&mut self, id: u64) {
+        if let Some(index) = self.position(id) {
+            // the tracks played in this cycle stay played, even when `id` is
+            // one of them, it moves to the place of the current track
+            self.order.remove(index);
+            let mut start = self.unplayed();
+            if index < start {
+                start -= 1;
+            }
+            self.order.insert(start, id);
+            self.current = Some(start);
+        }
+    }
+
+    /// Starts a new cycle with a new order.
+    pub fn reshuffle(&mut self) {
+        // Fisher-Yates
+        for i in (1..self.order.len()).rev() {
+            let j = self.rng.below(i + 1);
+            self.order.swap(i, j);
+        }
+        self.current = None;
+    }
+
+    /// Next track to play, once all the tracks are played a new cycle starts
+    /// only when `wrap` is set.
+    pub fn next(&mut self, wrap: bool) -> Option<u64> {
+        let next = self.unplayed();
+        if next < self.order.len() {
+            self.current = Some(next);
+            return Some(self.order[next]);
+        }
+
+        if !wrap || self.order.is_empty() {
+            return None;
+        }
+
+        let last = self.current.map(|current| self.order[current]);
+        self.reshuffle();
+        // don't play the same track twice in a row across cycles
+        if self.order.len() > 1 && Some(self.order[0]) == last {
+            let other = 1 + self.rng.below(self.order.len() - 1);
+            self.order.swap(0, other);
+        }
+        self.current = Some(0);
+        Some(self.order[0])
+    }
+
+    /// Track `next` returns within this cycle, without moving to it.
+    pub fn peek_next(&self) -> Option<u64> {
+        self.order.get(self.unplayed()).cloned()
+    }
+
+    pub fn previous(&mut self) -> Option<u64> {
+        let current = self.current?.checked_sub(1)?;
+        self.current = Some(current);
+        Some(self.order[current])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEED: u64 = 0x5eed;
+
+    fn shuffle(ids: std::ops::Range<u64>) -> Shuffle {
+        let mut shuffle = Shuffle::new(SEED);
+        for id in ids {
+            shuffle.insert(id);
+        }
+        shuffle
+    }
+
+    // the tracks `next` returns until the end of the cycle
+    fn rest_of_cycle(shuffle: &mut Shuffle) -> Vec<u64> {
+        let mut played = vec![];
+        while let Some(id) = shuffle.next(false) {
+            played.push(id);
+        }
+        played
+    }
+
+    fn sorted(mut ids: Vec<u64>) -> Vec<u64> {
+        ids.sort();
+        ids
+    }
+
+    #[test]
+    fn cycle_plays_each_track_once() {
+        let mut shuffle = shuffle(0..20);
+        let played = rest_of_cycle(&mut shuffle);
+        assert_eq!(sorted(played.clone()), (0..20).collect::<Vec<_>>());
+        assert_ne!(played, (0..20).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn same_seed_same_order() {
+        assert_eq!(
+            rest_of_cycle(&mut shuffle(0..20)),
+            rest_of_cycle(&mut shuffle(0..20))
+        );
+    }
+
+    #[test]
+    fn wrap_starts_a_new_cycle() {
+        let mut shuffle = shuffle(0..5);
+        let first = rest_of_cycle(&mut shuffle);
+        let mut second = vec![shuffle.next(true).unwrap()];
+        second.extend(rest_of_cycle(&mut shuffle));
+        assert_eq!(sorted(second.clone()), (0..5).collect::<Vec<_>>());
+        assert_ne!(first.last(), second.first());
+    }
+
+    #[test]
+    fn set_current_unplayed_track() {
+        let mut shuffle = shuffle(0..10);
+        let mut played = vec![shuffle.next(false).unwrap(), shuffle.next(false).unwrap()];
+        // a track later in the order than the next one
+        let next = shuffle.peek_next();
+        let chosen = (0..10)
+            .find(|id| !played.contains(id) && Some(*id) != next)
+            .unwrap();
+        shuffle.set_current(chosen);
+        played.push(chosen);
+        played.extend(rest_of_cycle(&mut shuffle));
+        assert_eq!(sorted(played), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn set_current_played_track() {
+        let mut shuffle = shuffle(0..10);
+        let played: Vec<_> = (0..4).map(|_| shuffle.next(false).unwrap()).collect();
+        shuffle.set_current(played[0]);
+        // the other tracks played stay played
+        let rest = rest_of_cycle(&mut shuffle);
+        assert_eq!(rest.len(), 6);
+        assert!(rest.iter().all(|id| !played.contains(id)));
+        let mut all = played.clone();
+        all.extend(rest);
+        assert_eq!(sorted(all), (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn peek_next_and_previous_agree_with_next() {
+        let mut shuffle = shuffle(0..8);
+        assert_eq!(shuffle.previous(), None);
+        let mut played = vec![];
+        while let Some(peeked) = shuffle.peek_next() {
+            assert_eq!(shuffle.next(false), Some(peeked));
+            played.push(peeked);
+        }
+        assert_eq!(shuffle.next(false), None);
+
+        for index in (0..played.len() - 1).rev() {
+            assert_eq!(shuffle.previous(), Some(played[index]));
+        }
+        assert_eq!(shuffle.previous(), None);
+        assert_eq!(shuffle.peek_next(), Some(played[1]));
+        assert_eq!(shuffle.next(false), Some(played[1]));
+    }
+
+    #[test]
+    fn insert_and_remove_during_a_cycle() {
+        let mut shuffle = shuffle(0..10);
+        let mut played: Vec<_> = (0..3).map(|_| shuffle.next(false).unwrap()).collect();
+        let unplayed = shuffle.peek_next().unwrap();
+        shuffle.remove(unplayed);
+        shuffle.insert(100);
+        shuffle.insert(101);
+        // removing the current track moves to the one which followed it
+        let current = *played.last().unwrap();
+        let following = shuffle.peek_next();
+        shuffle.remove(current);
+        assert_eq!(shuffle.peek_next(), following);
+
+        played.extend(rest_of_cycle(&mut shuffle));
+        let expected: Vec<_> = (0..10)
+            .filter(|id| *id != unplayed)
+            .chain(100..102)
+            .collect();
+        assert_eq!(sorted(played), expected);
+    }
+}
@@ -0,0 +1,878 @@
+use std::{
+    fs::File,
+    io::{self, BufWriter, Seek, SeekFrom, Write},
+    path::PathBuf,
+    str::FromStr,
+    thread,
+    time::{Duration, Instant},
+};
+
+use alsa::{
+    pcm::{Access, Format, HwParams, IoFormat, IO, PCM},
+    Direction, ValueOr,
+};
+use pulse_simple::Playback;
+
+use crate::{
+    dither::{Dither, DitherKind},
+    pcm::{RF64_PLACEHOLDER, WAVE_FORMAT_EXTENSIBLE, WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM},
+};
+
+/// Type of the samples written to the device.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SampleFormat {
+    S16,
+    /// 24 bits, in 3 bytes or in the low bytes of 4 depending on the output.
+    S24,
+    S32,
+    F32,
+}
+
+impl SampleFormat {
+    fn bits(self) -> u32 {
+        match self {
+            SampleFormat::S16 => 16,
+            SampleFormat::S24 => 24,
+            SampleFormat::S32 | SampleFormat::F32 => 32,
+        }
+    }
+}
+
+impl Default for SampleFormat {
+    fn default() -> Self {
+        SampleFormat::S16
+    }
+}
+
+impl FromStr for SampleFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "s16" => Ok(SampleFormat::S16),
+            "s24" => Ok(SampleFormat::S24),
+            "s32" => Ok(SampleFormat::S32),
+            "f32" => Ok(SampleFormat::F32),
+            _ => Err(format!("unknown sample format: {}", s)),
+        }
+    }
+}
+
+/// Format an output is asked for, and the one it has actually been opened
+/// with.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct OutputFormat {
+    pub sample_rate: u32,
+    pub channels: u16,
+    pub sample_format: SampleFormat,
+}
+
+/// Output of the player thread.
+pub trait AudioSink {
+    /// Prepares the output for a stream, called before the first write and
+    /// whenever a new track starts. The output may choose another rate or
+    /// sample format, and opens stereo when it does not support the channels
+    /// requested.
+    fn open(&mut self, format: OutputFormat) -> io::Result<OutputFormat>;
+
+    /// Writes interleaved samples in [-1, 1], converted to the sample format
+    /// of the output, blocks while the output is full.
+    fn write(&mut self, samples: &[f32]) -> io::Result<()>;
+
+    /// Waits until the samples written are played, at the end of a stream.
+    fn drain(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+
+    /// Drops the samples not played yet, when the playback stops or moves.
+    fn discard(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum SinkKind {
+    Alsa,
+    /// Discards the samples, at the pace of a real device or as fast as
+    /// they are decoded.
+    Null {
+        realtime: bool,
+    },
+    Pulse,
+    Wav(PathBuf),
+}
+
+impl Default for SinkKind {
+    fn default() -> Self {
+        SinkKind::Pulse
+    }
+}
+
+impl FromStr for SinkKind {
+    type Err = String;
+
+    /// Parses `pulse`, `alsa`, `null`, `null-fast` or `wav:<path>`.
+    fn from_str(s: &str) -> Result<Self, String> {
+        match s {
+            "pulse" => Ok(SinkKind::Pulse),
+            "alsa" => Ok(SinkKind::Alsa),
+            "null" => Ok(SinkKind::Null { realtime: true }),
+            "null-fast" => Ok(SinkKind::Null { realtime: false }),
+            _ if s.starts_with("wav:") && s.len() > 4 => Ok(SinkKind::Wav(PathBuf::from(&s[4..]))),
+            _ => Err(format!("unknown audio output: {}", s)),
+        }
+    }
+}
+
+pub fn new_sink(kind: &SinkKind, dither: DitherKind) -> Box<dyn AudioSink> {
+    let dither = Dither::new(dither);
+    match kind {
+        SinkKind::Alsa => Box::new(AlsaSink::new(dither)),
+        SinkKind::Null { realtime } => Box::new(NullSink::new(*realtime)),
+        SinkKind::Pulse => Box::new(PulseSink::new(dither)),
+        SinkKind::Wav(path) => Box::new(WavSink::new(path.clone(), dither)),
+    }
+}
+
+fn other_error<E: ToString>(err: E) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+enum PulseStream {
+    S16(Playback<[i16; 2]>),
+    S32(Playback<[i32; 2]>),
+    F32(Playback<[f32; 2]>),
+}
+
+/// Always stereo, PulseAudio does the remaining channel mapping itself.
+pub struct PulseSink {
+    dither: Dither,
+    format: Option<OutputFormat>,
+    quantized: Vec<i32>,
+    stream: Option<PulseStream>,
+}
+
+impl PulseSink {
+    fn new(dither: Dither) -> Self {
+        PulseSink {
+            dither,
+            format: None,
+            quantized: vec![],
+            stream: None,
+        }
+    }
+}
+
+// the simple API can neither flush nor drop a stream without draining it,
+// what is buffered plays out even when the playback stops
+impl AudioSink for PulseSink {
+    fn open(&mut self, requested: OutputFormat) -> io::Result<OutputFormat> {
+        let format = OutputFormat {
+            channels: 2,
+            // PulseAudio has no 24 bits format in 4 bytes
+            sample_format: match requested.sample_format {
+                SampleFormat::S24 => SampleFormat::S32,
+                sample_format => sample_format,
+            },
+            ..requested
+        };
+        if self.format != Some(format) {
+            let rate = format.sample_rate;
+            self.stream = Some(match format.sample_format {
+                SampleFormat::S16 => {
+                    PulseStream::S16(Playback::new("MP3", "MP3 Playback", None, rate))
+                }
+                SampleFormat::F32 => {
+                    PulseStream::F32(Playback::new("MP3", "MP3 Playback", None, rate))
+                }
+                _ => PulseStream::S32(Playback::new("MP3", "MP3 Playback", None, rate)),
+            });
+            self.format = Some(format);
+        }
+        Ok(format)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        self.quantized.clear();
+        match self.stream {
+            Some(PulseStream::S16(ref playback)) => {
+                self.dither.quantize(samples, 2, 16, &mut self.quantized);
+                let frames: Vec<_> = self
+                    .quantized
+                    .chunks_exact(2)
+                    .map(|frame| [frame[0] as i16, frame[1] as i16])
+                    .collect();
+                playback.write(&frames);
+            }
+            Some(PulseStream::S32(ref playback)) => {
+                self.dither.quantize(samples, 2, 32, &mut self.quantized);
+                let frames: Vec<_> = self
+                    .quantized
+                    .chunks_exact(2)
+                    .map(|frame| [frame[0], frame[1]])
+                    .collect();
+                playback.write(&frames);
+            }
+            Some(PulseStream::F32(ref playback)) => {
+                let frames: Vec<_> = samples
+                    .chunks_exact(2)
+                    .map(|frame| [frame[0], frame[1]])
+                    .collect();
+                playback.write(&frames);
+            }
+            None => return Err(other_error("PulseAudio stream not opened")),
+        }
+        Ok(())
+    }
+}
+
+// writes whole frames, recovering from underruns
+fn write_interleaved<S: IoFormat>(
+    pcm: &PCM,
+    io: &IO<S>,
+    samples: &[S],
+    channels: u16,
+) -> io::Result<()> {
+    let mut offset = 0;
+    while offset < samples.len() {
+        match io.writei(&samples[offset..]) {
+            Ok(written) => offset += written * channels as usize,
+            Err(err) => pcm.try_recover(err, true).map_err(other_error)?,
+        }
+    }
+    Ok(())
+}
+
+pub struct AlsaSink {
+    dither: Dither,
+    format: Option<OutputFormat>,
+    pcm: Option<PCM>,
+    quantized: Vec<i32>,
+    requested: Option<OutputFormat>,
+    shorts: Vec<i16>,
+}
+
+impl AlsaSink {
+    fn new(dither: Dither) -> Self {
+        AlsaSink {
+            dither,
+            format: None,
+            pcm: None,
+            quantized: vec![],
+            requested: None,
+            shorts: vec![],
+        }
+    }
+}
+
+impl AudioSink for AlsaSink {
+    fn open(&mut self, requested: OutputFormat) -> io::Result<OutputFormat> {
+        if let (Some(_), Some(format)) = (&self.pcm, self.format) {
+            if self.requested == Some(requested) {
+                return Ok(format);
+            }
+        }
+
+        // the previous stream must be closed before the device is reopened
+        self.pcm = None;
+        let pcm = PCM::new("default", Direction::Playback, false).map_err(other_error)?;
+        let format = {
+            let params = HwParams::any(&pcm).map_err(other_error)?;
+            let channels = if params.set_channels(u32::from(requested.channels)).is_ok() {
+                requested.channels
+            } else {
+                params.set_channels(2).map_err(other_error)?;
+                2
+            };
+            params
+                .set_rate(requested.sample_rate, ValueOr::Nearest)
+                .map_err(other_error)?;
+            let alsa_format = match requested.sample_format {
+                SampleFormat::S16 => Format::s16(),
+                SampleFormat::S24 => Format::s24(),
+                SampleFormat::S32 => Format::s32(),
+                SampleFormat::F32 => Format::float(),
+            };
+            let sample_format = if params.set_format(alsa_format).is_ok() {
+                requested.sample_format
+            } else {
+                params.set_format(Format::s16()).map_err(other_error)?;
+                SampleFormat::S16
+            };
+            params
+                .set_access(Access::RWInterleaved)
+                .map_err(other_error)?;
+            pcm.hw_params(&params).map_err(other_error)?;
+            // the nearest rate the device supports
+            OutputFormat {
+                sample_rate: params.get_rate().map_err(other_error)?,
+                channels,
+                sample_format,
+            }
+        };
+        self.pcm = Some(pcm);
+        self.format = Some(format);
+        self.requested = Some(requested);
+        Ok(format)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let (pcm, format) = match (&self.pcm, self.format) {
+            (Some(pcm), Some(format)) => (pcm, format),
+            _ => return Err(other_error("ALSA device not opened")),
+        };
+
+        let channels = format.channels;
+        match format.sample_format {
+            SampleFormat::F32 => {
+                let io = pcm.io_f32().map_err(other_error)?;
+                write_interleaved(pcm, &io, samples, channels)
+            }
+            SampleFormat::S16 => {
+                self.quantized.clear();
+                self.dither
+                    .quantize(samples, channels, 16, &mut self.quantized);
+                self.shorts.clear();
+                self.shorts
+                    .extend(self.quantized.iter().map(|sample| *sample as i16));
+                let io = pcm.io_i16().map_err(other_error)?;
+                write_interleaved(pcm, &io, &self.shorts, channels)
+            }
+            // 24 bits are in the low bytes of 4
+            sample_format => {
+                self.quantized.clear();
+                self.dither
+                    .quantize(samples, channels, sample_format.bits(), &mut self.quantized);
+                let io = pcm.io_i32().map_err(other_error)?;
+                write_interleaved(pcm, &io, &self.quantized, channels)
+            }
+        }
+    }
+
+    // the device must be prepared again before the next write
+    fn drain(&mut self) -> io::Result<()> {
+        match self.pcm {
+            Some(ref pcm) => pcm.drain().and_then(|_| pcm.prepare()).map_err(other_error),
+            None => Ok(()),
+        }
+    }
+
+    fn discard(&mut self) -> io::Result<()> {
+        match self.pcm {
+            Some(ref pcm) => pcm.drop().and_then(|_| pcm.prepare()).map_err(other_error),
+            None => Ok(()),
+        }
+    }
+}
+
+pub struct NullSink {
+    channels: u16,
+    realtime: bool,
+    sample_rate: u32,
+    started: Option<Instant>,
+    written: u64,
+}
+
+impl NullSink {
+    fn new(realtime: bool) -> Self {
+        NullSink {
+            channels: 2,
+            realtime,
+            sample_rate: 0,
+            started: None,
+            written: 0,
+        }
+    }
+}
+
+impl AudioSink for NullSink {
+    fn open(&mut self, format: OutputFormat) -> io::Result<OutputFormat> {
+        self.channels = format.channels;
+        self.sample_rate = format.sample_rate;
+        self.started = None;
+        self.written = 0;
+        Ok(format)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        if !self.realtime || self.sample_rate == 0 {
+            return Ok(());
+        }
+
+        let started = *self.started.get_or_insert_with(Instant::now);
+        self.written += (samples.len() / self.channels as usize) as u64;
+        let rate = u64::from(self.sample_rate);
+        let due = Duration::from_secs(self.written / rate)
+            + Duration::from_nanos(self.written % rate * 1_000_000_000 / rate);
+        let elapsed = started.elapsed();
+        if due > elapsed {
+            thread::sleep(due - elapsed);
+        }
+        Ok(())
+    }
+
+    fn drain(&mut self) -> io::Result<()> {
+        self.discard()
+    }
+
+    // the pace starts again from the next write
+    fn discard(&mut self) -> io::Result<()> {
+        self.started = None;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+// the "JUNK" chunk keeps the place of the "ds64" chunk of RF64, which the
+// header turns into once the data passes 4 GiB
+const DS64_SIZE: u32 = 28;
+
+// the rest of the GUID of the sub formats, after their format tag
+const SUBFORMAT_GUID: [u8; 14] = [
+    0x00, 0x00, 0x00, 0x00, 0x10, 0x00, 0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71,
+];
+
+// speakers of the channels in the WAV order, see `channels::stereo_matrix`
+fn channel_mask(channels: u16) -> u32 {
+    match channels {
+        1 => 0x4,
+        2 => 0x3,
+        3 => 0x7,
+        4 => 0x33,
+        5 => 0x37,
+        6 => 0x3f,
+        7 => 0x70f,
+        8 => 0x63f,
+        _ => 0,
+    }
+}
+
+// header of a WAV file, WAVE_FORMAT_EXTENSIBLE past 2 channels or 16 bits
+// and RF64 when the data passes 4 GiB, its size depends on the format only
+fn wav_header(format: OutputFormat, data_size: u64) -> Vec<u8> {
+    let bits = format.sample_format.bits() as u16;
+    let block_align = format.channels * bits / 8;
+    let format_tag = if format.sample_format == SampleFormat::F32 {
+        WAVE_FORMAT_IEEE_FLOAT
+    } else {
+        WAVE_FORMAT_PCM
+    };
+    let extensible = format.channels > 2 || bits > 16;
+    let fmt_size: u32 = if extensible { 40 } else { 16 };
+    let header_size = 12 + 8 + DS64_SIZE + 8 + fmt_size + 8;
+    let riff_size = u64::from(header_size) - 8 + data_size;
+    let rf64 = riff_size > u64::from(RF64_PLACEHOLDER);
+
+    let mut header = Vec::with_capacity(header_size as usize);
+    if rf64 {
+        header.extend_from_slice(b"RF64");
+        header.extend_from_slice(&RF64_PLACEHOLDER.to_le_bytes());
+        header.extend_from_slice(b"WAVEds64");
+        header.extend_from_slice(&DS64_SIZE.to_le_bytes());
+        header.extend_from_slice(&riff_size.to_le_bytes());
+        header.extend_from_slice(&data_size.to_le_bytes());
+        header.extend_from_slice(&(data_size / u64::from(block_align)).to_le_bytes());
+        // no table of other chunk sizes
+        header.extend_from_slice(&0u32.to_le_bytes());
+    } else {
+        header.extend_from_slice(b"RIFF");
+        header.extend_from_slice(&(riff_size as u32).to_le_bytes());
+        header.extend_from_slice(b"WAVEJUNK");
+        header.extend_from_slice(&DS64_SIZE.to_le_bytes());
+        header.extend_from_slice(&[0; DS64_SIZE as usize]);
+    }
+
+    header.extend_from_slice(b"fmt ");
+    header.extend_from_slice(&fmt_size.to_le_bytes());
+    let tag = if extensible {
+        WAVE_FORMAT_EXTENSIBLE
+    } else {
+        format_tag
+    };
+    header.extend_from_slice(&tag.to_le_bytes());
+    header.extend_from_slice(&format.channels.to_le_bytes());
+    header.extend_from_slice(&format.sample_rate.to_le_bytes());
+    header.extend_from_slice(&(format.sample_rate * u32::from(block_align)).to_le_bytes());
+    header.extend_from_slice(&block_align.to_le_bytes());
+    header.extend_from_slice(&bits.to_le_bytes());
+    if extensible {
+        // size of the extension, valid bits, speakers and sub format
+        header.extend_from_slice(&22u16.to_le_bytes());
+        header.extend_from_slice(&bits.to_le_bytes());
+        header.extend_from_slice(&channel_mask(format.channels).to_le_bytes());
+        header.extend_from_slice(&format_tag.to_le_bytes());
+        header.extend_from_slice(&SUBFORMAT_GUID);
+    }
+
+    header.extend_from_slice(b"data");
+    let data_size = if rf64 {
+        RF64_PLACEHOLDER
+    } else {
+        data_size as u32
+    };
+    header.extend_from_slice(&data_size.to_le_bytes());
+    header
+}
+
+/// Writes the output to a WAV file, in the channel layout of the source and
+/// the sample format asked for. Consecutive tracks of the same format are
+/// concatenated, a new file is started when the format changes.
+pub struct WavSink {
+    data_size: u64,
+    dither: Dither,
+    file: Option<BufWriter<File>>,
+    files: u32,
+    format: Option<OutputFormat>,
+    path: PathBuf,
+    quantized: Vec<i32>,
+}
+
+impl WavSink {
+    fn new(path: PathBuf, dither: Dither) -> Self {
+        WavSink {
+            data_size: 0,
+            dither,
+            file: None,
+            files: 0,
+            format: None,
+            path,
+            quantized: vec![],
+        }
+    }
+
+    fn write_header(&mut self) -> io::Result<()> {
+        let data_size = self.data_size;
+        let (file, format) = match (self.file.as_mut(), self.format) {
+            (Some(file), Some(format)) => (file, format),
+            _ => return Ok(()),
+        };
+
+        file.seek(SeekFrom::Start(0))?;
+        file.write_all(&wav_header(format, data_size))?;
+        file.seek(SeekFrom::End(0))?;
+        file.flush()
+    }
+
+    // the following files get a numeric suffix: out.wav, out-1.wav...
+    fn next_path(&self) -> PathBuf {
+        if self.files == 0 {
+            return self.path.clone();
+        }
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        self.path
+            .with_file_name(format!("{}-{}.wav", stem, self.files))
+    }
+}
+
+impl AudioSink for WavSink {
+    fn open(&mut self, format: OutputFormat) -> io::Result<OutputFormat> {
+        if self.file.is_some() && self.format == Some(format) {
+            return Ok(format);
+        }
+
+        self.write_header()?;
+        self.file = Some(BufWriter::new(File::create(self.next_path())?));
+        self.files += 1;
+        self.format = Some(format);
+        self.data_size = 0;
+        self.write_header()?;
+        Ok(format)
+    }
+
+    fn write(&mut self, samples: &[f32]) -> io::Result<()> {
+        let (file, format) = match (self.file.as_mut(), self.format) {
+            (Some(file), Some(format)) => (file, format),
+            _ => return Err(other_error("WAV file not opened")),
+        };
+
+        let bytes = format.sample_format.bits() as usize / 8;
+        if format.sample_format == SampleFormat::F32 {
+            for sample in samples {
+                file.write_all(&sample.to_bits().to_le_bytes())?;
+            }
+        } else {
+            self.quantized.clear();
+            self.dither.quantize(
+                samples,
+                format.channels,
+                format.sample_format.bits(),
+                &mut self.quantized,
+            );
+            // the low bytes, 24 bits samples are packed in 3
+            for sample in &self.quantized {
+                file.write_all(&sample.to_le_bytes()[..bytes])?;
+            }
+        }
+        self.data_size += (samples.len() * bytes) as u64;
+        Ok(())
+    }
+
+    // keeps the header valid when the playback stops, what is written
+    // stays in the file
+    fn drain(&mut self) -> io::Result<()> {
+        self.write_header()
+    }
+
+    fn discard(&mut self) -> io::Result<()> {
+        self.write_header()
+    }
+}
+
+impl Drop for WavSink {
+    fn drop(&mut self) {
+        let _ = self.write_header();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::Path, process};
+
+    use super::*;
+    use crate::{decoder::Decoder, pcm::PcmDecoder};
+
+    fn temp_path(name: &str) -> PathBuf {
+        env::temp_dir().join(format!("mmp-{}-{}", process::id(), name))
+    }
+
+    // a different level on each channel
+    fn sine(frames: usize, channels: u16) -> Vec<f32> {
+        (0..frames)
+            .flat_map(|frame| {
+                let value = (frame as f32 * 0.05).sin() * 0.5;
+                (0..channels).map(move |channel| value / f32::from(channel + 1))
+            })
+            .collect()
+    }
+
+    fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+        let mut field = [0; 4];
+        field.copy_from_slice(&bytes[offset..offset + 4]);
+        u32::from_le_bytes(field)
+    }
+
+    fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+        u16::from(bytes[offset]) | u16::from(bytes[offset + 1]) << 8
+    }
+
+    fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+        u64::from(u32_at(bytes, offset)) | u64::from(u32_at(bytes, offset + 4)) << 32
+    }
+
+    // the id, offset of the content and size of the chunks after "WAVE"
+    fn chunks(bytes: &[u8]) -> Vec<([u8; 4], usize, u32)> {
+        let mut chunks = vec![];
+        let mut offset = 12;
+        while offset + 8 <= bytes.len() {
+            let mut id = [0; 4];
+            id.copy_from_slice(&bytes[offset..offset + 4]);
+            let size = u32_at(bytes, offset + 4);
+            chunks.push((id, offset + 8, size));
+            offset += 8 + size as usize;
+        }
+        chunks
+    }
+
+    fn decode(path: &Path) -> Vec<f32> {
+        let mut decoder = PcmDecoder::new(File::open(path).unwrap()).unwrap();
+        let mut samples = vec![];
+        let mut buffer = [0.0; 1024];
+        loop {
+            let length = decoder.read(&mut buffer);
+            if length == 0 {
+                return samples;
+            }
+            samples.extend_from_slice(&buffer[..length]);
+        }
+    }
+
+    fn render(path: &Path, format: OutputFormat, samples: &[f32]) {
+        let mut sink = WavSink::new(path.to_path_buf(), Dither::new(DitherKind::None));
+        assert_eq!(sink.open(format).unwrap(), format);
+        let (first, second) = samples.split_at(samples.len() / 2);
+        sink.write(first).unwrap();
+        sink.write(second).unwrap();
+        sink.drain().unwrap();
+    }
+
+    #[test]
+    fn wav_header_and_samples() {
+        let path = temp_path("s16.wav");
+        let format = OutputFormat {
+            sample_rate: 44100,
+            channels: 2,
+            sample_format: SampleFormat::S16,
+        };
+        let samples = sine(1000, 2);
+        render(&path, format, &samples);
+
+        let bytes = fs::read(&path).unwrap();
+        assert_eq!(&bytes[..4], b"RIFF");
+        assert_eq!(u32_at(&bytes, 4) as usize, bytes.len() - 8);
+        assert_eq!(&bytes[8..12], b"WAVE");
+        let chunks = chunks(&bytes);
+        let ids: Vec<_> = chunks.iter().map(|(id, _, _)| id).collect();
+        assert_eq!(ids, [b"JUNK", b"fmt ", b"data"]);
+        let (_, fmt, fmt_size) = chunks[1];
+        assert_eq!(fmt_size, 16);
+        assert_eq!(u16_at(&bytes, fmt), WAVE_FORMAT_PCM);
+        assert_eq!(u16_at(&bytes, fmt + 2), 2);
+        assert_eq!(u32_at(&bytes, fmt + 4), 44100);
+        assert_eq!(u32_at(&bytes, fmt + 8), 44100 * 4);
+        assert_eq!(u16_at(&bytes, fmt + 12), 4);
+        assert_eq!(u16_at(&bytes, fmt + 14), 16);
+        let (_, data, data_size) = chunks[2];
+        assert_eq!(data_size, 1000 * 4);
+        assert_eq!(bytes.len(), data + 1000 * 4);
+
+        let decoded = decode(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        for (decoded, sample) in decoded.iter().zip(samples.iter()) {
+            assert!((decoded - sample).abs() <= 1.0 / 32768.0);
+        }
+    }
+
+    #[test]
+    fn wav_float_samples_are_exact() {
+        let path = temp_path("f32.wav");
+        let format = OutputFormat {
+            sample_rate: 48000,
+            channels: 1,
+            sample_format: SampleFormat::F32,
+        };
+        let samples = sine(500, 1);
+        render(&path, format, &samples);
+
+        let bytes = fs::read(&path).unwrap();
+        let chunks = chunks(&bytes);
+        let (_, fmt, _) = chunks[1];
+        // 32 bits need the extensible format
+        assert_eq!(u16_at(&bytes, fmt), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_at(&bytes, fmt + 14), 32);
+        assert_eq!(u32_at(&bytes, fmt + 20), 0x4);
+        assert_eq!(u16_at(&bytes, fmt + 24), WAVE_FORMAT_IEEE_FLOAT);
+        assert_eq!(chunks[2].2, 500 * 4);
+        let decoded = decode(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(decoded, samples);
+    }
+
+    #[test]
+    fn wav_extensible_past_stereo_or_16_bits() {
+        let path = temp_path("s24.wav");
+        let format = OutputFormat {
+            sample_rate: 96000,
+            channels: 6,
+            sample_format: SampleFormat::S24,
+        };
+        let samples = sine(300, 6);
+        render(&path, format, &samples);
+
+        let bytes = fs::read(&path).unwrap();
+        let chunks = chunks(&bytes);
+        let (_, fmt, fmt_size) = chunks[1];
+        assert_eq!(fmt_size, 40);
+        assert_eq!(u16_at(&bytes, fmt), WAVE_FORMAT_EXTENSIBLE);
+        assert_eq!(u16_at(&bytes, fmt + 12), 18);
+        assert_eq!(u16_at(&bytes, fmt + 14), 24);
+        assert_eq!(u16_at(&bytes, fmt + 16), 22);
+        assert_eq!(u16_at(&bytes, fmt + 18), 24);
+        assert_eq!(u32_at(&bytes, fmt + 20), 0x3f);
+        assert_eq!(u16_at(&bytes, fmt + 24), WAVE_FORMAT_PCM);
+        assert_eq!(&bytes[fmt + 26..fmt + 40], &SUBFORMAT_GUID);
+        assert_eq!(chunks[2].2, 300 * 18);
+
+        let decoded = decode(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(decoded.len(), samples.len());
+        for (decoded, sample) in decoded.iter().zip(samples.iter()) {
+            assert!((decoded - sample).abs() <= 1.0 / 8_388_608.0);
+        }
+    }
+
+    #[test]
+    fn wav_rf64_past_4_gib() {
+        let format = OutputFormat {
+            sample_rate: 44100,
+            channels: 2,
+            sample_format: SampleFormat::S16,
+        };
+        let small = wav_header(format, 1000);
+        let data_size = 5 << 30;
+        let header = wav_header(format, data_size);
+        // the data does not move
+        assert_eq!(header.len(), small.len());
+
+        assert_eq!(&header[..4], b"RF64");
+        assert_eq!(u32_at(&header, 4), RF64_PLACEHOLDER);
+        assert_eq!(&header[8..16], b"WAVEds64");
+        assert_eq!(u32_at(&header, 16), DS64_SIZE);
+        assert_eq!(u64_at(&header, 20), header.len() as u64 - 8 + data_size);
+        assert_eq!(u64_at(&header, 28), data_size);
+        assert_eq!(u64_at(&header, 36), data_size / 4);
+        assert_eq!(&header[header.len() - 8..header.len() - 4], b"data");
+        assert_eq!(u32_at(&header, header.len() - 4), RF64_PLACEHOLDER);
+
+        // the largest RIFF file
+        let riff_limit = u64::from(RF64_PLACEHOLDER) + 8 - small.len() as u64;
+        assert_eq!(&wav_header(format, riff_limit)[..4], b"RIFF");
+        assert_eq!(&wav_header(format, riff_limit + 1)[..4], b"RF64");
+    }
+
+    #[test]
+    fn wav_new_file_when_the_format_changes() {
+        let path = temp_path("tracks.wav");
+        let next_path = temp_path("tracks-1.wav");
+        let mut format = OutputFormat {
+            sample_rate: 44100,
+            channels: 2,
+            sample_format: SampleFormat::S16,
+        };
+        {
+            let mut sink = WavSink::new(path.clone(), Dither::new(DitherKind::None));
+            sink.open(format).unwrap();
+            sink.write(&sine(100, 2)).unwrap();
+            // the same format goes on in the same file
+            sink.open(format).unwrap();
+            sink.write(&sine(100, 2)).unwrap();
+            format.sample_rate = 48000;
+            sink.open(format).unwrap();
+            sink.write(&sine(50, 2)).unwrap();
+        }
+
+        let first = decode(&path);
+        let second = decode(&next_path);
+        fs::remove_file(&path).unwrap();
+        fs::remove_file(&next_path).unwrap();
+        assert_eq!(first.len(), 400);
+        assert_eq!(second.len(), 100);
+    }
+
+    #[test]
+    fn null_sink_paces_only_in_realtime() {
+        let format = OutputFormat {
+            sample_rate: 1000,
+            channels: 2,
+            sample_format: SampleFormat::S16,
+        };
+        let samples = sine(100, 2);
+
+        let mut sink = NullSink::new(false);
+        sink.open(format).unwrap();
+        let started = Instant::now();
+        sink.write(&samples).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(50));
+
+        let mut sink = NullSink::new(true);
+        sink.open(format).unwrap();
+        let started = Instant::now();
+        sink.write(&samples).unwrap();
+        sink.write(&samples).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(200));
+    }
+}
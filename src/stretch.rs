@@ -0,0 +1,252 @@
+/// Slowest and fastest playback speeds.
+pub const MIN_SPEED: f64 = 0.5;
+pub const MAX_SPEED: f64 = 3.0;
+
+// WSOLA: segments of the input are laid one after the other in the output,
+// overlapping a little, while the input advances faster or slower than the
+// output; each segment is taken where it best continues the previous one
+// within the seek window, which keeps the pitch and avoids phase jumps
+const SEGMENT_MILLIS: u32 = 40;
+const OVERLAP_MILLIS: u32 = 8;
+const SEEK_MILLIS: u32 = 15;
+
+/// Changes the speed of interleaved samples without changing their pitch.
+pub struct TimeStretch {
+    speed: f64,
+    channels: usize,
+    sample_rate: u32,
+    segment: usize,
+    overlap: usize,
+    seek: usize,
+    // samples not consumed yet, the next segment is taken from the beginning
+    pending: Vec<f32>,
+    // end of the previous segment, faded into the next one
+    tail: Vec<f32>,
+    // frame of `pending` which follows `tail` in the input, none before the
+    // first segment
+    continuation: Option<usize>,
+    // part of a frame the input should have advanced by
+    skip_fraction: f64,
+}
+
+impl TimeStretch {
+    pub fn new() -> Self {
+        TimeStretch {
+            speed: 1.0,
+            channels: 0,
+            sample_rate: 0,
+            segment: 0,
+            overlap: 0,
+            seek: 0,
+            pending: vec![],
+            tail: vec![],
+            continuation: None,
+            skip_fraction: 0.0,
+        }
+    }
+
+    pub fn speed(&self) -> f64 {
+        self.speed
+    }
+
+    /// Changes the speed, between `MIN_SPEED` and `MAX_SPEED`, the samples
+    /// already given keep the previous one.
+    pub fn set_speed(&mut self, speed: f64, output: &mut Vec<f32>) {
+        let speed = speed.max(MIN_SPEED).min(MAX_SPEED);
+        if speed != self.speed {
+            self.flush(output);
+            self.speed = speed;
+        }
+    }
+
+    fn set_format(&mut self, channels: usize, sample_rate: u32) {
+        let frames = |millis: u32| (sample_rate * millis / 1000).max(1) as usize;
+        self.channels = channels;
+        self.sample_rate = sample_rate;
+        self.segment = frames(SEGMENT_MILLIS);
+        self.overlap = frames(OVERLAP_MILLIS);
+        self.seek = frames(SEEK_MILLIS);
+        self.reset();
+    }
+
+    /// Appends the stretched `input` to `output`, part of it is kept until
+    /// more samples come.
+    pub fn process(
+        &mut self,
+        input: &[f32],
+        channels: u16,
+        sample_rate: u32,
+        output: &mut Vec<f32>,
+    ) {
+        let channels = channels as usize;
+        if channels != self.channels || sample_rate != self.sample_rate {
+            self.flush(output);
+            self.set_format(channels, sample_rate);
+        }
+        if self.speed == 1.0 {
+            output.extend_from_slice(input);
+            return;
+        }
+
+        self.pending.extend_from_slice(input);
+        let step = self.segment - self.overlap;
+        // the input advances this much for each segment
+        let advance = (step as f64 * self.speed).ceil() as usize;
+        let needed = (self.seek + self.segment).max(advance);
+        while self.pending.len() >= needed * channels {
+            let offset = match self.continuation {
+                Some(_) => self.best_offset(),
+                None => {
+                    self.tail = self.pending[..self.overlap * channels].to_vec();
+                    0
+                }
+            };
+
+            // the tail of the previous segment fades into this one
+            let start = offset * channels;
+            let overlap = self.overlap as f32;
+            let faded = self
+                .tail
+                .chunks(channels)
+                .zip(self.pending[start..].chunks(channels));
+            for (index, (previous, next)) in faded.enumerate() {
+                let progress = index as f32 / overlap;
+                for (previous, next) in previous.iter().zip(next.iter()) {
+                    output.push(previous * (1.0 - progress) + next * progress);
+                }
+            }
+            let end = (offset + self.segment) * channels;
+            let tail_start = end - self.overlap * channels;
+            output.extend_from_slice(&self.pending[start + self.overlap * channels..tail_start]);
+            self.tail.clear();
+            self.tail.extend_from_slice(&self.pending[tail_start..end]);
+
+            let skip = step as f64 * self.speed + self.skip_fraction;
+            let frames = skip as usize;
+            self.skip_fraction = skip - frames as f64;
+            self.pending.drain(..frames * channels);
+            self.continuation = Some((offset + self.segment).saturating_sub(frames));
+        }
+    }
+
+    // where the next segment continues the tail best, the cross-correlation
+    // normalized by the energy of the segment
+    fn best_offset(&self) -> usize {
+        let length = self.overlap * self.channels;
+        let mut best = (0, std::f32::MIN);
+        for offset in 0..self.seek {
+            let start = offset * self.channels;
+            let candidate = &self.pending[start..start + length];
+            let (correlation, energy) = self.tail.iter().zip(candidate.iter()).fold(
+                (0.0, 0.0),
+                |(correlation, energy), (tail, sample)| {
+                    (correlation + tail * sample, energy + sample * sample)
+                },
+            );
+            let score = correlation / (energy + 1e-9).sqrt();
+            if score > best.1 {
+                best = (offset, score);
+            }
+        }
+        best.0
+    }
+
+    /// Appends the samples kept, as they are, e.g. at the end of the stream.
+    pub fn flush(&mut self, output: &mut Vec<f32>) {
+        if let Some(continuation) = self.continuation {
+            output.extend_from_slice(&self.tail);
+            let start = (continuation * self.channels).min(self.pending.len());
+            output.extend_from_slice(&self.pending[start..]);
+        } else {
+            output.extend_from_slice(&self.pending);
+        }
+        self.reset();
+    }
+
+    /// Forgets the samples kept, e.g. after a seek.
+    pub fn reset(&mut self) {
+        self.pending.clear();
+        self.tail.clear();
+        self.continuation = None;
+        self.skip_fraction = 0.0;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::f32::consts::PI;
+
+    const RATE: u32 = 8000;
+
+    // one second of a stereo tone
+    fn tone() -> Vec<f32> {
+        (0..RATE)
+            .flat_map(|frame| {
+                let sample = (2.0 * PI * 440.0 * frame as f32 / RATE as f32).sin();
+                vec![sample, sample * 0.5]
+            })
+            .collect()
+    }
+
+    // frames out of one second given in chunks, at `speed`
+    fn stretched_frames(speed: f64) -> usize {
+        let mut stretch = TimeStretch::new();
+        let mut output = vec![];
+        stretch.set_speed(speed, &mut output);
+        for chunk in tone().chunks(512) {
+            stretch.process(chunk, 2, RATE, &mut output);
+        }
+        stretch.flush(&mut output);
+        assert_eq!(output.len() % 2, 0);
+        output.len() / 2
+    }
+
+    #[test]
+    fn speed_is_clamped() {
+        let mut stretch = TimeStretch::new();
+        let mut output = vec![];
+        stretch.set_speed(10.0, &mut output);
+        assert_eq!(stretch.speed(), MAX_SPEED);
+        stretch.set_speed(0.1, &mut output);
+        assert_eq!(stretch.speed(), MIN_SPEED);
+        stretch.set_speed(1.5, &mut output);
+        assert_eq!(stretch.speed(), 1.5);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn normal_speed_passes_the_samples() {
+        let input = tone();
+        let mut stretch = TimeStretch::new();
+        let mut output = vec![];
+        stretch.process(&input, 2, RATE, &mut output);
+        assert_eq!(output, input);
+        assert_eq!(stretched_frames(1.0), RATE as usize);
+    }
+
+    #[test]
+    fn output_length_follows_the_speed() {
+        // the samples kept when the input stops, less than two segments
+        // once stretched, are flushed as they are
+        let segment = f64::from(RATE * SEGMENT_MILLIS / 1000);
+        for &speed in &[2.0, 0.5] {
+            let expected = f64::from(RATE) / speed;
+            let frames = stretched_frames(speed) as f64;
+            let error = (frames - expected).abs();
+            assert!(error <= 2.0 * segment, "{}x: {}", speed, frames);
+        }
+    }
+
+    #[test]
+    fn speed_change_flushes_the_samples_kept() {
+        let input = tone();
+        let mut stretch = TimeStretch::new();
+        let mut output = vec![];
+        stretch.set_speed(2.0, &mut output);
+        stretch.process(&input[..200], 2, RATE, &mut output);
+        assert!(output.is_empty());
+        stretch.set_speed(1.0, &mut output);
+        assert_eq!(output, &input[..200]);
+    }
+}
@@ -1,9 +1,16 @@
-use std::{ffi::CStr, path::PathBuf, rc::Rc, sync::Arc};
+use std::{
+    cell::Cell,
+    ffi::CStr,
+    path::PathBuf,
+    rc::Rc,
+    sync::{Arc, Mutex},
+};
 
 use gtk::{
-    ApplicationWindow, ContainerExt, DialogExt, FileChooserAction, FileChooserDialog,
-    FileChooserExt, FileFilter, FileFilterExt, Image, ImageExt, SeparatorToolItem, ToolButton,
-    ToolButtonExt, Toolbar, WidgetExt,
+    ApplicationWindow, ComboBoxExt, ComboBoxText, ComboBoxTextExt, ContainerExt, Continue,
+    DialogExt, FileChooserAction, FileChooserDialog, FileChooserExt, FileFilter, FileFilterExt,
+    Image, ImageExt, ScaleButtonExt, SeparatorToolItem, ToggleToolButton, ToggleToolButtonExt,
+    ToolButton, ToolButtonExt, ToolItem, Toolbar, VolumeButton, WidgetExt,
 };
 use gtk_sys::{
     GTK_RESPONSE_ACCEPT, GTK_RESPONSE_CANCEL, GTK_STOCK_MEDIA_PAUSE, GTK_STOCK_MEDIA_PLAY,
@@ -12,7 +19,18 @@ use gtk_sys::{
 
 use libc::c_char;
 
-use crate::playlist::Playlist;
+use mmp::{player::PlaybackState, State};
+
+use crate::{
+    equalizer_window::EqualizerWindow,
+    playlist::{Playlist, RepeatMode},
+};
+
+// the settings changed by a slider are written once it stops, in ms
+const CONFIG_SAVE_DELAY: u32 = 500;
+
+// speeds offered by the selector
+const SPEEDS: [&str; 9] = ["0.5", "0.75", "1", "1.25", "1.5", "1.75", "2", "2.5", "3"];
 
 const PLAY_STOCK: &'static str = "gtk-media-play";
 const PAUSE_STOCK: &'static str = "gtk-media-pause";
@@ -20,6 +38,21 @@ const PAUSE_STOCK: &'static str = "gtk-media-pause";
 pub(crate) const PLAY_ICON: &'static str = PLAY_STOCK;
 pub(crate) const PAUSE_ICON: &'static str = PAUSE_STOCK;
 
+// writes the configuration unless it changes again within the delay,
+// `changes` counts the changes
+fn save_config_later(state: &Arc<Mutex<State>>, changes: &Rc<Cell<u32>>) {
+    let change = changes.get().wrapping_add(1);
+    changes.set(change);
+    let state = Arc::clone(state);
+    let changes = Rc::clone(changes);
+    gtk::timeout_add(CONFIG_SAVE_DELAY, move || {
+        if changes.get() == change {
+            state.lock().unwrap().config.save();
+        }
+        Continue(false)
+    });
+}
+
 pub(crate) fn set_image_icon(button: &ToolButton, icon: *const c_char) {
     let icon = unsafe { CStr::from_ptr(icon).to_str().unwrap() };
     // button.set_stock_id(icon)
@@ -27,14 +60,30 @@ pub(crate) fn set_image_icon(button: &ToolButton, icon: *const c_char) {
 }
 
 pub(crate) struct MusicToolbar {
+    pub chapters_button: ToggleToolButton,
+    pub equalizer_button: ToolButton,
     pub open_button: ToolButton,
     pub next_button: ToolButton,
     pub play_button: ToolButton,
     pub previous_button: ToolButton,
     pub quit_button: ToolButton,
     pub remove_button: ToolButton,
+    pub repeat_button: ToggleToolButton,
+    pub repeat_one_button: ToggleToolButton,
+    pub shuffle_button: ToggleToolButton,
+    pub speed_selector: ComboBoxText,
+    pub stop_after_button: ToggleToolButton,
     pub stop_button: ToolButton,
+    pub mute_button: ToggleToolButton,
     pub toolbar: Toolbar,
+    pub volume_button: VolumeButton,
+}
+
+fn new_toggle_button(icon: &str, tooltip: &str) -> ToggleToolButton {
+    let button = ToggleToolButton::new();
+    button.set_icon_name(icon);
+    button.set_tooltip_text(tooltip);
+    button
 }
 
 impl MusicToolbar {
@@ -60,6 +109,48 @@ impl MusicToolbar {
 
         toolbar.add(&SeparatorToolItem::new());
 
+        let shuffle_button = new_toggle_button("media-playlist-shuffle", "Shuffle");
+        toolbar.add(&shuffle_button);
+
+        let repeat_button = new_toggle_button("media-playlist-repeat", "Repeat all");
+        toolbar.add(&repeat_button);
+
+        let repeat_one_button = new_toggle_button("media-playlist-repeat-song", "Repeat one");
+        toolbar.add(&repeat_one_button);
+
+        let stop_after_button = new_toggle_button("media-playback-stop", "Stop after current");
+        toolbar.add(&stop_after_button);
+
+        let chapters_button = new_toggle_button("view-list", "Step by chapter");
+        toolbar.add(&chapters_button);
+
+        toolbar.add(&SeparatorToolItem::new());
+
+        let mute_button = new_toggle_button("audio-volume-muted", "Mute");
+        toolbar.add(&mute_button);
+
+        let volume_button = VolumeButton::new();
+        let volume_item = ToolItem::new();
+        volume_item.add(&volume_button);
+        toolbar.add(&volume_item);
+
+        let equalizer_button = ToolButton::new(None::<&Image>, "Equalizer");
+        equalizer_button.set_icon_name("multimedia-equalizer");
+        equalizer_button.set_tooltip_text("Equalizer");
+        toolbar.add(&equalizer_button);
+
+        let speed_selector = ComboBoxText::new();
+        for speed in SPEEDS.iter() {
+            speed_selector.append(Some(*speed), &format!("{}×", speed));
+        }
+        speed_selector.set_active_id(Some("1"));
+        speed_selector.set_tooltip_text("Playback speed");
+        let speed_item = ToolItem::new();
+        speed_item.add(&speed_selector);
+        toolbar.add(&speed_item);
+
+        toolbar.add(&SeparatorToolItem::new());
+
         let remove_button = ToolButton::new_from_stock("gtk-remove");
         toolbar.add(&remove_button);
 
@@ -69,14 +160,23 @@ impl MusicToolbar {
         toolbar.add(&quit_button);
 
         MusicToolbar {
+            chapters_button,
+            equalizer_button,
             open_button,
             next_button,
             play_button,
             previous_button,
             quit_button,
             remove_button,
+            repeat_button,
+            repeat_one_button,
+            shuffle_button,
+            speed_selector,
+            stop_after_button,
             stop_button,
+            mute_button,
             toolbar,
+            volume_button,
         }
     }
 }
@@ -89,13 +189,20 @@ impl App {
 
     fn show_open_dialog(parent: &ApplicationWindow) -> Option<PathBuf> {
         let dialog = FileChooserDialog::new(
-            Some("Select an MP3 audio file"),
+            Some("Select an audio file"),
             Some(parent),
             FileChooserAction::Open,
         );
         let filter = FileFilter::new();
         filter.add_mime_type("audio/mp3");
-        filter.set_name("MP3 audio file");
+        filter.add_mime_type("audio/flac");
+        filter.add_mime_type("audio/ogg");
+        filter.add_mime_type("audio/opus");
+        filter.add_mime_type("audio/mp4");
+        filter.add_mime_type("audio/x-m4a");
+        filter.add_mime_type("audio/x-wav");
+        filter.add_mime_type("audio/x-aiff");
+        filter.set_name("Audio file");
         dialog.add_filter(&filter);
         dialog.add_button("Cancel", Self::RESPONSE_CANCEL);
         dialog.add_button("Accept", Self::RESPONSE_ACCEPT);
@@ -118,33 +225,69 @@ impl App {
         // let playlist = self.playlist.clone();
         let playlist = Rc::clone(&self.playlist);
         let cover = self.cover.clone();
-        let state = Arc::clone(&self.state);
 
         let play_button = self.toolbar.play_button.clone();
 
-        self.toolbar.play_button.connect_clicked(move |_| {
-            if state.lock().unwrap().stopped {
-                if playlist.play() {
-                    // play_button.set_stock_id(PAUSE_STOCK);
-                    let icon_name =
-                        unsafe { CStr::from_ptr(GTK_STOCK_MEDIA_PAUSE).to_str().unwrap() };
-                    play_button.set_icon_name(icon_name);
-                    Self::set_cover(&cover, &playlist);
-                } else {
-                    // play_button.set_stock_id(PLAY_STOCK);
-                    let icon_name =
-                        unsafe { CStr::from_ptr(GTK_STOCK_MEDIA_PLAY).to_str().unwrap() };
-                    play_button.set_icon_name(icon_name);
+        self.toolbar
+            .play_button
+            .connect_clicked(move |_| match playlist.state() {
+                PlaybackState::Playing => {
+                    playlist.pause();
+                    set_image_icon(&play_button, GTK_STOCK_MEDIA_PLAY);
+                }
+
+                PlaybackState::Paused => {
+                    playlist.resume();
+                    set_image_icon(&play_button, GTK_STOCK_MEDIA_PAUSE);
+                }
+
+                PlaybackState::Stopped => {
+                    if playlist.play() {
+                        set_image_icon(&play_button, GTK_STOCK_MEDIA_PAUSE);
+                        Self::set_cover(&cover, &playlist);
+                    } else {
+                        set_image_icon(&play_button, GTK_STOCK_MEDIA_PLAY);
+                    }
                 }
+            });
+
+        let playlist = Rc::clone(&self.playlist);
+        let play_button = self.toolbar.play_button.clone();
+        self.toolbar.stop_button.connect_clicked(move |_| {
+            playlist.stop();
+            set_image_icon(&play_button, GTK_STOCK_MEDIA_PLAY);
+        });
+
+        let playlist = Rc::clone(&self.playlist);
+        let cover = self.cover.clone();
+        self.toolbar.next_button.connect_clicked(move |_| {
+            if playlist.next() {
+                Self::set_cover(&cover, &playlist);
+            }
+        });
+
+        let playlist = Rc::clone(&self.playlist);
+        let cover = self.cover.clone();
+        self.toolbar.previous_button.connect_clicked(move |_| {
+            if playlist.previous() {
+                Self::set_cover(&cover, &playlist);
             }
-            // if play_button.get_stock_id() == Some(String::from(PLAY_STOCK)) {
-            //     play_button.set_stock_id(PAUSE_STOCK);
-            //     Self::set_cover(&cover, &playlist);
-            // } else {
-            //     play_button.set_stock_id(PLAY_STOCK);
-            // }
         });
 
+        self.connect_mode_events();
+        self.connect_volume_events();
+        self.connect_speed_events();
+
+        // built now to apply the equalizer of the previous run
+        let equalizer = EqualizerWindow::new(
+            &self.window,
+            Rc::clone(&self.playlist),
+            Arc::clone(&self.state),
+        );
+        self.toolbar
+            .equalizer_button
+            .connect_clicked(move |_| equalizer.show());
+
         let parent = self.window.clone();
         let playlist = Rc::clone(&self.playlist);
         self.toolbar.open_button.connect_clicked(move |_| {
@@ -160,7 +303,115 @@ impl App {
         });
     }
 
-    fn set_cover(cover: &Image, playlist: &Rc<Playlist>) {
+    fn connect_mode_events(&self) {
+        let playlist = Rc::clone(&self.playlist);
+        self.toolbar.shuffle_button.connect_toggled(move |button| {
+            playlist.set_shuffle(button.get_active());
+        });
+
+        // "repeat all" and "repeat one" exclude each other
+        let playlist = Rc::clone(&self.playlist);
+        let repeat_one_button = self.toolbar.repeat_one_button.clone();
+        self.toolbar.repeat_button.connect_toggled(move |button| {
+            if button.get_active() {
+                repeat_one_button.set_active(false);
+                playlist.set_repeat(RepeatMode::All);
+            } else if !repeat_one_button.get_active() {
+                playlist.set_repeat(RepeatMode::Off);
+            }
+        });
+
+        let playlist = Rc::clone(&self.playlist);
+        let repeat_button = self.toolbar.repeat_button.clone();
+        self.toolbar
+            .repeat_one_button
+            .connect_toggled(move |button| {
+                if button.get_active() {
+                    repeat_button.set_active(false);
+                    playlist.set_repeat(RepeatMode::One);
+                } else if !repeat_button.get_active() {
+                    playlist.set_repeat(RepeatMode::Off);
+                }
+            });
+
+        let playlist = Rc::clone(&self.playlist);
+        self.toolbar
+            .stop_after_button
+            .connect_toggled(move |button| {
+                playlist.set_stop_after_current(button.get_active());
+            });
+
+        // next and previous move between the chapters of a podcast or an
+        // audiobook
+        let playlist = Rc::clone(&self.playlist);
+        self.toolbar.chapters_button.connect_toggled(move |button| {
+            playlist.set_chapter_steps(button.get_active());
+        });
+    }
+
+    // restores the volume of the previous run, then saves the changes
+    fn connect_volume_events(&self) {
+        let (volume, muted) = {
+            let state = self.state.lock().unwrap();
+            let volume = state.config.get::<f64>("volume").unwrap_or(1.0);
+            let muted = state.config.get::<bool>("muted").unwrap_or(false);
+            (volume, muted)
+        };
+        self.toolbar.volume_button.set_value(volume);
+        self.toolbar.mute_button.set_active(muted);
+        self.playlist.set_volume(volume);
+        self.playlist.mute(muted);
+
+        let changes = Rc::new(Cell::new(0));
+        let playlist = Rc::clone(&self.playlist);
+        let state = Arc::clone(&self.state);
+        let volume_changes = Rc::clone(&changes);
+        self.toolbar
+            .volume_button
+            .connect_value_changed(move |_, volume| {
+                playlist.set_volume(volume);
+                state.lock().unwrap().config.update("volume", volume);
+                save_config_later(&state, &volume_changes);
+            });
+
+        let playlist = Rc::clone(&self.playlist);
+        let state = Arc::clone(&self.state);
+        self.toolbar.mute_button.connect_toggled(move |button| {
+            let muted = button.get_active();
+            playlist.mute(muted);
+            state.lock().unwrap().config.update("muted", muted);
+            save_config_later(&state, &changes);
+        });
+    }
+
+    // restores the speed of the previous run, then saves the changes
+    fn connect_speed_events(&self) {
+        let speed = self.state.lock().unwrap().config.get::<f64>("speed");
+        if let Some(speed) = speed {
+            self.toolbar
+                .speed_selector
+                .set_active_id(Some(speed.to_string().as_str()));
+            self.playlist.set_speed(speed);
+        }
+
+        let changes = Rc::new(Cell::new(0));
+        let playlist = Rc::clone(&self.playlist);
+        let state = Arc::clone(&self.state);
+        self.toolbar
+            .speed_selector
+            .connect_changed(move |selector| {
+                let speed = selector
+                    .get_active_id()
+                    .and_then(|speed| speed.parse::<f64>().ok());
+                if let Some(speed) = speed {
+                    playlist.set_speed(speed);
+                    state.lock().unwrap().config.update("speed", speed);
+                    save_config_later(&state, &changes);
+                }
+            });
+    }
+
+    pub(crate) fn set_cover(cover: &Image, playlist: &Rc<Playlist>) {
         cover.set_from_pixbuf(playlist.pixbuf().as_ref());
         cover.show();
     }
@@ -0,0 +1,58 @@
+// duration of the ramp between two gains, short enough to feel immediate
+// but long enough to avoid the "zipper" noise of abrupt changes
+const RAMP_MILLIS: u32 = 30;
+
+/// Converts the position of a volume slider in [0, 1] to a gain: the
+/// loudness perceived is closer to the cube of the amplitude.
+pub fn volume_to_gain(volume: f64) -> f32 {
+    let volume = volume.max(0.0).min(1.0) as f32;
+    volume * volume * volume
+}
+
+/// Software gain applied to the samples before they go to the output.
+pub struct Gain {
+    current: f32,
+    target: f32,
+    step: f32,
+}
+
+impl Gain {
+    pub fn new(gain: f32) -> Self {
+        Gain {
+            current: gain,
+            target: gain,
+            step: 0.0,
+        }
+    }
+
+    /// Moves to `gain` progressively over a few milliseconds of audio.
+    pub fn set(&mut self, gain: f32, sample_rate: u32) {
+        let frames = (sample_rate * RAMP_MILLIS / 1000).max(1);
+        self.target = gain;
+        self.step = (gain - self.current) / frames as f32;
+    }
+
+    /// Scales interleaved samples, the ramp advances once per frame.
+    pub fn apply(&mut self, samples: &mut [f32], channels: u16) {
+        for frame in samples.chunks_mut(channels.max(1) as usize) {
+            if self.current != self.target {
+                self.current += self.step;
+                let reached = if self.step > 0.0 {
+                    self.current >= self.target
+                } else {
+                    self.current <= self.target
+                };
+                if reached {
+                    self.current = self.target;
+                }
+            }
+
+            if self.current == 1.0 {
+                continue;
+            }
+            for sample in frame.iter_mut() {
+                *sample *= self.current;
+            }
+        }
+    }
+}